@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+use stripe::Client;
+
+use crate::telemetry;
+use crate::url::validate_id;
+use crate::url::StripeUrl;
+use crate::StripePaymentError;
+
+/// Money pulled from a connected account's balance back to the platform.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct AccountDebitDto {
+    pub id: String,
+    pub account_id: String,
+    pub amount: i64,
+    pub currency: String,
+    pub status: String,
+    pub description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawAccount {
+    default_currency: String,
+}
+
+#[derive(Deserialize)]
+struct RawCharge {
+    id: String,
+    amount: i64,
+    currency: String,
+    status: String,
+    description: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DebitParams<'a> {
+    amount: i64,
+    currency: String,
+    source: &'a str,
+    description: &'a str,
+}
+
+fn checked_debit(account_id: &str, amount: i64) -> Result<(), StripePaymentError> {
+    validate_id(account_id, "acct")?;
+    if amount <= 0 {
+        return Err(StripePaymentError::from_general(format!(
+            "debit amount must be positive, got {}",
+            amount
+        )));
+    }
+    Ok(())
+}
+
+/// Debits `amount` from a connected account, e.g. to recover a refund that was
+/// already paid out to the seller. Stripe only allows this for Express and
+/// Custom accounts in the platform's region, and the debit is made in the
+/// account's default currency. If the account's balance is insufficient Stripe
+/// debits its external account instead.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn create_account_debit(
+    stripe_client: &Client,
+    account_id: &str,
+    amount: i64,
+    description: &str,
+) -> Result<AccountDebitDto, StripePaymentError> {
+    checked_debit(account_id, amount)?;
+    let account = telemetry::observe(
+        "accounts.retrieve",
+        stripe_client.get::<RawAccount>(&StripeUrl::new("/accounts").segment(account_id).build()),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+    let charge = telemetry::observe(
+        "charges.create",
+        stripe_client.post_form::<RawCharge, _>(
+            "/charges",
+            DebitParams {
+                amount,
+                currency: account.default_currency,
+                source: account_id,
+                description,
+            },
+        ),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+    Ok(AccountDebitDto {
+        id: charge.id,
+        account_id: account_id.to_string(),
+        amount: charge.amount,
+        currency: charge.currency,
+        status: charge.status,
+        description: charge.description,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_debits() {
+        assert!(checked_debit("acct_1", 1500).is_ok());
+        assert!(checked_debit("cus_1", 1500).is_err());
+        assert!(checked_debit("acct_1", 0).is_err());
+    }
+}
@@ -0,0 +1,397 @@
+use std::fmt::{Display, Formatter};
+
+use serde::Serialize;
+
+use crate::{CreatePaymentIntentShipping, CreatePaymentIntentShippingAddress, StripePaymentError};
+
+const COUNTRIES: &str =
+    "AD AE AF AG AI AL AM AO AQ AR AS AT AU AW AX AZ BA BB BD BE BF BG BH BI BJ BL \
+BM BN BO BQ BR BS BT BV BW BY BZ CA CC CD CF CG CH CI CK CL CM CN CO CR CU CV CW CX CY CZ DE DJ DK \
+DM DO DZ EC EE EG EH ER ES ET FI FJ FK FM FO FR GA GB GD GE GF GG GH GI GL GM GN GP GQ GR GS GT GU \
+GW GY HK HM HN HR HT HU ID IE IL IM IN IO IQ IR IS IT JE JM JO JP KE KG KH KI KM KN KP KR KW KY KZ \
+LA LB LC LI LK LR LS LT LU LV LY MA MC MD ME MF MG MH MK ML MM MN MO MP MQ MR MS MT MU MV MW MX MY \
+MZ NA NC NE NF NG NI NL NO NP NR NU NZ OM PA PE PF PG PH PK PL PM PN PR PS PT PW PY QA RE RO RS RU \
+RW SA SB SC SD SE SG SH SI SJ SK SL SM SN SO SR SS ST SV SX SY SZ TC TD TF TG TH TJ TK TL TM TN TO \
+TR TT TV TW TZ UA UG UM US UY UZ VA VC VE VG VI VN VU WF WS YE YT ZA ZM ZW";
+
+const US_STATES: &str = "AL AK AZ AR CA CO CT DE DC FL GA HI ID IL IN IA KS KY LA ME MD MA MI MN MS \
+MO MT NE NV NH NJ NM NY NC ND OH OK OR PA RI SC SD TN TX UT VT VA WA WV WI WY AS GU MP PR VI UM AA AE AP";
+
+const CA_PROVINCES: &str = "AB BC MB NB NL NS NT NU ON PE QC SK YT";
+
+const AU_STATES: &str = "ACT NSW NT QLD SA TAS VIC WA";
+
+// '9' is a digit, 'A' a letter, '?' either; anything else must match literally.
+fn postal_patterns(country: &str) -> Option<&'static [&'static str]> {
+    Some(match country {
+        "US" => &["99999", "99999-9999"],
+        "CA" => &["A9A 9A9"],
+        "GB" => &[
+            "A9 9AA", "A99 9AA", "AA9 9AA", "AA99 9AA", "A9A 9AA", "AA9A 9AA",
+        ],
+        "NL" => &["9999 AA"],
+        "SE" => &["999 99"],
+        "BR" => &["99999-999"],
+        "PL" => &["99-999"],
+        "PT" => &["9999-999"],
+        "JP" => &["999-9999"],
+        "IN" => &["999999"],
+        "DE" | "FR" | "IT" | "ES" | "FI" | "MX" => &["99999"],
+        "AT" | "AU" | "BE" | "CH" | "DK" | "NO" | "NZ" | "ZA" => &["9999"],
+        _ => return None,
+    })
+}
+
+fn matches_pattern(value: &str, pattern: &str) -> bool {
+    value.len() == pattern.len()
+        && value.chars().zip(pattern.chars()).all(|(v, p)| match p {
+            '9' => v.is_ascii_digit(),
+            'A' => v.is_ascii_alphabetic(),
+            '?' => v.is_ascii_alphanumeric(),
+            other => v == other,
+        })
+}
+
+fn normalize_postal_code(country: &str, postal_code: &str) -> String {
+    let compact = postal_code
+        .split_whitespace()
+        .collect::<String>()
+        .to_uppercase();
+    match country {
+        "CA" | "GB" if compact.len() > 3 => {
+            format!(
+                "{} {}",
+                &compact[..compact.len() - 3],
+                &compact[compact.len() - 3..]
+            )
+        }
+        "NL" if compact.len() == 6 => format!("{} {}", &compact[..4], &compact[4..]),
+        "SE" if compact.len() == 5 => format!("{} {}", &compact[..3], &compact[3..]),
+        _ => postal_code
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_uppercase(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressValidationError {
+    MissingField(&'static str),
+    UnknownCountry(String),
+    InvalidPostalCode {
+        country: String,
+        postal_code: String,
+    },
+    InvalidState {
+        country: String,
+        state: String,
+    },
+}
+
+impl Display for AddressValidationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddressValidationError::MissingField(x) => write!(f, "address {} is required", x),
+            AddressValidationError::UnknownCountry(x) => {
+                write!(f, "{} is not an ISO 3166-1 alpha-2 country code", x)
+            }
+            AddressValidationError::InvalidPostalCode {
+                country,
+                postal_code,
+            } => write!(f, "{} is not a valid {} postal code", postal_code, country),
+            AddressValidationError::InvalidState { country, state } => {
+                write!(f, "{} is not a valid {} state", state, country)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AddressValidationError {}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct AddressDto {
+    pub line1: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line2: Option<String>,
+    pub city: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub postal_code: Option<String>,
+    pub country: String,
+}
+
+impl AddressDto {
+    pub fn new(
+        line1: impl Into<String>,
+        city: impl Into<String>,
+        country: impl Into<String>,
+    ) -> Self {
+        AddressDto {
+            line1: line1.into(),
+            line2: None,
+            city: city.into(),
+            state: None,
+            postal_code: None,
+            country: country.into(),
+        }
+    }
+
+    pub fn with_line2(mut self, line2: impl Into<String>) -> Self {
+        self.line2 = Some(line2.into());
+        self
+    }
+
+    pub fn with_state(mut self, state: impl Into<String>) -> Self {
+        self.state = Some(state.into());
+        self
+    }
+
+    pub fn with_postal_code(mut self, postal_code: impl Into<String>) -> Self {
+        self.postal_code = Some(postal_code.into());
+        self
+    }
+
+    /// Trims every field, upper-cases country and state codes and formats
+    /// postal codes the way the destination country writes them.
+    pub fn normalize(&self) -> AddressDto {
+        let trim = |x: &str| x.split_whitespace().collect::<Vec<_>>().join(" ");
+        let country = self.country.trim().to_uppercase();
+        AddressDto {
+            line1: trim(&self.line1),
+            line2: self.line2.as_deref().map(trim).filter(|x| !x.is_empty()),
+            city: trim(&self.city),
+            state: self
+                .state
+                .as_deref()
+                .map(|x| trim(x).to_uppercase())
+                .filter(|x| !x.is_empty()),
+            postal_code: self
+                .postal_code
+                .as_deref()
+                .map(|x| normalize_postal_code(&country, x))
+                .filter(|x| !x.is_empty()),
+            country,
+        }
+    }
+
+    /// Validates the normalized form of the address, collecting every problem.
+    pub fn validate(&self) -> Result<AddressDto, Vec<AddressValidationError>> {
+        let address = self.normalize();
+        let mut errors = vec![];
+        if address.line1.is_empty() {
+            errors.push(AddressValidationError::MissingField("line1"));
+        }
+        if address.city.is_empty() {
+            errors.push(AddressValidationError::MissingField("city"));
+        }
+        let country = address.country.as_str();
+        if country.len() != 2 || !COUNTRIES.split(' ').any(|x| x == country) {
+            errors.push(AddressValidationError::UnknownCountry(
+                address.country.clone(),
+            ));
+        }
+        if let Some(patterns) = postal_patterns(country) {
+            match &address.postal_code {
+                Some(postal_code) if !patterns.iter().any(|x| matches_pattern(postal_code, x)) => {
+                    errors.push(AddressValidationError::InvalidPostalCode {
+                        country: address.country.clone(),
+                        postal_code: postal_code.clone(),
+                    })
+                }
+                Some(_) => {}
+                None => errors.push(AddressValidationError::MissingField("postal_code")),
+            }
+        }
+        let states = match country {
+            "US" => Some(US_STATES),
+            "CA" => Some(CA_PROVINCES),
+            "AU" => Some(AU_STATES),
+            _ => None,
+        };
+        if let Some(states) = states {
+            match &address.state {
+                Some(state) if !states.split(' ').any(|x| x == state) => {
+                    errors.push(AddressValidationError::InvalidState {
+                        country: address.country.clone(),
+                        state: state.clone(),
+                    })
+                }
+                Some(_) => {}
+                None => errors.push(AddressValidationError::MissingField("state")),
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(address),
+            false => Err(errors),
+        }
+    }
+
+    pub fn to_shipping(
+        &self,
+        name: impl Into<String>,
+        phone: Option<String>,
+    ) -> CreatePaymentIntentShipping {
+        CreatePaymentIntentShipping {
+            address: CreatePaymentIntentShippingAddress {
+                city: Some(self.city.clone()),
+                country: Some(self.country.clone()),
+                line1: Some(self.line1.clone()),
+                line2: self.line2.clone(),
+                postal_code: self.postal_code.clone(),
+                state: self.state.clone(),
+            },
+            carrier: None,
+            name: name.into(),
+            phone,
+            tracking_number: None,
+        }
+    }
+}
+
+impl From<&CreatePaymentIntentShippingAddress> for AddressDto {
+    fn from(x: &CreatePaymentIntentShippingAddress) -> Self {
+        AddressDto {
+            line1: x.line1.clone().unwrap_or_default(),
+            line2: x.line2.clone(),
+            city: x.city.clone().unwrap_or_default(),
+            state: x.state.clone(),
+            postal_code: x.postal_code.clone(),
+            country: x.country.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// Who pays, as opposed to where the goods are delivered. The billing address
+/// is what Stripe Tax uses to determine the tax jurisdiction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct BillingDetailsDto {
+    pub address: AddressDto,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phone: Option<String>,
+}
+
+impl BillingDetailsDto {
+    pub fn new(address: AddressDto) -> Self {
+        BillingDetailsDto {
+            address,
+            name: None,
+            email: None,
+            phone: None,
+        }
+    }
+
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn with_email(mut self, email: impl Into<String>) -> Self {
+        self.email = Some(email.into());
+        self
+    }
+
+    pub fn with_phone(mut self, phone: impl Into<String>) -> Self {
+        self.phone = Some(phone.into());
+        self
+    }
+}
+
+/// Validates and normalizes the address of a shipping record in place.
+pub fn validate_shipping(
+    shipping: &CreatePaymentIntentShipping,
+) -> Result<CreatePaymentIntentShipping, Vec<AddressValidationError>> {
+    let address = AddressDto::from(&shipping.address).validate()?;
+    let mut shipping = shipping.clone();
+    shipping.address = address.to_shipping(String::new(), None).address;
+    Ok(shipping)
+}
+
+pub(crate) fn checked_shipping(
+    shipping: &Option<CreatePaymentIntentShipping>,
+) -> Result<Option<CreatePaymentIntentShipping>, StripePaymentError> {
+    shipping
+        .as_ref()
+        .map(validate_shipping)
+        .transpose()
+        .map_err(|x| {
+            StripePaymentError::from_general(
+                x.iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            )
+        })
+}
+
+pub(crate) fn checked_billing_details(
+    billing_details: &Option<BillingDetailsDto>,
+) -> Result<Option<BillingDetailsDto>, StripePaymentError> {
+    billing_details
+        .as_ref()
+        .map(|x| {
+            x.address.validate().map(|address| BillingDetailsDto {
+                address,
+                ..x.clone()
+            })
+        })
+        .transpose()
+        .map_err(|x| {
+            StripePaymentError::from_general(
+                x.iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_country_specific_rules() {
+        let address = AddressDto::new(" 1  Main St ", "Toronto", "ca")
+            .with_state("on")
+            .with_postal_code("m5v3l9")
+            .validate()
+            .unwrap();
+        assert_eq!(address.line1, "1 Main St");
+        assert_eq!(address.postal_code.as_deref(), Some("M5V 3L9"));
+
+        let errors = AddressDto::new("1 Main St", "Springfield", "US")
+            .with_state("XX")
+            .with_postal_code("1234")
+            .validate()
+            .unwrap_err();
+        assert_eq!(errors.len(), 2);
+
+        assert_eq!(
+            AddressDto::new("1 Rue", "Paris", "ZZ")
+                .validate()
+                .unwrap_err(),
+            vec![AddressValidationError::UnknownCountry("ZZ".to_string())]
+        );
+    }
+
+    #[test]
+    fn normalizes_billing_address() {
+        let billing = BillingDetailsDto::new(
+            AddressDto::new("1 Main St", "Amsterdam", "nl").with_postal_code("1011ab"),
+        )
+        .with_name("Jane Doe");
+        let billing = checked_billing_details(&Some(billing)).unwrap().unwrap();
+        assert_eq!(billing.address.postal_code.as_deref(), Some("1011 AB"));
+        assert_eq!(billing.name.as_deref(), Some("Jane Doe"));
+        let invalid = BillingDetailsDto::new(AddressDto::new("", "Paris", "FR"));
+        assert!(checked_billing_details(&Some(invalid)).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+use crate::metadata::MetadataNamespace;
+
+pub const AMOUNT_ITEMS: &str = "amount_items";
+pub const AMOUNT_SHIPPING: &str = "amount_shipping";
+pub const AMOUNT_TAX: &str = "amount_tax";
+pub const AMOUNT_DISCOUNT: &str = "amount_discount";
+pub const AMOUNT_TOTAL: &str = "amount_total";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AmountError {
+    Overflow,
+    Negative(i64),
+    CurrencyMismatch { expected: String, found: String },
+    DiscountExceedsSubtotal { subtotal: i64, discount: i64 },
+}
+
+impl Display for AmountError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AmountError::Overflow => write!(f, "amount overflows"),
+            AmountError::Negative(x) => write!(f, "amount component {} is negative", x),
+            AmountError::CurrencyMismatch { expected, found } => {
+                write!(f, "expected an amount in {}, got {}", expected, found)
+            }
+            AmountError::DiscountExceedsSubtotal { subtotal, discount } => {
+                write!(f, "discount {} exceeds the subtotal {}", discount, subtotal)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AmountError {}
+
+/// Composes a payment amount in minor units. Every component is added in an
+/// explicit currency so mixing currencies is an error rather than a silent
+/// conversion; the first problem is reported by [`AmountBreakdown::total`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AmountBreakdown {
+    currency: String,
+    items: i64,
+    shipping: i64,
+    tax: i64,
+    discount: i64,
+    error: Option<AmountError>,
+}
+
+impl AmountBreakdown {
+    pub fn new(currency: impl Into<String>) -> Self {
+        AmountBreakdown {
+            currency: currency.into().to_lowercase(),
+            items: 0,
+            shipping: 0,
+            tax: 0,
+            discount: 0,
+            error: None,
+        }
+    }
+
+    pub fn currency(&self) -> &str {
+        &self.currency
+    }
+
+    fn add(mut self, currency: &str, amount: Option<i64>, to: fn(&mut Self) -> &mut i64) -> Self {
+        if self.error.is_some() {
+            return self;
+        }
+        let currency = currency.to_lowercase();
+        self.error = match amount {
+            _ if currency != self.currency => Some(AmountError::CurrencyMismatch {
+                expected: self.currency.clone(),
+                found: currency,
+            }),
+            None => Some(AmountError::Overflow),
+            Some(x) if x < 0 => Some(AmountError::Negative(x)),
+            Some(x) => {
+                let total = to(&mut self);
+                match total.checked_add(x) {
+                    Some(x) => {
+                        *total = x;
+                        None
+                    }
+                    None => Some(AmountError::Overflow),
+                }
+            }
+        };
+        self
+    }
+
+    pub fn with_item(self, currency: &str, quantity: u64, unit_amount: i64) -> Self {
+        let amount = i64::try_from(quantity)
+            .ok()
+            .and_then(|x| x.checked_mul(unit_amount));
+        self.add(currency, amount, |x| &mut x.items)
+    }
+
+    pub fn with_shipping(self, currency: &str, amount: i64) -> Self {
+        self.add(currency, Some(amount), |x| &mut x.shipping)
+    }
+
+    pub fn with_tax(self, currency: &str, amount: i64) -> Self {
+        self.add(currency, Some(amount), |x| &mut x.tax)
+    }
+
+    /// Discounts are given as positive amounts and subtracted from the items.
+    pub fn with_discount(self, currency: &str, amount: i64) -> Self {
+        self.add(currency, Some(amount), |x| &mut x.discount)
+    }
+
+    /// Items minus discount, plus shipping and tax.
+    pub fn total(&self) -> Result<i64, AmountError> {
+        if let Some(x) = &self.error {
+            return Err(x.clone());
+        }
+        if self.discount > self.items {
+            return Err(AmountError::DiscountExceedsSubtotal {
+                subtotal: self.items,
+                discount: self.discount,
+            });
+        }
+        (self.items - self.discount)
+            .checked_add(self.shipping)
+            .and_then(|x| x.checked_add(self.tax))
+            .ok_or(AmountError::Overflow)
+    }
+
+    /// The components and total as namespaced metadata, for reconciling the
+    /// PaymentIntent amount later.
+    pub fn metadata(
+        &self,
+        namespace: &MetadataNamespace,
+    ) -> Result<HashMap<String, String>, AmountError> {
+        let total = self.total()?;
+        let mut metadata = HashMap::new();
+        for (key, value) in [
+            (AMOUNT_ITEMS, self.items),
+            (AMOUNT_SHIPPING, self.shipping),
+            (AMOUNT_TAX, self.tax),
+            (AMOUNT_DISCOUNT, self.discount),
+            (AMOUNT_TOTAL, total),
+        ] {
+            namespace.insert(&mut metadata, key, value.to_string());
+        }
+        Ok(metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sums_components_with_checks() {
+        let breakdown = AmountBreakdown::new("EUR")
+            .with_item("eur", 3, 333)
+            .with_item("eur", 1, 1)
+            .with_shipping("eur", 495)
+            .with_tax("eur", 190)
+            .with_discount("eur", 100);
+        assert_eq!(breakdown.total(), Ok(1585));
+        let metadata = breakdown.metadata(&MetadataNamespace::default()).unwrap();
+        assert_eq!(
+            MetadataNamespace::default().get(&metadata, AMOUNT_TOTAL),
+            Some("1585")
+        );
+
+        assert_eq!(
+            AmountBreakdown::new("eur").with_tax("usd", 1).total(),
+            Err(AmountError::CurrencyMismatch {
+                expected: "eur".to_string(),
+                found: "usd".to_string()
+            })
+        );
+        assert_eq!(
+            AmountBreakdown::new("eur")
+                .with_item("eur", 2, i64::MAX)
+                .total(),
+            Err(AmountError::Overflow)
+        );
+        assert!(AmountBreakdown::new("eur")
+            .with_item("eur", 1, 100)
+            .with_discount("eur", 101)
+            .total()
+            .is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use std::fmt::{Display, Formatter};
+
+#[cfg(feature = "runtime-tokio-hyper")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "runtime-tokio-hyper")]
+use stripe::{ApiVersion, Client, Headers};
+
+#[cfg(feature = "runtime-tokio-hyper")]
+use crate::pagination::RawList;
+#[cfg(feature = "runtime-tokio-hyper")]
+use crate::telemetry;
+#[cfg(feature = "runtime-tokio-hyper")]
+use crate::StripePaymentError;
+
+/// The API version the request and webhook payload types in this crate are
+/// written against.
+pub const DEFAULT_API_VERSION: &str = "2020-08-27";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiVersionCheck {
+    Matches(String),
+    Drifted {
+        pinned: String,
+        account: String,
+    },
+    /// The account has no events yet, so its default version can't be read.
+    Unknown,
+}
+
+impl Display for ApiVersionCheck {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiVersionCheck::Matches(x) => write!(f, "api version {} matches the account", x),
+            ApiVersionCheck::Drifted { pinned, account } => write!(
+                f,
+                "api version is pinned to {} but the account defaults to {}",
+                pinned, account
+            ),
+            ApiVersionCheck::Unknown => write!(f, "account api version is unknown"),
+        }
+    }
+}
+
+impl ApiVersionCheck {
+    pub fn compare(pinned: &str, account: Option<&str>) -> ApiVersionCheck {
+        match account {
+            Some(x) if x == pinned => ApiVersionCheck::Matches(x.to_string()),
+            Some(x) => ApiVersionCheck::Drifted {
+                pinned: pinned.to_string(),
+                account: x.to_string(),
+            },
+            None => ApiVersionCheck::Unknown,
+        }
+    }
+}
+
+#[cfg(feature = "runtime-tokio-hyper")]
+#[derive(Deserialize)]
+struct RawEvent {
+    api_version: Option<String>,
+}
+
+#[cfg(feature = "runtime-tokio-hyper")]
+#[derive(Serialize)]
+struct LimitParams {
+    limit: u64,
+}
+
+#[cfg(feature = "runtime-tokio-hyper")]
+/// Reads the account's default API version from its most recent event, which
+/// Stripe renders (and sends to webhook endpoints) with that version.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn account_api_version(
+    stripe_client: &Client,
+) -> Result<Option<String>, StripePaymentError> {
+    telemetry::observe(
+        "events.list",
+        stripe_client.get_query::<RawList<RawEvent>, _>("/events", LimitParams { limit: 1 }),
+    )
+    .await
+    .map(|x| x.data.into_iter().next().and_then(|x| x.api_version))
+    .map_err(StripePaymentError::from_general)
+}
+
+#[cfg(feature = "runtime-tokio-hyper")]
+/// Compares `pinned` against the account's default version and logs a warning
+/// on drift. With `strict`, drift is returned as an error instead, which is
+/// meant for startup checks that should refuse to run.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn check_api_version(
+    stripe_client: &Client,
+    pinned: &str,
+    strict: bool,
+) -> Result<ApiVersionCheck, StripePaymentError> {
+    let check =
+        ApiVersionCheck::compare(pinned, account_api_version(stripe_client).await?.as_deref());
+    match &check {
+        ApiVersionCheck::Drifted { .. } if strict => {
+            return Err(StripePaymentError::from_general(check.to_string()))
+        }
+        ApiVersionCheck::Drifted { .. } => tracing::warn!("{}", check),
+        _ => {}
+    }
+    Ok(check)
+}
+
+#[cfg(feature = "runtime-tokio-hyper")]
+/// Sends `api_version` as the `Stripe-Version` header on every request made
+/// through the returned client. A version async-stripe doesn't know is logged
+/// and the client is returned unchanged.
+pub fn pin_client(client: Client, api_version: &str) -> Client {
+    match serde_json::from_value::<ApiVersion>(api_version.into()) {
+        Ok(x) => client.with_headers(Headers {
+            stripe_version: Some(x),
+            ..Headers::default()
+        }),
+        Err(_) => {
+            tracing::warn!("unknown stripe api version {}, not pinning it", api_version);
+            client
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_drift() {
+        assert_eq!(
+            ApiVersionCheck::compare("2020-08-27", Some("2020-08-27")),
+            ApiVersionCheck::Matches("2020-08-27".to_string())
+        );
+        assert!(matches!(
+            ApiVersionCheck::compare("2020-08-27", Some("2023-10-16")),
+            ApiVersionCheck::Drifted { account, .. } if account == "2023-10-16"
+        ));
+        assert_eq!(
+            ApiVersionCheck::compare("2020-08-27", None),
+            ApiVersionCheck::Unknown
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use stripe::{Client, PaymentIntentStatus, StripeError};
+use tokio::runtime::{Builder, Runtime};
+
+use crate::command::{CommandOutcome, OutboxEntry};
+use crate::pagination::{CreatedRange, Page, PageRequest};
+use crate::payment_intent::{CapturePaymentDto, PaymentIntentSummaryDto};
+use crate::refund::RefundStatusDto;
+use crate::{
+    CreateCustomerDto, CreatePaymentIntentDto, CustomerDto, GuestPaymentIntentDto,
+    GuestPaymentOptions, PaymentIntentDto, StripePaymentError,
+};
+
+/// Synchronous wrapper around the async helpers for CLI tools and scripts.
+/// Owns a current-thread runtime, so it must not be used from within an async context.
+pub struct BlockingClient {
+    client: Client,
+    runtime: Runtime,
+}
+
+impl std::fmt::Debug for BlockingClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlockingClient").finish()
+    }
+}
+
+impl BlockingClient {
+    pub fn new(secret_key: impl Into<String>) -> Result<Self, StripePaymentError> {
+        BlockingClient::from_client(Client::new(secret_key))
+    }
+
+    pub fn from_client(client: Client) -> Result<Self, StripePaymentError> {
+        let runtime = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|x| StripePaymentError::from_general(x.to_string()))?;
+        Ok(BlockingClient { client, runtime })
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn get_customer(&self, account_id: String) -> Result<CustomerDto, StripeError> {
+        self.runtime
+            .block_on(crate::get_customer(&self.client, account_id))
+    }
+
+    pub fn create_customer(
+        &self,
+        dto: &CreateCustomerDto,
+    ) -> Result<CustomerDto, StripePaymentError> {
+        self.runtime
+            .block_on(crate::create_customer(&self.client, dto))
+    }
+
+    pub fn create_payment_sheet(
+        &self,
+        dto: &CreatePaymentIntentDto,
+    ) -> Result<PaymentIntentDto, StripePaymentError> {
+        self.runtime
+            .block_on(crate::create_payment_sheet(&self.client, dto))
+    }
+
+    pub fn create_guest_payment_sheet(
+        &self,
+        amount: i64,
+        currency: &str,
+        options: &GuestPaymentOptions,
+    ) -> Result<GuestPaymentIntentDto, StripePaymentError> {
+        self.runtime.block_on(crate::create_guest_payment_sheet(
+            &self.client,
+            amount,
+            currency,
+            options,
+        ))
+    }
+
+    pub fn list_payment_intents(
+        &self,
+        stripe_customer_id: &str,
+        status_filter: &[PaymentIntentStatus],
+        created_range: Option<CreatedRange>,
+        page: &PageRequest,
+    ) -> Result<Page<PaymentIntentSummaryDto>, StripePaymentError> {
+        self.runtime
+            .block_on(crate::payment_intent::list_payment_intents(
+                &self.client,
+                stripe_customer_id,
+                status_filter,
+                created_range,
+                page,
+            ))
+    }
+
+    pub fn capture_payment(
+        &self,
+        dto: &CapturePaymentDto,
+    ) -> Result<PaymentIntentSummaryDto, StripePaymentError> {
+        self.runtime
+            .block_on(crate::payment_intent::capture_payment(&self.client, dto))
+    }
+
+    pub fn sync_refund_status(
+        &self,
+        refund_id: &str,
+    ) -> Result<RefundStatusDto, StripePaymentError> {
+        self.runtime
+            .block_on(crate::refund::sync_refund_status(&self.client, refund_id))
+    }
+
+    pub fn execute_command(
+        &self,
+        entry: &OutboxEntry,
+    ) -> Result<CommandOutcome, StripePaymentError> {
+        self.runtime
+            .block_on(crate::command::execute_command(&self.client, entry))
+    }
+}
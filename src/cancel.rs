@@ -0,0 +1,83 @@
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+
+pub use tokio_util::sync::CancellationToken;
+
+use crate::StripePaymentError;
+
+#[derive(Debug)]
+pub enum CancellableError {
+    Cancelled,
+    Stripe(StripePaymentError),
+}
+
+impl Display for CancellableError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CancellableError::Cancelled => write!(f, "operation was cancelled"),
+            CancellableError::Stripe(x) => write!(f, "{:?}", x),
+        }
+    }
+}
+
+impl std::error::Error for CancellableError {}
+
+impl From<StripePaymentError> for CancellableError {
+    fn from(x: StripePaymentError) -> Self {
+        CancellableError::Stripe(x)
+    }
+}
+
+impl CancellableError {
+    pub fn into_stripe_error(self) -> StripePaymentError {
+        match self {
+            CancellableError::Cancelled => {
+                StripePaymentError::from_general("operation was cancelled".to_string())
+            }
+            CancellableError::Stripe(x) => x,
+        }
+    }
+}
+
+pub(crate) fn check(cancel: Option<&CancellationToken>) -> Result<(), CancellableError> {
+    match cancel {
+        Some(x) if x.is_cancelled() => Err(CancellableError::Cancelled),
+        _ => Ok(()),
+    }
+}
+
+/// Awaits `future` unless `cancel` fires first, in which case the future is
+/// dropped. Only use this around requests that are safe to abandon.
+pub(crate) async fn or_cancelled<T, F>(
+    cancel: Option<&CancellationToken>,
+    future: F,
+) -> Result<T, CancellableError>
+where
+    F: Future<Output = Result<T, StripePaymentError>>,
+{
+    match cancel {
+        Some(cancel) => tokio::select! {
+            biased;
+            _ = cancel.cancelled() => Err(CancellableError::Cancelled),
+            x = future => x.map_err(CancellableError::Stripe),
+        },
+        None => future.await.map_err(CancellableError::Stripe),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancelled_token_fails_check() {
+        let cancel = CancellationToken::new();
+        assert!(check(Some(&cancel)).is_ok());
+        cancel.cancel();
+        assert!(matches!(
+            check(Some(&cancel)),
+            Err(CancellableError::Cancelled)
+        ));
+        assert!(check(None).is_ok());
+    }
+}
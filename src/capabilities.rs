@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use stripe::Client;
+use tokio::sync::RwLock;
+
+use crate::telemetry;
+use crate::url::StripeUrl;
+use crate::{CreatePaymentIntentDto, PaymentIntentDto, StripePaymentError};
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct AccountCapabilities {
+    pub account_id: String,
+    pub country: String,
+    pub default_currency: String,
+    pub supported_currencies: Vec<String>,
+    pub capabilities: HashMap<String, String>,
+}
+
+impl AccountCapabilities {
+    pub fn supports_currency(&self, currency: &str) -> bool {
+        let currency = currency.to_lowercase();
+        self.supported_currencies.iter().any(|x| *x == currency)
+    }
+
+    pub fn is_active(&self, capability: &str) -> bool {
+        self.capabilities.get(capability).map(|x| x.as_str()) == Some("active")
+    }
+
+    pub fn validate_payment(&self, dto: &CreatePaymentIntentDto) -> Result<(), CapabilityError> {
+        if !self.supports_currency(&dto.currency) {
+            return Err(CapabilityError::UnsupportedCurrency {
+                currency: dto.currency.to_lowercase(),
+                country: self.country.clone(),
+            });
+        }
+        if !self.capabilities.is_empty() && !self.is_active("card_payments") {
+            return Err(CapabilityError::InactiveCapability(
+                "card_payments".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum CapabilityError {
+    UnsupportedCurrency { currency: String, country: String },
+    InactiveCapability(String),
+    Stripe(StripePaymentError),
+}
+
+impl Display for CapabilityError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CapabilityError::UnsupportedCurrency { currency, country } => write!(
+                f,
+                "currency {} is not supported for accounts in {}",
+                currency, country
+            ),
+            CapabilityError::InactiveCapability(x) => write!(f, "capability {} is not active", x),
+            CapabilityError::Stripe(x) => write!(f, "{:?}", x),
+        }
+    }
+}
+
+impl std::error::Error for CapabilityError {}
+
+impl From<StripePaymentError> for CapabilityError {
+    fn from(x: StripePaymentError) -> Self {
+        CapabilityError::Stripe(x)
+    }
+}
+
+#[derive(Deserialize)]
+struct RawAccount {
+    id: String,
+    country: Option<String>,
+    default_currency: Option<String>,
+    #[serde(default)]
+    capabilities: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct RawCountrySpec {
+    #[serde(default)]
+    supported_payment_currencies: Vec<String>,
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn get_account_capabilities(
+    stripe_client: &Client,
+) -> Result<AccountCapabilities, StripePaymentError> {
+    let account = telemetry::observe(
+        "account.retrieve",
+        stripe_client.get::<RawAccount>("/account"),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+    let country = account.country.unwrap_or_default();
+    let country_spec = telemetry::observe(
+        "country_specs.retrieve",
+        stripe_client
+            .get::<RawCountrySpec>(&StripeUrl::new("/country_specs").segment(country).build()),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+    Ok(AccountCapabilities {
+        account_id: account.id,
+        country,
+        default_currency: account.default_currency.unwrap_or_default(),
+        supported_currencies: country_spec.supported_payment_currencies,
+        capabilities: account.capabilities,
+    })
+}
+
+/// Caches the platform account's capabilities, which change rarely, for `ttl`.
+#[derive(Debug)]
+pub struct CapabilitiesCache {
+    ttl: Duration,
+    cached: RwLock<Option<(Instant, AccountCapabilities)>>,
+}
+
+impl CapabilitiesCache {
+    pub fn new(ttl: Duration) -> Self {
+        CapabilitiesCache {
+            ttl,
+            cached: RwLock::new(None),
+        }
+    }
+
+    pub async fn get(
+        &self,
+        stripe_client: &Client,
+    ) -> Result<AccountCapabilities, StripePaymentError> {
+        if let Some((at, capabilities)) = self.cached.read().await.as_ref() {
+            if at.elapsed() < self.ttl {
+                return Ok(capabilities.clone());
+            }
+        }
+        let capabilities = get_account_capabilities(stripe_client).await?;
+        *self.cached.write().await = Some((Instant::now(), capabilities.clone()));
+        Ok(capabilities)
+    }
+
+    pub async fn invalidate(&self) {
+        *self.cached.write().await = None;
+    }
+}
+
+#[tracing::instrument(skip(stripe_client, cache))]
+pub async fn create_payment_sheet_checked(
+    stripe_client: &Client,
+    cache: &CapabilitiesCache,
+    dto: &CreatePaymentIntentDto,
+) -> Result<PaymentIntentDto, CapabilityError> {
+    cache.get(stripe_client).await?.validate_payment(dto)?;
+    Ok(crate::create_payment_sheet(stripe_client, dto).await?)
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CapabilityDto {
+    pub id: String,
+    pub account_id: String,
+    pub requested: bool,
+    pub status: String,
+    pub currently_due: Vec<String>,
+    pub disabled_reason: Option<String>,
+}
+
+impl CapabilityDto {
+    pub fn is_active(&self) -> bool {
+        self.status == "active"
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct RawRequirements {
+    #[serde(default)]
+    currently_due: Vec<String>,
+    disabled_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawCapability {
+    id: String,
+    account: String,
+    requested: bool,
+    status: String,
+    #[serde(default)]
+    requirements: Option<RawRequirements>,
+}
+
+#[derive(Deserialize)]
+struct RawCapabilityList {
+    data: Vec<RawCapability>,
+}
+
+impl From<RawCapability> for CapabilityDto {
+    fn from(x: RawCapability) -> Self {
+        let requirements = x.requirements.unwrap_or_default();
+        CapabilityDto {
+            id: x.id,
+            account_id: x.account,
+            requested: x.requested,
+            status: x.status,
+            currently_due: requirements.currently_due,
+            disabled_reason: requirements.disabled_reason,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RequestCapabilityParams {
+    requested: bool,
+}
+
+/// Requests a capability such as `card_payments`, `transfers` or `klarna_payments`
+/// for a connected account. It stays `pending` or `inactive` until the account's
+/// `currently_due` requirements are met.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn request_capability(
+    stripe_client: &Client,
+    account_id: &str,
+    capability: &str,
+) -> Result<CapabilityDto, StripePaymentError> {
+    telemetry::observe(
+        "capabilities.update",
+        stripe_client.post_form::<RawCapability, _>(
+            &StripeUrl::new("/accounts")
+                .segment(account_id)
+                .segment("capabilities")
+                .segment(capability)
+                .build(),
+            RequestCapabilityParams { requested: true },
+        ),
+    )
+    .await
+    .map(CapabilityDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn list_capabilities(
+    stripe_client: &Client,
+    account_id: &str,
+) -> Result<Vec<CapabilityDto>, StripePaymentError> {
+    telemetry::observe(
+        "capabilities.list",
+        stripe_client.get::<RawCapabilityList>(
+            &StripeUrl::new("/accounts")
+                .segment(account_id)
+                .segment("capabilities")
+                .build(),
+        ),
+    )
+    .await
+    .map(|x| x.data.into_iter().map(CapabilityDto::from).collect())
+    .map_err(StripePaymentError::from_general)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_unsupported_currency() {
+        let capabilities = AccountCapabilities {
+            account_id: "acct_1".to_string(),
+            country: "GB".to_string(),
+            default_currency: "gbp".to_string(),
+            supported_currencies: vec!["gbp".to_string(), "eur".to_string()],
+            capabilities: HashMap::from([("card_payments".to_string(), "active".to_string())]),
+        };
+        assert!(capabilities
+            .validate_payment(&CreatePaymentIntentDto::new(100, "cus_1", "EUR"))
+            .is_ok());
+        assert!(matches!(
+            capabilities.validate_payment(&CreatePaymentIntentDto::new(100, "cus_1", "brl")),
+            Err(CapabilityError::UnsupportedCurrency { .. })
+        ));
+    }
+
+    #[test]
+    fn parses_capability_requirements() {
+        let capability = serde_json::from_str::<RawCapability>(
+            r#"{"id":"transfers","account":"acct_1","requested":true,"status":"inactive",
+                "requirements":{"currently_due":["external_account"],"disabled_reason":"requirements.past_due"}}"#,
+        )
+        .map(CapabilityDto::from)
+        .unwrap();
+        assert!(!capability.is_active());
+        assert_eq!(capability.currently_due, vec!["external_account"]);
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use stripe::Client;
+
+use crate::telemetry;
+use crate::url::StripeUrl;
+use crate::webhook::WebhookEvent;
+use crate::StripePaymentError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BankTransferType {
+    Eu { country: String },
+    Gb,
+    Jp,
+    Mx,
+    Us,
+}
+
+impl BankTransferType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BankTransferType::Eu { .. } => "eu_bank_transfer",
+            BankTransferType::Gb => "gb_bank_transfer",
+            BankTransferType::Jp => "jp_bank_transfer",
+            BankTransferType::Mx => "mx_bank_transfer",
+            BankTransferType::Us => "us_bank_transfer",
+        }
+    }
+
+    fn params(&self) -> BankTransferParams<'_> {
+        BankTransferParams {
+            kind: self.as_str(),
+            eu_bank_transfer: match self {
+                BankTransferType::Eu { country } => Some(EuBankTransferParams { country }),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct FinancialAddressDto {
+    pub address_type: String,
+    pub fields: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct FundingInstructionsDto {
+    pub currency: String,
+    pub bank_transfer_type: String,
+    pub financial_addresses: Vec<FinancialAddressDto>,
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CashBalanceDto {
+    pub stripe_customer_id: String,
+    pub available: HashMap<String, i64>,
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CashBalanceTransactionDto {
+    pub id: String,
+    pub stripe_customer_id: String,
+    pub transaction_type: String,
+    pub currency: String,
+    pub net_amount: i64,
+    pub ending_balance: i64,
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct BankTransferPaymentDto {
+    pub id: String,
+    pub status: String,
+    pub amount_remaining: Option<i64>,
+    pub hosted_instructions_url: Option<String>,
+    pub reference: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum CashBalanceEvent {
+    FundsAvailable(CashBalanceDto),
+    TransactionCreated(CashBalanceTransactionDto),
+}
+
+impl CashBalanceEvent {
+    /// Maps `cash_balance.funds_available` and `customer_cash_balance_transaction.created`
+    /// events, returning `None` for any other event type.
+    pub fn from_event(event: &WebhookEvent) -> Option<CashBalanceEvent> {
+        match event.event_type.as_str() {
+            "cash_balance.funds_available" => event
+                .object_as::<RawCashBalance>()
+                .ok()
+                .map(|x| CashBalanceEvent::FundsAvailable(x.into())),
+            "customer_cash_balance_transaction.created" => event
+                .object_as::<RawCashBalanceTransaction>()
+                .ok()
+                .map(|x| CashBalanceEvent::TransactionCreated(x.into())),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EuBankTransferParams<'a> {
+    country: &'a str,
+}
+
+#[derive(Serialize)]
+struct BankTransferParams<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    eu_bank_transfer: Option<EuBankTransferParams<'a>>,
+}
+
+#[derive(Serialize)]
+struct FundingInstructionsParams<'a> {
+    bank_transfer: BankTransferParams<'a>,
+    currency: String,
+    funding_type: &'static str,
+}
+
+#[derive(Serialize)]
+struct CustomerBalanceOptions<'a> {
+    funding_type: &'static str,
+    bank_transfer: BankTransferParams<'a>,
+}
+
+#[derive(Serialize)]
+struct PaymentMethodOptions<'a> {
+    customer_balance: CustomerBalanceOptions<'a>,
+}
+
+#[derive(Serialize)]
+struct PaymentMethodData {
+    #[serde(rename = "type")]
+    kind: &'static str,
+}
+
+#[derive(Serialize)]
+struct BankTransferPaymentParams<'a> {
+    amount: i64,
+    currency: String,
+    customer: &'a str,
+    confirm: bool,
+    payment_method_types: Vec<&'static str>,
+    payment_method_data: PaymentMethodData,
+    payment_method_options: PaymentMethodOptions<'a>,
+}
+
+#[derive(Deserialize)]
+struct RawBankTransfer {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    financial_addresses: Vec<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct RawFundingInstructions {
+    currency: String,
+    bank_transfer: RawBankTransfer,
+}
+
+#[derive(Deserialize)]
+struct RawCashBalance {
+    customer: String,
+    #[serde(default)]
+    available: Option<HashMap<String, i64>>,
+}
+
+impl From<RawCashBalance> for CashBalanceDto {
+    fn from(x: RawCashBalance) -> Self {
+        CashBalanceDto {
+            stripe_customer_id: x.customer,
+            available: x.available.unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawCashBalanceTransaction {
+    id: String,
+    customer: String,
+    #[serde(rename = "type")]
+    kind: String,
+    currency: String,
+    net_amount: i64,
+    ending_balance: i64,
+}
+
+impl From<RawCashBalanceTransaction> for CashBalanceTransactionDto {
+    fn from(x: RawCashBalanceTransaction) -> Self {
+        CashBalanceTransactionDto {
+            id: x.id,
+            stripe_customer_id: x.customer,
+            transaction_type: x.kind,
+            currency: x.currency,
+            net_amount: x.net_amount,
+            ending_balance: x.ending_balance,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawDisplayBankTransferInstructions {
+    amount_remaining: Option<i64>,
+    hosted_instructions_url: Option<String>,
+    reference: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawNextAction {
+    display_bank_transfer_instructions: Option<RawDisplayBankTransferInstructions>,
+}
+
+#[derive(Deserialize)]
+struct RawPaymentIntent {
+    id: String,
+    status: String,
+    next_action: Option<RawNextAction>,
+}
+
+fn financial_address(value: &serde_json::Value) -> Option<FinancialAddressDto> {
+    let address_type = value.get("type")?.as_str()?.to_string();
+    let fields = value
+        .get(&address_type)
+        .and_then(|x| x.as_object())
+        .map(|x| {
+            x.iter()
+                .filter_map(|(k, v)| v.as_str().map(|v| (k.clone(), v.to_string())))
+                .collect()
+        })
+        .unwrap_or_default();
+    Some(FinancialAddressDto {
+        address_type,
+        fields,
+    })
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn create_funding_instructions(
+    stripe_client: &Client,
+    stripe_customer_id: &str,
+    currency: &str,
+    bank_transfer_type: &BankTransferType,
+) -> Result<FundingInstructionsDto, StripePaymentError> {
+    let instructions = telemetry::observe(
+        "funding_instructions.create",
+        stripe_client.post_form::<RawFundingInstructions, _>(
+            &StripeUrl::new("/customers")
+                .segment(stripe_customer_id)
+                .segment("funding_instructions")
+                .build(),
+            FundingInstructionsParams {
+                bank_transfer: bank_transfer_type.params(),
+                currency: currency.to_lowercase(),
+                funding_type: "bank_transfer",
+            },
+        ),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+    Ok(FundingInstructionsDto {
+        currency: instructions.currency,
+        bank_transfer_type: instructions.bank_transfer.kind,
+        financial_addresses: instructions
+            .bank_transfer
+            .financial_addresses
+            .iter()
+            .filter_map(financial_address)
+            .collect(),
+    })
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn get_cash_balance(
+    stripe_client: &Client,
+    stripe_customer_id: &str,
+) -> Result<CashBalanceDto, StripePaymentError> {
+    telemetry::observe(
+        "cash_balance.retrieve",
+        stripe_client.get::<RawCashBalance>(
+            &StripeUrl::new("/customers")
+                .segment(stripe_customer_id)
+                .segment("cash_balance")
+                .build(),
+        ),
+    )
+    .await
+    .map(CashBalanceDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+/// Creates and confirms a `customer_balance` payment intent; the returned DTO
+/// carries the bank transfer instructions to show to the customer.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn create_bank_transfer_payment(
+    stripe_client: &Client,
+    stripe_customer_id: &str,
+    amount: i64,
+    currency: &str,
+    bank_transfer_type: &BankTransferType,
+) -> Result<BankTransferPaymentDto, StripePaymentError> {
+    let payment_intent = telemetry::observe(
+        "payment_intents.create",
+        stripe_client.post_form::<RawPaymentIntent, _>(
+            "/payment_intents",
+            BankTransferPaymentParams {
+                amount,
+                currency: currency.to_lowercase(),
+                customer: stripe_customer_id,
+                confirm: true,
+                payment_method_types: vec!["customer_balance"],
+                payment_method_data: PaymentMethodData {
+                    kind: "customer_balance",
+                },
+                payment_method_options: PaymentMethodOptions {
+                    customer_balance: CustomerBalanceOptions {
+                        funding_type: "bank_transfer",
+                        bank_transfer: bank_transfer_type.params(),
+                    },
+                },
+            },
+        ),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+    let instructions = payment_intent
+        .next_action
+        .and_then(|x| x.display_bank_transfer_instructions);
+    Ok(BankTransferPaymentDto {
+        id: payment_intent.id,
+        status: payment_intent.status,
+        amount_remaining: instructions.as_ref().and_then(|x| x.amount_remaining),
+        hosted_instructions_url: instructions
+            .as_ref()
+            .and_then(|x| x.hosted_instructions_url.clone()),
+        reference: instructions.and_then(|x| x.reference),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flattens_financial_address() {
+        let value = serde_json::json!({
+            "type": "iban",
+            "iban": { "iban": "DE00123", "bic": "ABCDEF", "country": "DE" },
+            "supported_networks": ["sepa"]
+        });
+        let address = financial_address(&value).unwrap();
+        assert_eq!(address.address_type, "iban");
+        assert_eq!(
+            address.fields.get("iban").map(|x| x.as_str()),
+            Some("DE00123")
+        );
+    }
+}
@@ -0,0 +1,970 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use stripe::Client;
+
+use crate::dry_run::{validate_amount, validate_currency};
+use crate::pagination::RawList;
+use crate::region::RegionConfig;
+use crate::telemetry;
+use crate::url::StripeUrl;
+use crate::webhook::WebhookEvent;
+use crate::StripePaymentError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckoutLinePrice {
+    /// A price created beforehand in Stripe.
+    Price(String),
+    /// A one-off price declared inline, so ad-hoc amounts don't need a price
+    /// and product in the catalog.
+    PriceData {
+        product_name: String,
+        unit_amount: i64,
+        currency: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CheckoutLineItem {
+    pub price: CheckoutLinePrice,
+    pub quantity: u64,
+}
+
+impl CheckoutLineItem {
+    pub fn price(price_id: impl Into<String>, quantity: u64) -> Self {
+        CheckoutLineItem {
+            price: CheckoutLinePrice::Price(price_id.into()),
+            quantity,
+        }
+    }
+
+    /// `amount` is per unit, in the currency's smallest unit.
+    pub fn ad_hoc(
+        name: impl Into<String>,
+        amount: i64,
+        currency: impl Into<String>,
+        quantity: u64,
+    ) -> Self {
+        CheckoutLineItem {
+            price: CheckoutLinePrice::PriceData {
+                product_name: name.into(),
+                unit_amount: amount,
+                currency: currency.into().to_lowercase(),
+            },
+            quantity,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), StripePaymentError> {
+        if self.quantity == 0 {
+            return Err(StripePaymentError::from_general(
+                "line item quantity must be at least 1".to_string(),
+            ));
+        }
+        match &self.price {
+            CheckoutLinePrice::Price(_) => Ok(()),
+            CheckoutLinePrice::PriceData {
+                product_name,
+                unit_amount,
+                currency,
+            } => {
+                if product_name.trim().is_empty() {
+                    return Err(StripePaymentError::from_general(
+                        "ad-hoc line item needs a product name".to_string(),
+                    ));
+                }
+                validate_amount(*unit_amount)?;
+                validate_currency(currency)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckoutMode {
+    Payment,
+    /// Requires recurring prices, so line items can't be ad-hoc.
+    Subscription,
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CreateCheckoutSessionDto {
+    pub mode: CheckoutMode,
+    pub line_items: Vec<CheckoutLineItem>,
+    pub success_url: String,
+    pub cancel_url: Option<String>,
+    pub stripe_customer_id: Option<String>,
+    pub shipping_options: Vec<ShippingOption>,
+    /// Two-letter country codes the shipping address may be in; shipping
+    /// address collection is off when empty.
+    pub allowed_countries: Vec<String>,
+    /// Language of the hosted page; Stripe uses the browser's when unset.
+    pub locale: Option<String>,
+    /// Stripe picks the payment methods from the dashboard settings when
+    /// empty.
+    pub payment_method_types: Vec<String>,
+}
+
+impl CreateCheckoutSessionDto {
+    pub fn new(mode: CheckoutMode, success_url: impl Into<String>) -> Self {
+        CreateCheckoutSessionDto {
+            mode,
+            line_items: vec![],
+            success_url: success_url.into(),
+            cancel_url: None,
+            stripe_customer_id: None,
+            shipping_options: vec![],
+            allowed_countries: vec![],
+            locale: None,
+            payment_method_types: vec![],
+        }
+    }
+
+    pub fn with_line_item(mut self, line_item: CheckoutLineItem) -> Self {
+        self.line_items.push(line_item);
+        self
+    }
+
+    pub fn with_cancel_url(mut self, cancel_url: impl Into<String>) -> Self {
+        self.cancel_url = Some(cancel_url.into());
+        self
+    }
+
+    pub fn with_customer(mut self, stripe_customer_id: impl Into<String>) -> Self {
+        self.stripe_customer_id = Some(stripe_customer_id.into());
+        self
+    }
+
+    /// Offers a shipping rate created with [`create_shipping_rate`].
+    pub fn with_shipping_rate(mut self, shipping_rate_id: impl Into<String>) -> Self {
+        self.shipping_options
+            .push(ShippingOption::Rate(shipping_rate_id.into()));
+        self
+    }
+
+    /// Replaces the shipping options, e.g. with rates priced for this cart.
+    pub fn with_shipping_options(mut self, shipping_options: Vec<ShippingOption>) -> Self {
+        self.shipping_options = shipping_options;
+        self
+    }
+
+    pub fn with_allowed_countries<I, S>(mut self, countries: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.allowed_countries = countries
+            .into_iter()
+            .map(|x| x.into().to_uppercase())
+            .collect();
+        self
+    }
+
+    pub fn with_locale(mut self, locale: impl Into<String>) -> Self {
+        self.locale = Some(locale.into());
+        self
+    }
+
+    pub fn with_payment_method_types(mut self, payment_method_types: Vec<String>) -> Self {
+        self.payment_method_types = payment_method_types;
+        self
+    }
+
+    /// Fills in the locale and payment method types from `regions` where they
+    /// aren't set. The currency is that of the first ad-hoc line item, so
+    /// sessions made only of catalog prices are left as they are; the country
+    /// is used when shipping is limited to a single one.
+    pub fn with_region_config(mut self, regions: &RegionConfig) -> Self {
+        let currency = self.line_items.iter().find_map(|x| match &x.price {
+            CheckoutLinePrice::PriceData { currency, .. } => Some(currency.clone()),
+            CheckoutLinePrice::Price(_) => None,
+        });
+        let country = match self.allowed_countries.as_slice() {
+            [x] => Some(x.as_str()),
+            _ => None,
+        };
+        if let Some(settings) = currency.and_then(|x| regions.settings(country, &x)) {
+            if self.locale.is_none() {
+                self.locale = settings.locale.clone();
+            }
+            if self.payment_method_types.is_empty() {
+                self.payment_method_types =
+                    settings.payment_method_types.clone().unwrap_or_default();
+            }
+        }
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), StripePaymentError> {
+        if self.line_items.is_empty() {
+            return Err(StripePaymentError::from_general(
+                "checkout session has no line items".to_string(),
+            ));
+        }
+        for x in &self.line_items {
+            x.validate()?;
+            if self.mode == CheckoutMode::Subscription
+                && matches!(x.price, CheckoutLinePrice::PriceData { .. })
+            {
+                return Err(StripePaymentError::from_general(
+                    "subscription checkout needs recurring prices, not ad-hoc line items"
+                        .to_string(),
+                ));
+            }
+        }
+        if !self.shipping_options.is_empty() {
+            if self.mode != CheckoutMode::Payment {
+                return Err(StripePaymentError::from_general(
+                    "shipping options are only supported in payment mode".to_string(),
+                ));
+            }
+            if self.shipping_options.len() > MAX_SHIPPING_OPTIONS {
+                return Err(StripePaymentError::from_general(format!(
+                    "at most {} shipping options are allowed",
+                    MAX_SHIPPING_OPTIONS
+                )));
+            }
+            for x in &self.shipping_options {
+                if let ShippingOption::RateData(x) = x {
+                    x.validate()?;
+                }
+            }
+        }
+        for x in &self.allowed_countries {
+            if x.len() != 2 || !x.chars().all(|c| c.is_ascii_uppercase()) {
+                return Err(StripePaymentError::from_general(format!(
+                    "invalid country code: {}",
+                    x
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Stripe accepts at most 5 shipping options per checkout session.
+pub const MAX_SHIPPING_OPTIONS: usize = 5;
+
+/// Business days until delivery, shown next to the shipping option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeliveryEstimate {
+    pub min_business_days: u32,
+    pub max_business_days: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CreateShippingRateDto {
+    pub display_name: String,
+    /// In the currency's smallest unit; 0 for free shipping.
+    pub amount: i64,
+    pub currency: String,
+    pub delivery_estimate: Option<DeliveryEstimate>,
+}
+
+impl CreateShippingRateDto {
+    pub fn new(display_name: impl Into<String>, amount: i64, currency: impl Into<String>) -> Self {
+        CreateShippingRateDto {
+            display_name: display_name.into(),
+            amount,
+            currency: currency.into().to_lowercase(),
+            delivery_estimate: None,
+        }
+    }
+
+    pub fn with_delivery_estimate(
+        mut self,
+        min_business_days: u32,
+        max_business_days: u32,
+    ) -> Self {
+        self.delivery_estimate = Some(DeliveryEstimate {
+            min_business_days,
+            max_business_days,
+        });
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), StripePaymentError> {
+        if self.display_name.trim().is_empty() {
+            return Err(StripePaymentError::from_general(
+                "shipping rate needs a display name".to_string(),
+            ));
+        }
+        if self.amount != 0 {
+            validate_amount(self.amount)?;
+        }
+        validate_currency(&self.currency)?;
+        match self.delivery_estimate {
+            Some(x) if x.min_business_days > x.max_business_days => {
+                Err(StripePaymentError::from_general(
+                    "delivery estimate minimum is after its maximum".to_string(),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShippingOption {
+    /// A shipping rate created beforehand, see [`create_shipping_rate`].
+    Rate(String),
+    /// A rate declared inline, for shipping priced per cart.
+    RateData(CreateShippingRateDto),
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ShippingRateDto {
+    pub id: String,
+    pub display_name: Option<String>,
+    pub amount: i64,
+    pub currency: String,
+    pub active: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckoutPaymentStatus {
+    Paid,
+    /// Delayed payment methods such as bank debits complete the session
+    /// before the money arrives; fulfil on
+    /// `checkout.session.async_payment_succeeded` instead.
+    Unpaid,
+    NoPaymentRequired,
+    Other(String),
+}
+
+impl CheckoutPaymentStatus {
+    pub fn parse(status: &str) -> CheckoutPaymentStatus {
+        match status {
+            "paid" => CheckoutPaymentStatus::Paid,
+            "unpaid" => CheckoutPaymentStatus::Unpaid,
+            "no_payment_required" => CheckoutPaymentStatus::NoPaymentRequired,
+            other => CheckoutPaymentStatus::Other(other.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CheckoutLineItemDto {
+    pub id: String,
+    pub description: Option<String>,
+    pub price_id: Option<String>,
+    pub quantity: u64,
+    pub amount_total: i64,
+    pub currency: String,
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CheckoutSessionDto {
+    pub id: String,
+    pub url: Option<String>,
+    /// `open`, `complete` or `expired`.
+    pub status: Option<String>,
+    pub payment_status: CheckoutPaymentStatus,
+    pub stripe_customer_id: Option<String>,
+    pub payment_intent_id: Option<String>,
+    pub amount_total: Option<i64>,
+    pub currency: Option<String>,
+    /// Only loaded when requested, see [`get_checkout_session`].
+    pub line_items: Option<Vec<CheckoutLineItemDto>>,
+}
+
+impl CheckoutSessionDto {
+    /// Whether the order can be fulfilled: the session is complete and the
+    /// payment has arrived, or none was needed.
+    pub fn is_fulfillable(&self) -> bool {
+        self.status.as_deref() == Some("complete")
+            && matches!(
+                self.payment_status,
+                CheckoutPaymentStatus::Paid | CheckoutPaymentStatus::NoPaymentRequired
+            )
+    }
+}
+
+#[derive(Deserialize)]
+struct RawCheckoutSession {
+    id: String,
+    url: Option<String>,
+    status: Option<String>,
+    #[serde(default)]
+    payment_status: String,
+    customer: Option<String>,
+    payment_intent: Option<String>,
+    amount_total: Option<i64>,
+    currency: Option<String>,
+}
+
+impl From<RawCheckoutSession> for CheckoutSessionDto {
+    fn from(x: RawCheckoutSession) -> Self {
+        CheckoutSessionDto {
+            id: x.id,
+            url: x.url,
+            status: x.status,
+            payment_status: CheckoutPaymentStatus::parse(&x.payment_status),
+            stripe_customer_id: x.customer,
+            payment_intent_id: x.payment_intent,
+            amount_total: x.amount_total,
+            currency: x.currency,
+            line_items: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawLinePrice {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct RawCheckoutLineItem {
+    id: String,
+    description: Option<String>,
+    price: Option<RawLinePrice>,
+    #[serde(default)]
+    quantity: Option<u64>,
+    amount_total: i64,
+    currency: String,
+}
+
+impl From<RawCheckoutLineItem> for CheckoutLineItemDto {
+    fn from(x: RawCheckoutLineItem) -> Self {
+        CheckoutLineItemDto {
+            id: x.id,
+            description: x.description,
+            price_id: x.price.map(|x| x.id),
+            quantity: x.quantity.unwrap_or(1),
+            amount_total: x.amount_total,
+            currency: x.currency,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ProductDataParams<'a> {
+    name: &'a str,
+}
+
+#[derive(Serialize)]
+struct PriceDataParams<'a> {
+    currency: &'a str,
+    unit_amount: i64,
+    product_data: ProductDataParams<'a>,
+}
+
+#[derive(Serialize)]
+struct LineItemParams<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    price: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    price_data: Option<PriceDataParams<'a>>,
+    quantity: u64,
+}
+
+impl<'a> From<&'a CheckoutLineItem> for LineItemParams<'a> {
+    fn from(x: &'a CheckoutLineItem) -> Self {
+        match &x.price {
+            CheckoutLinePrice::Price(price) => LineItemParams {
+                price: Some(price),
+                price_data: None,
+                quantity: x.quantity,
+            },
+            CheckoutLinePrice::PriceData {
+                product_name,
+                unit_amount,
+                currency,
+            } => LineItemParams {
+                price: None,
+                price_data: Some(PriceDataParams {
+                    currency,
+                    unit_amount: *unit_amount,
+                    product_data: ProductDataParams { name: product_name },
+                }),
+                quantity: x.quantity,
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FixedAmountParams<'a> {
+    amount: i64,
+    currency: &'a str,
+}
+
+#[derive(Serialize)]
+struct DeliveryEstimateBoundParams {
+    unit: &'static str,
+    value: u32,
+}
+
+#[derive(Serialize)]
+struct DeliveryEstimateParams {
+    minimum: DeliveryEstimateBoundParams,
+    maximum: DeliveryEstimateBoundParams,
+}
+
+#[derive(Serialize)]
+struct ShippingRateParams<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    display_name: &'a str,
+    fixed_amount: FixedAmountParams<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delivery_estimate: Option<DeliveryEstimateParams>,
+}
+
+impl<'a> From<&'a CreateShippingRateDto> for ShippingRateParams<'a> {
+    fn from(x: &'a CreateShippingRateDto) -> Self {
+        let bound = |value| DeliveryEstimateBoundParams {
+            unit: "business_day",
+            value,
+        };
+        ShippingRateParams {
+            kind: "fixed_amount",
+            display_name: &x.display_name,
+            fixed_amount: FixedAmountParams {
+                amount: x.amount,
+                currency: &x.currency,
+            },
+            delivery_estimate: x.delivery_estimate.map(|x| DeliveryEstimateParams {
+                minimum: bound(x.min_business_days),
+                maximum: bound(x.max_business_days),
+            }),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ShippingOptionParams<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shipping_rate: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shipping_rate_data: Option<ShippingRateParams<'a>>,
+}
+
+impl<'a> From<&'a ShippingOption> for ShippingOptionParams<'a> {
+    fn from(x: &'a ShippingOption) -> Self {
+        match x {
+            ShippingOption::Rate(id) => ShippingOptionParams {
+                shipping_rate: Some(id),
+                shipping_rate_data: None,
+            },
+            ShippingOption::RateData(x) => ShippingOptionParams {
+                shipping_rate: None,
+                shipping_rate_data: Some(ShippingRateParams::from(x)),
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AddressCollectionParams<'a> {
+    allowed_countries: Vec<&'a str>,
+}
+
+#[derive(Serialize)]
+struct CreateSessionParams<'a> {
+    mode: CheckoutMode,
+    line_items: Vec<LineItemParams<'a>>,
+    success_url: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cancel_url: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    customer: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    shipping_options: Vec<ShippingOptionParams<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shipping_address_collection: Option<AddressCollectionParams<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    locale: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    payment_method_types: Vec<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct RawFixedAmount {
+    amount: i64,
+    currency: String,
+}
+
+#[derive(Deserialize)]
+struct RawShippingRate {
+    id: String,
+    display_name: Option<String>,
+    fixed_amount: RawFixedAmount,
+    active: bool,
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn create_shipping_rate(
+    stripe_client: &Client,
+    dto: &CreateShippingRateDto,
+) -> Result<ShippingRateDto, StripePaymentError> {
+    dto.validate()?;
+    telemetry::observe(
+        "shipping_rates.create",
+        stripe_client
+            .post_form::<RawShippingRate, _>("/shipping_rates", ShippingRateParams::from(dto)),
+    )
+    .await
+    .map(|x| ShippingRateDto {
+        id: x.id,
+        display_name: x.display_name,
+        amount: x.fixed_amount.amount,
+        currency: x.fixed_amount.currency,
+        active: x.active,
+    })
+    .map_err(StripePaymentError::from_general)
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn create_checkout_session(
+    stripe_client: &Client,
+    dto: &CreateCheckoutSessionDto,
+) -> Result<CheckoutSessionDto, StripePaymentError> {
+    dto.validate()?;
+    telemetry::observe(
+        "checkout.sessions.create",
+        stripe_client.post_form::<RawCheckoutSession, _>(
+            "/checkout/sessions",
+            CreateSessionParams {
+                mode: dto.mode,
+                line_items: dto.line_items.iter().map(LineItemParams::from).collect(),
+                success_url: &dto.success_url,
+                cancel_url: dto.cancel_url.as_deref(),
+                customer: dto.stripe_customer_id.as_deref(),
+                shipping_options: dto
+                    .shipping_options
+                    .iter()
+                    .map(ShippingOptionParams::from)
+                    .collect(),
+                shipping_address_collection: (!dto.allowed_countries.is_empty()).then(|| {
+                    AddressCollectionParams {
+                        allowed_countries: dto
+                            .allowed_countries
+                            .iter()
+                            .map(|x| x.as_str())
+                            .collect(),
+                    }
+                }),
+                locale: dto.locale.as_deref(),
+                payment_method_types: dto
+                    .payment_method_types
+                    .iter()
+                    .map(String::as_str)
+                    .collect(),
+            },
+        ),
+    )
+    .await
+    .map(CheckoutSessionDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+#[derive(Serialize)]
+struct LineItemsParams<'a> {
+    limit: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    starting_after: Option<&'a str>,
+}
+
+async fn list_line_items(
+    stripe_client: &Client,
+    session_id: &str,
+) -> Result<Vec<CheckoutLineItemDto>, StripePaymentError> {
+    let path = StripeUrl::new("/checkout/sessions")
+        .segment(session_id)
+        .segment("line_items")
+        .build();
+    let mut items = Vec::<CheckoutLineItemDto>::new();
+    loop {
+        let list = telemetry::observe(
+            "checkout.sessions.line_items",
+            stripe_client.get_query::<RawList<RawCheckoutLineItem>, _>(
+                &path,
+                LineItemsParams {
+                    limit: 100,
+                    starting_after: items.last().map(|x| x.id.as_str()),
+                },
+            ),
+        )
+        .await
+        .map_err(StripePaymentError::from_general)?;
+        let done = !list.has_more || list.data.is_empty();
+        items.extend(list.data.into_iter().map(CheckoutLineItemDto::from));
+        if done {
+            return Ok(items);
+        }
+    }
+}
+
+/// Fetches the session fresh from Stripe, so fulfillment doesn't act on a
+/// stale or forged webhook payload. With `expand_line_items` all line items
+/// are paged in as well.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn get_checkout_session(
+    stripe_client: &Client,
+    session_id: &str,
+    expand_line_items: bool,
+) -> Result<CheckoutSessionDto, StripePaymentError> {
+    let mut session = telemetry::observe(
+        "checkout.sessions.retrieve",
+        stripe_client.get::<RawCheckoutSession>(
+            &StripeUrl::new("/checkout/sessions")
+                .segment(session_id)
+                .build(),
+        ),
+    )
+    .await
+    .map(CheckoutSessionDto::from)
+    .map_err(StripePaymentError::from_general)?;
+    if expand_line_items {
+        session.line_items = Some(list_line_items(stripe_client, session_id).await?);
+    }
+    Ok(session)
+}
+
+/// Expires an open session so it can no longer be paid, e.g. when the cart
+/// changed or stock ran out. Stripe rejects expiring a completed session.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn expire_checkout_session(
+    stripe_client: &Client,
+    session_id: &str,
+) -> Result<CheckoutSessionDto, StripePaymentError> {
+    telemetry::observe(
+        "checkout.sessions.expire",
+        stripe_client.post_form::<RawCheckoutSession, _>(
+            &StripeUrl::new("/checkout/sessions")
+                .segment(session_id)
+                .segment("expire")
+                .build(),
+            HashMap::<&str, &str>::new(),
+        ),
+    )
+    .await
+    .map(CheckoutSessionDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+/// Maps `checkout.session.*` events. Check [`CheckoutSessionDto::is_fulfillable`]
+/// before fulfilling: a completed session paid by a delayed method is still
+/// unpaid.
+pub fn checkout_session_from_event(event: &WebhookEvent) -> Option<CheckoutSessionDto> {
+    match event.event_type.as_str() {
+        "checkout.session.completed"
+        | "checkout.session.async_payment_succeeded"
+        | "checkout.session.async_payment_failed"
+        | "checkout.session.expired" => event
+            .object_as::<RawCheckoutSession>()
+            .ok()
+            .map(CheckoutSessionDto::from),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PaymentLinkDto {
+    pub id: String,
+    pub url: String,
+    pub active: bool,
+}
+
+#[derive(Deserialize)]
+struct RawPaymentLink {
+    id: String,
+    url: String,
+    active: bool,
+}
+
+#[derive(Deserialize)]
+struct RawPrice {
+    id: String,
+}
+
+#[derive(Serialize)]
+struct CreatePriceParams<'a> {
+    currency: &'a str,
+    unit_amount: i64,
+    product_data: ProductDataParams<'a>,
+}
+
+#[derive(Serialize)]
+struct LinkLineItemParams<'a> {
+    price: &'a str,
+    quantity: u64,
+}
+
+#[derive(Serialize)]
+struct CreateLinkParams<'a> {
+    line_items: Vec<LinkLineItemParams<'a>>,
+}
+
+/// Payment links only accept existing prices, so a one-off price is created
+/// for each ad-hoc line item first.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn create_payment_link(
+    stripe_client: &Client,
+    line_items: &[CheckoutLineItem],
+) -> Result<PaymentLinkDto, StripePaymentError> {
+    if line_items.is_empty() {
+        return Err(StripePaymentError::from_general(
+            "payment link has no line items".to_string(),
+        ));
+    }
+    let mut prices = Vec::with_capacity(line_items.len());
+    for x in line_items {
+        x.validate()?;
+        let price = match &x.price {
+            CheckoutLinePrice::Price(price) => price.clone(),
+            CheckoutLinePrice::PriceData {
+                product_name,
+                unit_amount,
+                currency,
+            } => {
+                telemetry::observe(
+                    "prices.create",
+                    stripe_client.post_form::<RawPrice, _>(
+                        "/prices",
+                        CreatePriceParams {
+                            currency,
+                            unit_amount: *unit_amount,
+                            product_data: ProductDataParams { name: product_name },
+                        },
+                    ),
+                )
+                .await
+                .map_err(StripePaymentError::from_general)?
+                .id
+            }
+        };
+        prices.push(price);
+    }
+    telemetry::observe(
+        "payment_links.create",
+        stripe_client.post_form::<RawPaymentLink, _>(
+            "/payment_links",
+            CreateLinkParams {
+                line_items: line_items
+                    .iter()
+                    .zip(&prices)
+                    .map(|(x, price)| LinkLineItemParams {
+                        price,
+                        quantity: x.quantity,
+                    })
+                    .collect(),
+            },
+        ),
+    )
+    .await
+    .map(|x| PaymentLinkDto {
+        id: x.id,
+        url: x.url,
+        active: x.active,
+    })
+    .map_err(StripePaymentError::from_general)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::region::RegionSettings;
+
+    #[test]
+    fn serializes_inline_price_data() {
+        let item = CheckoutLineItem::ad_hoc("Gift wrapping", 350, "EUR", 2);
+        let params = serde_json::to_value(LineItemParams::from(&item)).unwrap();
+        assert_eq!(params["price_data"]["currency"], "eur");
+        assert_eq!(
+            params["price_data"]["product_data"]["name"],
+            "Gift wrapping"
+        );
+        assert!(params.get("price").is_none());
+
+        let dto = CreateCheckoutSessionDto::new(CheckoutMode::Subscription, "https://x.io/ok")
+            .with_line_item(item);
+        assert!(dto.validate().is_err());
+        assert!(CheckoutLineItem::ad_hoc("", 350, "eur", 1)
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn delayed_payments_are_not_fulfillable_on_completion() {
+        let event = serde_json::from_str::<WebhookEvent>(
+            r#"{"id":"evt_1","type":"checkout.session.completed","created":1,"livemode":false,
+                "data":{"object":{"id":"cs_1","url":null,"status":"complete",
+                "payment_status":"unpaid","customer":"cus_1","payment_intent":"pi_1",
+                "amount_total":1200,"currency":"eur"}}}"#,
+        )
+        .unwrap();
+        let session = checkout_session_from_event(&event).unwrap();
+        assert_eq!(session.payment_status, CheckoutPaymentStatus::Unpaid);
+        assert!(!session.is_fulfillable());
+    }
+
+    #[test]
+    fn serializes_shipping_options() {
+        let express =
+            CreateShippingRateDto::new("Express", 1500, "EUR").with_delivery_estimate(1, 2);
+        let option = ShippingOption::RateData(express.clone());
+        let params = serde_json::to_value(ShippingOptionParams::from(&option)).unwrap();
+        assert_eq!(params["shipping_rate_data"]["type"], "fixed_amount");
+        assert_eq!(
+            params["shipping_rate_data"]["fixed_amount"]["currency"],
+            "eur"
+        );
+        assert_eq!(
+            params["shipping_rate_data"]["delivery_estimate"]["maximum"]["value"],
+            2
+        );
+        assert!(params.get("shipping_rate").is_none());
+
+        let dto = CreateCheckoutSessionDto::new(CheckoutMode::Payment, "https://x.io/ok")
+            .with_line_item(CheckoutLineItem::price("price_1", 1))
+            .with_shipping_rate("shr_standard")
+            .with_shipping_options(vec![option])
+            .with_allowed_countries(["de", "AT"]);
+        assert_eq!(dto.shipping_options.len(), 1);
+        assert!(dto.validate().is_ok());
+        assert!(dto
+            .clone()
+            .with_allowed_countries(["DEU"])
+            .validate()
+            .is_err());
+        assert!(CreateShippingRateDto::new("Slow", 0, "eur")
+            .with_delivery_estimate(5, 3)
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn fills_in_region_defaults() {
+        let regions = RegionConfig::new().with_country(
+            "NL",
+            "eur",
+            RegionSettings::new()
+                .with_locale("nl")
+                .with_payment_method_types(vec!["ideal".into(), "card".into()]),
+        );
+        let dto = CreateCheckoutSessionDto::new(CheckoutMode::Payment, "https://x.io/ok")
+            .with_line_item(CheckoutLineItem::ad_hoc("Poster", 1200, "EUR", 1))
+            .with_allowed_countries(["nl"])
+            .with_locale("en")
+            .with_region_config(&regions);
+        assert_eq!(dto.locale.as_deref(), Some("en"));
+        assert_eq!(dto.payment_method_types, ["ideal", "card"]);
+        let dto = CreateCheckoutSessionDto::new(CheckoutMode::Payment, "https://x.io/ok")
+            .with_line_item(CheckoutLineItem::price("price_1", 1))
+            .with_allowed_countries(["nl"])
+            .with_region_config(&regions);
+        assert_eq!(dto.locale, None);
+        assert!(dto.payment_method_types.is_empty());
+    }
+}
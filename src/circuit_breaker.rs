@@ -0,0 +1,365 @@
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use stripe::StripeError;
+
+use crate::StripePaymentError;
+
+pub const DEFAULT_FAILURE_RATE: f64 = 0.5;
+pub const DEFAULT_MINIMUM_REQUESTS: u32 = 20;
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(30);
+pub const DEFAULT_OPEN_DURATION: Duration = Duration::from_secs(30);
+
+/// Returned instead of calling Stripe while the circuit is open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitOpen {
+    /// Time until the next probe request is let through.
+    pub retry_after: Duration,
+}
+
+impl Display for CircuitOpen {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "stripe circuit open, retry after {}s",
+            self.retry_after.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    /// Requests fail fast with [`CircuitOpen`].
+    Open,
+    /// A single probe request is let through; its outcome closes or reopens
+    /// the circuit.
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct Window {
+    state: CircuitState,
+    started: Instant,
+    requests: u32,
+    failures: u32,
+    opened_at: Instant,
+    probing: bool,
+}
+
+/// Trips after a sustained Stripe outage so checkout traffic fails fast
+/// instead of waiting on timeouts. Server errors, timeouts, connection errors
+/// and calls slower than the latency budget count as failures; card declines
+/// and other client errors don't. Once the failure rate over a window reaches
+/// the threshold the circuit opens, and after `open_duration` a probe request
+/// decides whether it closes again. Give it to
+/// [`crate::facade::LibStripe::with_circuit_breaker`], or wrap calls in
+/// [`CircuitBreaker::call`].
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_rate: f64,
+    minimum_requests: u32,
+    window: Duration,
+    open_duration: Duration,
+    latency_budget: Option<Duration>,
+    inner: Mutex<Window>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker::new()
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        CircuitBreaker {
+            failure_rate: DEFAULT_FAILURE_RATE,
+            minimum_requests: DEFAULT_MINIMUM_REQUESTS,
+            window: DEFAULT_WINDOW,
+            open_duration: DEFAULT_OPEN_DURATION,
+            latency_budget: None,
+            inner: Mutex::new(Window {
+                state: CircuitState::Closed,
+                started: now,
+                requests: 0,
+                failures: 0,
+                opened_at: now,
+                probing: false,
+            }),
+        }
+    }
+
+    /// `failure_rate` is clamped to `0.0..=1.0`; the circuit only opens once a
+    /// window has seen `minimum_requests` requests.
+    pub fn with_failure_threshold(mut self, failure_rate: f64, minimum_requests: u32) -> Self {
+        self.failure_rate = failure_rate.clamp(0.0, 1.0);
+        self.minimum_requests = minimum_requests.max(1);
+        self
+    }
+
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    pub fn with_open_duration(mut self, open_duration: Duration) -> Self {
+        self.open_duration = open_duration;
+        self
+    }
+
+    /// Successful calls slower than `latency_budget` count as failures.
+    pub fn with_latency_budget(mut self, latency_budget: Duration) -> Self {
+        self.latency_budget = Some(latency_budget);
+        self
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.inner
+            .lock()
+            .map(|x| x.state)
+            .unwrap_or(CircuitState::Closed)
+    }
+
+    pub fn acquire(&self) -> Result<(), CircuitOpen> {
+        self.acquire_at(Instant::now())
+    }
+
+    /// Like [`CircuitBreaker::acquire`], but a probe permit gives up the
+    /// half-open probe slot if it is dropped before its outcome is recorded,
+    /// e.g. when the request future is cancelled.
+    pub(crate) fn permit(&self) -> Result<Permit<'_>, CircuitOpen> {
+        self.try_acquire(Instant::now()).map(|is_probe| Permit {
+            breaker: self,
+            is_probe,
+            recorded: false,
+        })
+    }
+
+    /// Runs `request` unless the circuit is open and records its outcome;
+    /// errors for which [`is_outage`] holds count as failures.
+    pub async fn call<T, F>(&self, request: F) -> Result<T, StripePaymentError>
+    where
+        F: Future<Output = Result<T, StripePaymentError>>,
+    {
+        let permit = self.permit()?;
+        let started = Instant::now();
+        let result = request.await;
+        permit.record(
+            matches!(&result, Err(x) if x.stripe_error().map(is_outage).unwrap_or(false)),
+            started.elapsed(),
+        );
+        result
+    }
+
+    /// Frees the probe slot without deciding the circuit's state, so the next
+    /// request becomes the probe.
+    pub fn abandon_probe(&self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            if inner.state == CircuitState::HalfOpen {
+                inner.probing = false;
+            }
+        }
+    }
+
+    pub fn acquire_at(&self, now: Instant) -> Result<(), CircuitOpen> {
+        self.try_acquire(now).map(|_| ())
+    }
+
+    /// Returns whether the request let through is the half-open probe.
+    fn try_acquire(&self, now: Instant) -> Result<bool, CircuitOpen> {
+        let mut inner = match self.inner.lock() {
+            Ok(x) => x,
+            Err(_) => return Ok(false),
+        };
+        match inner.state {
+            CircuitState::Closed => Ok(false),
+            CircuitState::Open => {
+                let elapsed = now.saturating_duration_since(inner.opened_at);
+                if elapsed < self.open_duration {
+                    return Err(CircuitOpen {
+                        retry_after: self.open_duration - elapsed,
+                    });
+                }
+                inner.state = CircuitState::HalfOpen;
+                inner.probing = true;
+                Ok(true)
+            }
+            CircuitState::HalfOpen if inner.probing => Err(CircuitOpen {
+                retry_after: Duration::ZERO,
+            }),
+            CircuitState::HalfOpen => {
+                inner.probing = true;
+                Ok(true)
+            }
+        }
+    }
+
+    pub fn record(&self, failed: bool, latency: Duration) {
+        self.record_at(failed, latency, Instant::now())
+    }
+
+    pub fn record_at(&self, failed: bool, latency: Duration, now: Instant) {
+        let failed = failed || self.latency_budget.map(|x| latency > x).unwrap_or(false);
+        let mut inner = match self.inner.lock() {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        match inner.state {
+            CircuitState::Open => {}
+            CircuitState::HalfOpen => {
+                inner.probing = false;
+                match failed {
+                    true => {
+                        tracing::warn!("stripe circuit probe failed, reopening");
+                        inner.state = CircuitState::Open;
+                        inner.opened_at = now;
+                    }
+                    false => {
+                        tracing::info!("stripe circuit closed");
+                        inner.state = CircuitState::Closed;
+                        inner.started = now;
+                        inner.requests = 0;
+                        inner.failures = 0;
+                    }
+                }
+            }
+            CircuitState::Closed => {
+                if now.saturating_duration_since(inner.started) >= self.window {
+                    inner.started = now;
+                    inner.requests = 0;
+                    inner.failures = 0;
+                }
+                inner.requests += 1;
+                inner.failures += failed as u32;
+                if inner.requests >= self.minimum_requests
+                    && inner.failures as f64 >= self.failure_rate * inner.requests as f64
+                {
+                    tracing::error!(
+                        "stripe circuit opened after {} of {} requests failed",
+                        inner.failures,
+                        inner.requests
+                    );
+                    inner.state = CircuitState::Open;
+                    inner.opened_at = now;
+                }
+            }
+        }
+    }
+}
+
+pub(crate) struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    is_probe: bool,
+    recorded: bool,
+}
+
+impl Permit<'_> {
+    pub(crate) fn record(mut self, failed: bool, latency: Duration) {
+        self.recorded = true;
+        self.breaker.record(failed, latency);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.is_probe && !self.recorded {
+            self.breaker.abandon_probe();
+        }
+    }
+}
+
+/// Whether `error` points at Stripe being unavailable rather than at the
+/// request itself: a server error, a timeout or a connection failure, which
+/// async-stripe reports as a client error carrying hyper's message.
+pub fn is_outage(error: &StripeError) -> bool {
+    match error {
+        StripeError::Stripe(x) => x.http_status >= 500,
+        StripeError::Timeout => true,
+        StripeError::ClientError(x) => x.to_lowercase().contains("connect"),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_and_recovers_through_half_open() {
+        let breaker = CircuitBreaker::new()
+            .with_failure_threshold(0.5, 4)
+            .with_open_duration(Duration::from_secs(10));
+        let start = Instant::now();
+        for failed in [false, true, true, true] {
+            assert!(breaker.acquire_at(start).is_ok());
+            breaker.record_at(failed, Duration::ZERO, start);
+        }
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert_eq!(
+            breaker.acquire_at(start + Duration::from_secs(4)),
+            Err(CircuitOpen {
+                retry_after: Duration::from_secs(6)
+            })
+        );
+        let later = start + Duration::from_secs(10);
+        assert!(breaker.acquire_at(later).is_ok());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(breaker.acquire_at(later).is_err());
+        breaker.record_at(false, Duration::ZERO, later);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn dropped_probe_frees_the_slot() {
+        let breaker = CircuitBreaker::new()
+            .with_failure_threshold(1.0, 1)
+            .with_open_duration(Duration::ZERO);
+        breaker.record(true, Duration::ZERO);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        drop(breaker.permit().unwrap());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        let probe = breaker.permit().unwrap();
+        assert!(breaker.permit().is_err());
+        probe.record(false, Duration::ZERO);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn dropped_request_keeps_the_probe_slot() {
+        let breaker = CircuitBreaker::new()
+            .with_failure_threshold(1.0, 1)
+            .with_open_duration(Duration::ZERO);
+        let request = breaker.permit().unwrap();
+        breaker.record(true, Duration::ZERO);
+        let probe = breaker.permit().unwrap();
+        drop(request);
+        assert!(breaker.permit().is_err());
+        drop(probe);
+        assert!(breaker.permit().is_ok());
+    }
+
+    #[test]
+    fn only_outages_count_as_failures() {
+        let status = |http_status| {
+            StripeError::Stripe(stripe::RequestError {
+                http_status,
+                ..Default::default()
+            })
+        };
+        assert!(is_outage(&status(503)));
+        assert!(is_outage(&StripeError::Timeout));
+        assert!(is_outage(&StripeError::ClientError(
+            "error trying to connect: tcp connect error".to_string()
+        )));
+        assert!(!is_outage(&status(402)));
+        assert!(!is_outage(&StripeError::ClientError(
+            "invalid amount".to_string()
+        )));
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use stripe::Client;
+
+use crate::metadata::MetadataNamespace;
+use crate::telemetry;
+use crate::url::StripeUrl;
+use crate::StripePaymentError;
+
+pub const CLIMATE_ORDER: &str = "climate_order";
+
+/// Commits a share of each payment, in basis points, to a Stripe Climate product.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ClimateContribution {
+    pub product_id: String,
+    pub basis_points: u32,
+}
+
+impl ClimateContribution {
+    pub fn new(product_id: impl Into<String>, basis_points: u32) -> Self {
+        ClimateContribution {
+            product_id: product_id.into(),
+            basis_points: basis_points.min(10_000),
+        }
+    }
+
+    /// The contribution for a payment, rounded down to the minor unit.
+    pub fn amount_for(&self, payment_amount: i64) -> i64 {
+        payment_amount.max(0) * self.basis_points as i64 / 10_000
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ClimateOrderDto {
+    pub id: String,
+    pub product_id: String,
+    pub amount_total: i64,
+    pub currency: String,
+    pub metric_tons: String,
+    pub status: String,
+}
+
+#[derive(Serialize)]
+struct OrderParams<'a> {
+    product: &'a str,
+    amount: i64,
+    currency: String,
+    metadata: HashMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct MetadataParams {
+    metadata: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct RawOrder {
+    id: String,
+    product: String,
+    amount_total: i64,
+    currency: String,
+    metric_tons: String,
+    status: String,
+}
+
+#[derive(Deserialize)]
+struct RawPaymentIntent {}
+
+impl From<RawOrder> for ClimateOrderDto {
+    fn from(x: RawOrder) -> Self {
+        ClimateOrderDto {
+            id: x.id,
+            product_id: x.product,
+            amount_total: x.amount_total,
+            currency: x.currency,
+            metric_tons: x.metric_tons,
+            status: x.status,
+        }
+    }
+}
+
+/// Creates a Climate order for the payment's share and records the order id in
+/// the payment intent's metadata. Returns `None` when the share rounds to zero.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn commit_climate_contribution(
+    stripe_client: &Client,
+    contribution: &ClimateContribution,
+    payment_intent_id: &str,
+    payment_amount: i64,
+    currency: &str,
+) -> Result<Option<ClimateOrderDto>, StripePaymentError> {
+    let amount = contribution.amount_for(payment_amount);
+    if amount == 0 {
+        return Ok(None);
+    }
+    let namespace = MetadataNamespace::default();
+    let mut metadata = HashMap::new();
+    namespace.insert(&mut metadata, "payment_intent", payment_intent_id);
+    let order = telemetry::observe(
+        "climate.orders.create",
+        stripe_client.post_form::<RawOrder, _>(
+            "/climate/orders",
+            OrderParams {
+                product: &contribution.product_id,
+                amount,
+                currency: currency.to_lowercase(),
+                metadata,
+            },
+        ),
+    )
+    .await
+    .map(ClimateOrderDto::from)
+    .map_err(StripePaymentError::from_general)?;
+    let mut metadata = HashMap::new();
+    namespace.insert(&mut metadata, CLIMATE_ORDER, order.id.clone());
+    telemetry::observe(
+        "payment_intents.update",
+        stripe_client.post_form::<RawPaymentIntent, _>(
+            &StripeUrl::new("/payment_intents")
+                .segment(payment_intent_id)
+                .build(),
+            MetadataParams { metadata },
+        ),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+    Ok(Some(order))
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn get_climate_order(
+    stripe_client: &Client,
+    order_id: &str,
+) -> Result<ClimateOrderDto, StripePaymentError> {
+    telemetry::observe(
+        "climate.orders.retrieve",
+        stripe_client.get::<RawOrder>(&StripeUrl::new("/climate/orders").segment(order_id).build()),
+    )
+    .await
+    .map(ClimateOrderDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_share_in_basis_points() {
+        let contribution = ClimateContribution::new("climsku_1", 100);
+        assert_eq!(contribution.amount_for(12_345), 123);
+        assert_eq!(contribution.amount_for(-5), 0);
+        assert_eq!(
+            ClimateContribution::new("climsku_1", 20_000).basis_points,
+            10_000
+        );
+    }
+}
@@ -0,0 +1,278 @@
+use serde::{Deserialize, Serialize};
+use stripe::{Client, RequestStrategy};
+
+use crate::telemetry;
+use crate::url::StripeUrl;
+use crate::StripePaymentError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RefundReason {
+    Duplicate,
+    Fraudulent,
+    RequestedByCustomer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CancellationReason {
+    Duplicate,
+    Fraudulent,
+    RequestedByCustomer,
+    Abandoned,
+}
+
+/// A Stripe operation that can be stored in an application outbox and executed later.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StripeCommand {
+    CreateRefund {
+        payment_intent_id: String,
+        amount: Option<i64>,
+        reason: Option<RefundReason>,
+    },
+    CancelIntent {
+        payment_intent_id: String,
+        reason: Option<CancellationReason>,
+    },
+    CapturePayment {
+        payment_intent_id: String,
+        amount_to_capture: Option<i64>,
+    },
+    CreateTransfer {
+        amount: i64,
+        currency: String,
+        destination: String,
+        transfer_group: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct OutboxEntry {
+    pub idempotency_key: String,
+    pub command: StripeCommand,
+}
+
+impl OutboxEntry {
+    pub fn new(idempotency_key: impl Into<String>, command: StripeCommand) -> Self {
+        OutboxEntry {
+            idempotency_key: idempotency_key.into(),
+            command,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct CommandOutcome {
+    pub idempotency_key: String,
+    pub object_id: String,
+    pub status: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StripeObject {
+    id: String,
+    status: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct RefundParams<'a> {
+    pub(crate) payment_intent: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) amount: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) reason: Option<RefundReason>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct CancelParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) cancellation_reason: Option<CancellationReason>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct CaptureParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) amount_to_capture: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct TransferParams<'a> {
+    pub(crate) amount: i64,
+    pub(crate) currency: String,
+    pub(crate) destination: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) transfer_group: Option<&'a str>,
+}
+
+pub(crate) fn endpoint(command: &StripeCommand) -> &'static str {
+    match command {
+        StripeCommand::CreateRefund { .. } => "refunds.create",
+        StripeCommand::CancelIntent { .. } => "payment_intents.cancel",
+        StripeCommand::CapturePayment { .. } => "payment_intents.capture",
+        StripeCommand::CreateTransfer { .. } => "transfers.create",
+    }
+}
+
+/// Executes an outbox entry. The idempotency key is sent with the request so
+/// re-running an entry that already reached Stripe returns the original object.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn execute_command(
+    stripe_client: &Client,
+    entry: &OutboxEntry,
+) -> Result<CommandOutcome, StripePaymentError> {
+    let client = stripe_client
+        .clone()
+        .with_strategy(RequestStrategy::Idempotent(entry.idempotency_key.clone()));
+    let result = telemetry::observe(endpoint(&entry.command), async {
+        match &entry.command {
+            StripeCommand::CreateRefund {
+                payment_intent_id,
+                amount,
+                reason,
+            } => {
+                client
+                    .post_form::<StripeObject, _>(
+                        "/refunds",
+                        RefundParams {
+                            payment_intent: payment_intent_id,
+                            amount: *amount,
+                            reason: *reason,
+                        },
+                    )
+                    .await
+            }
+            StripeCommand::CancelIntent {
+                payment_intent_id,
+                reason,
+            } => {
+                client
+                    .post_form::<StripeObject, _>(
+                        &StripeUrl::new("/payment_intents")
+                            .segment(payment_intent_id)
+                            .segment("cancel")
+                            .build(),
+                        CancelParams {
+                            cancellation_reason: *reason,
+                        },
+                    )
+                    .await
+            }
+            StripeCommand::CapturePayment {
+                payment_intent_id,
+                amount_to_capture,
+            } => {
+                client
+                    .post_form::<StripeObject, _>(
+                        &StripeUrl::new("/payment_intents")
+                            .segment(payment_intent_id)
+                            .segment("capture")
+                            .build(),
+                        CaptureParams {
+                            amount_to_capture: *amount_to_capture,
+                        },
+                    )
+                    .await
+            }
+            StripeCommand::CreateTransfer {
+                amount,
+                currency,
+                destination,
+                transfer_group,
+            } => {
+                client
+                    .post_form::<StripeObject, _>(
+                        "/transfers",
+                        TransferParams {
+                            amount: *amount,
+                            currency: currency.to_lowercase(),
+                            destination,
+                            transfer_group: transfer_group.as_deref(),
+                        },
+                    )
+                    .await
+            }
+        }
+    })
+    .await;
+    let object = match result {
+        Ok(x) => x,
+        Err(e) => return reconcile(stripe_client, entry, e).await,
+    };
+    Ok(CommandOutcome {
+        idempotency_key: entry.idempotency_key.clone(),
+        object_id: object.id,
+        status: object.status,
+    })
+}
+
+// A cancel or capture that was applied by an earlier attempt whose idempotency
+// key has since expired fails with a state error; treat the current state as the outcome.
+async fn reconcile(
+    stripe_client: &Client,
+    entry: &OutboxEntry,
+    error: StripePaymentError,
+) -> Result<CommandOutcome, StripePaymentError> {
+    let (payment_intent_id, expected) = match &entry.command {
+        StripeCommand::CancelIntent {
+            payment_intent_id, ..
+        } => (payment_intent_id, "canceled"),
+        StripeCommand::CapturePayment {
+            payment_intent_id, ..
+        } => (payment_intent_id, "succeeded"),
+        _ => return Err(error),
+    };
+    let current = telemetry::observe(
+        "payment_intents.retrieve",
+        stripe_client.get::<StripeObject>(
+            &StripeUrl::new("/payment_intents")
+                .segment(payment_intent_id)
+                .build(),
+        ),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+    if current.status.as_deref() != Some(expected) {
+        return Err(error);
+    }
+    tracing::debug!("{} already {}", payment_intent_id, expected);
+    Ok(CommandOutcome {
+        idempotency_key: entry.idempotency_key.clone(),
+        object_id: current.id,
+        status: current.status,
+    })
+}
+
+pub async fn execute_commands(
+    stripe_client: &Client,
+    entries: &[OutboxEntry],
+) -> Vec<Result<CommandOutcome, StripePaymentError>> {
+    let mut results = Vec::with_capacity(entries.len());
+    for entry in entries {
+        results.push(execute_command(stripe_client, entry).await);
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_roundtrips_through_json() {
+        let entry = OutboxEntry::new(
+            "order-1-refund",
+            StripeCommand::CreateRefund {
+                payment_intent_id: "pi_1".to_string(),
+                amount: Some(500),
+                reason: Some(RefundReason::RequestedByCustomer),
+            },
+        );
+        let json = serde_json::to_string(&entry).unwrap();
+        assert!(json.contains(r#""type":"create_refund""#));
+        assert!(json.contains(r#""reason":"requested_by_customer""#));
+        assert_eq!(serde_json::from_str::<OutboxEntry>(&json).unwrap(), entry);
+    }
+}
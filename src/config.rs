@@ -0,0 +1,256 @@
+use std::fmt::{Display, Formatter};
+
+#[cfg(feature = "runtime-tokio-hyper")]
+use crate::facade::LibStripe;
+use crate::redact::SecretString;
+use crate::webhook::WebhookVerifier;
+
+pub const SECRET_KEY_VAR: &str = "STRIPE_SECRET_KEY";
+pub const SECONDARY_SECRET_KEY_VAR: &str = "STRIPE_SECONDARY_SECRET_KEY";
+pub const WEBHOOK_SECRET_VAR: &str = "STRIPE_WEBHOOK_SECRET";
+pub const API_VERSION_VAR: &str = "STRIPE_API_VERSION";
+/// `test` or `live`; when set, keys of the other mode are rejected.
+pub const MODE_VAR: &str = "STRIPE_MODE";
+pub const DRY_RUN_VAR: &str = "STRIPE_DRY_RUN";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StripeMode {
+    Test,
+    Live,
+}
+
+impl StripeMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StripeMode::Test => "test",
+            StripeMode::Live => "live",
+        }
+    }
+
+    /// The mode of a secret (`sk_`) or restricted (`rk_`) key.
+    pub fn of_key(key: &str) -> Option<StripeMode> {
+        let rest = key
+            .strip_prefix("sk_")
+            .or_else(|| key.strip_prefix("rk_"))?;
+        match rest.split_once('_') {
+            Some(("test", x)) if !x.is_empty() => Some(StripeMode::Test),
+            Some(("live", x)) if !x.is_empty() => Some(StripeMode::Live),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    Missing(&'static str),
+    /// The variable is set but its value is malformed; the value itself is
+    /// not included since it may be a secret.
+    Invalid {
+        var: &'static str,
+        reason: String,
+    },
+    ModeMismatch {
+        var: &'static str,
+        expected: StripeMode,
+        actual: StripeMode,
+    },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Missing(var) => write!(f, "{} is not set", var),
+            ConfigError::Invalid { var, reason } => write!(f, "{} is invalid: {}", var, reason),
+            ConfigError::ModeMismatch {
+                var,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} is a {} mode key but {} mode is expected",
+                var,
+                actual.as_str(),
+                expected.as_str()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Validated settings for a [`LibStripe`] facade and its webhook endpoint, so
+/// services using the crate are set up the same way.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct LibStripeConfig {
+    pub secret_key: SecretString,
+    pub secondary_secret_key: Option<SecretString>,
+    pub webhook_secret: Option<SecretString>,
+    pub api_version: Option<String>,
+    pub mode: StripeMode,
+    pub dry_run: bool,
+}
+
+fn key_mode(var: &'static str, key: &str) -> Result<StripeMode, ConfigError> {
+    StripeMode::of_key(key).ok_or_else(|| ConfigError::Invalid {
+        var,
+        reason: "expected an sk_test_, sk_live_, rk_test_ or rk_live_ key".to_string(),
+    })
+}
+
+fn is_api_version(x: &str) -> bool {
+    let date = x.split_once('.').map(|(date, _)| date).unwrap_or(x);
+    date.len() == 10
+        && date.char_indices().all(|(i, x)| match i {
+            4 | 7 => x == '-',
+            _ => x.is_ascii_digit(),
+        })
+}
+
+impl LibStripeConfig {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        LibStripeConfig::from_lookup(|x| std::env::var(x).ok())
+    }
+
+    /// Like [`LibStripeConfig::from_env`], reading variables through `lookup`.
+    /// Empty values are treated as unset.
+    pub fn from_lookup<F>(lookup: F) -> Result<Self, ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let var = |name: &str| lookup(name).filter(|x| !x.trim().is_empty());
+        let secret_key = var(SECRET_KEY_VAR).ok_or(ConfigError::Missing(SECRET_KEY_VAR))?;
+        let mode = key_mode(SECRET_KEY_VAR, &secret_key)?;
+        if let Some(expected) = var(MODE_VAR) {
+            let expected = match expected.to_lowercase().as_str() {
+                "test" => StripeMode::Test,
+                "live" => StripeMode::Live,
+                _ => {
+                    return Err(ConfigError::Invalid {
+                        var: MODE_VAR,
+                        reason: format!("expected test or live, got {}", expected),
+                    })
+                }
+            };
+            if expected != mode {
+                return Err(ConfigError::ModeMismatch {
+                    var: SECRET_KEY_VAR,
+                    expected,
+                    actual: mode,
+                });
+            }
+        }
+        let secondary_secret_key = var(SECONDARY_SECRET_KEY_VAR);
+        if let Some(x) = &secondary_secret_key {
+            let actual = key_mode(SECONDARY_SECRET_KEY_VAR, x)?;
+            if actual != mode {
+                return Err(ConfigError::ModeMismatch {
+                    var: SECONDARY_SECRET_KEY_VAR,
+                    expected: mode,
+                    actual,
+                });
+            }
+        }
+        let webhook_secret = var(WEBHOOK_SECRET_VAR);
+        if matches!(&webhook_secret, Some(x) if !x.starts_with("whsec_")) {
+            return Err(ConfigError::Invalid {
+                var: WEBHOOK_SECRET_VAR,
+                reason: "expected a whsec_ signing secret".to_string(),
+            });
+        }
+        let api_version = var(API_VERSION_VAR);
+        if let Some(x) = api_version.as_ref().filter(|x| !is_api_version(x)) {
+            return Err(ConfigError::Invalid {
+                var: API_VERSION_VAR,
+                reason: format!("expected a YYYY-MM-DD version, got {}", x),
+            });
+        }
+        let dry_run = match var(DRY_RUN_VAR).map(|x| x.to_lowercase()).as_deref() {
+            None | Some("0") | Some("false") => false,
+            Some("1") | Some("true") => true,
+            Some(x) => {
+                return Err(ConfigError::Invalid {
+                    var: DRY_RUN_VAR,
+                    reason: format!("expected true or false, got {}", x),
+                })
+            }
+        };
+        Ok(LibStripeConfig {
+            secret_key: secret_key.into(),
+            secondary_secret_key: secondary_secret_key.map(SecretString::from),
+            webhook_secret: webhook_secret.map(SecretString::from),
+            api_version,
+            mode,
+            dry_run,
+        })
+    }
+
+    pub fn is_live(&self) -> bool {
+        self.mode == StripeMode::Live
+    }
+
+    #[cfg(feature = "runtime-tokio-hyper")]
+    pub fn lib_stripe(&self) -> LibStripe {
+        let mut lib_stripe = LibStripe::new(self.secret_key.expose()).with_dry_run(self.dry_run);
+        if let Some(x) = &self.secondary_secret_key {
+            lib_stripe = lib_stripe.with_secondary_key(x.expose());
+        }
+        if let Some(x) = &self.api_version {
+            lib_stripe = lib_stripe.with_api_version(x);
+        }
+        lib_stripe
+    }
+
+    /// `None` when no webhook secret is configured.
+    pub fn webhook_verifier(&self) -> Option<WebhookVerifier> {
+        self.webhook_secret
+            .as_ref()
+            .map(|x| WebhookVerifier::new(x.expose()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn config(vars: &[(&str, &str)]) -> Result<LibStripeConfig, ConfigError> {
+        let vars = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<_, _>>();
+        LibStripeConfig::from_lookup(|x| vars.get(x).cloned())
+    }
+
+    #[test]
+    fn validates_keys_and_mode() {
+        let x = config(&[
+            (SECRET_KEY_VAR, "sk_test_123"),
+            (WEBHOOK_SECRET_VAR, "whsec_abc"),
+            (API_VERSION_VAR, "2022-11-15"),
+        ])
+        .unwrap();
+        assert_eq!(x.mode, StripeMode::Test);
+        assert!(x.webhook_verifier().is_some());
+        assert_eq!(
+            config(&[]).unwrap_err(),
+            ConfigError::Missing(SECRET_KEY_VAR)
+        );
+        assert!(matches!(
+            config(&[(SECRET_KEY_VAR, "pk_test_123")]),
+            Err(ConfigError::Invalid { .. })
+        ));
+        assert!(matches!(
+            config(&[(SECRET_KEY_VAR, "sk_test_123"), (MODE_VAR, "live")]),
+            Err(ConfigError::ModeMismatch { .. })
+        ));
+        assert!(matches!(
+            config(&[
+                (SECRET_KEY_VAR, "sk_live_123"),
+                (SECONDARY_SECRET_KEY_VAR, "rk_test_456")
+            ]),
+            Err(ConfigError::ModeMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,242 @@
+use serde::{Deserialize, Serialize};
+use stripe::Client;
+
+use crate::pagination::{Page, PageRequest, RawList};
+use crate::telemetry;
+use crate::StripePaymentError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CreditNoteReason {
+    Duplicate,
+    Fraudulent,
+    OrderChange,
+    ProductUnsatisfactory,
+}
+
+/// A line of a credit note: either (part of) an existing invoice line, or a
+/// free-form amount such as a goodwill credit.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CreditNoteLine {
+    InvoiceLineItem {
+        invoice_line_item: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        quantity: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        amount: Option<i64>,
+    },
+    CustomLineItem {
+        description: String,
+        quantity: u64,
+        unit_amount: i64,
+    },
+}
+
+impl CreditNoteLine {
+    /// Credits the whole invoice line.
+    pub fn invoice_line(invoice_line_item: impl Into<String>) -> Self {
+        CreditNoteLine::InvoiceLineItem {
+            invoice_line_item: invoice_line_item.into(),
+            quantity: None,
+            amount: None,
+        }
+    }
+
+    pub fn invoice_line_amount(invoice_line_item: impl Into<String>, amount: i64) -> Self {
+        CreditNoteLine::InvoiceLineItem {
+            invoice_line_item: invoice_line_item.into(),
+            quantity: None,
+            amount: Some(amount),
+        }
+    }
+
+    pub fn custom(description: impl Into<String>, unit_amount: i64) -> Self {
+        CreditNoteLine::CustomLineItem {
+            description: description.into(),
+            quantity: 1,
+            unit_amount,
+        }
+    }
+}
+
+/// Where the credited amount goes. Open invoices can only have their amount
+/// due reduced; paid invoices must settle the full credit note total.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CreditNoteSettlement {
+    ReduceAmountDue,
+    Refund(i64),
+    CustomerBalance(i64),
+    OutOfBand(i64),
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CreditNoteDto {
+    pub id: String,
+    pub number: String,
+    pub invoice_id: String,
+    pub stripe_customer_id: String,
+    pub status: String,
+    pub reason: Option<String>,
+    pub amount: i64,
+    pub currency: String,
+    pub refund_id: Option<String>,
+    pub out_of_band_amount: Option<i64>,
+    pub created: i64,
+}
+
+#[derive(Deserialize)]
+struct RawCreditNote {
+    id: String,
+    number: String,
+    invoice: String,
+    customer: String,
+    status: String,
+    reason: Option<String>,
+    amount: i64,
+    currency: String,
+    refund: Option<String>,
+    out_of_band_amount: Option<i64>,
+    created: i64,
+}
+
+impl From<RawCreditNote> for CreditNoteDto {
+    fn from(x: RawCreditNote) -> Self {
+        CreditNoteDto {
+            id: x.id,
+            number: x.number,
+            invoice_id: x.invoice,
+            stripe_customer_id: x.customer,
+            status: x.status,
+            reason: x.reason,
+            amount: x.amount,
+            currency: x.currency,
+            refund_id: x.refund,
+            out_of_band_amount: x.out_of_band_amount,
+            created: x.created,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CreateParams<'a> {
+    invoice: &'a str,
+    lines: &'a [CreditNoteLine],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<CreditNoteReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refund_amount: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credit_amount: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    out_of_band_amount: Option<i64>,
+}
+
+impl<'a> CreateParams<'a> {
+    fn new(
+        invoice: &'a str,
+        lines: &'a [CreditNoteLine],
+        reason: Option<CreditNoteReason>,
+        refund_or_credit: CreditNoteSettlement,
+    ) -> Self {
+        let mut params = CreateParams {
+            invoice,
+            lines,
+            reason,
+            refund_amount: None,
+            credit_amount: None,
+            out_of_band_amount: None,
+        };
+        match refund_or_credit {
+            CreditNoteSettlement::ReduceAmountDue => {}
+            CreditNoteSettlement::Refund(x) => params.refund_amount = Some(x),
+            CreditNoteSettlement::CustomerBalance(x) => params.credit_amount = Some(x),
+            CreditNoteSettlement::OutOfBand(x) => params.out_of_band_amount = Some(x),
+        }
+        params
+    }
+}
+
+/// Issues a credit note against a finalized invoice.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn create_credit_note(
+    stripe_client: &Client,
+    invoice_id: &str,
+    lines: &[CreditNoteLine],
+    reason: Option<CreditNoteReason>,
+    refund_or_credit: CreditNoteSettlement,
+) -> Result<CreditNoteDto, StripePaymentError> {
+    if lines.is_empty() {
+        return Err(StripePaymentError::from_general(
+            "a credit note needs at least one line".to_string(),
+        ));
+    }
+    telemetry::observe(
+        "credit_notes.create",
+        stripe_client.post_form::<RawCreditNote, _>(
+            "/credit_notes",
+            CreateParams::new(invoice_id, lines, reason, refund_or_credit),
+        ),
+    )
+    .await
+    .map(CreditNoteDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+#[derive(Serialize)]
+struct ListParams<'a> {
+    invoice: &'a str,
+    limit: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    starting_after: Option<&'a str>,
+}
+
+/// Lists the credit notes issued against an invoice, newest first.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn list_credit_notes(
+    stripe_client: &Client,
+    invoice_id: &str,
+    page: &PageRequest,
+) -> Result<Page<CreditNoteDto>, StripePaymentError> {
+    let list = telemetry::observe(
+        "credit_notes.list",
+        stripe_client.get_query::<RawList<RawCreditNote>, _>(
+            "/credit_notes",
+            ListParams {
+                invoice: invoice_id,
+                limit: page.limit,
+                starting_after: page.starting_after.as_deref(),
+            },
+        ),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+    let next_cursor = list.data.last().map(|x| x.id.clone());
+    Ok(Page {
+        data: list.data.into_iter().map(CreditNoteDto::from).collect(),
+        has_more: list.has_more,
+        next_cursor,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settlement_sets_one_amount() {
+        let lines = [CreditNoteLine::custom("Goodwill", 500)];
+        let params = serde_json::to_value(CreateParams::new(
+            "in_1",
+            &lines,
+            Some(CreditNoteReason::ProductUnsatisfactory),
+            CreditNoteSettlement::CustomerBalance(500),
+        ))
+        .unwrap();
+        assert_eq!(params["credit_amount"], 500);
+        assert!(params.get("refund_amount").is_none());
+        assert_eq!(params["reason"], "product_unsatisfactory");
+        assert_eq!(params["lines"][0]["type"], "custom_line_item");
+    }
+}
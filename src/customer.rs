@@ -0,0 +1,597 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use stripe::Client;
+use tokio::sync::OnceCell;
+
+use crate::pagination::{RawList, RawSearchResult};
+use crate::telemetry;
+use crate::{CreateCustomerDto, CustomerDto, StripePaymentError};
+
+#[derive(Debug)]
+pub enum CustomerLookupError {
+    NotFound(String),
+    Ambiguous { email: String, ids: Vec<String> },
+    Stripe(StripePaymentError),
+}
+
+impl Display for CustomerLookupError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CustomerLookupError::NotFound(x) => write!(f, "no customer with email {}", x),
+            CustomerLookupError::Ambiguous { email, ids } => write!(
+                f,
+                "{} customers with email {}: {}",
+                ids.len(),
+                email,
+                ids.join(", ")
+            ),
+            CustomerLookupError::Stripe(x) => write!(f, "{:?}", x),
+        }
+    }
+}
+
+impl std::error::Error for CustomerLookupError {}
+
+impl From<StripePaymentError> for CustomerLookupError {
+    fn from(x: StripePaymentError) -> Self {
+        CustomerLookupError::Stripe(x)
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CustomerSummaryDto {
+    pub id: String,
+    pub email: Option<String>,
+    pub name: Option<String>,
+    pub created: i64,
+}
+
+#[derive(Deserialize)]
+struct RawCustomer {
+    id: String,
+    email: Option<String>,
+    name: Option<String>,
+    created: i64,
+}
+
+impl From<RawCustomer> for CustomerSummaryDto {
+    fn from(x: RawCustomer) -> Self {
+        CustomerSummaryDto {
+            id: x.id,
+            email: x.email,
+            name: x.name,
+            created: x.created,
+        }
+    }
+}
+
+/// Customers whose email matches exactly, oldest first.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CustomerMatches {
+    pub email: String,
+    pub customers: Vec<CustomerSummaryDto>,
+}
+
+impl CustomerMatches {
+    pub fn exactly_one(self) -> Result<CustomerSummaryDto, CustomerLookupError> {
+        match self.customers.len() {
+            0 => Err(CustomerLookupError::NotFound(self.email)),
+            1 => Ok(self.customers.into_iter().next().unwrap()),
+            _ => Err(CustomerLookupError::Ambiguous {
+                email: self.email,
+                ids: self.customers.into_iter().map(|x| x.id).collect(),
+            }),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SearchParams<'a> {
+    query: &'a str,
+    limit: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    page: Option<&'a str>,
+}
+
+pub fn email_query(email: &str) -> String {
+    format!(
+        "email:'{}'",
+        email.replace('\\', "\\\\").replace('\'', "\\'")
+    )
+}
+
+/// Searches customers by email. Search is case-insensitive on Stripe's side, so
+/// results are filtered again to drop near matches.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn find_customer_by_email(
+    stripe_client: &Client,
+    email: &str,
+) -> Result<CustomerMatches, StripePaymentError> {
+    let query = email_query(email);
+    let mut customers = vec![];
+    let mut page = None;
+    loop {
+        let result = telemetry::observe(
+            "customers.search",
+            stripe_client.get_query::<RawSearchResult<RawCustomer>, _>(
+                "/customers/search",
+                SearchParams {
+                    query: &query,
+                    limit: 100,
+                    page: page.as_deref(),
+                },
+            ),
+        )
+        .await
+        .map_err(StripePaymentError::from_general)?;
+        customers.extend(
+            result
+                .data
+                .into_iter()
+                .filter(|x| {
+                    x.email
+                        .as_deref()
+                        .map(|x| x.trim().eq_ignore_ascii_case(email.trim()))
+                        .unwrap_or(false)
+                })
+                .map(CustomerSummaryDto::from),
+        );
+        match (result.has_more, result.next_page) {
+            (true, Some(next_page)) => page = Some(next_page),
+            _ => break,
+        }
+    }
+    customers.sort_by_key(|x| x.created);
+    Ok(CustomerMatches {
+        email: email.to_string(),
+        customers,
+    })
+}
+
+/// A customer's lifetime value from their successful charges. Amounts are
+/// kept per currency since amounts in different currencies can't be summed.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct CustomerLtv {
+    pub customer_id: String,
+    pub purchases: u64,
+    /// Purchases that were refunded in full or in part.
+    pub refunded_purchases: u64,
+    pub gross: HashMap<String, i64>,
+    pub refunded: HashMap<String, i64>,
+    pub first_purchase_at: Option<i64>,
+    pub last_purchase_at: Option<i64>,
+}
+
+impl CustomerLtv {
+    /// Gross minus refunded amount in `currency`.
+    pub fn net(&self, currency: &str) -> i64 {
+        let currency = currency.to_lowercase();
+        self.gross.get(&currency).copied().unwrap_or_default()
+            - self.refunded.get(&currency).copied().unwrap_or_default()
+    }
+
+    /// Share of the gross amount in `currency` that was refunded.
+    pub fn refund_rate(&self, currency: &str) -> f64 {
+        let currency = currency.to_lowercase();
+        match self.gross.get(&currency).copied().unwrap_or_default() {
+            0 => 0.0,
+            x => self.refunded.get(&currency).copied().unwrap_or_default() as f64 / x as f64,
+        }
+    }
+
+    fn add(&mut self, charge: RawCustomerCharge) {
+        if charge.status != "succeeded" {
+            return;
+        }
+        self.purchases += 1;
+        *self.gross.entry(charge.currency.clone()).or_default() += charge.amount;
+        if charge.amount_refunded > 0 {
+            self.refunded_purchases += 1;
+            *self.refunded.entry(charge.currency).or_default() += charge.amount_refunded;
+        }
+        self.first_purchase_at = Some(
+            self.first_purchase_at
+                .map_or(charge.created, |x| x.min(charge.created)),
+        );
+        self.last_purchase_at = Some(
+            self.last_purchase_at
+                .map_or(charge.created, |x| x.max(charge.created)),
+        );
+    }
+}
+
+#[derive(Deserialize)]
+struct RawCustomerCharge {
+    id: String,
+    amount: i64,
+    #[serde(default)]
+    amount_refunded: i64,
+    currency: String,
+    status: String,
+    created: i64,
+}
+
+#[derive(Serialize)]
+struct CustomerChargesParams<'a> {
+    customer: &'a str,
+    limit: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    starting_after: Option<&'a str>,
+}
+
+/// Pages through the customer's charges. Refunds are taken from each charge's
+/// refunded amount, which covers every refund against it, since Stripe can't
+/// list refunds by customer.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn customer_ltv(
+    stripe_client: &Client,
+    customer_id: &str,
+) -> Result<CustomerLtv, StripePaymentError> {
+    let mut ltv = CustomerLtv {
+        customer_id: customer_id.to_string(),
+        ..CustomerLtv::default()
+    };
+    let mut starting_after = None::<String>;
+    loop {
+        let list = telemetry::observe(
+            "charges.list",
+            stripe_client.get_query::<RawList<RawCustomerCharge>, _>(
+                "/charges",
+                CustomerChargesParams {
+                    customer: customer_id,
+                    limit: 100,
+                    starting_after: starting_after.as_deref(),
+                },
+            ),
+        )
+        .await
+        .map_err(StripePaymentError::from_general)?;
+        let done = !list.has_more || list.data.is_empty();
+        starting_after = list.data.last().map(|x| x.id.clone());
+        list.data.into_iter().for_each(|x| ltv.add(x));
+        if done {
+            return Ok(ltv);
+        }
+    }
+}
+
+/// Durable mapping between internal account ids and Stripe customer ids.
+/// Implement it on the application database so lookups don't depend on
+/// Stripe's metadata search, which is eventually consistent and rate limited.
+#[async_trait]
+pub trait CustomerStore: Send + Sync {
+    async fn get(&self, internal_id: &str) -> Result<Option<String>, StripePaymentError>;
+
+    async fn internal_id(
+        &self,
+        stripe_customer_id: &str,
+    ) -> Result<Option<String>, StripePaymentError>;
+
+    async fn put(
+        &self,
+        internal_id: &str,
+        stripe_customer_id: &str,
+    ) -> Result<(), StripePaymentError>;
+}
+
+#[derive(Debug, Default)]
+pub struct InMemoryCustomerStore {
+    ids: Mutex<HashMap<String, String>>,
+}
+
+impl InMemoryCustomerStore {
+    pub fn new() -> Self {
+        InMemoryCustomerStore::default()
+    }
+}
+
+#[async_trait]
+impl CustomerStore for InMemoryCustomerStore {
+    async fn get(&self, internal_id: &str) -> Result<Option<String>, StripePaymentError> {
+        Ok(self
+            .ids
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .get(internal_id)
+            .cloned())
+    }
+
+    async fn internal_id(
+        &self,
+        stripe_customer_id: &str,
+    ) -> Result<Option<String>, StripePaymentError> {
+        Ok(self
+            .ids
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .iter()
+            .find(|(_, v)| v.as_str() == stripe_customer_id)
+            .map(|(k, _)| k.clone()))
+    }
+
+    async fn put(
+        &self,
+        internal_id: &str,
+        stripe_customer_id: &str,
+    ) -> Result<(), StripePaymentError> {
+        self.ids
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .insert(internal_id.to_string(), stripe_customer_id.to_string());
+        Ok(())
+    }
+}
+
+/// Postgres-backed [`CustomerStore`], expecting a table like
+///
+/// ```sql
+/// CREATE TABLE stripe_customers (
+///     internal_id TEXT PRIMARY KEY,
+///     stripe_customer_id TEXT NOT NULL UNIQUE
+/// );
+/// ```
+#[cfg(feature = "sqlx-postgres")]
+#[derive(Debug, Clone)]
+pub struct SqlxCustomerStore {
+    pool: sqlx::PgPool,
+    table: String,
+}
+
+#[cfg(feature = "sqlx-postgres")]
+impl SqlxCustomerStore {
+    pub fn new(pool: sqlx::PgPool) -> Self {
+        SqlxCustomerStore {
+            pool,
+            table: "stripe_customers".to_string(),
+        }
+    }
+
+    /// The table name is interpolated into the queries, so it must not come
+    /// from user input.
+    pub fn with_table(mut self, table: impl Into<String>) -> Self {
+        self.table = table.into();
+        self
+    }
+}
+
+#[cfg(feature = "sqlx-postgres")]
+#[async_trait]
+impl CustomerStore for SqlxCustomerStore {
+    async fn get(&self, internal_id: &str) -> Result<Option<String>, StripePaymentError> {
+        sqlx::query_scalar::<_, String>(&format!(
+            "SELECT stripe_customer_id FROM {} WHERE internal_id = $1",
+            self.table
+        ))
+        .bind(internal_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|x| StripePaymentError::from_general(x.to_string()))
+    }
+
+    async fn internal_id(
+        &self,
+        stripe_customer_id: &str,
+    ) -> Result<Option<String>, StripePaymentError> {
+        sqlx::query_scalar::<_, String>(&format!(
+            "SELECT internal_id FROM {} WHERE stripe_customer_id = $1",
+            self.table
+        ))
+        .bind(stripe_customer_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|x| StripePaymentError::from_general(x.to_string()))
+    }
+
+    async fn put(
+        &self,
+        internal_id: &str,
+        stripe_customer_id: &str,
+    ) -> Result<(), StripePaymentError> {
+        sqlx::query(&format!(
+            "INSERT INTO {} (internal_id, stripe_customer_id) VALUES ($1, $2) \
+             ON CONFLICT (internal_id) DO UPDATE SET stripe_customer_id = EXCLUDED.stripe_customer_id",
+            self.table
+        ))
+        .bind(internal_id)
+        .bind(stripe_customer_id)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|x| StripePaymentError::from_general(x.to_string()))
+    }
+}
+
+/// Returns the customer of `dto.id` from `store`. On a miss the metadata
+/// search is tried as a fallback, for customers created before the store
+/// existed, and the customer is created if that finds nothing; either way the
+/// mapping is written back to `store`.
+#[tracing::instrument(skip(stripe_client, store))]
+pub async fn get_or_create_customer(
+    stripe_client: &Client,
+    store: &dyn CustomerStore,
+    dto: &CreateCustomerDto,
+) -> Result<CustomerDto, StripePaymentError> {
+    if let Some(x) = store.get(&dto.id).await? {
+        return Ok(CustomerDto::new(x));
+    }
+    let customer = match crate::find_customer(stripe_client, &dto.id)
+        .await
+        .map_err(StripePaymentError::from_general)?
+    {
+        Some(x) => {
+            tracing::info!("customer of {} found by metadata search", dto.id);
+            x
+        }
+        None => crate::create_customer(stripe_client, dto).await?,
+    };
+    store.put(&dto.id, &customer.id).await?;
+    Ok(customer)
+}
+
+/// Maps internal account ids to Stripe customer ids. Concurrent lookups for the
+/// same account share a single search (and create, if the customer does not
+/// exist yet) instead of each hitting Stripe. Failed lookups are not cached.
+#[derive(Default)]
+pub struct CustomerIdCache {
+    entries: Mutex<HashMap<String, Arc<OnceCell<String>>>>,
+    store: Option<Arc<dyn CustomerStore>>,
+}
+
+impl std::fmt::Debug for CustomerIdCache {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CustomerIdCache")
+            .field("entries", &self.entries)
+            .field("store", &self.store.is_some())
+            .finish()
+    }
+}
+
+impl CustomerIdCache {
+    pub fn new() -> Self {
+        CustomerIdCache::default()
+    }
+
+    /// Consults `store` on a miss before searching Stripe, see
+    /// [`get_or_create_customer`].
+    pub fn with_store(mut self, store: Arc<dyn CustomerStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    fn entry(&self, account_id: &str) -> Arc<OnceCell<String>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .entry(account_id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    pub fn get(&self, account_id: &str) -> Option<String> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .get(account_id)
+            .and_then(|x| x.get().cloned())
+    }
+
+    pub fn insert(&self, account_id: impl Into<String>, stripe_customer_id: impl Into<String>) {
+        self.entries
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .insert(
+                account_id.into(),
+                Arc::new(OnceCell::new_with(Some(stripe_customer_id.into()))),
+            );
+    }
+
+    /// Forgets the account, e.g. after its customer was deleted in Stripe.
+    pub fn invalidate(&self, account_id: &str) {
+        self.entries
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .remove(account_id);
+    }
+
+    /// Returns the customer of `dto.id`, searching Stripe on a miss and
+    /// creating the customer if none exists.
+    #[tracing::instrument(skip(self, stripe_client))]
+    pub async fn get_or_create(
+        &self,
+        stripe_client: &Client,
+        dto: &CreateCustomerDto,
+    ) -> Result<CustomerDto, StripePaymentError> {
+        let entry = self.entry(&dto.id);
+        entry
+            .get_or_try_init(|| async {
+                if let Some(store) = &self.store {
+                    return get_or_create_customer(stripe_client, store.as_ref(), dto)
+                        .await
+                        .map(|x| x.id);
+                }
+                match crate::find_customer(stripe_client, &dto.id).await {
+                    Ok(Some(x)) => Ok(x.id),
+                    Ok(None) => crate::create_customer(stripe_client, dto)
+                        .await
+                        .map(|x| x.id),
+                    Err(x) => Err(StripePaymentError::from_general(x)),
+                }
+            })
+            .await
+            .map(|x| CustomerDto::new(x.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn customer(id: &str, created: i64) -> CustomerSummaryDto {
+        CustomerSummaryDto {
+            id: id.to_string(),
+            email: Some("a@example.com".to_string()),
+            name: None,
+            created,
+        }
+    }
+
+    #[test]
+    fn exactly_one_rejects_ambiguity() {
+        assert_eq!(email_query("o'neil@x.io"), r"email:'o\'neil@x.io'");
+
+        let matches = CustomerMatches {
+            email: "a@example.com".to_string(),
+            customers: vec![customer("cus_1", 1), customer("cus_2", 2)],
+        };
+        assert!(matches!(
+            matches.exactly_one(),
+            Err(CustomerLookupError::Ambiguous { ids, .. }) if ids == ["cus_1", "cus_2"]
+        ));
+
+        let matches = CustomerMatches {
+            email: "a@example.com".to_string(),
+            customers: vec![],
+        };
+        assert!(matches!(
+            matches.exactly_one(),
+            Err(CustomerLookupError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn computes_lifetime_value() {
+        let charges = serde_json::from_str::<Vec<RawCustomerCharge>>(
+            r#"[{"id":"ch_1","amount":1000,"amount_refunded":250,"currency":"eur",
+                "status":"succeeded","created":20},
+               {"id":"ch_2","amount":3000,"currency":"eur","status":"succeeded","created":10},
+               {"id":"ch_3","amount":9000,"currency":"eur","status":"failed","created":5}]"#,
+        )
+        .unwrap();
+        let mut ltv = CustomerLtv::default();
+        charges.into_iter().for_each(|x| ltv.add(x));
+        assert_eq!((ltv.purchases, ltv.refunded_purchases), (2, 1));
+        assert_eq!(ltv.net("EUR"), 3750);
+        assert_eq!(ltv.refund_rate("eur"), 0.0625);
+        assert_eq!(
+            (ltv.first_purchase_at, ltv.last_purchase_at),
+            (Some(10), Some(20))
+        );
+    }
+
+    #[test]
+    fn cache_returns_inserted_ids() {
+        let cache = CustomerIdCache::new();
+        assert_eq!(cache.get("42"), None);
+        cache.insert("42", "cus_1");
+        assert_eq!(cache.get("42").as_deref(), Some("cus_1"));
+        cache.invalidate("42");
+        assert_eq!(cache.get("42"), None);
+    }
+}
@@ -0,0 +1,209 @@
+use stripe::StripeError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeclineCode {
+    InsufficientFunds,
+    CardVelocityExceeded,
+    WithdrawalCountLimitExceeded,
+    ExpiredCard,
+    IncorrectCvc,
+    IncorrectNumber,
+    IncorrectZip,
+    LostCard,
+    StolenCard,
+    PickupCard,
+    Fraudulent,
+    DoNotHonor,
+    GenericDecline,
+    CardNotSupported,
+    CurrencyNotSupported,
+    AuthenticationRequired,
+    ProcessingError,
+    TryAgainLater,
+    Other(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeclineCategory {
+    InsufficientFunds,
+    FixCardDetails,
+    TryAnotherCard,
+    ContactBank,
+    AuthenticationRequired,
+    RetryLater,
+}
+
+impl DeclineCode {
+    pub fn parse(code: &str) -> DeclineCode {
+        match code {
+            "insufficient_funds" => DeclineCode::InsufficientFunds,
+            "card_velocity_exceeded" => DeclineCode::CardVelocityExceeded,
+            "withdrawal_count_limit_exceeded" => DeclineCode::WithdrawalCountLimitExceeded,
+            "expired_card" => DeclineCode::ExpiredCard,
+            "incorrect_cvc" | "invalid_cvc" => DeclineCode::IncorrectCvc,
+            "incorrect_number" | "invalid_number" => DeclineCode::IncorrectNumber,
+            "incorrect_zip" => DeclineCode::IncorrectZip,
+            "lost_card" => DeclineCode::LostCard,
+            "stolen_card" => DeclineCode::StolenCard,
+            "pickup_card" => DeclineCode::PickupCard,
+            "fraudulent" | "merchant_blacklist" => DeclineCode::Fraudulent,
+            "do_not_honor" => DeclineCode::DoNotHonor,
+            "generic_decline" | "card_declined" => DeclineCode::GenericDecline,
+            "card_not_supported" => DeclineCode::CardNotSupported,
+            "currency_not_supported" => DeclineCode::CurrencyNotSupported,
+            "authentication_required" => DeclineCode::AuthenticationRequired,
+            "processing_error" => DeclineCode::ProcessingError,
+            "try_again_later" | "issuer_not_available" | "reenter_transaction" => {
+                DeclineCode::TryAgainLater
+            }
+            other => DeclineCode::Other(other.to_string()),
+        }
+    }
+
+    /// Extracts the decline code from a card error, preferring `decline_code` over
+    /// the top-level error `code`. Returns `None` for non-card errors.
+    pub fn from_stripe_error(error: &StripeError) -> Option<DeclineCode> {
+        let request_error = match error {
+            StripeError::Stripe(x) => x,
+            _ => return None,
+        };
+        if let Some(decline_code) = &request_error.decline_code {
+            return Some(DeclineCode::parse(decline_code));
+        }
+        request_error
+            .code
+            .as_ref()
+            .and_then(|x| serde_json::to_value(x).ok())
+            .and_then(|x| x.as_str().map(DeclineCode::parse))
+    }
+
+    pub fn category(&self) -> DeclineCategory {
+        match self {
+            DeclineCode::InsufficientFunds
+            | DeclineCode::CardVelocityExceeded
+            | DeclineCode::WithdrawalCountLimitExceeded => DeclineCategory::InsufficientFunds,
+            DeclineCode::ExpiredCard
+            | DeclineCode::IncorrectCvc
+            | DeclineCode::IncorrectNumber
+            | DeclineCode::IncorrectZip => DeclineCategory::FixCardDetails,
+            DeclineCode::LostCard
+            | DeclineCode::StolenCard
+            | DeclineCode::PickupCard
+            | DeclineCode::Fraudulent
+            | DeclineCode::CardNotSupported
+            | DeclineCode::CurrencyNotSupported => DeclineCategory::TryAnotherCard,
+            DeclineCode::AuthenticationRequired => DeclineCategory::AuthenticationRequired,
+            DeclineCode::ProcessingError | DeclineCode::TryAgainLater => {
+                DeclineCategory::RetryLater
+            }
+            DeclineCode::DoNotHonor | DeclineCode::GenericDecline | DeclineCode::Other(_) => {
+                DeclineCategory::ContactBank
+            }
+        }
+    }
+
+    /// Customer-presentable message. Lost, stolen and fraud declines deliberately
+    /// share the generic "try another card" wording.
+    pub fn user_message(&self, locale: &str) -> &'static str {
+        self.category().user_message(locale)
+    }
+}
+
+impl DeclineCategory {
+    pub fn user_message(&self, locale: &str) -> &'static str {
+        let language = locale
+            .split(['-', '_'])
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        match (language.as_str(), self) {
+            ("de", DeclineCategory::InsufficientFunds) => {
+                "Ihre Karte ist nicht ausreichend gedeckt."
+            }
+            ("de", DeclineCategory::FixCardDetails) => {
+                "Bitte überprüfen Sie Ihre Kartendaten und versuchen Sie es erneut."
+            }
+            ("de", DeclineCategory::TryAnotherCard) => {
+                "Ihre Karte wurde abgelehnt. Bitte verwenden Sie eine andere Karte."
+            }
+            ("de", DeclineCategory::ContactBank) => {
+                "Ihre Karte wurde abgelehnt. Bitte wenden Sie sich an Ihre Bank."
+            }
+            ("de", DeclineCategory::AuthenticationRequired) => {
+                "Ihre Bank verlangt eine zusätzliche Bestätigung dieser Zahlung."
+            }
+            ("de", DeclineCategory::RetryLater) => {
+                "Die Zahlung konnte nicht verarbeitet werden. Bitte versuchen Sie es später erneut."
+            }
+            ("fr", DeclineCategory::InsufficientFunds) => {
+                "Votre carte n'est pas suffisamment approvisionnée."
+            }
+            ("fr", DeclineCategory::FixCardDetails) => {
+                "Veuillez vérifier les informations de votre carte et réessayer."
+            }
+            ("fr", DeclineCategory::TryAnotherCard) => {
+                "Votre carte a été refusée. Veuillez utiliser une autre carte."
+            }
+            ("fr", DeclineCategory::ContactBank) => {
+                "Votre carte a été refusée. Veuillez contacter votre banque."
+            }
+            ("fr", DeclineCategory::AuthenticationRequired) => {
+                "Votre banque demande une confirmation supplémentaire pour ce paiement."
+            }
+            ("fr", DeclineCategory::RetryLater) => {
+                "Le paiement n'a pas pu être traité. Veuillez réessayer plus tard."
+            }
+            ("es", DeclineCategory::InsufficientFunds) => "Tu tarjeta no tiene fondos suficientes.",
+            ("es", DeclineCategory::FixCardDetails) => {
+                "Revisa los datos de tu tarjeta e inténtalo de nuevo."
+            }
+            ("es", DeclineCategory::TryAnotherCard) => {
+                "Tu tarjeta ha sido rechazada. Utiliza otra tarjeta."
+            }
+            ("es", DeclineCategory::ContactBank) => {
+                "Tu tarjeta ha sido rechazada. Ponte en contacto con tu banco."
+            }
+            ("es", DeclineCategory::AuthenticationRequired) => {
+                "Tu banco requiere una confirmación adicional para este pago."
+            }
+            ("es", DeclineCategory::RetryLater) => {
+                "No se ha podido procesar el pago. Inténtalo de nuevo más tarde."
+            }
+            (_, DeclineCategory::InsufficientFunds) => "Your card has insufficient funds.",
+            (_, DeclineCategory::FixCardDetails) => "Please check your card details and try again.",
+            (_, DeclineCategory::TryAnotherCard) => {
+                "Your card was declined. Please try another card."
+            }
+            (_, DeclineCategory::ContactBank) => {
+                "Your card was declined. Please contact your bank for more information."
+            }
+            (_, DeclineCategory::AuthenticationRequired) => {
+                "Your bank requires additional confirmation for this payment."
+            }
+            (_, DeclineCategory::RetryLater) => {
+                "The payment could not be processed. Please try again later."
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_codes_to_messages() {
+        assert_eq!(
+            DeclineCode::parse("stolen_card").category(),
+            DeclineCategory::TryAnotherCard
+        );
+        assert_eq!(
+            DeclineCode::parse("insufficient_funds").user_message("en-GB"),
+            "Your card has insufficient funds."
+        );
+        assert_eq!(
+            DeclineCode::parse("something_new").user_message("de_DE"),
+            "Ihre Karte wurde abgelehnt. Bitte wenden Sie sich an Ihre Bank."
+        );
+    }
+}
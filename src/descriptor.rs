@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+pub const MAX_LENGTH: usize = 22;
+pub const MIN_LENGTH: usize = 5;
+const FORBIDDEN: [char; 5] = ['<', '>', '\\', '\'', '"'];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DescriptorError {
+    TooShort(String),
+    TooLong(String),
+    InvalidCharacter(char),
+    NoLetter(String),
+    MissingValue(String),
+}
+
+impl Display for DescriptorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DescriptorError::TooShort(x) => {
+                write!(
+                    f,
+                    "statement descriptor {:?} is shorter than {}",
+                    x, MIN_LENGTH
+                )
+            }
+            DescriptorError::TooLong(x) => {
+                write!(
+                    f,
+                    "statement descriptor {:?} is longer than {}",
+                    x, MAX_LENGTH
+                )
+            }
+            DescriptorError::InvalidCharacter(x) => {
+                write!(f, "statement descriptor contains invalid character {:?}", x)
+            }
+            DescriptorError::NoLetter(x) => {
+                write!(f, "statement descriptor {:?} contains no letter", x)
+            }
+            DescriptorError::MissingValue(x) => {
+                write!(f, "no value for statement descriptor placeholder {{{}}}", x)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DescriptorError {}
+
+/// A statement descriptor that satisfies Stripe's rules. Either a full
+/// descriptor, or a suffix appended to the account's shortened descriptor
+/// (templates of the form `"MYSHOP* {order_id}"`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatementDescriptor {
+    full: Option<String>,
+    suffix: Option<String>,
+}
+
+fn validate_chars(value: &str) -> Result<(), DescriptorError> {
+    match value
+        .chars()
+        .find(|x| !x.is_ascii() || x.is_ascii_control() || FORBIDDEN.contains(x) || *x == '*')
+    {
+        Some(x) => Err(DescriptorError::InvalidCharacter(x)),
+        None => Ok(()),
+    }
+}
+
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .filter(|x| x.is_ascii() && !x.is_ascii_control() && !FORBIDDEN.contains(x) && *x != '*')
+        .collect()
+}
+
+fn validate_full(value: &str) -> Result<(), DescriptorError> {
+    validate_chars(value)?;
+    if value.len() < MIN_LENGTH {
+        return Err(DescriptorError::TooShort(value.to_string()));
+    }
+    if value.len() > MAX_LENGTH {
+        return Err(DescriptorError::TooLong(value.to_string()));
+    }
+    if !value.chars().any(|x| x.is_ascii_alphabetic()) {
+        return Err(DescriptorError::NoLetter(value.to_string()));
+    }
+    Ok(())
+}
+
+fn substitute(template: &str, values: &HashMap<&str, &str>) -> Result<String, DescriptorError> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .map(|x| x + start)
+            .ok_or_else(|| DescriptorError::MissingValue(rest[start + 1..].to_string()))?;
+        let key = &rest[start + 1..end];
+        let value = values
+            .get(key)
+            .ok_or_else(|| DescriptorError::MissingValue(key.to_string()))?;
+        rendered.push_str(&sanitize(value));
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+impl StatementDescriptor {
+    pub fn new(full: &str) -> Result<Self, DescriptorError> {
+        validate_full(full)?;
+        Ok(StatementDescriptor {
+            full: Some(full.to_string()),
+            suffix: None,
+        })
+    }
+
+    pub fn new_suffix(prefix: &str, suffix: &str) -> Result<Self, DescriptorError> {
+        validate_chars(suffix)?;
+        let combined = format!("{}* {}", prefix, suffix);
+        if combined.len() > MAX_LENGTH {
+            return Err(DescriptorError::TooLong(combined));
+        }
+        if !suffix.chars().any(|x| x.is_ascii_alphanumeric()) {
+            return Err(DescriptorError::NoLetter(suffix.to_string()));
+        }
+        Ok(StatementDescriptor {
+            full: None,
+            suffix: Some(suffix.to_string()),
+        })
+    }
+
+    /// Renders `{placeholder}`s from `values`, strips characters Stripe rejects
+    /// from the substituted values and truncates the result to fit 22 characters.
+    pub fn render(template: &str, values: &HashMap<&str, &str>) -> Result<Self, DescriptorError> {
+        match template.split_once("* ") {
+            Some((prefix, suffix_template)) => {
+                validate_chars(prefix)?;
+                let budget = MAX_LENGTH.saturating_sub(prefix.len() + 2);
+                let suffix = substitute(suffix_template, values)?;
+                let suffix = suffix.chars().take(budget).collect::<String>();
+                StatementDescriptor::new_suffix(prefix, suffix.trim_end())
+            }
+            None => {
+                let full = substitute(template, values)?;
+                let full = full.chars().take(MAX_LENGTH).collect::<String>();
+                StatementDescriptor::new(full.trim_end())
+            }
+        }
+    }
+
+    pub fn statement_descriptor(&self) -> Option<&str> {
+        self.full.as_deref()
+    }
+
+    pub fn statement_descriptor_suffix(&self) -> Option<&str> {
+        self.suffix.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_and_truncates_suffix() {
+        let values = HashMap::from([("order_id", "ORD-1234567890123\"")]);
+        let descriptor = StatementDescriptor::render("MYSHOP* {order_id}", &values).unwrap();
+        assert_eq!(descriptor.statement_descriptor(), None);
+        assert_eq!(
+            descriptor.statement_descriptor_suffix(),
+            Some("ORD-1234567890")
+        );
+
+        assert!(matches!(
+            StatementDescriptor::new("<shop>"),
+            Err(DescriptorError::InvalidCharacter('<'))
+        ));
+        assert!(matches!(
+            StatementDescriptor::render("SHOP {missing}", &HashMap::new()),
+            Err(DescriptorError::MissingValue(_))
+        ));
+    }
+}
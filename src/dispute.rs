@@ -0,0 +1,365 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+use stripe::Client;
+
+use crate::telemetry;
+use crate::url::StripeUrl;
+use crate::StripePaymentError;
+
+pub const MAX_TEXT_LENGTH: usize = 20_000;
+pub const MAX_TOTAL_TEXT_LENGTH: usize = 150_000;
+
+const FILE_FIELDS: [&str; 9] = [
+    "cancellation_policy",
+    "customer_communication",
+    "customer_signature",
+    "duplicate_charge_documentation",
+    "receipt",
+    "refund_policy",
+    "service_documentation",
+    "shipping_documentation",
+    "uncategorized_file",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TextEvidence {
+    AccessActivityLog,
+    BillingAddress,
+    CancellationPolicyDisclosure,
+    CancellationRebuttal,
+    CustomerEmailAddress,
+    CustomerName,
+    CustomerPurchaseIp,
+    DuplicateChargeExplanation,
+    DuplicateChargeId,
+    ProductDescription,
+    RefundPolicyDisclosure,
+    RefundRefusalExplanation,
+    ServiceDate,
+    ShippingAddress,
+    ShippingCarrier,
+    ShippingDate,
+    ShippingTrackingNumber,
+    UncategorizedText,
+}
+
+impl TextEvidence {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TextEvidence::AccessActivityLog => "access_activity_log",
+            TextEvidence::BillingAddress => "billing_address",
+            TextEvidence::CancellationPolicyDisclosure => "cancellation_policy_disclosure",
+            TextEvidence::CancellationRebuttal => "cancellation_rebuttal",
+            TextEvidence::CustomerEmailAddress => "customer_email_address",
+            TextEvidence::CustomerName => "customer_name",
+            TextEvidence::CustomerPurchaseIp => "customer_purchase_ip",
+            TextEvidence::DuplicateChargeExplanation => "duplicate_charge_explanation",
+            TextEvidence::DuplicateChargeId => "duplicate_charge_id",
+            TextEvidence::ProductDescription => "product_description",
+            TextEvidence::RefundPolicyDisclosure => "refund_policy_disclosure",
+            TextEvidence::RefundRefusalExplanation => "refund_refusal_explanation",
+            TextEvidence::ServiceDate => "service_date",
+            TextEvidence::ShippingAddress => "shipping_address",
+            TextEvidence::ShippingCarrier => "shipping_carrier",
+            TextEvidence::ShippingDate => "shipping_date",
+            TextEvidence::ShippingTrackingNumber => "shipping_tracking_number",
+            TextEvidence::UncategorizedText => "uncategorized_text",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FileEvidence {
+    CancellationPolicy,
+    CustomerCommunication,
+    CustomerSignature,
+    DuplicateChargeDocumentation,
+    Receipt,
+    RefundPolicy,
+    ServiceDocumentation,
+    ShippingDocumentation,
+    UncategorizedFile,
+}
+
+impl FileEvidence {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileEvidence::CancellationPolicy => "cancellation_policy",
+            FileEvidence::CustomerCommunication => "customer_communication",
+            FileEvidence::CustomerSignature => "customer_signature",
+            FileEvidence::DuplicateChargeDocumentation => "duplicate_charge_documentation",
+            FileEvidence::Receipt => "receipt",
+            FileEvidence::RefundPolicy => "refund_policy",
+            FileEvidence::ServiceDocumentation => "service_documentation",
+            FileEvidence::ShippingDocumentation => "shipping_documentation",
+            FileEvidence::UncategorizedFile => "uncategorized_file",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisputeEvidenceError {
+    TooLong {
+        field: &'static str,
+        length: usize,
+    },
+    TotalTooLong(usize),
+    InvalidFileId {
+        field: &'static str,
+        file_id: String,
+    },
+}
+
+impl Display for DisputeEvidenceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisputeEvidenceError::TooLong { field, length } => write!(
+                f,
+                "evidence {} has {} characters, the limit is {}",
+                field, length, MAX_TEXT_LENGTH
+            ),
+            DisputeEvidenceError::TotalTooLong(x) => write!(
+                f,
+                "evidence has {} characters in total, the limit is {}",
+                x, MAX_TOTAL_TEXT_LENGTH
+            ),
+            DisputeEvidenceError::InvalidFileId { field, file_id } => {
+                write!(f, "evidence {} has invalid file id {}", field, file_id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DisputeEvidenceError {}
+
+fn recommended_fields(reason: &str) -> &'static [&'static str] {
+    match reason {
+        "fraudulent" => &[
+            "customer_purchase_ip",
+            "billing_address",
+            "customer_signature",
+            "access_activity_log",
+            "customer_communication",
+        ],
+        "product_not_received" => &[
+            "shipping_documentation",
+            "shipping_carrier",
+            "shipping_tracking_number",
+            "shipping_date",
+            "customer_communication",
+        ],
+        "product_unacceptable" => &[
+            "product_description",
+            "refund_policy",
+            "customer_communication",
+        ],
+        "subscription_canceled" => &[
+            "cancellation_policy",
+            "cancellation_policy_disclosure",
+            "cancellation_rebuttal",
+            "customer_communication",
+        ],
+        "duplicate" => &[
+            "duplicate_charge_documentation",
+            "duplicate_charge_explanation",
+            "duplicate_charge_id",
+        ],
+        "credit_not_processed" => &[
+            "refund_policy",
+            "refund_policy_disclosure",
+            "refund_refusal_explanation",
+        ],
+        _ => &["receipt", "customer_communication", "uncategorized_text"],
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct DisputeEvidence {
+    pub fields: BTreeMap<&'static str, String>,
+    pub missing_recommended: Vec<&'static str>,
+}
+
+/// Collects dispute evidence, checking Stripe's length limits and reporting
+/// which of the fields recommended for the dispute reason are still missing.
+#[derive(Debug, Clone)]
+pub struct DisputeEvidenceBuilder {
+    reason: String,
+    fields: BTreeMap<&'static str, String>,
+}
+
+impl DisputeEvidenceBuilder {
+    pub fn new(reason: impl Into<String>) -> Self {
+        DisputeEvidenceBuilder {
+            reason: reason.into(),
+            fields: BTreeMap::new(),
+        }
+    }
+
+    pub fn text(mut self, field: TextEvidence, value: impl Into<String>) -> Self {
+        self.fields.insert(field.as_str(), value.into());
+        self
+    }
+
+    pub fn file(mut self, field: FileEvidence, file_id: impl Into<String>) -> Self {
+        self.fields.insert(field.as_str(), file_id.into());
+        self
+    }
+
+    pub fn build(self) -> Result<DisputeEvidence, DisputeEvidenceError> {
+        let mut total = 0;
+        for (field, value) in &self.fields {
+            let is_file = FILE_FIELDS.contains(field);
+            if is_file && !value.starts_with("file_") {
+                return Err(DisputeEvidenceError::InvalidFileId {
+                    field: *field,
+                    file_id: value.clone(),
+                });
+            }
+            if !is_file {
+                let length = value.chars().count();
+                if length > MAX_TEXT_LENGTH {
+                    return Err(DisputeEvidenceError::TooLong {
+                        field: *field,
+                        length,
+                    });
+                }
+                total += length;
+            }
+        }
+        if total > MAX_TOTAL_TEXT_LENGTH {
+            return Err(DisputeEvidenceError::TotalTooLong(total));
+        }
+        let missing_recommended = recommended_fields(&self.reason)
+            .iter()
+            .filter(|x| !self.fields.contains_key(*x))
+            .copied()
+            .collect::<Vec<_>>();
+        if !missing_recommended.is_empty() {
+            tracing::warn!(
+                "{} dispute evidence is missing recommended fields {:?}",
+                self.reason,
+                missing_recommended
+            );
+        }
+        Ok(DisputeEvidence {
+            fields: self.fields,
+            missing_recommended,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct DisputeDto {
+    pub id: String,
+    pub charge_id: String,
+    pub amount: i64,
+    pub currency: String,
+    pub reason: String,
+    pub status: String,
+}
+
+#[derive(Deserialize)]
+struct RawDispute {
+    id: String,
+    charge: String,
+    amount: i64,
+    currency: String,
+    reason: String,
+    status: String,
+}
+
+impl From<RawDispute> for DisputeDto {
+    fn from(x: RawDispute) -> Self {
+        DisputeDto {
+            id: x.id,
+            charge_id: x.charge,
+            amount: x.amount,
+            currency: x.currency,
+            reason: x.reason,
+            status: x.status,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EvidenceParams<'a> {
+    evidence: &'a BTreeMap<&'static str, String>,
+    submit: bool,
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn get_dispute(
+    stripe_client: &Client,
+    dispute_id: &str,
+) -> Result<DisputeDto, StripePaymentError> {
+    telemetry::observe(
+        "disputes.retrieve",
+        stripe_client.get::<RawDispute>(&StripeUrl::new("/disputes").segment(dispute_id).build()),
+    )
+    .await
+    .map(DisputeDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+/// Uploads evidence to the dispute. With `submit` false the evidence is only
+/// staged and can still be changed until the response deadline.
+#[tracing::instrument(skip(stripe_client, evidence))]
+pub async fn submit_dispute_evidence(
+    stripe_client: &Client,
+    dispute_id: &str,
+    evidence: &DisputeEvidence,
+    submit: bool,
+) -> Result<DisputeDto, StripePaymentError> {
+    telemetry::observe(
+        "disputes.update",
+        stripe_client.post_form::<RawDispute, _>(
+            &StripeUrl::new("/disputes").segment(dispute_id).build(),
+            EvidenceParams {
+                evidence: &evidence.fields,
+                submit,
+            },
+        ),
+    )
+    .await
+    .map(DisputeDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_missing_recommended_fields() {
+        let evidence = DisputeEvidenceBuilder::new("product_not_received")
+            .text(TextEvidence::ShippingCarrier, "DHL")
+            .text(TextEvidence::ShippingTrackingNumber, "123")
+            .file(FileEvidence::ShippingDocumentation, "file_1")
+            .build()
+            .unwrap();
+        assert_eq!(
+            evidence.missing_recommended,
+            vec!["shipping_date", "customer_communication"]
+        );
+
+        assert!(matches!(
+            DisputeEvidenceBuilder::new("fraudulent")
+                .file(FileEvidence::Receipt, "receipt.pdf")
+                .build(),
+            Err(DisputeEvidenceError::InvalidFileId { .. })
+        ));
+        assert!(matches!(
+            DisputeEvidenceBuilder::new("general")
+                .text(
+                    TextEvidence::UncategorizedText,
+                    "x".repeat(MAX_TEXT_LENGTH + 1)
+                )
+                .build(),
+            Err(DisputeEvidenceError::TooLong { .. })
+        ));
+    }
+}
@@ -0,0 +1,205 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::address::{checked_billing_details, checked_shipping};
+use crate::command::{CommandOutcome, OutboxEntry, StripeCommand};
+use crate::level3::checked_level3;
+use crate::payment_method;
+use crate::refund_batch::RefundRequest;
+pub use crate::url::validate_id;
+use crate::{
+    CreateCustomerDto, CreatePaymentIntentDto, CustomerDto, GuestPaymentIntentDto,
+    GuestPaymentOptions, PaymentIntentDto, StripePaymentError,
+};
+
+pub const MAX_AMOUNT: i64 = 99_999_999;
+
+static SEQUENCE: AtomicU64 = AtomicU64::new(1);
+
+fn synthetic_id(prefix: &str) -> String {
+    format!(
+        "{}_dryrun{}",
+        prefix,
+        SEQUENCE.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+fn invalid(message: String) -> StripePaymentError {
+    StripePaymentError::from_general(message)
+}
+
+pub fn validate_currency(currency: &str) -> Result<(), StripePaymentError> {
+    stripe::Currency::from_str(currency.to_lowercase().as_str())
+        .map(|_| ())
+        .map_err(|_| invalid(format!("unknown currency {}", currency)))
+}
+
+pub fn validate_amount(amount: i64) -> Result<(), StripePaymentError> {
+    match amount {
+        1..=MAX_AMOUNT => Ok(()),
+        _ => Err(invalid(format!(
+            "amount {} is outside 1..={}",
+            amount, MAX_AMOUNT
+        ))),
+    }
+}
+
+/// Runs the checks `create_payment_sheet` would need to pass and returns a
+/// synthesized DTO without contacting Stripe.
+pub fn create_payment_sheet(
+    dto: &CreatePaymentIntentDto,
+) -> Result<PaymentIntentDto, StripePaymentError> {
+    validate_amount(dto.amount)?;
+    validate_currency(&dto.currency)?;
+    validate_id(&dto.stripe_customer_id, "cus")?;
+    checked_shipping(&dto.delivery_address)?;
+    checked_billing_details(&dto.billing_details)?;
+    dto.validate_settlement()?;
+    checked_level3(&dto.level3, dto.amount)?;
+    if dto.payment_method_types.is_empty() {
+        return Err(invalid("no payment_method_types".to_string()));
+    }
+    for x in &dto.payment_method_types {
+        payment_method::validate_payment_method_currency(x, &dto.currency)?;
+    }
+    let id = synthetic_id("pi");
+    Ok(PaymentIntentDto::new(
+        id.clone(),
+        synthetic_id("ek_secret"),
+        format!("{}_secret_dryrun", id),
+        dto.stripe_customer_id.clone(),
+    ))
+}
+
+pub fn create_guest_payment_sheet(
+    amount: i64,
+    currency: &str,
+    options: &GuestPaymentOptions,
+) -> Result<GuestPaymentIntentDto, StripePaymentError> {
+    validate_amount(amount)?;
+    validate_currency(currency)?;
+    checked_shipping(&options.delivery_address)?;
+    let id = synthetic_id("pi");
+    Ok(GuestPaymentIntentDto::new(
+        id.clone(),
+        format!("{}_secret_dryrun", id),
+    ))
+}
+
+pub fn create_customer(dto: &CreateCustomerDto) -> Result<CustomerDto, StripePaymentError> {
+    if dto.id.is_empty() {
+        return Err(invalid("customer account id is empty".to_string()));
+    }
+    Ok(CustomerDto::new(synthetic_id("cus")))
+}
+
+/// Validates an outbox entry and returns the outcome Stripe would report,
+/// without sending it.
+pub fn execute_command(entry: &OutboxEntry) -> Result<CommandOutcome, StripePaymentError> {
+    if entry.idempotency_key.is_empty() {
+        return Err(invalid("idempotency key is empty".to_string()));
+    }
+    let (object_id, status) = match &entry.command {
+        StripeCommand::CreateRefund {
+            payment_intent_id,
+            amount,
+            ..
+        } => {
+            validate_id(payment_intent_id, "pi")?;
+            if let Some(x) = amount {
+                validate_amount(*x)?;
+            }
+            (synthetic_id("re"), Some("succeeded"))
+        }
+        StripeCommand::CancelIntent {
+            payment_intent_id, ..
+        } => {
+            validate_id(payment_intent_id, "pi")?;
+            (payment_intent_id.clone(), Some("canceled"))
+        }
+        StripeCommand::CapturePayment {
+            payment_intent_id,
+            amount_to_capture,
+        } => {
+            validate_id(payment_intent_id, "pi")?;
+            if let Some(x) = amount_to_capture {
+                validate_amount(*x)?;
+            }
+            (payment_intent_id.clone(), Some("succeeded"))
+        }
+        StripeCommand::CreateTransfer {
+            amount,
+            currency,
+            destination,
+            ..
+        } => {
+            validate_amount(*amount)?;
+            validate_currency(currency)?;
+            validate_id(destination, "acct")?;
+            (synthetic_id("tr"), None)
+        }
+    };
+    Ok(CommandOutcome {
+        idempotency_key: entry.idempotency_key.clone(),
+        object_id,
+        status: status.map(String::from),
+    })
+}
+
+pub fn refund(request: &RefundRequest) -> Result<CommandOutcome, StripePaymentError> {
+    if request.row_id.is_empty() {
+        return Err(invalid("row id is empty".to_string()));
+    }
+    validate_id(&request.charge_id, "ch").or_else(|_| validate_id(&request.charge_id, "py"))?;
+    if let Some(x) = request.amount {
+        validate_amount(x)?;
+    }
+    Ok(CommandOutcome {
+        idempotency_key: request.idempotency_key(),
+        object_id: synthetic_id("re"),
+        status: Some("succeeded".to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_without_network() {
+        let sheet =
+            create_payment_sheet(&CreatePaymentIntentDto::new(500, "cus_ABC123", "eur")).unwrap();
+        assert!(sheet.id.starts_with("pi_dryrun"));
+        assert!(validate_id(&sheet.id, "pi").is_ok());
+        assert!(
+            create_payment_sheet(&CreatePaymentIntentDto::new(0, "cus_ABC123", "eur")).is_err()
+        );
+        assert!(create_payment_sheet(&CreatePaymentIntentDto::new(500, "acct_1", "eur")).is_err());
+        assert!(create_payment_sheet(&CreatePaymentIntentDto::new(500, "cus_1", "xxx")).is_err());
+    }
+
+    #[test]
+    fn synthesizes_command_outcomes() {
+        let refund = OutboxEntry::new(
+            "order-1-refund",
+            StripeCommand::CreateRefund {
+                payment_intent_id: "pi_1".to_string(),
+                amount: Some(500),
+                reason: None,
+            },
+        );
+        let outcome = execute_command(&refund).unwrap();
+        assert!(outcome.object_id.starts_with("re_dryrun"));
+        assert_eq!(outcome.status.as_deref(), Some("succeeded"));
+        let cancel = OutboxEntry::new(
+            "order-1-cancel",
+            StripeCommand::CancelIntent {
+                payment_intent_id: "ch_1".to_string(),
+                reason: None,
+            },
+        );
+        assert!(execute_command(&cancel).is_err());
+        assert!(refund(&RefundRequest::new("T-1", "py_1").with_amount(500)).is_ok());
+        assert!(refund(&RefundRequest::new("T-1", "pi_1")).is_err());
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use stripe::{RequestError, StripeError};
+
+use crate::address::{checked_billing_details, checked_shipping};
+use crate::level3::{checked_level3, Level3Data};
+use crate::redact::SecretString;
+use crate::telemetry;
+use crate::url::StripeUrl;
+use crate::{
+    CreatePaymentIntentDto, CreatePaymentIntentShipping, GuestPaymentIntentDto,
+    GuestPaymentOptions, PaymentIntentDto, PaymentIntentSetupFutureUsage, StripePaymentError,
+};
+
+pub const DEFAULT_BASE_URL: &str = "https://api.stripe.com/v1";
+pub const API_VERSION: &str = crate::api_version::DEFAULT_API_VERSION;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpMethod {
+    Get,
+    Post,
+    Delete,
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct HttpRequest {
+    pub method: HttpMethod,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, body: impl Into<String>) -> Self {
+        HttpResponse {
+            status,
+            body: body.into(),
+        }
+    }
+}
+
+/// Sends a fully built request, e.g. through the Workers `fetch` API.
+#[async_trait(?Send)]
+pub trait HttpExecutor {
+    async fn execute(&self, request: HttpRequest) -> Result<HttpResponse, String>;
+}
+
+/// Creates payment sheets over a caller-supplied [`HttpExecutor`] instead of the
+/// tokio/hyper backend of `async-stripe`, for edge runtimes such as Cloudflare Workers.
+/// Build with `default-features = false` to leave that backend out.
+pub struct EdgeClient<E> {
+    secret_key: SecretString,
+    base_url: String,
+    api_version: String,
+    executor: E,
+}
+
+impl<E> std::fmt::Debug for EdgeClient<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EdgeClient")
+            .field("base_url", &self.base_url)
+            .field("api_version", &self.api_version)
+            .finish()
+    }
+}
+
+#[derive(Serialize)]
+struct EphemeralKeyParams<'a> {
+    customer: &'a str,
+}
+
+#[derive(Serialize)]
+struct PaymentIntentParams<'a> {
+    amount: i64,
+    currency: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    customer: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    receipt_email: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<&'a HashMap<String, String>>,
+    payment_method_types: Vec<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    setup_future_usage: Option<PaymentIntentSetupFutureUsage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shipping: Option<&'a CreatePaymentIntentShipping>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    statement_descriptor: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    statement_descriptor_suffix: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    on_behalf_of: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transfer_data: Option<TransferData<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    level3: Option<&'a Level3Data>,
+}
+
+#[derive(Serialize)]
+struct TransferData<'a> {
+    destination: &'a str,
+}
+
+#[derive(Deserialize)]
+struct RawEphemeralKey {
+    secret: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawPaymentIntent {
+    id: String,
+    client_secret: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawError {
+    error: RequestError,
+}
+
+fn payment_intent_params<'a>(
+    dto: &'a CreatePaymentIntentDto,
+    shipping: Option<&'a CreatePaymentIntentShipping>,
+) -> PaymentIntentParams<'a> {
+    PaymentIntentParams {
+        amount: dto.amount,
+        currency: dto.currency.to_lowercase(),
+        customer: Some(&dto.stripe_customer_id),
+        description: None,
+        receipt_email: None,
+        metadata: match dto.metadata.is_empty() {
+            true => None,
+            false => Some(&dto.metadata),
+        },
+        payment_method_types: dto
+            .payment_method_types
+            .iter()
+            .map(String::as_str)
+            .collect(),
+        setup_future_usage: dto.setup_future_usage,
+        shipping,
+        statement_descriptor: dto
+            .statement_descriptor
+            .as_ref()
+            .and_then(|x| x.statement_descriptor()),
+        statement_descriptor_suffix: dto
+            .statement_descriptor
+            .as_ref()
+            .and_then(|x| x.statement_descriptor_suffix()),
+        on_behalf_of: dto.on_behalf_of.as_deref(),
+        transfer_data: dto
+            .transfer_destination
+            .as_deref()
+            .map(|destination| TransferData { destination }),
+        level3: dto.level3.as_ref(),
+    }
+}
+
+impl<E: HttpExecutor> EdgeClient<E> {
+    pub fn new(secret_key: impl Into<String>, executor: E) -> Self {
+        EdgeClient {
+            secret_key: SecretString::from(secret_key.into()),
+            base_url: DEFAULT_BASE_URL.to_string(),
+            api_version: API_VERSION.to_string(),
+            executor,
+        }
+    }
+
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    /// Pins the `Stripe-Version` header sent with every request.
+    pub fn with_api_version(mut self, api_version: impl Into<String>) -> Self {
+        self.api_version = api_version.into();
+        self
+    }
+
+    async fn post_form<T: DeserializeOwned, F: Serialize>(
+        &self,
+        endpoint: &'static str,
+        path: &str,
+        form: &F,
+    ) -> Result<T, StripePaymentError> {
+        telemetry::observe(endpoint, self.send_form(path, form)).await
+    }
+
+    /// Maps failures to the [`StripeError`] variants `async-stripe` would
+    /// return, so telemetry and the circuit breaker classify them the same.
+    async fn send_form<T: DeserializeOwned, F: Serialize>(
+        &self,
+        path: &str,
+        form: &F,
+    ) -> Result<T, StripeError> {
+        let body =
+            serde_qs::to_string(form).map_err(|x| StripeError::ClientError(x.to_string()))?;
+        let response = self
+            .executor
+            .execute(HttpRequest {
+                method: HttpMethod::Post,
+                url: format!("{}{}", self.base_url, path),
+                headers: vec![
+                    (
+                        "Authorization".to_string(),
+                        format!("Bearer {}", self.secret_key.expose()),
+                    ),
+                    ("Stripe-Version".to_string(), self.api_version.clone()),
+                    (
+                        "Content-Type".to_string(),
+                        "application/x-www-form-urlencoded".to_string(),
+                    ),
+                ],
+                body: Some(body),
+            })
+            .await
+            .map_err(StripeError::ClientError)?;
+        if !(200..300).contains(&response.status) {
+            return Err(match serde_json::from_str::<RawError>(&response.body) {
+                Ok(mut x) => {
+                    x.error.http_status = response.status;
+                    StripeError::Stripe(x.error)
+                }
+                Err(_) => StripeError::ClientError(format!(
+                    "stripe returned {}: {}",
+                    response.status, response.body
+                )),
+            });
+        }
+        serde_json::from_str(&response.body).map_err(StripeError::JSONSerialize)
+    }
+
+    pub async fn create_payment_sheet(
+        &self,
+        dto: &CreatePaymentIntentDto,
+    ) -> Result<PaymentIntentDto, StripePaymentError> {
+        let shipping = checked_shipping(&dto.delivery_address)?;
+        let billing_details = checked_billing_details(&dto.billing_details)?;
+        dto.validate_settlement()?;
+        checked_level3(&dto.level3, dto.amount)?;
+        if let Some(billing_details) = &billing_details {
+            self.post_form::<serde_json::Value, _>(
+                "customers.update",
+                &StripeUrl::new("/customers")
+                    .segment(&dto.stripe_customer_id)
+                    .build(),
+                billing_details,
+            )
+            .await?;
+        }
+        let ephemeral_key = self
+            .post_form::<RawEphemeralKey, _>(
+                "ephemeral_keys.create",
+                "/ephemeral_keys",
+                &EphemeralKeyParams {
+                    customer: &dto.stripe_customer_id,
+                },
+            )
+            .await?;
+        let ephemeral_key_secret = ephemeral_key
+            .secret
+            .ok_or(StripePaymentError::from_general(
+                "no ephemeral_key_secret".to_string(),
+            ))?;
+        let payment_intent = self
+            .post_form::<RawPaymentIntent, _>(
+                "payment_intents.create",
+                "/payment_intents",
+                &payment_intent_params(dto, shipping.as_ref()),
+            )
+            .await?;
+        let payment_client_secret =
+            payment_intent
+                .client_secret
+                .ok_or(StripePaymentError::from_general(
+                    "no payment_client_secret".to_string(),
+                ))?;
+        Ok(PaymentIntentDto::new(
+            payment_intent.id,
+            ephemeral_key_secret,
+            payment_client_secret,
+            dto.stripe_customer_id.clone(),
+        ))
+    }
+
+    pub async fn create_guest_payment_sheet(
+        &self,
+        amount: i64,
+        currency: &str,
+        options: &GuestPaymentOptions,
+    ) -> Result<GuestPaymentIntentDto, StripePaymentError> {
+        let shipping = checked_shipping(&options.delivery_address)?;
+        let payment_intent = self
+            .post_form::<RawPaymentIntent, _>(
+                "payment_intents.create",
+                "/payment_intents",
+                &PaymentIntentParams {
+                    amount,
+                    currency: currency.to_lowercase(),
+                    customer: None,
+                    description: options.description.as_deref(),
+                    receipt_email: options.receipt_email.as_deref(),
+                    metadata: options.metadata.as_ref(),
+                    payment_method_types: vec!["card"],
+                    setup_future_usage: None,
+                    shipping: shipping.as_ref(),
+                    statement_descriptor: options
+                        .statement_descriptor
+                        .as_ref()
+                        .and_then(|x| x.statement_descriptor()),
+                    statement_descriptor_suffix: options
+                        .statement_descriptor
+                        .as_ref()
+                        .and_then(|x| x.statement_descriptor_suffix()),
+                    on_behalf_of: None,
+                    transfer_data: None,
+                    level3: None,
+                },
+            )
+            .await?;
+        let payment_client_secret =
+            payment_intent
+                .client_secret
+                .ok_or(StripePaymentError::from_general(
+                    "no payment_client_secret".to_string(),
+                ))?;
+        Ok(GuestPaymentIntentDto::new(
+            payment_intent.id,
+            payment_client_secret,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_metadata_and_setup_future_usage() {
+        let mut dto = CreatePaymentIntentDto::new(500, "cus_1", "EUR")
+            .with_setup_future_usage(PaymentIntentSetupFutureUsage::OffSession);
+        dto.metadata
+            .insert("order_id".to_string(), "ord_1".to_string());
+        let form = serde_qs::to_string(&payment_intent_params(&dto, None)).unwrap();
+        assert!(form.contains("metadata[order_id]=ord_1"));
+        assert!(form.contains("setup_future_usage=off_session"));
+        assert!(!serde_qs::to_string(&payment_intent_params(
+            &CreatePaymentIntentDto::new(500, "cus_1", "EUR"),
+            None
+        ))
+        .unwrap()
+        .contains("metadata"));
+    }
+}
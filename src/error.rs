@@ -0,0 +1,135 @@
+use std::fmt::{Display, Formatter};
+
+use stripe::StripeError;
+
+use crate::circuit_breaker::CircuitOpen;
+use crate::permission;
+use crate::spending::SpendingViolation;
+
+/// Error returned by the crate's helpers.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum StripePaymentError {
+    /// Returned by Stripe or by the HTTP client.
+    Stripe(StripeError),
+    /// Local validation failures and responses the crate can't use.
+    General(String),
+    /// A [`CircuitBreaker`](crate::circuit_breaker::CircuitBreaker) refused to
+    /// send the request.
+    CircuitOpen(CircuitOpen),
+    /// A restricted key was refused. Grant `permission` (`read` or `write`)
+    /// on `resource` to the key in the dashboard.
+    MissingPermission {
+        resource: String,
+        permission: String,
+    },
+    /// A spending policy refused the payment; nothing was sent to Stripe.
+    SpendingLimit(SpendingViolation),
+}
+
+impl StripePaymentError {
+    /// Accepts a [`StripeError`] or a message.
+    pub fn from_general(x: impl Into<StripePaymentError>) -> Self {
+        x.into()
+    }
+
+    pub fn stripe_error(&self) -> Option<&StripeError> {
+        match self {
+            StripePaymentError::Stripe(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    /// For the helpers whose signature predates this type and returns
+    /// [`StripeError`]; other variants become [`StripeError::ClientError`].
+    pub fn into_stripe_error(self) -> StripeError {
+        match self {
+            StripePaymentError::Stripe(x) => x,
+            x => StripeError::ClientError(x.to_string()),
+        }
+    }
+}
+
+impl Display for StripePaymentError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StripePaymentError::Stripe(x) => write!(f, "{}", x),
+            StripePaymentError::General(x) => f.write_str(x),
+            StripePaymentError::CircuitOpen(x) => write!(f, "{}", x),
+            StripePaymentError::MissingPermission {
+                resource,
+                permission,
+            } => write!(
+                f,
+                "the restricted key lacks {} permission on {}",
+                permission, resource
+            ),
+            StripePaymentError::SpendingLimit(x) => write!(f, "spending policy violated: {}", x),
+        }
+    }
+}
+
+impl std::error::Error for StripePaymentError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StripePaymentError::Stripe(x) => Some(x),
+            StripePaymentError::CircuitOpen(x) => Some(x),
+            StripePaymentError::SpendingLimit(x) => Some(x),
+            StripePaymentError::General(_) | StripePaymentError::MissingPermission { .. } => None,
+        }
+    }
+}
+
+/// Restricted-key refusals become [`StripePaymentError::MissingPermission`].
+impl From<StripeError> for StripePaymentError {
+    fn from(x: StripeError) -> Self {
+        match permission::missing_permission_of(&x) {
+            Some((resource, permission)) => StripePaymentError::MissingPermission {
+                resource,
+                permission,
+            },
+            None => StripePaymentError::Stripe(x),
+        }
+    }
+}
+
+impl From<CircuitOpen> for StripePaymentError {
+    fn from(x: CircuitOpen) -> Self {
+        StripePaymentError::CircuitOpen(x)
+    }
+}
+
+impl From<SpendingViolation> for StripePaymentError {
+    fn from(x: SpendingViolation) -> Self {
+        StripePaymentError::SpendingLimit(x)
+    }
+}
+
+impl From<String> for StripePaymentError {
+    fn from(x: String) -> Self {
+        StripePaymentError::General(x)
+    }
+}
+
+impl From<&str> for StripePaymentError {
+    fn from(x: &str) -> Self {
+        StripePaymentError::General(x.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_stripe_errors_typed() {
+        assert!(matches!(
+            StripePaymentError::from_general(StripeError::Timeout),
+            StripePaymentError::Stripe(StripeError::Timeout)
+        ));
+        assert_eq!(
+            StripePaymentError::from_general("invalid amount".to_string()).to_string(),
+            "invalid amount"
+        );
+    }
+}
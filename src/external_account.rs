@@ -0,0 +1,178 @@
+use serde::{Deserialize, Serialize};
+use stripe::Client;
+
+use crate::pagination::RawList;
+use crate::telemetry;
+use crate::url::StripeUrl;
+use crate::StripePaymentError;
+
+/// A bank account or debit card a connected account is paid out to.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ExternalAccountDto {
+    pub id: String,
+    /// `bank_account` or `card`.
+    pub kind: String,
+    pub currency: String,
+    pub country: Option<String>,
+    pub last4: Option<String>,
+    /// Bank name for bank accounts, brand for cards.
+    pub institution: Option<String>,
+    pub default_for_currency: bool,
+    pub status: Option<String>,
+    pub available_payout_methods: Vec<String>,
+}
+
+impl ExternalAccountDto {
+    pub fn supports_instant_payouts(&self) -> bool {
+        self.available_payout_methods.iter().any(|x| x == "instant")
+    }
+}
+
+#[derive(Deserialize)]
+struct RawExternalAccount {
+    id: String,
+    object: String,
+    currency: String,
+    country: Option<String>,
+    last4: Option<String>,
+    bank_name: Option<String>,
+    brand: Option<String>,
+    #[serde(default)]
+    default_for_currency: bool,
+    status: Option<String>,
+    #[serde(default)]
+    available_payout_methods: Vec<String>,
+}
+
+impl From<RawExternalAccount> for ExternalAccountDto {
+    fn from(x: RawExternalAccount) -> Self {
+        ExternalAccountDto {
+            id: x.id,
+            kind: x.object,
+            currency: x.currency,
+            country: x.country,
+            last4: x.last4,
+            institution: x.bank_name.or(x.brand),
+            default_for_currency: x.default_for_currency,
+            status: x.status,
+            available_payout_methods: x.available_payout_methods,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ListParams {
+    limit: u64,
+}
+
+#[derive(Serialize)]
+struct AddParams<'a> {
+    external_account: &'a str,
+    default_for_currency: bool,
+}
+
+#[derive(Serialize)]
+struct UpdateParams {
+    default_for_currency: bool,
+}
+
+fn external_accounts_url(account_id: &str) -> StripeUrl {
+    StripeUrl::new("/accounts")
+        .segment(account_id)
+        .segment("external_accounts")
+}
+
+/// Attaches a bank account or debit card to a connected account. `token` is a
+/// `btok_`/`tok_` token created client-side, so raw account numbers never
+/// reach the server.
+#[tracing::instrument(skip(stripe_client, token))]
+pub async fn add_external_account(
+    stripe_client: &Client,
+    account_id: &str,
+    token: &str,
+    default_for_currency: bool,
+) -> Result<ExternalAccountDto, StripePaymentError> {
+    telemetry::observe(
+        "accounts.external_accounts.create",
+        stripe_client.post_form::<RawExternalAccount, _>(
+            &external_accounts_url(account_id).build(),
+            AddParams {
+                external_account: token,
+                default_for_currency,
+            },
+        ),
+    )
+    .await
+    .map(ExternalAccountDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn list_external_accounts(
+    stripe_client: &Client,
+    account_id: &str,
+) -> Result<Vec<ExternalAccountDto>, StripePaymentError> {
+    telemetry::observe(
+        "accounts.external_accounts.list",
+        stripe_client.get_query::<RawList<RawExternalAccount>, _>(
+            &external_accounts_url(account_id).build(),
+            ListParams { limit: 100 },
+        ),
+    )
+    .await
+    .map(|x| x.data.into_iter().map(ExternalAccountDto::from).collect())
+    .map_err(StripePaymentError::from_general)
+}
+
+/// Makes `external_account_id` the payout destination for its currency; the
+/// previous default for that currency is unset by Stripe.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn set_default_for_currency(
+    stripe_client: &Client,
+    account_id: &str,
+    external_account_id: &str,
+) -> Result<ExternalAccountDto, StripePaymentError> {
+    telemetry::observe(
+        "accounts.external_accounts.update",
+        stripe_client.post_form::<RawExternalAccount, _>(
+            &external_accounts_url(account_id)
+                .segment(external_account_id)
+                .build(),
+            UpdateParams {
+                default_for_currency: true,
+            },
+        ),
+    )
+    .await
+    .map(ExternalAccountDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_bank_account_and_card() {
+        let list = serde_json::from_str::<RawList<RawExternalAccount>>(
+            r#"{"object":"list","has_more":false,"data":[
+                {"id":"ba_1","object":"bank_account","currency":"usd","country":"US","last4":"6789",
+                 "bank_name":"STRIPE TEST BANK","default_for_currency":true,"status":"new",
+                 "available_payout_methods":["standard"]},
+                {"id":"card_1","object":"card","currency":"usd","country":"US","last4":"4242",
+                 "brand":"Visa","default_for_currency":false,
+                 "available_payout_methods":["standard","instant"]}]}"#,
+        )
+        .unwrap();
+        let accounts = list
+            .data
+            .into_iter()
+            .map(ExternalAccountDto::from)
+            .collect::<Vec<_>>();
+        assert_eq!(accounts[0].institution.as_deref(), Some("STRIPE TEST BANK"));
+        assert!(!accounts[0].supports_instant_payouts());
+        assert_eq!(accounts[1].institution.as_deref(), Some("Visa"));
+        assert!(accounts[1].supports_instant_payouts());
+    }
+}
@@ -0,0 +1,598 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use stripe::{Client, StripeError};
+
+use crate::api_version::{self, ApiVersionCheck, DEFAULT_API_VERSION};
+use crate::cancel::CancellationToken;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::command::{self, CommandOutcome, OutboxEntry, StripeCommand};
+use crate::config::StripeMode;
+use crate::dry_run;
+use crate::health::{self, KeyHealthReport};
+use crate::localization::PaymentSheetLocalization;
+#[cfg(feature = "prometheus")]
+use crate::prometheus::PrometheusRecorder;
+use crate::redact::SecretString;
+use crate::refund_batch::{self, RefundBatchReport, RefundRequest};
+use crate::region::RegionConfig;
+use crate::shadow::{self, ShadowRequest, ShadowSink};
+use crate::spending::{SpendingPolicy, SpendingRequest};
+use crate::telemetry;
+use crate::{
+    CreateCustomerDto, CreatePaymentIntentDto, CustomerDto, GuestPaymentIntentDto,
+    GuestPaymentOptions, PaymentIntentDto, PaymentSheetResult, StripePaymentError,
+};
+
+pub(crate) fn is_authentication_error(error: &StripeError) -> bool {
+    matches!(error, StripeError::Stripe(x) if x.http_status == 401)
+}
+
+/// Errors that may come from a revoked key or an unreachable endpoint, the
+/// only ones worth probing the key for. Declines and invalid requests fail the
+/// same way with any key.
+fn may_need_failover(error: &StripePaymentError) -> bool {
+    matches!(
+        error.stripe_error(),
+        Some(x) if is_authentication_error(x)
+            || matches!(x, StripeError::Timeout | StripeError::ClientError(_))
+    )
+}
+
+/// Entry point holding the Stripe client(s). A secondary key can be configured
+/// for zero-downtime rotation: when a call fails and the active key no longer
+/// authenticates, the facade switches to the other key and retries once.
+pub struct LibStripe {
+    clients: Vec<Client>,
+    active: AtomicUsize,
+    dry_run: bool,
+    api_version: String,
+    regions: RegionConfig,
+    mode: StripeMode,
+    allow_live: bool,
+    spending_policy: Option<Arc<dyn SpendingPolicy>>,
+    shadow_sink: Option<Arc<dyn ShadowSink>>,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    merchant_display_name: Option<String>,
+    #[cfg(feature = "prometheus")]
+    prometheus: Option<PrometheusRecorder>,
+}
+
+impl std::fmt::Debug for LibStripe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LibStripe")
+            .field("keys", &self.clients.len())
+            .field("active", &self.active.load(Ordering::Relaxed))
+            .field("dry_run", &self.dry_run)
+            .field("api_version", &self.api_version)
+            .field("regions", &self.regions)
+            .field("mode", &self.mode)
+            .field("allow_live", &self.allow_live)
+            .field("spending_policy", &self.spending_policy.is_some())
+            .field("shadow_sink", &self.shadow_sink.is_some())
+            .field("circuit_breaker", &self.circuit_breaker)
+            .field("merchant_display_name", &self.merchant_display_name)
+            .finish()
+    }
+}
+
+impl LibStripe {
+    /// With the `zeroize` feature the key passed in is cleared once the client
+    /// is built; the copy async-stripe keeps in its request headers is not.
+    pub fn new(secret_key: impl Into<String>) -> Self {
+        let secret_key = SecretString::from(secret_key.into());
+        let mode = StripeMode::of_key(secret_key.expose()).unwrap_or(StripeMode::Live);
+        LibStripe::from_client(Client::new(secret_key.expose())).with_mode(mode)
+    }
+
+    /// The key of a ready-made client can't be inspected, so the facade
+    /// assumes live mode until [`LibStripe::with_mode`] says otherwise. The
+    /// client is pinned to [`DEFAULT_API_VERSION`] like the secondary key's.
+    pub fn from_client(client: Client) -> Self {
+        LibStripe {
+            clients: vec![api_version::pin_client(client, DEFAULT_API_VERSION)],
+            active: AtomicUsize::new(0),
+            dry_run: false,
+            api_version: DEFAULT_API_VERSION.to_string(),
+            regions: RegionConfig::default(),
+            mode: StripeMode::Live,
+            allow_live: false,
+            spending_policy: None,
+            shadow_sink: None,
+            circuit_breaker: None,
+            merchant_display_name: None,
+            #[cfg(feature = "prometheus")]
+            prometheus: None,
+        }
+    }
+
+    pub fn with_secondary_key(mut self, secret_key: impl Into<String>) -> Self {
+        self.clients.truncate(1);
+        let secret_key = SecretString::from(secret_key.into());
+        self.clients.push(api_version::pin_client(
+            Client::new(secret_key.expose()),
+            &self.api_version,
+        ));
+        self
+    }
+
+    /// In dry-run mode mutating helpers only run local validation and return
+    /// synthesized DTOs, without calling Stripe.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn with_mode(mut self, mode: StripeMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn mode(&self) -> StripeMode {
+        self.mode
+    }
+
+    /// Permits destructive bulk operations such as
+    /// [`LibStripe::execute_commands`] and [`LibStripe::refund_batch`] in live
+    /// mode. Off by default so a live
+    /// key configured by mistake can't mass-refund or mass-cancel.
+    pub fn with_allow_live(mut self, allow_live: bool) -> Self {
+        self.allow_live = allow_live;
+        self
+    }
+
+    /// Fails unless the facade uses a test mode key; call it from fixtures and
+    /// scripts that must never touch real money.
+    pub fn assert_test_mode(&self) -> Result<(), StripePaymentError> {
+        match self.mode {
+            StripeMode::Test => Ok(()),
+            StripeMode::Live => Err(StripePaymentError::from_general(
+                "expected a test mode key but the facade is in live mode".to_string(),
+            )),
+        }
+    }
+
+    fn check_destructive_bulk(&self, operation: &str) -> Result<(), StripePaymentError> {
+        match (self.mode, self.allow_live) {
+            (StripeMode::Live, false) => Err(StripePaymentError::from_general(format!(
+                "refusing {} in live mode; set with_allow_live(true) to permit it",
+                operation
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Shadow-write mode for migrating from another provider: customer and
+    /// payment sheet creation and outbox commands hand the requests they would send to
+    /// `sink` and return dry-run DTOs instead of calling Stripe.
+    pub fn with_shadow_sink(mut self, sink: Arc<dyn ShadowSink>) -> Self {
+        self.shadow_sink = Some(sink);
+        self
+    }
+
+    pub fn is_shadow_mode(&self) -> bool {
+        self.shadow_sink.is_some()
+    }
+
+    /// Puts `breaker` in front of the calls made through [`LibStripe::run`]
+    /// and the methods built on it; each call counts as one request. Share
+    /// the breaker between facades that should trip together.
+    pub fn with_circuit_breaker(mut self, breaker: Arc<CircuitBreaker>) -> Self {
+        self.circuit_breaker = Some(breaker);
+        self
+    }
+
+    pub fn circuit_breaker(&self) -> Option<&CircuitBreaker> {
+        self.circuit_breaker.as_deref()
+    }
+
+    async fn guarded<T, Fut>(&self, request: Fut) -> Result<T, StripePaymentError>
+    where
+        Fut: Future<Output = Result<T, StripePaymentError>>,
+    {
+        match &self.circuit_breaker {
+            Some(x) => x.call(request).await,
+            None => request.await,
+        }
+    }
+
+    async fn write_shadow<F>(&self, requests: F) -> Result<(), StripePaymentError>
+    where
+        F: FnOnce() -> Result<Vec<ShadowRequest>, StripePaymentError>,
+    {
+        match &self.shadow_sink {
+            Some(sink) => sink.write(&requests()?).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Pins the API version: it is sent as the `Stripe-Version` header by
+    /// every client of the facade, including the secondary key's, and is the
+    /// version webhook payloads are expected in. Call
+    /// [`LibStripe::check_api_version`] at startup to detect an account whose
+    /// default version (used for webhooks) has drifted.
+    pub fn with_api_version(mut self, api_version: impl Into<String>) -> Self {
+        self.api_version = api_version.into();
+        self.clients = self
+            .clients
+            .into_iter()
+            .map(|x| api_version::pin_client(x, &self.api_version))
+            .collect();
+        self
+    }
+
+    pub fn api_version(&self) -> &str {
+        &self.api_version
+    }
+
+    /// Consulted by the payment sheet methods before the intent is created; a
+    /// violation is returned as [`StripePaymentError::SpendingLimit`] and
+    /// nothing is sent to Stripe. Amounts reserved by the policy are released when the
+    /// intent isn't created, including in dry-run and shadow mode.
+    pub fn with_spending_policy(mut self, policy: Arc<dyn SpendingPolicy>) -> Self {
+        self.spending_policy = Some(policy);
+        self
+    }
+
+    async fn spend<T, Fut>(
+        &self,
+        request: SpendingRequest<'_>,
+        create: Fut,
+    ) -> Result<T, StripePaymentError>
+    where
+        Fut: Future<Output = Result<T, StripePaymentError>>,
+    {
+        let policy = match &self.spending_policy {
+            Some(x) => x,
+            None => return create.await,
+        };
+        policy.check(request).await?;
+        let result = create.await;
+        if result.is_err() || self.dry_run || self.is_shadow_mode() {
+            policy.release(request).await;
+        }
+        result
+    }
+
+    pub async fn check_api_version(
+        &self,
+        strict: bool,
+    ) -> Result<ApiVersionCheck, StripePaymentError> {
+        self.run(|client| api_version::check_api_version(client, &self.api_version, strict))
+            .await
+    }
+
+    /// Verifies the active key at startup, see [`health::health_check`].
+    pub async fn health_check(&self) -> Result<KeyHealthReport, StripePaymentError> {
+        self.run(|client| health::health_check(client)).await
+    }
+
+    /// Regional defaults applied to payment sheets before they are created, so
+    /// callers don't need to pick descriptors and payment methods per market.
+    pub fn with_region_config(mut self, regions: RegionConfig) -> Self {
+        self.regions = regions;
+        self
+    }
+
+    pub fn region_config(&self) -> &RegionConfig {
+        &self.regions
+    }
+
+    /// Returned in [`PaymentIntentDto::localization`] together with the
+    /// locale from the region config, so mobile clients can initialize
+    /// PaymentSheet from the payment sheet response alone.
+    pub fn with_merchant_display_name(mut self, merchant_display_name: impl Into<String>) -> Self {
+        self.merchant_display_name = Some(merchant_display_name.into());
+        self
+    }
+
+    pub fn payment_sheet_localization(
+        &self,
+        dto: &CreatePaymentIntentDto,
+    ) -> PaymentSheetLocalization {
+        let localization = self.regions.payment_sheet_localization(dto);
+        match &self.merchant_display_name {
+            Some(x) => localization.with_merchant_display_name(x),
+            None => localization,
+        }
+    }
+
+    /// Keeps the recorder with the facade so the application's `/metrics`
+    /// handler can render it through [`LibStripe::render_metrics`].
+    #[cfg(feature = "prometheus")]
+    pub fn with_prometheus_recorder(mut self, recorder: PrometheusRecorder) -> Self {
+        self.prometheus = Some(recorder);
+        self
+    }
+
+    /// `None` unless a Prometheus recorder was registered.
+    #[cfg(feature = "prometheus")]
+    pub fn render_metrics(&self) -> Option<String> {
+        self.prometheus.as_ref().map(|x| x.render())
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.clients[self.active.load(Ordering::Acquire) % self.clients.len()]
+    }
+
+    pub fn is_using_secondary_key(&self) -> bool {
+        self.active.load(Ordering::Acquire) % self.clients.len() != 0
+    }
+
+    /// Switches to the other key if the active one is rejected by Stripe.
+    /// Returns whether a switch happened.
+    pub async fn failover_if_unauthorized(&self) -> bool {
+        if self.clients.len() < 2 {
+            return false;
+        }
+        let active = self.active.load(Ordering::Acquire);
+        match telemetry::observe(
+            "balance.retrieve",
+            self.clients[active % self.clients.len()].get::<serde_json::Value>("/balance"),
+        )
+        .await
+        {
+            Err(x)
+                if x.stripe_error()
+                    .map(is_authentication_error)
+                    .unwrap_or(false) =>
+            {
+                tracing::warn!("stripe api key rejected, switching to the other configured key");
+                let _ = self.active.compare_exchange(
+                    active,
+                    (active + 1) % self.clients.len(),
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub async fn run<'a, T, F, Fut>(&'a self, f: F) -> Result<T, StripePaymentError>
+    where
+        F: Fn(&'a Client) -> Fut,
+        Fut: Future<Output = Result<T, StripePaymentError>> + 'a,
+    {
+        match self.guarded(f(self.client())).await {
+            Err(x) if may_need_failover(&x) && self.failover_if_unauthorized().await => {
+                self.guarded(f(self.client())).await
+            }
+            x => x,
+        }
+    }
+
+    pub async fn get_customer(&self, account_id: String) -> Result<CustomerDto, StripeError> {
+        match crate::get_customer(self.client(), account_id.clone()).await {
+            Err(x) if is_authentication_error(&x) && self.failover_if_unauthorized().await => {
+                crate::get_customer(self.client(), account_id).await
+            }
+            x => x,
+        }
+    }
+
+    /// Fills in the preferred locale from the region config when the DTO
+    /// names a currency and has none.
+    pub async fn create_customer(
+        &self,
+        dto: &CreateCustomerDto,
+    ) -> Result<CustomerDto, StripePaymentError> {
+        let dto = &self.regions.apply_to_customer(dto.clone());
+        self.write_shadow(|| shadow::customer_requests(dto)).await?;
+        if self.dry_run || self.is_shadow_mode() {
+            return dry_run::create_customer(dto);
+        }
+        self.run(|client| crate::create_customer(client, dto)).await
+    }
+
+    pub async fn create_payment_sheet(
+        &self,
+        dto: &CreatePaymentIntentDto,
+    ) -> Result<PaymentIntentDto, StripePaymentError> {
+        let dto = &self.regions.apply_to_payment_sheet(dto.clone());
+        self.spend(dto.into(), async {
+            self.write_shadow(|| shadow::payment_sheet_requests(dto))
+                .await?;
+            let localization = self.payment_sheet_localization(dto);
+            if self.dry_run || self.is_shadow_mode() {
+                return dry_run::create_payment_sheet(dto)
+                    .map(|x| x.with_localization(localization));
+            }
+            self.run(|client| crate::create_payment_sheet(client, dto))
+                .await
+                .map(|x| x.with_localization(localization))
+        })
+        .await
+    }
+
+    pub async fn create_payment_sheet_allowing_guest_fallback(
+        &self,
+        dto: &CreatePaymentIntentDto,
+    ) -> Result<PaymentSheetResult, StripePaymentError> {
+        let dto = &self.regions.apply_to_payment_sheet(dto.clone());
+        self.spend(dto.into(), async {
+            self.write_shadow(|| shadow::payment_sheet_requests(dto))
+                .await?;
+            if self.dry_run || self.is_shadow_mode() {
+                return dry_run::create_payment_sheet(dto).map(|x| PaymentSheetResult {
+                    id: x.id,
+                    client_secret: x.client_secret,
+                    stripe_customer_id: x.stripe_customer_id,
+                    ephemeral_secret: Some(x.ephemeral_secret),
+                    ephemeral_key_error: None,
+                });
+            }
+            self.run(|client| crate::create_payment_sheet_allowing_guest_fallback(client, dto))
+                .await
+        })
+        .await
+    }
+
+    pub async fn create_guest_payment_sheet(
+        &self,
+        amount: i64,
+        currency: &str,
+        options: &GuestPaymentOptions,
+    ) -> Result<GuestPaymentIntentDto, StripePaymentError> {
+        let options = &self
+            .regions
+            .apply_to_guest_payment(currency, options.clone());
+        self.spend(SpendingRequest::guest(amount, currency), async {
+            self.write_shadow(|| shadow::guest_payment_sheet_requests(amount, currency, options))
+                .await?;
+            if self.dry_run || self.is_shadow_mode() {
+                return dry_run::create_guest_payment_sheet(amount, currency, options);
+            }
+            self.run(|client| crate::create_guest_payment_sheet(client, amount, currency, options))
+                .await
+        })
+        .await
+    }
+
+    /// Runs outbox entries in order. Batches with more than one refund or
+    /// cancellation are refused in live mode unless live mode is allowed. In
+    /// dry-run and shadow mode each entry is only validated and a synthesized
+    /// outcome is returned; shadow mode also hands the request to the sink.
+    pub async fn execute_commands(
+        &self,
+        entries: &[OutboxEntry],
+    ) -> Result<Vec<Result<CommandOutcome, StripePaymentError>>, StripePaymentError> {
+        let destructive = entries
+            .iter()
+            .filter(|x| {
+                matches!(
+                    x.command,
+                    StripeCommand::CreateRefund { .. } | StripeCommand::CancelIntent { .. }
+                )
+            })
+            .count();
+        if destructive > 1 {
+            self.check_destructive_bulk(&format!("{} refunds/cancellations", destructive))?;
+        }
+        let mut results = Vec::with_capacity(entries.len());
+        for entry in entries {
+            if self.dry_run || self.is_shadow_mode() {
+                let outcome = dry_run::execute_command(entry);
+                if outcome.is_ok() {
+                    self.write_shadow(|| shadow::command_requests(entry))
+                        .await?;
+                }
+                results.push(outcome);
+                continue;
+            }
+            results.push(
+                self.run(|client| command::execute_command(client, entry))
+                    .await,
+            );
+        }
+        Ok(results)
+    }
+
+    /// See [`refund_batch::refund_batch_cancellable`]. Batches of more than
+    /// one refund are refused in live mode unless live mode is allowed; in
+    /// dry-run and shadow mode each row is only validated.
+    pub async fn refund_batch(
+        &self,
+        requests: Vec<RefundRequest>,
+        concurrency: usize,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<RefundBatchReport, StripePaymentError> {
+        if requests.len() > 1 {
+            self.check_destructive_bulk(&format!("{} refunds", requests.len()))?;
+        }
+        let simulate = self.dry_run || self.is_shadow_mode();
+        let report = refund_batch::run_batch(requests, concurrency, cancel, |request| async move {
+            if !simulate {
+                return self
+                    .run(|client| refund_batch::refund(client, &request))
+                    .await;
+            }
+            let outcome = dry_run::refund(&request)?;
+            self.write_shadow(|| shadow::refund_requests(&request))
+                .await?;
+            Ok(outcome)
+        })
+        .await;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::region::RegionSettings;
+    use crate::shadow::InMemoryShadowSink;
+
+    #[test]
+    fn secondary_key_is_optional() {
+        let lib = LibStripe::new("sk_test_old");
+        assert!(!lib.is_using_secondary_key());
+        let lib = lib.with_secondary_key("sk_test_new");
+        assert_eq!(lib.clients.len(), 2);
+        lib.active.store(1, Ordering::Release);
+        assert!(lib.is_using_secondary_key());
+    }
+
+    #[test]
+    fn only_probes_on_auth_or_connectivity_errors() {
+        assert!(may_need_failover(&StripePaymentError::from_general(
+            StripeError::Timeout
+        )));
+        assert!(!may_need_failover(&StripePaymentError::from_general(
+            "invalid amount".to_string()
+        )));
+    }
+
+    #[test]
+    fn live_mode_needs_explicit_opt_in() {
+        let lib = LibStripe::new("sk_test_123");
+        assert_eq!(lib.mode(), StripeMode::Test);
+        assert!(lib.assert_test_mode().is_ok());
+        assert!(lib.check_destructive_bulk("mass refund").is_ok());
+        let lib = LibStripe::new("sk_live_123");
+        assert!(lib.assert_test_mode().is_err());
+        assert!(lib.check_destructive_bulk("mass refund").is_err());
+        let lib = lib.with_allow_live(true);
+        assert!(lib.check_destructive_bulk("mass refund").is_ok());
+    }
+
+    #[tokio::test]
+    async fn creates_customers_with_the_region_locale() {
+        let sink = Arc::new(InMemoryShadowSink::new());
+        let lib = LibStripe::new("sk_test_123")
+            .with_region_config(
+                RegionConfig::new().with_currency("jpy", RegionSettings::new().with_locale("ja")),
+            )
+            .with_shadow_sink(sink.clone());
+        lib.create_customer(&CreateCustomerDto::new("42").with_currency("JPY"))
+            .await
+            .unwrap();
+        lib.create_customer(
+            &CreateCustomerDto::new("43")
+                .with_currency("jpy")
+                .with_preferred_locales(vec!["en".into()]),
+        )
+        .await
+        .unwrap();
+        lib.create_customer(&CreateCustomerDto::new("44"))
+            .await
+            .unwrap();
+        let requests = sink.requests();
+        assert_eq!(requests[0].endpoint, "customers.create");
+        assert_eq!(
+            requests[0].params["preferred_locales"],
+            serde_json::json!(["ja"])
+        );
+        assert_eq!(
+            requests[1].params["preferred_locales"],
+            serde_json::json!(["en"])
+        );
+        assert!(requests[2].params.get("preferred_locales").is_none());
+    }
+}
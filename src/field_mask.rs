@@ -0,0 +1,61 @@
+use std::collections::BTreeSet;
+
+/// Selects the optional fields a retrieval helper populates, named after the
+/// DTO's fields. Fields left out are returned as `None` or empty; ids and
+/// amounts are always populated. Only invoice `lines`, and a charge's
+/// `outcome` and `payment_method_details` when both are left out, are skipped
+/// while deserializing; the other fields are parsed and then dropped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FieldMask {
+    /// `None` selects every field.
+    fields: Option<BTreeSet<String>>,
+}
+
+impl FieldMask {
+    pub fn all() -> Self {
+        FieldMask::default()
+    }
+
+    pub fn only<I, S>(fields: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        FieldMask {
+            fields: Some(fields.into_iter().map(Into::into).collect()),
+        }
+    }
+
+    pub fn with_field(mut self, field: impl Into<String>) -> Self {
+        if let Some(x) = &mut self.fields {
+            x.insert(field.into());
+        }
+        self
+    }
+
+    pub fn includes(&self, field: &str) -> bool {
+        self.fields
+            .as_ref()
+            .map(|x| x.contains(field))
+            .unwrap_or(true)
+    }
+
+    pub(crate) fn keep<T>(&self, field: &str, value: Option<T>) -> Option<T> {
+        value.filter(|_| self.includes(field))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selects_fields() {
+        assert!(FieldMask::all().includes("lines"));
+        let mask = FieldMask::only(["status"]).with_field("tax");
+        assert!(mask.includes("tax"));
+        assert!(!mask.includes("lines"));
+        assert_eq!(mask.keep("lines", Some(1)), None);
+        assert_eq!(mask.keep("status", Some(1)), Some(1));
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use stripe::Client;
+
+use crate::redact::{self, Redacted, SecretString};
+use crate::telemetry;
+use crate::url::StripeUrl;
+use crate::StripePaymentError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinancialConnectionsPermission {
+    Balances,
+    Ownership,
+    PaymentMethod,
+    Transactions,
+}
+
+impl FinancialConnectionsPermission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FinancialConnectionsPermission::Balances => "balances",
+            FinancialConnectionsPermission::Ownership => "ownership",
+            FinancialConnectionsPermission::PaymentMethod => "payment_method",
+            FinancialConnectionsPermission::Transactions => "transactions",
+        }
+    }
+}
+
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct FinancialConnectionsSessionDto {
+    pub id: String,
+    pub client_secret: SecretString,
+}
+
+impl Redacted for FinancialConnectionsSessionDto {
+    fn fmt_redacted(&self, f: &mut std::fmt::Formatter<'_>, reveal: bool) -> std::fmt::Result {
+        f.debug_struct("FinancialConnectionsSessionDto")
+            .field("id", &self.id)
+            .field("client_secret", redact::secret(&self.client_secret, reveal))
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for FinancialConnectionsSessionDto {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_redacted(f, false)
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct FinancialConnectionsAccountDto {
+    pub id: String,
+    pub institution_name: String,
+    pub last4: Option<String>,
+    pub category: String,
+    pub status: String,
+    pub permissions: Vec<String>,
+    pub current_balance: HashMap<String, i64>,
+    pub balance_as_of: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct AccountHolder<'a> {
+    #[serde(rename = "type")]
+    holder_type: &'static str,
+    customer: &'a str,
+}
+
+#[derive(Serialize)]
+struct SessionParams<'a> {
+    account_holder: AccountHolder<'a>,
+    permissions: &'a [FinancialConnectionsPermission],
+}
+
+#[derive(Serialize)]
+struct RefreshParams {
+    features: [&'static str; 1],
+}
+
+#[derive(Deserialize)]
+struct RawSession {
+    id: String,
+    client_secret: String,
+}
+
+#[derive(Deserialize)]
+struct RawBalance {
+    as_of: i64,
+    #[serde(default)]
+    current: HashMap<String, i64>,
+}
+
+#[derive(Deserialize)]
+struct RawAccount {
+    id: String,
+    institution_name: String,
+    last4: Option<String>,
+    category: String,
+    status: String,
+    #[serde(default)]
+    permissions: Vec<String>,
+    balance: Option<RawBalance>,
+}
+
+#[derive(Deserialize)]
+struct RawAccountList {
+    data: Vec<RawAccount>,
+}
+
+impl From<RawAccount> for FinancialConnectionsAccountDto {
+    fn from(x: RawAccount) -> Self {
+        FinancialConnectionsAccountDto {
+            id: x.id,
+            institution_name: x.institution_name,
+            last4: x.last4,
+            category: x.category,
+            status: x.status,
+            permissions: x.permissions,
+            balance_as_of: x.balance.as_ref().map(|x| x.as_of),
+            current_balance: x.balance.map(|x| x.current).unwrap_or_default(),
+        }
+    }
+}
+
+/// Starts a session for the customer to link bank accounts. The client secret
+/// is handed to Stripe.js `collectFinancialConnectionsAccounts`.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn create_financial_connections_session(
+    stripe_client: &Client,
+    stripe_customer_id: &str,
+    permissions: &[FinancialConnectionsPermission],
+) -> Result<FinancialConnectionsSessionDto, StripePaymentError> {
+    telemetry::observe(
+        "financial_connections.sessions.create",
+        stripe_client.post_form::<RawSession, _>(
+            "/financial_connections/sessions",
+            SessionParams {
+                account_holder: AccountHolder {
+                    holder_type: "customer",
+                    customer: stripe_customer_id,
+                },
+                permissions,
+            },
+        ),
+    )
+    .await
+    .map(|x| FinancialConnectionsSessionDto {
+        id: x.id,
+        client_secret: x.client_secret.into(),
+    })
+    .map_err(StripePaymentError::from_general)
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn get_financial_connections_account(
+    stripe_client: &Client,
+    account_id: &str,
+) -> Result<FinancialConnectionsAccountDto, StripePaymentError> {
+    telemetry::observe(
+        "financial_connections.accounts.retrieve",
+        stripe_client.get::<RawAccount>(
+            &StripeUrl::new("/financial_connections/accounts")
+                .segment(account_id)
+                .build(),
+        ),
+    )
+    .await
+    .map(FinancialConnectionsAccountDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+#[derive(Serialize)]
+struct AccountHolderParams<'a> {
+    customer: &'a str,
+}
+
+#[derive(Serialize)]
+struct ListParams<'a> {
+    account_holder: AccountHolderParams<'a>,
+    limit: u64,
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn list_financial_connections_accounts(
+    stripe_client: &Client,
+    stripe_customer_id: &str,
+) -> Result<Vec<FinancialConnectionsAccountDto>, StripePaymentError> {
+    telemetry::observe(
+        "financial_connections.accounts.list",
+        stripe_client.get_query::<RawAccountList, _>(
+            "/financial_connections/accounts",
+            ListParams {
+                account_holder: AccountHolderParams {
+                    customer: stripe_customer_id,
+                },
+                limit: 100,
+            },
+        ),
+    )
+    .await
+    .map(|x| {
+        x.data
+            .into_iter()
+            .map(FinancialConnectionsAccountDto::from)
+            .collect()
+    })
+    .map_err(StripePaymentError::from_general)
+}
+
+/// Requests a balance refresh. Refreshes are asynchronous; the returned account
+/// carries the previous balance until Stripe finishes.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn refresh_account_balance(
+    stripe_client: &Client,
+    account_id: &str,
+) -> Result<FinancialConnectionsAccountDto, StripePaymentError> {
+    telemetry::observe(
+        "financial_connections.accounts.refresh",
+        stripe_client.post_form::<RawAccount, _>(
+            &StripeUrl::new("/financial_connections/accounts")
+                .segment(account_id)
+                .segment("refresh")
+                .build(),
+            RefreshParams {
+                features: ["balance"],
+            },
+        ),
+    )
+    .await
+    .map(FinancialConnectionsAccountDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_account_balance() {
+        let account = serde_json::from_str::<RawAccount>(
+            r#"{"id":"fca_1","institution_name":"Bank","last4":"6789","category":"cash",
+                "status":"active","permissions":["balances","payment_method"],
+                "balance":{"as_of":1700000000,"current":{"usd":12345}}}"#,
+        )
+        .map(FinancialConnectionsAccountDto::from)
+        .unwrap();
+        assert_eq!(account.current_balance.get("usd"), Some(&12345));
+        assert_eq!(account.balance_as_of, Some(1700000000));
+        assert_eq!(
+            FinancialConnectionsPermission::PaymentMethod.as_str(),
+            "payment_method"
+        );
+    }
+}
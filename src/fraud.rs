@@ -0,0 +1,228 @@
+use serde::{Deserialize, Serialize};
+use stripe::Client;
+
+use crate::cancel::{self, CancellableError, CancellationToken};
+use crate::command::{execute_command, OutboxEntry, RefundReason, StripeCommand};
+use crate::pagination::RawList;
+use crate::telemetry;
+use crate::StripePaymentError;
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct EarlyFraudWarningDto {
+    pub id: String,
+    pub actionable: bool,
+    pub charge_id: String,
+    pub payment_intent_id: Option<String>,
+    pub fraud_type: String,
+    pub created: i64,
+}
+
+#[derive(Deserialize)]
+struct RawEarlyFraudWarning {
+    id: String,
+    actionable: bool,
+    charge: String,
+    payment_intent: Option<String>,
+    fraud_type: String,
+    created: i64,
+}
+
+impl From<RawEarlyFraudWarning> for EarlyFraudWarningDto {
+    fn from(x: RawEarlyFraudWarning) -> Self {
+        EarlyFraudWarningDto {
+            id: x.id,
+            actionable: x.actionable,
+            charge_id: x.charge,
+            payment_intent_id: x.payment_intent,
+            fraud_type: x.fraud_type,
+            created: x.created,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CreatedGte {
+    gte: i64,
+}
+
+#[derive(Serialize)]
+struct ListParams<'a> {
+    created: CreatedGte,
+    limit: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    starting_after: Option<&'a str>,
+}
+
+/// Lists all early fraud warnings created at or after `since`, newest first.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn list_early_fraud_warnings(
+    stripe_client: &Client,
+    since: i64,
+) -> Result<Vec<EarlyFraudWarningDto>, StripePaymentError> {
+    list_early_fraud_warnings_cancellable(stripe_client, since, None)
+        .await
+        .map_err(CancellableError::into_stripe_error)
+}
+
+/// Like [`list_early_fraud_warnings`], but stops between pages once `cancel`
+/// fires.
+#[tracing::instrument(skip(stripe_client, cancel))]
+pub async fn list_early_fraud_warnings_cancellable(
+    stripe_client: &Client,
+    since: i64,
+    cancel: Option<&CancellationToken>,
+) -> Result<Vec<EarlyFraudWarningDto>, CancellableError> {
+    let mut warnings = Vec::<EarlyFraudWarningDto>::new();
+    loop {
+        let list = cancel::or_cancelled(
+            cancel,
+            telemetry::observe(
+                "radar.early_fraud_warnings.list",
+                stripe_client.get_query::<RawList<RawEarlyFraudWarning>, _>(
+                    "/radar/early_fraud_warnings",
+                    ListParams {
+                        created: CreatedGte { gte: since },
+                        limit: 100,
+                        starting_after: warnings.last().map(|x| x.id.as_str()),
+                    },
+                ),
+            ),
+        )
+        .await?;
+        let done = !list.has_more || list.data.is_empty();
+        warnings.extend(list.data.into_iter().map(EarlyFraudWarningDto::from));
+        if done {
+            return Ok(warnings);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FraudDecision {
+    Refunded { refund_id: String },
+    SkippedNotActionable,
+    SkippedShipped,
+    SkippedNoPaymentIntent,
+    Failed(String),
+    Cancelled,
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct FraudDecisionReport {
+    pub decisions: Vec<(EarlyFraudWarningDto, FraudDecision)>,
+}
+
+impl FraudDecisionReport {
+    pub fn refunded(&self) -> usize {
+        self.decisions
+            .iter()
+            .filter(|(_, x)| matches!(x, FraudDecision::Refunded { .. }))
+            .count()
+    }
+}
+
+// Returns the refund command for a warning, or the reason it is skipped.
+fn plan(warning: &EarlyFraudWarningDto, shipped: bool) -> Result<OutboxEntry, FraudDecision> {
+    if !warning.actionable {
+        return Err(FraudDecision::SkippedNotActionable);
+    }
+    if shipped {
+        return Err(FraudDecision::SkippedShipped);
+    }
+    let payment_intent_id = warning
+        .payment_intent_id
+        .clone()
+        .ok_or(FraudDecision::SkippedNoPaymentIntent)?;
+    Ok(OutboxEntry::new(
+        format!("efw-refund-{}", warning.id),
+        StripeCommand::CreateRefund {
+            payment_intent_id,
+            amount: None,
+            reason: Some(RefundReason::Fraudulent),
+        },
+    ))
+}
+
+/// Fully refunds actionable warnings whose order hasn't shipped, as decided by
+/// `is_shipped`. Refunding before the dispute arrives avoids the dispute fee.
+/// Refunds are idempotent per warning, so the policy can run repeatedly.
+#[tracing::instrument(skip(stripe_client, warnings, is_shipped))]
+pub async fn refund_unshipped_fraud_warnings<F>(
+    stripe_client: &Client,
+    warnings: Vec<EarlyFraudWarningDto>,
+    is_shipped: F,
+) -> FraudDecisionReport
+where
+    F: Fn(&EarlyFraudWarningDto) -> bool,
+{
+    refund_unshipped_fraud_warnings_cancellable(stripe_client, warnings, is_shipped, None).await
+}
+
+/// Like [`refund_unshipped_fraud_warnings`], but once `cancel` fires the
+/// remaining warnings are reported as [`FraudDecision::Cancelled`]. A refund
+/// already in flight is always completed.
+#[tracing::instrument(skip(stripe_client, warnings, is_shipped, cancel))]
+pub async fn refund_unshipped_fraud_warnings_cancellable<F>(
+    stripe_client: &Client,
+    warnings: Vec<EarlyFraudWarningDto>,
+    is_shipped: F,
+    cancel: Option<&CancellationToken>,
+) -> FraudDecisionReport
+where
+    F: Fn(&EarlyFraudWarningDto) -> bool,
+{
+    let mut decisions = Vec::with_capacity(warnings.len());
+    for warning in warnings {
+        if cancel::check(cancel).is_err() {
+            decisions.push((warning, FraudDecision::Cancelled));
+            continue;
+        }
+        let decision = match plan(&warning, is_shipped(&warning)) {
+            Ok(entry) => match execute_command(stripe_client, &entry).await {
+                Ok(x) => FraudDecision::Refunded {
+                    refund_id: x.object_id,
+                },
+                Err(x) => FraudDecision::Failed(format!("{:?}", x)),
+            },
+            Err(x) => x,
+        };
+        tracing::info!("early fraud warning {}: {:?}", warning.id, decision);
+        decisions.push((warning, decision));
+    }
+    FraudDecisionReport { decisions }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_refunds_unshipped_actionable_warnings() {
+        let warning = EarlyFraudWarningDto {
+            id: "issfr_1".to_string(),
+            actionable: true,
+            charge_id: "ch_1".to_string(),
+            payment_intent_id: Some("pi_1".to_string()),
+            fraud_type: "made_with_stolen_card".to_string(),
+            created: 0,
+        };
+        assert_eq!(
+            plan(&warning, false).unwrap().idempotency_key,
+            "efw-refund-issfr_1"
+        );
+        assert_eq!(
+            plan(&warning, true).unwrap_err(),
+            FraudDecision::SkippedShipped
+        );
+        let warning = EarlyFraudWarningDto {
+            actionable: false,
+            ..warning
+        };
+        assert_eq!(
+            plan(&warning, false).unwrap_err(),
+            FraudDecision::SkippedNotActionable
+        );
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use stripe::{Client, StripeError};
+
+use crate::config::StripeMode;
+use crate::pagination::{CreatedRange, RawList};
+use crate::telemetry;
+use crate::StripePaymentError;
+
+/// Dispute rate above which card networks and Stripe start monitoring an account.
+pub const DISPUTE_RATE_THRESHOLD: f64 = 0.0075;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct PaymentHealthReport {
+    pub period: CreatedRange,
+    pub succeeded_charges: u64,
+    pub failed_charges: u64,
+    pub refunds: u64,
+    pub disputes: u64,
+    pub charge_volume: HashMap<String, i64>,
+    pub refund_volume: HashMap<String, i64>,
+    pub dispute_volume: HashMap<String, i64>,
+}
+
+impl PaymentHealthReport {
+    /// Disputes per successful charge, as used in account reviews.
+    pub fn dispute_rate(&self) -> f64 {
+        match self.succeeded_charges {
+            0 => 0.0,
+            x => self.disputes as f64 / x as f64,
+        }
+    }
+
+    pub fn refund_rate(&self) -> f64 {
+        match self.succeeded_charges {
+            0 => 0.0,
+            x => self.refunds as f64 / x as f64,
+        }
+    }
+
+    pub fn exceeds_dispute_threshold(&self, threshold: f64) -> bool {
+        self.dispute_rate() > threshold
+    }
+}
+
+#[derive(Deserialize)]
+struct RawCharge {
+    id: String,
+    amount: i64,
+    currency: String,
+    status: String,
+}
+
+#[derive(Deserialize)]
+struct RawRefund {
+    id: String,
+    amount: i64,
+    currency: String,
+    status: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawDispute {
+    id: String,
+    amount: i64,
+    currency: String,
+}
+
+#[derive(Serialize)]
+struct ListParams<'a> {
+    created: CreatedRange,
+    limit: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    starting_after: Option<&'a str>,
+}
+
+async fn list_all<T: DeserializeOwned>(
+    stripe_client: &Client,
+    endpoint: &'static str,
+    path: &str,
+    period: CreatedRange,
+    id: fn(&T) -> &str,
+) -> Result<Vec<T>, StripePaymentError> {
+    let mut items = Vec::<T>::new();
+    loop {
+        let list = telemetry::observe(
+            endpoint,
+            stripe_client.get_query::<RawList<T>, _>(
+                path,
+                ListParams {
+                    created: period,
+                    limit: 100,
+                    starting_after: items.last().map(id),
+                },
+            ),
+        )
+        .await
+        .map_err(StripePaymentError::from_general)?;
+        let done = !list.has_more || list.data.is_empty();
+        items.extend(list.data);
+        if done {
+            return Ok(items);
+        }
+    }
+}
+
+fn add(volume: &mut HashMap<String, i64>, currency: String, amount: i64) {
+    *volume.entry(currency).or_default() += amount;
+}
+
+/// Aggregates charges, refunds and disputes created within `period`. Volumes
+/// are kept per currency since amounts in different currencies can't be summed.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn payment_health_report(
+    stripe_client: &Client,
+    period: CreatedRange,
+) -> Result<PaymentHealthReport, StripePaymentError> {
+    let mut report = PaymentHealthReport {
+        period,
+        ..PaymentHealthReport::default()
+    };
+    for x in
+        list_all::<RawCharge>(stripe_client, "charges.list", "/charges", period, |x| &x.id).await?
+    {
+        match x.status.as_str() {
+            "succeeded" => {
+                report.succeeded_charges += 1;
+                add(&mut report.charge_volume, x.currency, x.amount);
+            }
+            "failed" => report.failed_charges += 1,
+            _ => {}
+        }
+    }
+    for x in
+        list_all::<RawRefund>(stripe_client, "refunds.list", "/refunds", period, |x| &x.id).await?
+    {
+        if matches!(x.status.as_deref(), Some("failed") | Some("canceled")) {
+            continue;
+        }
+        report.refunds += 1;
+        add(&mut report.refund_volume, x.currency, x.amount);
+    }
+    for x in list_all::<RawDispute>(stripe_client, "disputes.list", "/disputes", period, |x| {
+        &x.id
+    })
+    .await?
+    {
+        report.disputes += 1;
+        add(&mut report.dispute_volume, x.currency, x.amount);
+    }
+    if report.exceeds_dispute_threshold(DISPUTE_RATE_THRESHOLD) {
+        tracing::warn!(
+            "dispute rate {:.4} exceeds {}",
+            report.dispute_rate(),
+            DISPUTE_RATE_THRESHOLD
+        );
+    }
+    Ok(report)
+}
+
+/// Resources [`health_check`] probes with a one-item list request. A
+/// restricted key without read access to one is refused with a 403.
+pub const PERMISSION_PROBES: &[(&str, &str)] = &[
+    ("charges", "/charges"),
+    ("customers", "/customers"),
+    ("payment_intents", "/payment_intents"),
+    ("refunds", "/refunds"),
+    ("subscriptions", "/subscriptions"),
+    ("webhook_endpoints", "/webhook_endpoints"),
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct KeyHealthReport {
+    /// Read from the balance, so `None` when the key can't read it.
+    pub mode: Option<StripeMode>,
+    /// The account fields are `None` when the key can't read the account.
+    pub account_id: Option<String>,
+    pub country: Option<String>,
+    pub default_currency: Option<String>,
+    pub charges_enabled: bool,
+    pub payouts_enabled: bool,
+    /// Capability name to status, e.g. `card_payments` to `active`.
+    pub capabilities: HashMap<String, String>,
+    /// Whether the key may read each resource, keyed by resource name;
+    /// covers `account`, `balance` and [`PERMISSION_PROBES`].
+    pub permissions: BTreeMap<String, bool>,
+}
+
+impl KeyHealthReport {
+    /// Resources the key can't read, e.g. for a restricted key missing a
+    /// permission the service relies on.
+    pub fn missing_permissions(&self) -> Vec<&str> {
+        self.permissions
+            .iter()
+            .filter(|(_, allowed)| !**allowed)
+            .map(|(x, _)| x.as_str())
+            .collect()
+    }
+
+    /// Only secret keys can read everything; a restricted key may too, if it
+    /// was granted every probed permission.
+    pub fn is_restricted(&self) -> bool {
+        !self.missing_permissions().is_empty()
+    }
+
+    pub fn is_capability_active(&self, capability: &str) -> bool {
+        self.capabilities.get(capability).map(String::as_str) == Some("active")
+    }
+}
+
+#[derive(Deserialize)]
+struct RawBalance {
+    livemode: bool,
+}
+
+#[derive(Deserialize)]
+struct RawAccount {
+    id: String,
+    country: Option<String>,
+    default_currency: Option<String>,
+    #[serde(default)]
+    charges_enabled: bool,
+    #[serde(default)]
+    payouts_enabled: bool,
+    #[serde(default)]
+    capabilities: HashMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct ProbeParams {
+    limit: u64,
+}
+
+/// `Ok(None)` when the key lacks the permission; an invalid key or any other
+/// failure is an error.
+fn permitted<T>(result: Result<T, StripePaymentError>) -> Result<Option<T>, StripePaymentError> {
+    match result {
+        Ok(x) => Ok(Some(x)),
+        Err(StripePaymentError::MissingPermission { .. }) => Ok(None),
+        Err(StripePaymentError::Stripe(StripeError::Stripe(x))) if x.http_status == 403 => Ok(None),
+        Err(x) => Err(x),
+    }
+}
+
+/// Pre-flight check for startup diagnostics: fails when the key is invalid,
+/// otherwise reports the mode, the account and which resources the key can
+/// read.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn health_check(stripe_client: &Client) -> Result<KeyHealthReport, StripePaymentError> {
+    let mut report = KeyHealthReport::default();
+    let balance = permitted(
+        telemetry::observe(
+            "balance.retrieve",
+            stripe_client.get::<RawBalance>("/balance"),
+        )
+        .await,
+    )?;
+    report.mode = balance.as_ref().map(|x| match x.livemode {
+        true => StripeMode::Live,
+        false => StripeMode::Test,
+    });
+    report
+        .permissions
+        .insert("balance".to_string(), balance.is_some());
+    let account = permitted(
+        telemetry::observe(
+            "account.retrieve",
+            stripe_client.get::<RawAccount>("/account"),
+        )
+        .await,
+    )?;
+    report
+        .permissions
+        .insert("account".to_string(), account.is_some());
+    if let Some(x) = account {
+        report.account_id = Some(x.id);
+        report.country = x.country;
+        report.default_currency = x.default_currency;
+        report.charges_enabled = x.charges_enabled;
+        report.payouts_enabled = x.payouts_enabled;
+        report.capabilities = x.capabilities;
+    }
+    for (resource, path) in PERMISSION_PROBES {
+        let list = permitted(
+            telemetry::observe(
+                "health.probe",
+                stripe_client.get_query::<serde_json::Value, _>(path, ProbeParams { limit: 1 }),
+            )
+            .await,
+        )?;
+        report
+            .permissions
+            .insert(resource.to_string(), list.is_some());
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_rates() {
+        let report = PaymentHealthReport {
+            succeeded_charges: 200,
+            refunds: 10,
+            disputes: 2,
+            ..PaymentHealthReport::default()
+        };
+        assert_eq!(report.dispute_rate(), 0.01);
+        assert_eq!(report.refund_rate(), 0.05);
+        assert!(report.exceeds_dispute_threshold(DISPUTE_RATE_THRESHOLD));
+        assert_eq!(PaymentHealthReport::default().dispute_rate(), 0.0);
+    }
+
+    #[test]
+    fn reports_missing_permissions() {
+        let mut report = KeyHealthReport::default();
+        report.permissions.insert("charges".to_string(), true);
+        report.permissions.insert("refunds".to_string(), false);
+        report.permissions.insert("balance".to_string(), false);
+        report
+            .capabilities
+            .insert("card_payments".to_string(), "active".to_string());
+        assert_eq!(report.missing_permissions(), ["balance", "refunds"]);
+        assert!(report.is_restricted());
+        assert!(report.is_capability_active("card_payments"));
+        assert!(!report.is_capability_active("transfers"));
+    }
+}
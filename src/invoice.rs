@@ -0,0 +1,604 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use stripe::Client;
+
+use crate::cancel::{self, CancellableError, CancellationToken};
+use crate::field_mask::FieldMask;
+use crate::pagination::{Page, PageRequest, RawList};
+use crate::telemetry;
+use crate::url::StripeUrl;
+use crate::StripePaymentError;
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct DownloadOptions {
+    pub max_bytes: usize,
+    pub max_attempts: u32,
+    pub backoff: Duration,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            max_bytes: 10 * 1024 * 1024,
+            max_attempts: 3,
+            backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+impl DownloadOptions {
+    pub fn new() -> Self {
+        DownloadOptions::default()
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn with_retry(mut self, max_attempts: u32, backoff: Duration) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self.backoff = backoff;
+        self
+    }
+}
+
+#[derive(Deserialize)]
+struct RawInvoicePdf {
+    invoice_pdf: Option<String>,
+}
+
+enum Attempt {
+    Retry(String),
+    Fail(String),
+}
+
+async fn fetch(url: &str, max_bytes: usize) -> Result<Vec<u8>, Attempt> {
+    let mut response = reqwest::get(url)
+        .await
+        .map_err(|x| Attempt::Retry(x.to_string()))?;
+    let status = response.status();
+    if status.is_server_error() || status.as_u16() == 429 {
+        return Err(Attempt::Retry(format!("{} returned {}", url, status)));
+    }
+    if !status.is_success() {
+        return Err(Attempt::Fail(format!("{} returned {}", url, status)));
+    }
+    if response.content_length().unwrap_or_default() as usize > max_bytes {
+        return Err(Attempt::Fail(format!(
+            "document exceeds {} bytes",
+            max_bytes
+        )));
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|x| Attempt::Retry(x.to_string()))?
+    {
+        if bytes.len() + chunk.len() > max_bytes {
+            return Err(Attempt::Fail(format!(
+                "document exceeds {} bytes",
+                max_bytes
+            )));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+/// Downloads a document hosted by Stripe, retrying transport and 5xx failures
+/// with a linear backoff and aborting once `max_bytes` is exceeded.
+#[tracing::instrument]
+pub async fn download_document(
+    url: &str,
+    options: &DownloadOptions,
+) -> Result<Vec<u8>, StripePaymentError> {
+    download_document_cancellable(url, options, None)
+        .await
+        .map_err(CancellableError::into_stripe_error)
+}
+
+/// Like [`download_document`], but abandons the download or the backoff wait
+/// as soon as `cancel` fires.
+#[tracing::instrument(skip(cancel))]
+pub async fn download_document_cancellable(
+    url: &str,
+    options: &DownloadOptions,
+    cancel: Option<&CancellationToken>,
+) -> Result<Vec<u8>, CancellableError> {
+    let mut attempt = 1;
+    loop {
+        match cancel::or_cancelled(cancel, async { Ok(fetch(url, options.max_bytes).await) })
+            .await?
+        {
+            Ok(x) => return Ok(x),
+            Err(Attempt::Retry(e)) if attempt < options.max_attempts => {
+                tracing::warn!("download attempt {} failed: {}", attempt, e);
+                cancel::or_cancelled(cancel, async {
+                    tokio::time::sleep(options.backoff * attempt).await;
+                    Ok(())
+                })
+                .await?;
+                attempt += 1;
+            }
+            Err(Attempt::Retry(e)) | Err(Attempt::Fail(e)) => {
+                return Err(CancellableError::Stripe(StripePaymentError::from_general(
+                    e,
+                )))
+            }
+        }
+    }
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn download_invoice_pdf(
+    stripe_client: &Client,
+    invoice_id: &str,
+    options: &DownloadOptions,
+) -> Result<Vec<u8>, StripePaymentError> {
+    let url = telemetry::observe(
+        "invoices.retrieve",
+        stripe_client
+            .get::<RawInvoicePdf>(&StripeUrl::new("/invoices").segment(invoice_id).build()),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?
+    .invoice_pdf
+    .ok_or_else(|| {
+        StripePaymentError::from_general(format!("invoice {} has no pdf yet", invoice_id))
+    })?;
+    download_document(&url, options).await
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TaxAmountDto {
+    pub amount: i64,
+    pub inclusive: bool,
+    pub tax_rate_id: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct InvoiceLineItemDto {
+    pub id: String,
+    pub description: Option<String>,
+    pub quantity: Option<u64>,
+    pub unit_amount: Option<i64>,
+    pub amount: i64,
+    pub currency: String,
+    pub tax_amounts: Vec<TaxAmountDto>,
+    pub period_start: i64,
+    pub period_end: i64,
+    pub price_id: Option<String>,
+}
+
+impl InvoiceLineItemDto {
+    pub fn tax_total(&self) -> i64 {
+        self.tax_amounts.iter().map(|x| x.amount).sum()
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct InvoiceDto {
+    pub id: String,
+    pub stripe_customer_id: Option<String>,
+    pub status: Option<String>,
+    pub currency: String,
+    pub subtotal: i64,
+    pub tax: Option<i64>,
+    pub total: i64,
+    pub amount_due: i64,
+    pub lines: Vec<InvoiceLineItemDto>,
+}
+
+#[derive(Deserialize)]
+struct RawTaxAmount {
+    amount: i64,
+    inclusive: bool,
+    tax_rate: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct RawPeriod {
+    start: i64,
+    end: i64,
+}
+
+#[derive(Deserialize)]
+struct RawLinePrice {
+    id: String,
+    unit_amount: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct RawLineItem {
+    id: String,
+    description: Option<String>,
+    quantity: Option<u64>,
+    amount: i64,
+    currency: String,
+    #[serde(default)]
+    tax_amounts: Vec<RawTaxAmount>,
+    period: RawPeriod,
+    price: Option<RawLinePrice>,
+}
+
+impl From<RawLineItem> for InvoiceLineItemDto {
+    fn from(x: RawLineItem) -> Self {
+        InvoiceLineItemDto {
+            id: x.id,
+            description: x.description,
+            quantity: x.quantity,
+            unit_amount: x.price.as_ref().and_then(|x| x.unit_amount),
+            amount: x.amount,
+            currency: x.currency,
+            tax_amounts: x
+                .tax_amounts
+                .into_iter()
+                .map(|x| TaxAmountDto {
+                    amount: x.amount,
+                    inclusive: x.inclusive,
+                    // tax_rate is an id unless expanded
+                    tax_rate_id: x.tax_rate.and_then(|x| match x {
+                        serde_json::Value::String(x) => Some(x),
+                        x => x.get("id").and_then(|x| x.as_str()).map(|x| x.to_string()),
+                    }),
+                })
+                .collect(),
+            period_start: x.period.start,
+            period_end: x.period.end,
+            price_id: x.price.map(|x| x.id),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawInvoice {
+    id: String,
+    customer: Option<String>,
+    status: Option<String>,
+    currency: String,
+    subtotal: i64,
+    tax: Option<i64>,
+    total: i64,
+    amount_due: i64,
+    lines: RawList<RawLineItem>,
+}
+
+impl From<RawInvoice> for InvoiceDto {
+    fn from(x: RawInvoice) -> Self {
+        InvoiceDto {
+            id: x.id,
+            stripe_customer_id: x.customer,
+            status: x.status,
+            currency: x.currency,
+            subtotal: x.subtotal,
+            tax: x.tax,
+            total: x.total,
+            amount_due: x.amount_due,
+            lines: x
+                .lines
+                .data
+                .into_iter()
+                .map(InvoiceLineItemDto::from)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct LinesParams<'a> {
+    limit: u64,
+    starting_after: &'a str,
+}
+
+/// Retrieves an invoice with all of its line items, fetching further pages of
+/// lines when the invoice has more than Stripe embeds.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn get_invoice(
+    stripe_client: &Client,
+    invoice_id: &str,
+) -> Result<InvoiceDto, StripePaymentError> {
+    let invoice = telemetry::observe(
+        "invoices.retrieve",
+        stripe_client.get::<RawInvoice>(&StripeUrl::new("/invoices").segment(invoice_id).build()),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+    let mut has_more = invoice.lines.has_more;
+    let mut invoice = InvoiceDto::from(invoice);
+    while has_more {
+        let starting_after = match invoice.lines.last() {
+            Some(x) => x.id.clone(),
+            None => break,
+        };
+        let page = telemetry::observe(
+            "invoices.lines.list",
+            stripe_client.get_query::<RawList<RawLineItem>, _>(
+                &StripeUrl::new("/invoices")
+                    .segment(invoice_id)
+                    .segment("lines")
+                    .build(),
+                LinesParams {
+                    limit: 100,
+                    starting_after: &starting_after,
+                },
+            ),
+        )
+        .await
+        .map_err(StripePaymentError::from_general)?;
+        has_more = page.has_more && !page.data.is_empty();
+        invoice
+            .lines
+            .extend(page.data.into_iter().map(InvoiceLineItemDto::from));
+    }
+    Ok(invoice)
+}
+
+/// An invoice without its `lines`, which serde then skips instead of
+/// building every line item.
+#[derive(Deserialize)]
+struct RawInvoiceHeader {
+    id: String,
+    customer: Option<String>,
+    status: Option<String>,
+    currency: String,
+    subtotal: i64,
+    tax: Option<i64>,
+    total: i64,
+    amount_due: i64,
+}
+
+impl From<RawInvoiceHeader> for InvoiceDto {
+    fn from(x: RawInvoiceHeader) -> Self {
+        InvoiceDto {
+            id: x.id,
+            stripe_customer_id: x.customer,
+            status: x.status,
+            currency: x.currency,
+            subtotal: x.subtotal,
+            tax: x.tax,
+            total: x.total,
+            amount_due: x.amount_due,
+            lines: vec![],
+        }
+    }
+}
+
+fn masked(mut invoice: InvoiceDto, mask: &FieldMask) -> InvoiceDto {
+    invoice.stripe_customer_id = mask.keep("stripe_customer_id", invoice.stripe_customer_id);
+    invoice.status = mask.keep("status", invoice.status);
+    invoice.tax = mask.keep("tax", invoice.tax);
+    invoice
+}
+
+/// [`get_invoice`] populating only the fields in `mask`; without `lines` no
+/// line items are deserialized or paged in.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn get_invoice_with_mask(
+    stripe_client: &Client,
+    invoice_id: &str,
+    mask: &FieldMask,
+) -> Result<InvoiceDto, StripePaymentError> {
+    if mask.includes("lines") {
+        return get_invoice(stripe_client, invoice_id)
+            .await
+            .map(|x| masked(x, mask));
+    }
+    telemetry::observe(
+        "invoices.retrieve",
+        stripe_client
+            .get::<RawInvoiceHeader>(&StripeUrl::new("/invoices").segment(invoice_id).build()),
+    )
+    .await
+    .map(|x| masked(x.into(), mask))
+    .map_err(StripePaymentError::from_general)
+}
+
+#[derive(Serialize)]
+struct ListParams<'a> {
+    customer: &'a str,
+    limit: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    starting_after: Option<&'a str>,
+}
+
+/// Lists a customer's invoices, newest first. `lines` holds only the line
+/// items Stripe embeds in each listed invoice; use [`get_invoice`] for all of
+/// them.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn list_invoices(
+    stripe_client: &Client,
+    stripe_customer_id: &str,
+    page: &PageRequest,
+    mask: &FieldMask,
+) -> Result<Page<InvoiceDto>, StripePaymentError> {
+    let params = ListParams {
+        customer: stripe_customer_id,
+        limit: page.limit,
+        starting_after: page.starting_after.as_deref(),
+    };
+    let list = match mask.includes("lines") {
+        true => telemetry::observe(
+            "invoices.list",
+            stripe_client.get_query::<RawList<RawInvoice>, _>("/invoices", params),
+        )
+        .await
+        .map(|x| {
+            (
+                x.data.into_iter().map(InvoiceDto::from).collect::<Vec<_>>(),
+                x.has_more,
+            )
+        }),
+        false => telemetry::observe(
+            "invoices.list",
+            stripe_client.get_query::<RawList<RawInvoiceHeader>, _>("/invoices", params),
+        )
+        .await
+        .map(|x| {
+            (
+                x.data.into_iter().map(InvoiceDto::from).collect::<Vec<_>>(),
+                x.has_more,
+            )
+        }),
+    };
+    let (data, has_more) = list.map_err(StripePaymentError::from_general)?;
+    Ok(Page {
+        next_cursor: data.last().map(|x| x.id.clone()),
+        data: data.into_iter().map(|x| masked(x, mask)).collect(),
+        has_more,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CollectionMethod {
+    ChargeAutomatically,
+    SendInvoice,
+}
+
+/// `days_until_due` only applies to invoices that are sent to the customer.
+pub(crate) fn check_collection(
+    collection_method: CollectionMethod,
+    days_until_due: Option<u32>,
+) -> Result<(), StripePaymentError> {
+    match (collection_method, days_until_due) {
+        (CollectionMethod::ChargeAutomatically, Some(_)) => Err(StripePaymentError::from_general(
+            "days_until_due requires the send_invoice collection method".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CreateInvoiceDto {
+    pub stripe_customer_id: String,
+    pub collection_method: CollectionMethod,
+    pub days_until_due: Option<u32>,
+    pub description: Option<String>,
+    pub auto_advance: Option<bool>,
+}
+
+impl CreateInvoiceDto {
+    pub fn new(stripe_customer_id: impl Into<String>) -> Self {
+        CreateInvoiceDto {
+            stripe_customer_id: stripe_customer_id.into(),
+            collection_method: CollectionMethod::ChargeAutomatically,
+            days_until_due: None,
+            description: None,
+            auto_advance: None,
+        }
+    }
+
+    pub fn with_collection_method(mut self, collection_method: CollectionMethod) -> Self {
+        self.collection_method = collection_method;
+        self
+    }
+
+    /// Sends the invoice instead of charging the default payment method, due
+    /// `days_until_due` days after finalization.
+    pub fn with_send_invoice(mut self, days_until_due: u32) -> Self {
+        self.collection_method = CollectionMethod::SendInvoice;
+        self.days_until_due = Some(days_until_due);
+        self
+    }
+
+    pub fn with_description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    pub fn with_auto_advance(mut self, auto_advance: bool) -> Self {
+        self.auto_advance = Some(auto_advance);
+        self
+    }
+}
+
+#[derive(Serialize)]
+struct CreateInvoiceParams<'a> {
+    customer: &'a str,
+    collection_method: CollectionMethod,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    days_until_due: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auto_advance: Option<bool>,
+}
+
+/// Creates a draft invoice collecting the customer's pending invoice items.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn create_invoice(
+    stripe_client: &Client,
+    dto: &CreateInvoiceDto,
+) -> Result<InvoiceDto, StripePaymentError> {
+    check_collection(dto.collection_method, dto.days_until_due)?;
+    telemetry::observe(
+        "invoices.create",
+        stripe_client.post_form::<RawInvoice, _>(
+            "/invoices",
+            CreateInvoiceParams {
+                customer: &dto.stripe_customer_id,
+                collection_method: dto.collection_method,
+                days_until_due: dto.days_until_due,
+                description: dto.description.as_deref(),
+                auto_advance: dto.auto_advance,
+            },
+        ),
+    )
+    .await
+    .map(InvoiceDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_invoice_fields() {
+        let invoice = serde_json::from_str::<RawInvoiceHeader>(
+            r#"{"id":"in_1","customer":"cus_1","status":"open","currency":"eur","subtotal":1000,
+                "tax":190,"total":1190,"amount_due":1190,
+                "lines":{"data":[{"id":"il_1"}],"has_more":false}}"#,
+        )
+        .map(InvoiceDto::from)
+        .unwrap();
+        let invoice = masked(invoice, &FieldMask::only(["status"]));
+        assert_eq!(invoice.status.as_deref(), Some("open"));
+        assert_eq!(invoice.stripe_customer_id, None);
+        assert_eq!(invoice.tax, None);
+        assert_eq!(invoice.total, 1190);
+        assert!(invoice.lines.is_empty());
+    }
+
+    #[test]
+    fn parses_line_item_taxes() {
+        let line = serde_json::from_str::<RawLineItem>(
+            r#"{"id":"il_1","description":"Pro plan","quantity":2,"amount":2000,"currency":"eur",
+                "tax_amounts":[{"amount":380,"inclusive":false,"tax_rate":"txr_1"},
+                               {"amount":20,"inclusive":false,"tax_rate":{"id":"txr_2"}}],
+                "period":{"start":1,"end":2},"price":{"id":"price_1","unit_amount":1000}}"#,
+        )
+        .map(InvoiceLineItemDto::from)
+        .unwrap();
+        assert_eq!(line.unit_amount, Some(1000));
+        assert_eq!(line.tax_total(), 400);
+        assert_eq!(line.tax_amounts[1].tax_rate_id.as_deref(), Some("txr_2"));
+    }
+
+    #[test]
+    fn days_until_due_requires_send_invoice() {
+        assert!(check_collection(CollectionMethod::ChargeAutomatically, Some(30)).is_err());
+        assert!(check_collection(CollectionMethod::SendInvoice, Some(30)).is_ok());
+        let dto = CreateInvoiceDto::new("cus_1").with_send_invoice(14);
+        assert_eq!(dto.collection_method, CollectionMethod::SendInvoice);
+        assert_eq!(dto.days_until_due, Some(14));
+    }
+}
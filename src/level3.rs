@@ -0,0 +1,275 @@
+use std::fmt::{Display, Formatter};
+
+use serde::Serialize;
+
+use crate::StripePaymentError;
+
+pub const MERCHANT_REFERENCE_MAX_LENGTH: usize = 25;
+pub const CUSTOMER_REFERENCE_MAX_LENGTH: usize = 17;
+pub const PRODUCT_CODE_MAX_LENGTH: usize = 12;
+pub const PRODUCT_DESCRIPTION_MAX_LENGTH: usize = 26;
+pub const MAX_LINE_ITEMS: usize = 200;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Level3Error {
+    Empty(&'static str),
+    TooLong {
+        field: &'static str,
+        value: String,
+        max: usize,
+    },
+    NoLineItems,
+    TooManyLineItems(usize),
+    Negative {
+        field: &'static str,
+        amount: i64,
+    },
+    ZeroQuantity(String),
+    /// Line items plus shipping must add up to the payment amount.
+    TotalMismatch {
+        amount: i64,
+        total: i64,
+    },
+}
+
+impl Display for Level3Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Level3Error::Empty(x) => write!(f, "level 3 {} is empty", x),
+            Level3Error::TooLong { field, value, max } => {
+                write!(f, "level 3 {} {:?} is longer than {}", field, value, max)
+            }
+            Level3Error::NoLineItems => write!(f, "level 3 data needs at least one line item"),
+            Level3Error::TooManyLineItems(x) => {
+                write!(f, "{} level 3 line items exceed {}", x, MAX_LINE_ITEMS)
+            }
+            Level3Error::Negative { field, amount } => {
+                write!(f, "level 3 {} {} is negative", field, amount)
+            }
+            Level3Error::ZeroQuantity(x) => write!(f, "level 3 line item {} has no quantity", x),
+            Level3Error::TotalMismatch { amount, total } => write!(
+                f,
+                "level 3 total {} does not match the payment amount {}",
+                total, amount
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Level3Error {}
+
+fn check_length(field: &'static str, value: &str, max: usize) -> Result<(), Level3Error> {
+    if value.trim().is_empty() {
+        return Err(Level3Error::Empty(field));
+    }
+    match value.chars().count() > max {
+        true => Err(Level3Error::TooLong {
+            field,
+            value: value.to_string(),
+            max,
+        }),
+        false => Ok(()),
+    }
+}
+
+fn check_amount(field: &'static str, amount: i64) -> Result<(), Level3Error> {
+    match amount < 0 {
+        true => Err(Level3Error::Negative { field, amount }),
+        false => Ok(()),
+    }
+}
+
+/// One item of a B2B purchase. Amounts are in the smallest currency unit;
+/// `tax_amount` and `discount_amount` apply to the whole line.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct Level3LineItem {
+    pub product_code: String,
+    pub product_description: String,
+    pub unit_cost: i64,
+    pub quantity: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tax_amount: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discount_amount: Option<i64>,
+}
+
+impl Level3LineItem {
+    pub fn new(
+        product_code: impl Into<String>,
+        product_description: impl Into<String>,
+        unit_cost: i64,
+        quantity: u64,
+    ) -> Self {
+        Level3LineItem {
+            product_code: product_code.into(),
+            product_description: product_description.into(),
+            unit_cost,
+            quantity,
+            tax_amount: None,
+            discount_amount: None,
+        }
+    }
+
+    pub fn with_tax_amount(mut self, tax_amount: i64) -> Self {
+        self.tax_amount = Some(tax_amount);
+        self
+    }
+
+    pub fn with_discount_amount(mut self, discount_amount: i64) -> Self {
+        self.discount_amount = Some(discount_amount);
+        self
+    }
+
+    pub fn total(&self) -> i64 {
+        self.unit_cost * self.quantity as i64 + self.tax_amount.unwrap_or(0)
+            - self.discount_amount.unwrap_or(0)
+    }
+
+    fn validate(&self) -> Result<(), Level3Error> {
+        check_length("product_code", &self.product_code, PRODUCT_CODE_MAX_LENGTH)?;
+        check_length(
+            "product_description",
+            &self.product_description,
+            PRODUCT_DESCRIPTION_MAX_LENGTH,
+        )?;
+        check_amount("unit_cost", self.unit_cost)?;
+        check_amount("tax_amount", self.tax_amount.unwrap_or(0))?;
+        check_amount("discount_amount", self.discount_amount.unwrap_or(0))?;
+        match self.quantity {
+            0 => Err(Level3Error::ZeroQuantity(self.product_code.clone())),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Level 2/3 card data, which qualifies commercial card payments for lower
+/// interchange rates. Sent as the payment intent's `level3` hash.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct Level3Data {
+    pub merchant_reference: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub customer_reference: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shipping_amount: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shipping_address_zip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shipping_from_zip: Option<String>,
+    pub line_items: Vec<Level3LineItem>,
+}
+
+impl Level3Data {
+    /// `merchant_reference` is usually the order or invoice number.
+    pub fn new(merchant_reference: impl Into<String>) -> Self {
+        Level3Data {
+            merchant_reference: merchant_reference.into(),
+            customer_reference: None,
+            shipping_amount: None,
+            shipping_address_zip: None,
+            shipping_from_zip: None,
+            line_items: Vec::new(),
+        }
+    }
+
+    /// The buyer's purchase order number.
+    pub fn with_customer_reference(mut self, customer_reference: impl Into<String>) -> Self {
+        self.customer_reference = Some(customer_reference.into());
+        self
+    }
+
+    pub fn with_shipping(
+        mut self,
+        shipping_amount: i64,
+        shipping_from_zip: impl Into<String>,
+        shipping_address_zip: impl Into<String>,
+    ) -> Self {
+        self.shipping_amount = Some(shipping_amount);
+        self.shipping_from_zip = Some(shipping_from_zip.into());
+        self.shipping_address_zip = Some(shipping_address_zip.into());
+        self
+    }
+
+    pub fn with_line_item(mut self, line_item: Level3LineItem) -> Self {
+        self.line_items.push(line_item);
+        self
+    }
+
+    pub fn total(&self) -> i64 {
+        self.line_items
+            .iter()
+            .map(Level3LineItem::total)
+            .sum::<i64>()
+            + self.shipping_amount.unwrap_or(0)
+    }
+
+    /// Checks Stripe's field limits and that the data adds up to `amount`;
+    /// Stripe rejects level 3 data whose total differs from the charge.
+    pub fn validate(&self, amount: i64) -> Result<(), Level3Error> {
+        check_length(
+            "merchant_reference",
+            &self.merchant_reference,
+            MERCHANT_REFERENCE_MAX_LENGTH,
+        )?;
+        if let Some(x) = &self.customer_reference {
+            check_length("customer_reference", x, CUSTOMER_REFERENCE_MAX_LENGTH)?;
+        }
+        check_amount("shipping_amount", self.shipping_amount.unwrap_or(0))?;
+        match self.line_items.len() {
+            0 => return Err(Level3Error::NoLineItems),
+            x if x > MAX_LINE_ITEMS => return Err(Level3Error::TooManyLineItems(x)),
+            _ => {}
+        }
+        for x in &self.line_items {
+            x.validate()?;
+        }
+        match self.total() {
+            x if x != amount => Err(Level3Error::TotalMismatch { amount, total: x }),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub(crate) fn checked_level3(
+    level3: &Option<Level3Data>,
+    amount: i64,
+) -> Result<(), StripePaymentError> {
+    match level3 {
+        Some(x) => x
+            .validate(amount)
+            .map_err(|x| StripePaymentError::from_general(x.to_string())),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_total_and_lengths() {
+        let data = Level3Data::new("ORDER-42")
+            .with_shipping(500, "10115", "80331")
+            .with_line_item(Level3LineItem::new("SKU-1", "Paper A4", 250, 4).with_tax_amount(190))
+            .with_line_item(
+                Level3LineItem::new("SKU-2", "Toner", 2000, 1).with_discount_amount(90),
+            );
+        assert_eq!(data.validate(3600), Ok(()));
+        assert_eq!(
+            data.validate(3500),
+            Err(Level3Error::TotalMismatch {
+                amount: 3500,
+                total: 3600
+            })
+        );
+        assert!(matches!(
+            data.with_customer_reference("PO-0000000000000001")
+                .validate(3600),
+            Err(Level3Error::TooLong {
+                field: "customer_reference",
+                ..
+            })
+        ));
+    }
+}
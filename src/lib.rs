@@ -1,95 +1,587 @@
 use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
+#[cfg(feature = "runtime-tokio-hyper")]
 use std::str::FromStr;
-use stripe::{
-    CreateCustomer, CreateEphemeralKey, Customer, EphemeralKey, PaymentIntent, StripeError,
-};
+
+#[cfg(feature = "runtime-tokio-hyper")]
+use serde::Serialize;
+#[cfg(feature = "runtime-tokio-hyper")]
+use stripe::{CreateCustomer, CreateEphemeralKey, Customer, EphemeralKey, PaymentIntent};
+#[cfg(feature = "runtime-tokio-hyper")]
 use stripe::{CreatePaymentIntent, CustomerId};
 
 pub use stripe::CreatePaymentIntentShipping;
 pub use stripe::CreatePaymentIntentShippingAddress;
+pub use stripe::PaymentIntentSetupFutureUsage;
+pub use stripe::PaymentIntentStatus;
+pub use stripe::StripeError;
 
-use my_macros::make_error;
+pub use crate::error::StripePaymentError;
+#[cfg(feature = "runtime-tokio-hyper")]
 pub use stripe::Client;
 
-make_error!(StripePaymentError);
+use crate::address::BillingDetailsDto;
+use crate::descriptor::StatementDescriptor;
+use crate::level3::Level3Data;
+use crate::line_items::LineItem;
+use crate::localization::PaymentSheetLocalization;
+use crate::metadata::MetadataNamespace;
+#[cfg(feature = "runtime-tokio-hyper")]
+use crate::metadata::{ACCOUNT_ID, LEGACY_ACCOUNT_ID};
+#[cfg(feature = "runtime-tokio-hyper")]
+use crate::pagination::RawSearchResult;
+use crate::redact::{self, Redacted, SecretString};
+#[cfg(feature = "runtime-tokio-hyper")]
+use crate::url::StripeUrl;
 
-#[derive(Debug)]
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod account_debit;
+pub mod address;
+pub mod amount;
+pub mod api_version;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod cancel;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod capabilities;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod cash_balance;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod checkout;
+pub mod circuit_breaker;
+#[cfg(feature = "climate")]
+pub mod climate;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod command;
+pub mod config;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod credit_note;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod customer;
+pub mod decline;
+pub mod descriptor;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod dispute;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod dry_run;
+#[cfg(feature = "edge")]
+pub mod edge;
+pub mod error;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod export;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod external_account;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod facade;
+pub mod field_mask;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod financial_connections;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod fraud;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod health;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod invoice;
+pub mod level3;
+pub mod line_items;
+pub mod localization;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod mandate;
+pub mod metadata;
+pub mod pagination;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod payment_intent;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod payment_method;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod payout;
+pub mod permission;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod portal;
+pub mod prelude;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod provider;
+pub mod redact;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod refund;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod refund_batch;
+pub mod region;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod registry;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod shadow;
+pub mod spending;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod subscription;
+pub mod telemetry;
+#[cfg(feature = "test-support")]
+pub mod test_support;
+pub mod url;
+#[cfg(feature = "vcr")]
+pub mod vcr;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod voucher;
+pub mod webhook;
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct CreatePaymentIntentDto {
     pub amount: i64,
     pub stripe_customer_id: String,
     pub delivery_address: Option<CreatePaymentIntentShipping>,
     pub currency: String,
+    pub statement_descriptor: Option<StatementDescriptor>,
+    pub billing_details: Option<BillingDetailsDto>,
+    pub on_behalf_of: Option<String>,
+    pub transfer_destination: Option<String>,
+    pub payment_method: Option<String>,
+    pub level3: Option<Level3Data>,
+    pub payment_method_types: Vec<String>,
+    pub metadata: HashMap<String, String>,
+    pub setup_future_usage: Option<PaymentIntentSetupFutureUsage>,
 }
 
-#[derive(Debug)]
+impl CreatePaymentIntentDto {
+    pub fn new(
+        amount: i64,
+        stripe_customer_id: impl Into<String>,
+        currency: impl Into<String>,
+    ) -> Self {
+        CreatePaymentIntentDto {
+            amount,
+            stripe_customer_id: stripe_customer_id.into(),
+            delivery_address: None,
+            currency: currency.into(),
+            statement_descriptor: None,
+            billing_details: None,
+            on_behalf_of: None,
+            transfer_destination: None,
+            payment_method: None,
+            level3: None,
+            payment_method_types: vec!["card".to_string()],
+            metadata: HashMap::new(),
+            setup_future_usage: None,
+        }
+    }
+
+    pub fn with_delivery_address(mut self, delivery_address: CreatePaymentIntentShipping) -> Self {
+        self.delivery_address = Some(delivery_address);
+        self
+    }
+
+    pub fn with_statement_descriptor(mut self, statement_descriptor: StatementDescriptor) -> Self {
+        self.statement_descriptor = Some(statement_descriptor);
+        self
+    }
+
+    /// Stored on the customer record before the payment intent is created, so
+    /// tax is computed for the billing address rather than the delivery address.
+    pub fn with_billing_details(mut self, billing_details: BillingDetailsDto) -> Self {
+        self.billing_details = Some(billing_details);
+        self
+    }
+
+    /// Creates a destination charge: the funds are transferred to
+    /// `connected_account` after the payment succeeds.
+    pub fn with_transfer_destination(mut self, connected_account: impl Into<String>) -> Self {
+        self.transfer_destination = Some(connected_account.into());
+        self
+    }
+
+    /// Makes `connected_account` the settlement merchant, whose descriptor and
+    /// country apply to the charge. Requires a transfer to the same account,
+    /// so this also sets the transfer destination.
+    pub fn with_on_behalf_of(mut self, connected_account: impl Into<String>) -> Self {
+        let connected_account = connected_account.into();
+        self.transfer_destination = Some(connected_account.clone());
+        self.on_behalf_of = Some(connected_account);
+        self
+    }
+
+    pub fn with_payment_method_types(mut self, payment_method_types: Vec<String>) -> Self {
+        self.payment_method_types = payment_method_types;
+        self
+    }
+
+    /// Requests the payment methods customers in `country` expect for the
+    /// DTO's currency, see [`localization::recommended_payment_methods`].
+    pub fn with_recommended_payment_methods(mut self, country: &str) -> Self {
+        self.payment_method_types =
+            localization::recommended_payment_methods(country, &self.currency)
+                .into_iter()
+                .map(String::from)
+                .collect();
+        self
+    }
+
+    /// Line item data for commercial cards; must add up to `amount`.
+    pub fn with_level3(mut self, level3: Level3Data) -> Self {
+        self.level3 = Some(level3);
+        self
+    }
+
+    /// Stores a snapshot of the order's line items in the intent's metadata,
+    /// within Stripe's size limits, see [`line_items::insert_line_items`].
+    pub fn with_line_items(mut self, items: &[LineItem]) -> Self {
+        line_items::insert_line_items(&mut self.metadata, &MetadataNamespace::default(), items);
+        self
+    }
+
+    /// Saves the payment method to the customer once the payment succeeds, so
+    /// later charges such as subscription renewals can reuse it. Use
+    /// `OffSession` when those charges happen without the customer present.
+    pub fn with_setup_future_usage(
+        mut self,
+        setup_future_usage: PaymentIntentSetupFutureUsage,
+    ) -> Self {
+        self.setup_future_usage = Some(setup_future_usage);
+        self
+    }
+
+    /// Saved payment method to confirm with, used by
+    /// [`payment_intent::create_and_confirm_payment`]; payment sheets ignore it.
+    pub fn with_payment_method(mut self, payment_method: impl Into<String>) -> Self {
+        self.payment_method = Some(payment_method.into());
+        self
+    }
+
+    /// Checks that `on_behalf_of` is only set together with a transfer to the
+    /// same connected account, as Stripe requires for destination charges.
+    pub fn validate_settlement(&self) -> Result<(), StripePaymentError> {
+        if let Some(x) = &self.transfer_destination {
+            url::validate_id(x, "acct")?;
+        }
+        match (&self.on_behalf_of, &self.transfer_destination) {
+            (Some(x), Some(y)) if x != y => Err(StripePaymentError::from_general(format!(
+                "on_behalf_of {} must match the transfer destination {}",
+                x, y
+            ))),
+            (Some(x), None) => Err(StripePaymentError::from_general(format!(
+                "on_behalf_of {} requires a transfer destination",
+                x
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[non_exhaustive]
 pub struct PaymentIntentDto {
     pub id: String,
-    pub ephemeral_secret: String,
-    pub client_secret: String,
+    pub ephemeral_secret: SecretString,
+    pub client_secret: SecretString,
     pub stripe_customer_id: String,
+    /// Set by [`facade::LibStripe::create_payment_sheet`].
+    pub localization: Option<PaymentSheetLocalization>,
 }
 
-#[derive(Debug)]
+impl PaymentIntentDto {
+    pub fn new(
+        id: impl Into<String>,
+        ephemeral_secret: impl Into<SecretString>,
+        client_secret: impl Into<SecretString>,
+        stripe_customer_id: impl Into<String>,
+    ) -> Self {
+        PaymentIntentDto {
+            id: id.into(),
+            ephemeral_secret: ephemeral_secret.into(),
+            client_secret: client_secret.into(),
+            stripe_customer_id: stripe_customer_id.into(),
+            localization: None,
+        }
+    }
+
+    pub fn with_localization(mut self, localization: PaymentSheetLocalization) -> Self {
+        self.localization = Some(localization);
+        self
+    }
+}
+
+impl Redacted for PaymentIntentDto {
+    fn fmt_redacted(&self, f: &mut Formatter<'_>, reveal: bool) -> std::fmt::Result {
+        f.debug_struct("PaymentIntentDto")
+            .field("id", &self.id)
+            .field(
+                "ephemeral_secret",
+                redact::secret(&self.ephemeral_secret, reveal),
+            )
+            .field("client_secret", redact::secret(&self.client_secret, reveal))
+            .field("stripe_customer_id", &self.stripe_customer_id)
+            .field("localization", &self.localization)
+            .finish()
+    }
+}
+
+impl Debug for PaymentIntentDto {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.fmt_redacted(f, false)
+    }
+}
+
+/// A payment sheet whose ephemeral key is optional. Without one the customer's
+/// saved payment methods are unavailable, but the intent can still be confirmed.
+#[non_exhaustive]
+pub struct PaymentSheetResult {
+    pub id: String,
+    pub client_secret: SecretString,
+    pub stripe_customer_id: String,
+    pub ephemeral_secret: Option<SecretString>,
+    pub ephemeral_key_error: Option<StripePaymentError>,
+}
+
+impl PaymentSheetResult {
+    pub fn is_degraded(&self) -> bool {
+        self.ephemeral_secret.is_none()
+    }
+}
+
+impl Redacted for PaymentSheetResult {
+    fn fmt_redacted(&self, f: &mut Formatter<'_>, reveal: bool) -> std::fmt::Result {
+        f.debug_struct("PaymentSheetResult")
+            .field("id", &self.id)
+            .field("client_secret", redact::secret(&self.client_secret, reveal))
+            .field("stripe_customer_id", &self.stripe_customer_id)
+            .field(
+                "ephemeral_secret",
+                &self
+                    .ephemeral_secret
+                    .as_ref()
+                    .map(|x| redact::secret(x, reveal)),
+            )
+            .field("ephemeral_key_error", &self.ephemeral_key_error)
+            .finish()
+    }
+}
+
+impl Debug for PaymentSheetResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.fmt_redacted(f, false)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct GuestPaymentOptions {
+    pub delivery_address: Option<CreatePaymentIntentShipping>,
+    pub receipt_email: Option<String>,
+    pub description: Option<String>,
+    pub metadata: Option<HashMap<String, String>>,
+    pub statement_descriptor: Option<StatementDescriptor>,
+}
+
+impl GuestPaymentOptions {
+    pub fn new() -> Self {
+        GuestPaymentOptions::default()
+    }
+
+    pub fn with_delivery_address(mut self, delivery_address: CreatePaymentIntentShipping) -> Self {
+        self.delivery_address = Some(delivery_address);
+        self
+    }
+
+    pub fn with_receipt_email(mut self, receipt_email: impl Into<String>) -> Self {
+        self.receipt_email = Some(receipt_email.into());
+        self
+    }
+
+    pub fn with_description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: HashMap<String, String>) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    pub fn with_statement_descriptor(mut self, statement_descriptor: StatementDescriptor) -> Self {
+        self.statement_descriptor = Some(statement_descriptor);
+        self
+    }
+}
+
+#[non_exhaustive]
+pub struct GuestPaymentIntentDto {
+    pub id: String,
+    pub client_secret: SecretString,
+}
+
+impl GuestPaymentIntentDto {
+    pub fn new(id: impl Into<String>, client_secret: impl Into<SecretString>) -> Self {
+        GuestPaymentIntentDto {
+            id: id.into(),
+            client_secret: client_secret.into(),
+        }
+    }
+}
+
+impl Redacted for GuestPaymentIntentDto {
+    fn fmt_redacted(&self, f: &mut Formatter<'_>, reveal: bool) -> std::fmt::Result {
+        f.debug_struct("GuestPaymentIntentDto")
+            .field("id", &self.id)
+            .field("client_secret", redact::secret(&self.client_secret, reveal))
+            .finish()
+    }
+}
+
+impl Debug for GuestPaymentIntentDto {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.fmt_redacted(f, false)
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct CreateCustomerDto {
     pub id: String,
+    pub preferred_locales: Option<Vec<String>>,
+    /// Picks the [`region::RegionConfig`] defaults applied when the customer
+    /// is created through the facade; not sent to Stripe.
+    pub currency: Option<String>,
+    /// Narrows the region lookup like `currency`; not sent to Stripe.
+    pub country: Option<String>,
+}
+
+impl CreateCustomerDto {
+    pub fn new(id: impl Into<String>) -> Self {
+        CreateCustomerDto {
+            id: id.into(),
+            preferred_locales: None,
+            currency: None,
+            country: None,
+        }
+    }
+
+    pub fn with_preferred_locales(mut self, preferred_locales: Vec<String>) -> Self {
+        self.preferred_locales = Some(preferred_locales);
+        self
+    }
+
+    pub fn with_currency(mut self, currency: impl Into<String>) -> Self {
+        self.currency = Some(currency.into().to_lowercase());
+        self
+    }
+
+    pub fn with_country(mut self, country: impl Into<String>) -> Self {
+        self.country = Some(country.into().to_uppercase());
+        self
+    }
+}
+
+#[cfg(feature = "runtime-tokio-hyper")]
+#[derive(Serialize)]
+struct UpdateCustomerLocales<'a> {
+    preferred_locales: &'a [String],
 }
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub struct CustomerDto {
     pub id: String,
 }
 
+impl CustomerDto {
+    pub fn new(id: impl Into<String>) -> Self {
+        CustomerDto { id: id.into() }
+    }
+}
+
+#[cfg(feature = "runtime-tokio-hyper")]
+#[derive(Serialize)]
+struct SearchParams {
+    query: String,
+}
+
+/// Also finds customers created with the unprefixed [`LEGACY_ACCOUNT_ID`]
+/// key; one carrying the namespaced key wins when both match.
+#[cfg(feature = "runtime-tokio-hyper")]
+pub(crate) async fn find_customer(
+    stripe_client: &stripe::Client,
+    account_id: &str,
+) -> Result<Option<CustomerDto>, StripePaymentError> {
+    let namespace = MetadataNamespace::default();
+    telemetry::observe(
+        "customers.search",
+        stripe_client.get_query::<RawSearchResult<Customer>, _>(
+            "/customers/search",
+            SearchParams {
+                query: namespace.search_query_with_legacy(
+                    ACCOUNT_ID,
+                    LEGACY_ACCOUNT_ID,
+                    account_id,
+                ),
+            },
+        ),
+    )
+    .await
+    .map(|x| {
+        let namespaced = x
+            .data
+            .iter()
+            .position(|x| namespace.get(&x.metadata, ACCOUNT_ID) == Some(account_id));
+        x.data
+            .into_iter()
+            .nth(namespaced.unwrap_or_default())
+            .map(|x| CustomerDto {
+                id: x.id.to_string(),
+            })
+    })
+}
+
+#[cfg(feature = "runtime-tokio-hyper")]
 #[tracing::instrument(skip(stripe_client))]
 pub async fn get_customer(
     stripe_client: &stripe::Client,
     account_id: String,
 ) -> Result<CustomerDto, StripeError> {
-    let url = format!(
-        "/v1/customers/search?query=metadata%5B%account_id%27%5D%3A%27{}%27",
-        account_id
-    );
-    stripe_client
-        .get::<Customer>(url.as_str())
+    find_customer(stripe_client, &account_id)
         .await
-        .map(|x| CustomerDto {
-            id: x.id.to_string(),
-        })
+        .map_err(StripePaymentError::into_stripe_error)?
+        .ok_or_else(|| StripeError::ClientError(format!("no customer for account {}", account_id)))
+}
+
+#[cfg(feature = "runtime-tokio-hyper")]
+pub(crate) fn customer_params(dto: &CreateCustomerDto) -> CreateCustomer<'static> {
+    let mut meta = HashMap::<String, String>::new();
+    MetadataNamespace::default().insert(&mut meta, ACCOUNT_ID, dto.id.clone());
+    CreateCustomer {
+        address: None,
+        balance: None,
+        cash_balance: None,
+        coupon: None,
+        description: None,
+        email: None,
+        expand: &[],
+        invoice_prefix: None,
+        invoice_settings: None,
+        metadata: Some(meta),
+        name: None,
+        next_invoice_sequence: None,
+        payment_method: None,
+        phone: None,
+        preferred_locales: dto.preferred_locales.clone(),
+        promotion_code: None,
+        shipping: None,
+        source: None,
+        tax: None,
+        tax_exempt: None,
+        tax_id_data: None,
+        test_clock: None,
+    }
 }
 
+#[cfg(feature = "runtime-tokio-hyper")]
 #[tracing::instrument(skip(stripe_client))]
 pub async fn create_customer(
     stripe_client: &Client,
     dto: &CreateCustomerDto,
 ) -> Result<CustomerDto, StripePaymentError> {
-    let mut meta = HashMap::<String, String>::new();
-    meta.insert("id".to_string(), dto.id.clone());
-    Customer::create(
-        &stripe_client,
-        CreateCustomer {
-            address: None,
-            balance: None,
-            cash_balance: None,
-            coupon: None,
-            description: None,
-            email: None,
-            expand: &[],
-            invoice_prefix: None,
-            invoice_settings: None,
-            metadata: Some(meta),
-            name: None,
-            next_invoice_sequence: None,
-            payment_method: None,
-            phone: None,
-            preferred_locales: None,
-            promotion_code: None,
-            shipping: None,
-            source: None,
-            tax: None,
-            tax_exempt: None,
-            tax_id_data: None,
-            test_clock: None,
-        },
+    telemetry::observe(
+        "customers.create",
+        Customer::create(&stripe_client, customer_params(dto)),
     )
     .await
     .map(|x| CustomerDto {
@@ -98,71 +590,209 @@ pub async fn create_customer(
     .map_err(StripePaymentError::from_general)
 }
 
+#[cfg(feature = "runtime-tokio-hyper")]
+/// Sets the languages Stripe uses for receipts and invoice emails, most preferred first.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn update_customer_locales(
+    stripe_client: &Client,
+    stripe_customer_id: &str,
+    preferred_locales: &[String],
+) -> Result<CustomerDto, StripePaymentError> {
+    telemetry::observe(
+        "customers.update",
+        stripe_client.post_form::<Customer, _>(
+            &StripeUrl::new("/customers")
+                .segment(stripe_customer_id)
+                .build(),
+            UpdateCustomerLocales { preferred_locales },
+        ),
+    )
+    .await
+    .map(|x| CustomerDto {
+        id: x.id.to_string(),
+    })
+    .map_err(StripePaymentError::from_general)
+}
+
+#[cfg(feature = "runtime-tokio-hyper")]
+#[tracing::instrument(skip(stripe_client))]
+pub async fn set_customer_locale(
+    stripe_client: &Client,
+    stripe_customer_id: &str,
+    locale: &str,
+) -> Result<CustomerDto, StripePaymentError> {
+    update_customer_locales(stripe_client, stripe_customer_id, &[locale.to_string()]).await
+}
+
+#[cfg(feature = "runtime-tokio-hyper")]
 #[tracing::instrument(skip(stripe_client))]
 pub async fn create_payment_sheet(
     stripe_client: &Client,
     dto: &CreatePaymentIntentDto,
 ) -> Result<PaymentIntentDto, StripePaymentError> {
+    let sheet = create_sheet(stripe_client, dto, true).await?;
+    Ok(PaymentIntentDto {
+        id: sheet.id,
+        ephemeral_secret: sheet.ephemeral_secret.unwrap_or_default(),
+        client_secret: sheet.client_secret,
+        stripe_customer_id: sheet.stripe_customer_id,
+        localization: None,
+    })
+}
+
+#[cfg(feature = "runtime-tokio-hyper")]
+/// Like [`create_payment_sheet`], but still creates the payment intent when the
+/// ephemeral key can't be created. The result then has no ephemeral secret and
+/// carries the key error, so the client can confirm guest-style.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn create_payment_sheet_allowing_guest_fallback(
+    stripe_client: &Client,
+    dto: &CreatePaymentIntentDto,
+) -> Result<PaymentSheetResult, StripePaymentError> {
+    create_sheet(stripe_client, dto, false).await
+}
+
+#[cfg(feature = "runtime-tokio-hyper")]
+#[derive(Serialize)]
+struct Level3Params<'a> {
+    level3: &'a Level3Data,
+}
+
+#[cfg(feature = "runtime-tokio-hyper")]
+pub(crate) fn payment_intent_params(
+    dto: &CreatePaymentIntentDto,
+    stripe_customer_id: CustomerId,
+    shipping: Option<CreatePaymentIntentShipping>,
+) -> Result<CreatePaymentIntent<'_>, StripePaymentError> {
+    Ok(CreatePaymentIntent {
+        amount: dto.amount,
+        application_fee_amount: None,
+        automatic_payment_methods: None,
+        capture_method: None,
+        confirm: None,
+        confirmation_method: None,
+        currency: stripe::Currency::from_str(dto.currency.to_lowercase().as_str())
+            .map_err(|x| StripePaymentError::from_general(x.to_string()))?,
+        customer: Some(stripe_customer_id),
+        description: None,
+        error_on_requires_action: None,
+        expand: &[],
+        mandate: None,
+        mandate_data: None,
+        metadata: match dto.metadata.is_empty() {
+            true => None,
+            false => Some(dto.metadata.clone()),
+        },
+        off_session: None,
+        on_behalf_of: dto.on_behalf_of.as_deref(),
+        payment_method: None,
+        payment_method_data: None,
+        payment_method_options: None,
+        payment_method_types: Some(dto.payment_method_types.clone()),
+        receipt_email: None,
+        return_url: None,
+        setup_future_usage: dto.setup_future_usage,
+        shipping,
+        statement_descriptor: dto
+            .statement_descriptor
+            .as_ref()
+            .and_then(|x| x.statement_descriptor()),
+        statement_descriptor_suffix: dto
+            .statement_descriptor
+            .as_ref()
+            .and_then(|x| x.statement_descriptor_suffix()),
+        transfer_data: dto.transfer_destination.as_ref().map(|x| {
+            stripe::CreatePaymentIntentTransferData {
+                amount: None,
+                destination: x.clone(),
+            }
+        }),
+        transfer_group: None,
+        use_stripe_sdk: None,
+    })
+}
+
+#[cfg(feature = "runtime-tokio-hyper")]
+async fn create_sheet(
+    stripe_client: &Client,
+    dto: &CreatePaymentIntentDto,
+    require_ephemeral_key: bool,
+) -> Result<PaymentSheetResult, StripePaymentError> {
     tracing::debug!("creating payment request");
+    let shipping = address::checked_shipping(&dto.delivery_address)?;
+    let billing_details = address::checked_billing_details(&dto.billing_details)?;
+    dto.validate_settlement()?;
+    level3::checked_level3(&dto.level3, dto.amount)?;
     let stripe_customer_id = CustomerId::from_str(dto.stripe_customer_id.as_str())
         .map_err(|x| StripePaymentError::from_general(x.to_string()))?;
-    let ephemeral_key = EphemeralKey::create(
-        &stripe_client,
-        CreateEphemeralKey {
-            customer: Some(stripe_customer_id.clone()),
-            expand: &[],
-            issuing_card: None,
-        },
+    if let Some(billing_details) = &billing_details {
+        telemetry::observe(
+            "customers.update",
+            stripe_client.post_form::<Customer, _>(
+                &StripeUrl::new("/customers")
+                    .segment(&dto.stripe_customer_id)
+                    .build(),
+                billing_details,
+            ),
+        )
+        .await
+        .map_err(StripePaymentError::from_general)?;
+    }
+    let ephemeral_key = telemetry::observe(
+        "ephemeral_keys.create",
+        EphemeralKey::create(
+            &stripe_client,
+            CreateEphemeralKey {
+                customer: Some(stripe_customer_id.clone()),
+                expand: &[],
+                issuing_card: None,
+            },
+        ),
     )
     .await
-    .map_err(StripePaymentError::from_general)?;
-    let ephemeral_key_secret = ephemeral_key
-        .secret
-        .ok_or(StripePaymentError::from_general(
+    .map_err(StripePaymentError::from_general)
+    .and_then(|x| {
+        x.secret.ok_or(StripePaymentError::from_general(
             "no ephemeral_key_secret".to_string(),
-        ))?;
+        ))
+    });
+    let (ephemeral_secret, ephemeral_key_error) = match ephemeral_key {
+        Ok(x) => (Some(SecretString::from(x)), None),
+        Err(x) if !require_ephemeral_key => {
+            tracing::warn!("ephemeral key creation failed, continuing without: {:?}", x);
+            (None, Some(x))
+        }
+        Err(x) => return Err(x),
+    };
     tracing::debug!(
         "creating payment request stage 2 {:?}",
         dto.delivery_address.clone()
     );
 
-    let payment_intent = PaymentIntent::create(
-        &stripe_client,
-        CreatePaymentIntent {
-            amount: dto.amount,
-            application_fee_amount: None,
-            automatic_payment_methods: None,
-            capture_method: None,
-            confirm: None,
-            confirmation_method: None,
-            currency: stripe::Currency::from_str(dto.currency.to_lowercase().as_str())
-                .map_err(|x| StripePaymentError::from_general(x.to_string()))?,
-            customer: Some(stripe_customer_id),
-            description: None,
-            error_on_requires_action: None,
-            expand: &[],
-            mandate: None,
-            mandate_data: None,
-            metadata: None,
-            off_session: None,
-            on_behalf_of: None,
-            payment_method: None,
-            payment_method_data: None,
-            payment_method_options: None,
-            payment_method_types: Some(vec!["card".to_string()]),
-            receipt_email: None,
-            return_url: None,
-            setup_future_usage: None,
-            shipping: dto.delivery_address.clone(),
-            statement_descriptor: None,
-            statement_descriptor_suffix: None,
-            transfer_data: None,
-            transfer_group: None,
-            use_stripe_sdk: None,
-        },
+    let payment_intent = telemetry::observe(
+        "payment_intents.create",
+        PaymentIntent::create(
+            &stripe_client,
+            payment_intent_params(dto, stripe_customer_id, shipping)?,
+        ),
     )
     .await
     .map_err(StripePaymentError::from_general)?;
+    // async-stripe has no `level3` parameter, so it's attached in a second call
+    // before the intent is handed to the client.
+    if let Some(level3) = &dto.level3 {
+        telemetry::observe(
+            "payment_intents.update",
+            stripe_client.post_form::<PaymentIntent, _>(
+                &StripeUrl::new("/payment_intents")
+                    .segment(payment_intent.id.as_str())
+                    .build(),
+                Level3Params { level3 },
+            ),
+        )
+        .await
+        .map_err(StripePaymentError::from_general)?;
+    }
 
     let payment_client_secret =
         payment_intent
@@ -171,19 +801,102 @@ pub async fn create_payment_sheet(
                 "no payment_client_secret".to_string(),
             ))?;
 
-    Ok(PaymentIntentDto {
+    Ok(PaymentSheetResult {
         id: payment_intent.id.to_string(),
-        ephemeral_secret: ephemeral_key_secret,
-        client_secret: payment_client_secret,
+        client_secret: payment_client_secret.into(),
         stripe_customer_id: dto.stripe_customer_id.clone(),
+        ephemeral_secret,
+        ephemeral_key_error,
+    })
+}
+
+#[cfg(feature = "runtime-tokio-hyper")]
+pub(crate) fn guest_payment_intent_params<'a>(
+    amount: i64,
+    currency: &str,
+    options: &'a GuestPaymentOptions,
+    shipping: Option<CreatePaymentIntentShipping>,
+) -> Result<CreatePaymentIntent<'a>, StripePaymentError> {
+    Ok(CreatePaymentIntent {
+        amount,
+        application_fee_amount: None,
+        automatic_payment_methods: None,
+        capture_method: None,
+        confirm: None,
+        confirmation_method: None,
+        currency: stripe::Currency::from_str(currency.to_lowercase().as_str())
+            .map_err(|x| StripePaymentError::from_general(x.to_string()))?,
+        customer: None,
+        description: options.description.as_deref(),
+        error_on_requires_action: None,
+        expand: &[],
+        mandate: None,
+        mandate_data: None,
+        metadata: options.metadata.clone(),
+        off_session: None,
+        on_behalf_of: None,
+        payment_method: None,
+        payment_method_data: None,
+        payment_method_options: None,
+        payment_method_types: Some(vec!["card".to_string()]),
+        receipt_email: options.receipt_email.as_deref(),
+        return_url: None,
+        setup_future_usage: None,
+        shipping,
+        statement_descriptor: options
+            .statement_descriptor
+            .as_ref()
+            .and_then(|x| x.statement_descriptor()),
+        statement_descriptor_suffix: options
+            .statement_descriptor
+            .as_ref()
+            .and_then(|x| x.statement_descriptor_suffix()),
+        transfer_data: None,
+        transfer_group: None,
+        use_stripe_sdk: None,
+    })
+}
+
+#[cfg(feature = "runtime-tokio-hyper")]
+#[tracing::instrument(skip(stripe_client))]
+pub async fn create_guest_payment_sheet(
+    stripe_client: &Client,
+    amount: i64,
+    currency: &str,
+    options: &GuestPaymentOptions,
+) -> Result<GuestPaymentIntentDto, StripePaymentError> {
+    tracing::debug!("creating guest payment request");
+    let shipping = address::checked_shipping(&options.delivery_address)?;
+    let payment_intent = telemetry::observe(
+        "payment_intents.create",
+        PaymentIntent::create(
+            &stripe_client,
+            guest_payment_intent_params(amount, currency, options, shipping)?,
+        ),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+
+    let payment_client_secret =
+        payment_intent
+            .client_secret
+            .ok_or(StripePaymentError::from_general(
+                "no payment_client_secret".to_string(),
+            ))?;
+
+    Ok(GuestPaymentIntentDto {
+        id: payment_intent.id.to_string(),
+        client_secret: payment_client_secret.into(),
     })
 }
 
 #[cfg(test)]
 mod tests {
+    #[cfg(feature = "runtime-tokio-hyper")]
     use stripe::{CreatePaymentIntent, PaymentIntent};
 
     #[test]
+    #[cfg(feature = "runtime-tokio-hyper")]
     fn hello() {
         let stripe_client = stripe::Client::new("");
 
@@ -222,4 +935,40 @@ mod tests {
             },
         );
     }
+
+    #[test]
+    fn on_behalf_of_requires_matching_transfer() {
+        let dto = || super::CreatePaymentIntentDto::new(500, "cus_1", "EUR");
+        assert!(dto()
+            .with_on_behalf_of("acct_1")
+            .validate_settlement()
+            .is_ok());
+        assert!(dto()
+            .with_on_behalf_of("acct_1")
+            .with_transfer_destination("acct_2")
+            .validate_settlement()
+            .is_err());
+        let mut orphan = dto();
+        orphan.on_behalf_of = Some("acct_1".to_string());
+        assert!(orphan.validate_settlement().is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "runtime-tokio-hyper")]
+    fn passes_setup_future_usage() {
+        use std::str::FromStr;
+
+        let dto = super::CreatePaymentIntentDto::new(500, "cus_1", "EUR")
+            .with_setup_future_usage(super::PaymentIntentSetupFutureUsage::OffSession);
+        let params = super::payment_intent_params(
+            &dto,
+            stripe::CustomerId::from_str("cus_1").unwrap(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            params.setup_future_usage,
+            Some(super::PaymentIntentSetupFutureUsage::OffSession)
+        );
+    }
 }
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+use crate::metadata::MetadataNamespace;
+
+/// Stripe accepts at most 50 metadata keys per object.
+pub const MAX_METADATA_KEYS: usize = 50;
+/// Stripe rejects metadata values longer than 500 characters.
+pub const MAX_METADATA_VALUE_LEN: usize = 500;
+
+/// Chunks are stored under `line_items.0`, `line_items.1`, ...
+pub const LINE_ITEMS: &str = "line_items";
+/// Number of trailing items left out of the snapshot, when any were.
+pub const LINE_ITEMS_OMITTED: &str = "line_items_omitted";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LineItem {
+    /// SKU or product id; keep it short, it counts against the metadata limits.
+    pub sku: String,
+    pub quantity: u64,
+    pub unit_amount: i64,
+}
+
+impl LineItem {
+    pub fn new(sku: impl Into<String>, quantity: u64, unit_amount: i64) -> Self {
+        LineItem {
+            sku: sku.into(),
+            quantity,
+            unit_amount,
+        }
+    }
+}
+
+fn encode(item: &LineItem) -> String {
+    serde_json::to_string(&(&item.sku, item.quantity, item.unit_amount)).unwrap_or_default()
+}
+
+fn chunk_key(index: usize) -> String {
+    format!("{}.{}", LINE_ITEMS, index)
+}
+
+/// Writes a compact JSON snapshot of `items`, `[["sku",qty,unit_amount],...]`,
+/// into `metadata` under `namespace`, split into values of at most 500
+/// characters. Only as many chunks as fit next to the keys already in
+/// `metadata` are written; if the snapshot doesn't fit, trailing items are
+/// dropped whole and their count is recorded under [`LINE_ITEMS_OMITTED`], so
+/// the same items always produce the same metadata. An earlier snapshot is
+/// replaced.
+pub fn insert_line_items(
+    metadata: &mut HashMap<String, String>,
+    namespace: &MetadataNamespace,
+    items: &[LineItem],
+) {
+    let prefix = namespace.key(LINE_ITEMS);
+    metadata.retain(|k, _| !k.starts_with(&prefix));
+    let available = MAX_METADATA_KEYS.saturating_sub(metadata.len());
+    let encoded = items.iter().map(encode).collect::<Vec<_>>();
+    let length = |n: usize| {
+        encoded[..n]
+            .iter()
+            .map(|x| x.chars().count())
+            .sum::<usize>()
+            + n.saturating_sub(1)
+            + 2
+    };
+    let mut included = encoded.len();
+    let mut chunks = available;
+    if length(included) > chunks * MAX_METADATA_VALUE_LEN {
+        // One key goes to the omitted count.
+        chunks = available.saturating_sub(1);
+        while included > 0 && length(included) > chunks * MAX_METADATA_VALUE_LEN {
+            included -= 1;
+        }
+    }
+    if chunks == 0 {
+        return;
+    }
+    let snapshot = format!("[{}]", encoded[..included].join(","));
+    let snapshot = snapshot.chars().collect::<Vec<_>>();
+    for (i, x) in snapshot.chunks(MAX_METADATA_VALUE_LEN).enumerate() {
+        namespace.insert(metadata, &chunk_key(i), x.iter().collect::<String>());
+    }
+    if included < items.len() {
+        namespace.insert(
+            metadata,
+            LINE_ITEMS_OMITTED,
+            (items.len() - included).to_string(),
+        );
+    }
+}
+
+/// Reads back a snapshot written by [`insert_line_items`], e.g. from a
+/// `payment_intent.succeeded` event.
+pub fn line_items(
+    metadata: &HashMap<String, String>,
+    namespace: &MetadataNamespace,
+) -> Option<Vec<LineItem>> {
+    let mut snapshot = String::new();
+    let mut i = 0;
+    while let Some(x) = namespace.get(metadata, &chunk_key(i)) {
+        snapshot.push_str(x);
+        i += 1;
+    }
+    let items = serde_json::from_str::<Vec<(String, u64, i64)>>(&snapshot).ok()?;
+    Some(
+        items
+            .into_iter()
+            .map(|(sku, quantity, unit_amount)| LineItem::new(sku, quantity, unit_amount))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_and_truncates_within_limits() {
+        let namespace = MetadataNamespace::default();
+        let items = (0..200)
+            .map(|x| LineItem::new(format!("sku-{:04}", x), 1, 100))
+            .collect::<Vec<_>>();
+        let mut metadata = HashMap::new();
+        insert_line_items(&mut metadata, &namespace, &items);
+        assert!(metadata.len() <= MAX_METADATA_KEYS);
+        assert!(metadata
+            .values()
+            .all(|x| x.chars().count() <= MAX_METADATA_VALUE_LEN));
+        assert_eq!(line_items(&metadata, &namespace).unwrap(), items);
+
+        let mut metadata = (0..48)
+            .map(|x| (x.to_string(), String::new()))
+            .collect::<HashMap<_, _>>();
+        insert_line_items(&mut metadata, &namespace, &items);
+        assert_eq!(metadata.len(), MAX_METADATA_KEYS);
+        let kept = line_items(&metadata, &namespace).unwrap();
+        assert_eq!(kept, items[..kept.len()]);
+        assert_eq!(
+            namespace.get(&metadata, LINE_ITEMS_OMITTED),
+            Some((items.len() - kept.len()).to_string().as_str())
+        );
+    }
+}
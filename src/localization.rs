@@ -0,0 +1,167 @@
+/// Currencies Stripe charges in whole units.
+const ZERO_DECIMAL: &[&str] = &[
+    "bif", "clp", "djf", "gnf", "jpy", "kmf", "krw", "mga", "pyg", "rwf", "ugx", "vnd", "vuv",
+    "xaf", "xof", "xpf",
+];
+
+const THREE_DECIMAL: &[&str] = &["bhd", "jod", "kwd", "omr", "tnd"];
+
+const SYMBOLS: &[(&str, &str)] = &[
+    ("aud", "A$"),
+    ("brl", "R$"),
+    ("cad", "CA$"),
+    ("chf", "CHF"),
+    ("cny", "CN¥"),
+    ("eur", "€"),
+    ("gbp", "£"),
+    ("inr", "₹"),
+    ("jpy", "¥"),
+    ("krw", "₩"),
+    ("mxn", "MX$"),
+    ("usd", "$"),
+];
+
+/// How to display amounts of a currency, so clients don't have to ship their
+/// own table of minor units.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CurrencyFormat {
+    pub currency: String,
+    /// Digits after the decimal separator; amounts are sent to Stripe in
+    /// `10^decimal_digits` minor units.
+    pub decimal_digits: u32,
+    pub symbol: Option<String>,
+}
+
+impl CurrencyFormat {
+    pub fn for_currency(currency: &str) -> Self {
+        let currency = currency.to_lowercase();
+        let decimal_digits = match currency.as_str() {
+            x if ZERO_DECIMAL.contains(&x) => 0,
+            x if THREE_DECIMAL.contains(&x) => 3,
+            _ => 2,
+        };
+        let symbol = SYMBOLS
+            .iter()
+            .find(|(code, _)| *code == currency)
+            .map(|(_, x)| x.to_string());
+        CurrencyFormat {
+            currency,
+            decimal_digits,
+            symbol,
+        }
+    }
+
+    /// `amount` in minor units as a plain decimal string, e.g. `12.50`.
+    pub fn format_amount(&self, amount: i64) -> String {
+        if self.decimal_digits == 0 {
+            return amount.to_string();
+        }
+        let scale = 10i64.pow(self.decimal_digits);
+        format!(
+            "{}{}.{:0width$}",
+            if amount < 0 { "-" } else { "" },
+            (amount / scale).abs(),
+            (amount % scale).abs(),
+            width = self.decimal_digits as usize
+        )
+    }
+}
+
+/// Everything a mobile PaymentSheet needs besides the secrets, returned with
+/// [`crate::PaymentIntentDto`] so clients don't need a separate config call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PaymentSheetLocalization {
+    /// From the region settings for the payment's country and currency.
+    pub locale: Option<String>,
+    pub merchant_display_name: Option<String>,
+    pub currency_format: CurrencyFormat,
+}
+
+impl PaymentSheetLocalization {
+    pub fn new(currency: &str) -> Self {
+        PaymentSheetLocalization {
+            locale: None,
+            merchant_display_name: None,
+            currency_format: CurrencyFormat::for_currency(currency),
+        }
+    }
+
+    pub fn with_locale(mut self, locale: impl Into<String>) -> Self {
+        self.locale = Some(locale.into());
+        self
+    }
+
+    pub fn with_merchant_display_name(mut self, merchant_display_name: impl Into<String>) -> Self {
+        self.merchant_display_name = Some(merchant_display_name.into());
+        self
+    }
+}
+
+/// Payment method types worth offering to a customer in `country` paying in
+/// `currency`, most relevant local method first. Card is always included
+/// last so it stays available as a fallback.
+pub fn recommended_payment_methods(country: &str, currency: &str) -> Vec<&'static str> {
+    let country = country.to_uppercase();
+    let currency = currency.to_lowercase();
+    let mut methods = match (country.as_str(), currency.as_str()) {
+        ("NL", "eur") => vec!["ideal"],
+        ("BE", "eur") => vec!["bancontact"],
+        ("AT", "eur") => vec!["eps"],
+        ("GB", "gbp") => vec!["bacs_debit"],
+        ("AU", "aud") => vec!["au_becs_debit"],
+        ("DE", "eur") => vec!["giropay"],
+        ("PL", "pln") => vec!["blik", "p24"],
+        ("MX", "mxn") => vec!["oxxo"],
+        ("BR", "brl") => vec!["boleto"],
+        ("JP", "jpy") => vec!["konbini"],
+        ("MY", "myr") => vec!["fpx"],
+        ("SG", "sgd") => vec!["paynow"],
+        ("TH", "thb") => vec!["promptpay"],
+        ("CN", "cny") => vec!["alipay", "wechat_pay"],
+        _ => vec![],
+    };
+    methods.push("card");
+    methods
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_minor_units_per_currency() {
+        assert_eq!(
+            CurrencyFormat::for_currency("EUR").format_amount(1250),
+            "12.50"
+        );
+        assert_eq!(
+            CurrencyFormat::for_currency("jpy").format_amount(1250),
+            "1250"
+        );
+        assert_eq!(
+            CurrencyFormat::for_currency("kwd").format_amount(-5),
+            "-0.005"
+        );
+        assert_eq!(
+            CurrencyFormat::for_currency("gbp").symbol.as_deref(),
+            Some("£")
+        );
+    }
+
+    #[test]
+    fn recommends_local_methods() {
+        assert_eq!(recommended_payment_methods("nl", "EUR"), ["ideal", "card"]);
+        assert_eq!(recommended_payment_methods("MX", "mxn"), ["oxxo", "card"]);
+        assert_eq!(recommended_payment_methods("NL", "usd"), ["card"]);
+        assert_eq!(
+            recommended_payment_methods("GB", "gbp"),
+            ["bacs_debit", "card"]
+        );
+        assert_eq!(
+            recommended_payment_methods("AU", "aud"),
+            ["au_becs_debit", "card"]
+        );
+    }
+}
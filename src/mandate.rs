@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use stripe::Client;
+
+use crate::pagination::RawList;
+use crate::payment_method::PaymentMethodDto;
+use crate::telemetry;
+use crate::url::StripeUrl;
+use crate::StripePaymentError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MandateStatus {
+    Active,
+    /// Revoked by the customer or their bank, or the payment method was
+    /// detached; debits on it will fail.
+    Inactive,
+    /// Not yet confirmed by the bank, e.g. a BACS mandate within its first
+    /// days.
+    Pending,
+    Other(String),
+}
+
+impl MandateStatus {
+    pub fn parse(status: &str) -> MandateStatus {
+        match status {
+            "active" => MandateStatus::Active,
+            "inactive" => MandateStatus::Inactive,
+            "pending" => MandateStatus::Pending,
+            other => MandateStatus::Other(other.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MandateDto {
+    pub id: String,
+    pub status: MandateStatus,
+    pub payment_method_id: String,
+    /// `sepa_debit`, `bacs_debit`, ...
+    pub payment_method_type: String,
+    /// Single-use mandates cover one payment only.
+    pub multi_use: bool,
+    /// Mandate reference the bank shows the customer, for SEPA and BACS.
+    pub reference: Option<String>,
+    pub accepted_at: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct RawCustomerAcceptance {
+    accepted_at: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct RawDebitDetails {
+    reference: Option<String>,
+    url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawPaymentMethodDetails {
+    #[serde(rename = "type")]
+    method_type: String,
+    sepa_debit: Option<RawDebitDetails>,
+    bacs_debit: Option<RawDebitDetails>,
+    au_becs_debit: Option<RawDebitDetails>,
+}
+
+impl RawPaymentMethodDetails {
+    fn debit(self) -> Option<RawDebitDetails> {
+        self.sepa_debit.or(self.bacs_debit).or(self.au_becs_debit)
+    }
+}
+
+#[derive(Deserialize)]
+struct RawMandate {
+    id: String,
+    status: String,
+    #[serde(rename = "type")]
+    mandate_type: String,
+    payment_method: String,
+    payment_method_details: RawPaymentMethodDetails,
+    customer_acceptance: Option<RawCustomerAcceptance>,
+}
+
+impl From<RawMandate> for MandateDto {
+    fn from(x: RawMandate) -> Self {
+        MandateDto {
+            id: x.id,
+            status: MandateStatus::parse(&x.status),
+            payment_method_id: x.payment_method,
+            payment_method_type: x.payment_method_details.method_type.clone(),
+            multi_use: x.mandate_type == "multi_use",
+            reference: x.payment_method_details.debit().and_then(|x| x.reference),
+            accepted_at: x.customer_acceptance.and_then(|x| x.accepted_at),
+        }
+    }
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn get_mandate(
+    stripe_client: &Client,
+    mandate_id: &str,
+) -> Result<MandateDto, StripePaymentError> {
+    telemetry::observe(
+        "mandates.retrieve",
+        stripe_client.get::<RawMandate>(&StripeUrl::new("/mandates").segment(mandate_id).build()),
+    )
+    .await
+    .map(MandateDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+/// The details BACS and BECS scheme rules require on the mandate confirmation
+/// shown or emailed to the customer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MandateDisplayDto {
+    pub mandate_id: String,
+    /// `bacs_debit`, `au_becs_debit` or `sepa_debit`.
+    pub payment_method_type: String,
+    pub last4: Option<String>,
+    /// BACS only.
+    pub sort_code: Option<String>,
+    /// BECS only.
+    pub bsb_number: Option<String>,
+    pub reference: Option<String>,
+    /// Stripe-hosted page with the full mandate text.
+    pub url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawDebitAccount {
+    last4: Option<String>,
+    sort_code: Option<String>,
+    bsb_number: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawDebitPaymentMethod {
+    sepa_debit: Option<RawDebitAccount>,
+    bacs_debit: Option<RawDebitAccount>,
+    au_becs_debit: Option<RawDebitAccount>,
+}
+
+#[derive(Deserialize)]
+struct RawExpandedMandate {
+    id: String,
+    payment_method: RawDebitPaymentMethod,
+    payment_method_details: RawPaymentMethodDetails,
+}
+
+impl From<RawExpandedMandate> for MandateDisplayDto {
+    fn from(x: RawExpandedMandate) -> Self {
+        let account = x
+            .payment_method
+            .bacs_debit
+            .or(x.payment_method.au_becs_debit)
+            .or(x.payment_method.sepa_debit);
+        let payment_method_type = x.payment_method_details.method_type.clone();
+        let details = x.payment_method_details.debit();
+        MandateDisplayDto {
+            mandate_id: x.id,
+            payment_method_type,
+            last4: account.as_ref().and_then(|x| x.last4.clone()),
+            sort_code: account.as_ref().and_then(|x| x.sort_code.clone()),
+            bsb_number: account.and_then(|x| x.bsb_number),
+            reference: details.as_ref().and_then(|x| x.reference.clone()),
+            url: details.and_then(|x| x.url),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ExpandParams<'a> {
+    expand: &'a [&'a str],
+}
+
+/// Fetches the mandate with its payment method expanded, for the mandate
+/// confirmation UK and Australian customers must receive.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn get_mandate_display(
+    stripe_client: &Client,
+    mandate_id: &str,
+) -> Result<MandateDisplayDto, StripePaymentError> {
+    telemetry::observe(
+        "mandates.retrieve",
+        stripe_client.get_query::<RawExpandedMandate, _>(
+            &StripeUrl::new("/mandates").segment(mandate_id).build(),
+            ExpandParams {
+                expand: &["payment_method"],
+            },
+        ),
+    )
+    .await
+    .map(MandateDisplayDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+#[derive(Deserialize)]
+struct RawSetupIntent {
+    id: String,
+    mandate: Option<RawMandate>,
+}
+
+#[derive(Serialize)]
+struct ListSetupIntentsParams<'a> {
+    customer: &'a str,
+    limit: u64,
+    expand: &'a [&'a str],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    starting_after: Option<&'a str>,
+}
+
+/// Lists the customer's mandates, found through the setup intents that
+/// created them. A payment method set up more than once yields one mandate
+/// per setup.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn list_mandates(
+    stripe_client: &Client,
+    stripe_customer_id: &str,
+) -> Result<Vec<MandateDto>, StripePaymentError> {
+    let mut mandates = Vec::new();
+    let mut starting_after = None::<String>;
+    loop {
+        let list = telemetry::observe(
+            "setup_intents.list",
+            stripe_client.get_query::<RawList<RawSetupIntent>, _>(
+                "/setup_intents",
+                ListSetupIntentsParams {
+                    customer: stripe_customer_id,
+                    limit: 100,
+                    expand: &["data.mandate"],
+                    starting_after: starting_after.as_deref(),
+                },
+            ),
+        )
+        .await
+        .map_err(StripePaymentError::from_general)?;
+        let done = !list.has_more || list.data.is_empty();
+        starting_after = list.data.last().map(|x| x.id.clone());
+        mandates.extend(
+            list.data
+                .into_iter()
+                .filter_map(|x| x.mandate)
+                .map(MandateDto::from),
+        );
+        if done {
+            return Ok(mandates);
+        }
+    }
+}
+
+/// Sets [`PaymentMethodDto::mandate_status`] from `mandates`. When a payment
+/// method has several mandates an active one wins, so a stale inactive
+/// mandate doesn't hide a newer one.
+pub fn apply_mandate_status(payment_methods: &mut [PaymentMethodDto], mandates: &[MandateDto]) {
+    let mut statuses = HashMap::<&str, &MandateStatus>::new();
+    for x in mandates {
+        let current = statuses.entry(&x.payment_method_id).or_insert(&x.status);
+        if x.status == MandateStatus::Active {
+            *current = &x.status;
+        }
+    }
+    for x in payment_methods {
+        if let Some(status) = statuses.get(x.id.as_str()) {
+            x.mandate_status = Some((*status).clone());
+        }
+    }
+}
+
+/// Stripe has no endpoint to revoke a mandate directly; detaching its payment
+/// method from the customer deactivates the mandate, and later debits on it
+/// are refused.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn revoke_mandate(
+    stripe_client: &Client,
+    mandate_id: &str,
+) -> Result<(), StripePaymentError> {
+    let mandate = get_mandate(stripe_client, mandate_id).await?;
+    telemetry::observe(
+        "payment_methods.detach",
+        stripe_client.post_form::<serde_json::Value, _>(
+            &StripeUrl::new("/payment_methods")
+                .segment(&mandate.payment_method_id)
+                .segment("detach")
+                .build(),
+            HashMap::<&str, &str>::new(),
+        ),
+    )
+    .await
+    .map(|_| ())
+    .map_err(StripePaymentError::from_general)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_sepa_mandate() {
+        let mandate = serde_json::from_str::<RawMandate>(
+            r#"{"id":"mandate_1","status":"inactive","type":"multi_use","payment_method":"pm_1",
+                "payment_method_details":{"type":"sepa_debit",
+                "sepa_debit":{"reference":"ABC123","url":"https://x.io"}},
+                "customer_acceptance":{"type":"online","accepted_at":1700000000,"online":{}}}"#,
+        )
+        .map(MandateDto::from)
+        .unwrap();
+        assert_eq!(mandate.status, MandateStatus::Inactive);
+        assert_eq!(mandate.reference.as_deref(), Some("ABC123"));
+        assert!(mandate.multi_use);
+        assert_eq!(mandate.accepted_at, Some(1700000000));
+
+        let active = MandateDto {
+            id: "mandate_2".to_string(),
+            status: MandateStatus::Active,
+            ..mandate.clone()
+        };
+        let mut methods = vec![PaymentMethodDto {
+            id: "pm_1".to_string(),
+            brand: String::new(),
+            network: String::new(),
+            last4: "3000".to_string(),
+            exp_month: 0,
+            exp_year: 0,
+            funding: crate::payment_method::CardFunding::Unknown,
+            wallet: None,
+            three_d_secure_supported: false,
+            stripe_customer_id: Some("cus_1".to_string()),
+            mandate_status: None,
+        }];
+        apply_mandate_status(&mut methods, &[mandate, active]);
+        assert_eq!(methods[0].mandate_status, Some(MandateStatus::Active));
+    }
+
+    #[test]
+    fn extracts_bacs_display_data() {
+        let display = serde_json::from_str::<RawExpandedMandate>(
+            r#"{"id":"mandate_1","payment_method":{"id":"pm_1","type":"bacs_debit",
+                "bacs_debit":{"fingerprint":"f","last4":"2345","sort_code":"108800"}},
+                "payment_method_details":{"type":"bacs_debit","bacs_debit":{
+                "network_status":"pending","reference":"MANDATE-1","url":"https://x.io/m"}}}"#,
+        )
+        .map(MandateDisplayDto::from)
+        .unwrap();
+        assert_eq!(display.sort_code.as_deref(), Some("108800"));
+        assert_eq!(display.last4.as_deref(), Some("2345"));
+        assert_eq!(display.reference.as_deref(), Some("MANDATE-1"));
+        assert_eq!(display.bsb_number, None);
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+
+pub const ACCOUNT_ID: &str = "account_id";
+/// Unprefixed key customers created before keys were namespaced carry the
+/// account id under. Lookups still match it, so they needn't be migrated.
+pub const LEGACY_ACCOUNT_ID: &str = "id";
+
+/// Prefixes metadata keys written by this crate so they don't collide with
+/// keys written by other systems on the same Stripe objects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataNamespace {
+    prefix: String,
+}
+
+impl Default for MetadataNamespace {
+    fn default() -> Self {
+        MetadataNamespace::new("libstripe")
+    }
+}
+
+impl MetadataNamespace {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        MetadataNamespace {
+            prefix: prefix.into(),
+        }
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn key(&self, key: &str) -> String {
+        format!("{}:{}", self.prefix, key)
+    }
+
+    pub fn insert(
+        &self,
+        metadata: &mut HashMap<String, String>,
+        key: &str,
+        value: impl Into<String>,
+    ) {
+        metadata.insert(self.key(key), value.into());
+    }
+
+    pub fn get<'a>(&self, metadata: &'a HashMap<String, String>, key: &str) -> Option<&'a str> {
+        metadata.get(&self.key(key)).map(|x| x.as_str())
+    }
+
+    pub fn extract(&self, metadata: &HashMap<String, String>) -> HashMap<String, String> {
+        let prefix = format!("{}:", self.prefix);
+        metadata
+            .iter()
+            .filter_map(|(k, v)| k.strip_prefix(&prefix).map(|x| (x.to_string(), v.clone())))
+            .collect()
+    }
+
+    pub fn search_query(&self, key: &str, value: &str) -> String {
+        format!("metadata['{}']:'{}'", self.key(key), escaped(value))
+    }
+
+    /// Like [`MetadataNamespace::search_query`], also matching `legacy_key`
+    /// written without the prefix.
+    pub fn search_query_with_legacy(&self, key: &str, legacy_key: &str, value: &str) -> String {
+        format!(
+            "{} OR metadata['{}']:'{}'",
+            self.search_query(key, value),
+            legacy_key,
+            escaped(value)
+        )
+    }
+}
+
+fn escaped(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn namespaces_keys() {
+        let namespace = MetadataNamespace::default();
+        let mut metadata = HashMap::new();
+        namespace.insert(&mut metadata, ACCOUNT_ID, "42");
+        metadata.insert("account_id".to_string(), "other".to_string());
+
+        assert_eq!(namespace.get(&metadata, ACCOUNT_ID), Some("42"));
+        assert_eq!(namespace.extract(&metadata).len(), 1);
+        assert_eq!(
+            namespace.search_query(ACCOUNT_ID, "o'neil"),
+            r"metadata['libstripe:account_id']:'o\'neil'"
+        );
+        assert_eq!(
+            namespace.search_query_with_legacy(ACCOUNT_ID, LEGACY_ACCOUNT_ID, "42"),
+            "metadata['libstripe:account_id']:'42' OR metadata['id']:'42'"
+        );
+    }
+}
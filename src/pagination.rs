@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_PAGE_SIZE: u64 = 25;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PageRequest {
+    pub limit: u64,
+    pub starting_after: Option<String>,
+}
+
+impl Default for PageRequest {
+    fn default() -> Self {
+        PageRequest {
+            limit: DEFAULT_PAGE_SIZE,
+            starting_after: None,
+        }
+    }
+}
+
+impl PageRequest {
+    pub fn new(limit: u64) -> Self {
+        PageRequest {
+            limit: limit.clamp(1, 100),
+            starting_after: None,
+        }
+    }
+
+    pub fn with_starting_after(mut self, starting_after: impl Into<String>) -> Self {
+        self.starting_after = Some(starting_after.into());
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Page<T> {
+    pub data: Vec<T>,
+    pub has_more: bool,
+    pub next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    pub fn next_page(&self, limit: u64) -> Option<PageRequest> {
+        match (&self.next_cursor, self.has_more) {
+            (Some(cursor), true) => Some(PageRequest::new(limit).with_starting_after(cursor)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct CreatedRange {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gte: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lte: Option<i64>,
+}
+
+impl CreatedRange {
+    pub fn new(gte: Option<i64>, lte: Option<i64>) -> Self {
+        CreatedRange { gte, lte }
+    }
+
+    pub fn since(gte: i64) -> Self {
+        CreatedRange {
+            gte: Some(gte),
+            lte: None,
+        }
+    }
+
+    pub fn contains(&self, created: i64) -> bool {
+        self.gte.map(|x| created >= x).unwrap_or(true)
+            && self.lte.map(|x| created <= x).unwrap_or(true)
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RawList<T> {
+    pub data: Vec<T>,
+    pub has_more: bool,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RawSearchResult<T> {
+    pub data: Vec<T>,
+    pub has_more: bool,
+    pub next_page: Option<String>,
+}
@@ -0,0 +1,1036 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use stripe::{Client, Customer, PaymentIntentStatus};
+
+use crate::address::{self, BillingDetailsDto};
+use crate::api_version::DEFAULT_API_VERSION;
+use crate::descriptor::StatementDescriptor;
+use crate::field_mask::FieldMask;
+use crate::level3::{self, Level3Data};
+use crate::pagination::{CreatedRange, Page, PageRequest, RawList};
+use crate::redact::{self, Redacted, SecretString};
+use crate::telemetry;
+use crate::url::StripeUrl;
+use crate::webhook::WebhookEvent;
+use crate::{
+    CreatePaymentIntentDto, CreatePaymentIntentShipping, PaymentIntentSetupFutureUsage,
+    StripePaymentError,
+};
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PaymentIntentSummaryDto {
+    pub id: String,
+    pub amount: i64,
+    pub amount_received: i64,
+    pub amount_capturable: i64,
+    pub currency: String,
+    pub status: PaymentIntentStatus,
+    pub created: i64,
+    pub description: Option<String>,
+    pub stripe_customer_id: Option<String>,
+    /// Set by [`get_payment_intent`] with `expand_latest_charge`, and on API
+    /// versions before 2022-11-15, which embed the intent's charges.
+    pub latest_charge: Option<ChargeDetailsDto>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ChargeOutcomeDto {
+    pub outcome_type: String,
+    pub network_status: Option<String>,
+    pub reason: Option<String>,
+    pub risk_level: Option<String>,
+    pub seller_message: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ChargeDetailsDto {
+    pub id: String,
+    pub paid: bool,
+    pub receipt_url: Option<String>,
+    pub outcome: Option<ChargeOutcomeDto>,
+    pub payment_method_type: Option<String>,
+    pub card_brand: Option<String>,
+    pub card_last4: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawOutcome {
+    #[serde(rename = "type")]
+    outcome_type: String,
+    network_status: Option<String>,
+    reason: Option<String>,
+    risk_level: Option<String>,
+    seller_message: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawCardDetails {
+    brand: Option<String>,
+    last4: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawPaymentMethodDetails {
+    #[serde(rename = "type")]
+    kind: String,
+    card: Option<RawCardDetails>,
+}
+
+#[derive(Deserialize)]
+struct RawCharge {
+    id: String,
+    #[serde(default)]
+    paid: bool,
+    receipt_url: Option<String>,
+    outcome: Option<RawOutcome>,
+    payment_method_details: Option<RawPaymentMethodDetails>,
+}
+
+impl From<RawCharge> for ChargeDetailsDto {
+    fn from(x: RawCharge) -> Self {
+        let card = x
+            .payment_method_details
+            .as_ref()
+            .and_then(|x| x.card.as_ref());
+        ChargeDetailsDto {
+            card_brand: card.and_then(|x| x.brand.clone()),
+            card_last4: card.and_then(|x| x.last4.clone()),
+            payment_method_type: x.payment_method_details.map(|x| x.kind),
+            id: x.id,
+            paid: x.paid,
+            receipt_url: x.receipt_url,
+            outcome: x.outcome.map(|x| ChargeOutcomeDto {
+                outcome_type: x.outcome_type,
+                network_status: x.network_status,
+                reason: x.reason,
+                risk_level: x.risk_level,
+                seller_message: x.seller_message,
+            }),
+        }
+    }
+}
+
+/// A charge without `outcome` and `payment_method_details`, which serde then
+/// skips.
+#[derive(Deserialize)]
+struct RawChargeCore {
+    id: String,
+    #[serde(default)]
+    paid: bool,
+    receipt_url: Option<String>,
+}
+
+impl From<RawChargeCore> for ChargeDetailsDto {
+    fn from(x: RawChargeCore) -> Self {
+        ChargeDetailsDto {
+            id: x.id,
+            paid: x.paid,
+            receipt_url: x.receipt_url,
+            outcome: None,
+            payment_method_type: None,
+            card_brand: None,
+            card_last4: None,
+        }
+    }
+}
+
+fn masked_charge(mut charge: ChargeDetailsDto, mask: &FieldMask) -> ChargeDetailsDto {
+    charge.receipt_url = mask.keep("receipt_url", charge.receipt_url);
+    charge.outcome = mask.keep("outcome", charge.outcome);
+    if !mask.includes("payment_method_details") {
+        charge.payment_method_type = None;
+        charge.card_brand = None;
+        charge.card_last4 = None;
+    }
+    charge
+}
+
+#[derive(Serialize)]
+struct ListChargesParams<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    customer: Option<&'a str>,
+    limit: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    starting_after: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created: Option<CreatedRange>,
+}
+
+/// Lists charges, newest first, of one customer or of the whole account.
+/// `payment_method_details` in `mask` covers the payment method type and
+/// card fields; leaving it and `outcome` out skips both objects entirely.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn list_charges(
+    stripe_client: &Client,
+    stripe_customer_id: Option<&str>,
+    created_range: Option<CreatedRange>,
+    page: &PageRequest,
+    mask: &FieldMask,
+) -> Result<Page<ChargeDetailsDto>, StripePaymentError> {
+    let params = ListChargesParams {
+        customer: stripe_customer_id,
+        limit: page.limit,
+        starting_after: page.starting_after.as_deref(),
+        created: created_range,
+    };
+    let list = match mask.includes("outcome") || mask.includes("payment_method_details") {
+        true => telemetry::observe(
+            "charges.list",
+            stripe_client.get_query::<RawList<RawCharge>, _>("/charges", params),
+        )
+        .await
+        .map(|x| {
+            (
+                x.data
+                    .into_iter()
+                    .map(ChargeDetailsDto::from)
+                    .collect::<Vec<_>>(),
+                x.has_more,
+            )
+        }),
+        false => telemetry::observe(
+            "charges.list",
+            stripe_client.get_query::<RawList<RawChargeCore>, _>("/charges", params),
+        )
+        .await
+        .map(|x| {
+            (
+                x.data
+                    .into_iter()
+                    .map(ChargeDetailsDto::from)
+                    .collect::<Vec<_>>(),
+                x.has_more,
+            )
+        }),
+    };
+    let (data, has_more) = list.map_err(StripePaymentError::from_general)?;
+    Ok(Page {
+        next_cursor: data.last().map(|x| x.id.clone()),
+        data: data.into_iter().map(|x| masked_charge(x, mask)).collect(),
+        has_more,
+    })
+}
+
+/// `latest_charge` is an id unless expanded.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawLatestCharge {
+    Expanded(Box<RawCharge>),
+    Id(String),
+}
+
+#[derive(Deserialize)]
+struct RawPaymentIntent {
+    id: String,
+    amount: i64,
+    #[serde(default)]
+    amount_received: i64,
+    #[serde(default)]
+    amount_capturable: i64,
+    currency: String,
+    status: PaymentIntentStatus,
+    created: i64,
+    description: Option<String>,
+    customer: Option<String>,
+    client_secret: Option<String>,
+    next_action: Option<RawNextAction>,
+    latest_charge: Option<RawLatestCharge>,
+    /// Before 2022-11-15 the intent embeds its charges, newest first, instead
+    /// of `latest_charge`.
+    charges: Option<RawList<RawCharge>>,
+}
+
+#[derive(Deserialize)]
+struct RawRedirectToUrl {
+    url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawQrCode {
+    data: String,
+    image_data_url: Option<String>,
+    image_url_png: Option<String>,
+    hosted_instructions_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawNextAction {
+    redirect_to_url: Option<RawRedirectToUrl>,
+    alipay_handle_redirect: Option<RawRedirectToUrl>,
+    wechat_pay_display_qr_code: Option<RawQrCode>,
+    paynow_display_qr_code: Option<RawQrCode>,
+    promptpay_display_qr_code: Option<RawQrCode>,
+}
+
+impl From<RawPaymentIntent> for PaymentIntentSummaryDto {
+    fn from(x: RawPaymentIntent) -> Self {
+        PaymentIntentSummaryDto {
+            id: x.id,
+            amount: x.amount,
+            amount_received: x.amount_received,
+            amount_capturable: x.amount_capturable,
+            currency: x.currency,
+            status: x.status,
+            created: x.created,
+            description: x.description,
+            stripe_customer_id: x.customer,
+            latest_charge: match x.latest_charge {
+                Some(RawLatestCharge::Expanded(x)) => Some((*x).into()),
+                _ => x
+                    .charges
+                    .and_then(|x| x.data.into_iter().next())
+                    .map(ChargeDetailsDto::from),
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ExpandParams<'a> {
+    expand: &'a [&'a str],
+}
+
+/// Versions from 2022-11-15 replace the embedded `charges` list with a
+/// `latest_charge` that has to be expanded.
+fn latest_charge_expand(api_version: &str) -> &'static [&'static str] {
+    match api_version >= "2022-11-15" {
+        true => &["latest_charge"],
+        false => &[],
+    }
+}
+
+/// Fetches a payment intent. With `expand_latest_charge` the receipt URL,
+/// outcome and payment method details of the latest charge are included,
+/// saving a second request after payment. Assumes the client is pinned to
+/// [`DEFAULT_API_VERSION`]; see [`get_payment_intent_at_version`] otherwise.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn get_payment_intent(
+    stripe_client: &Client,
+    payment_intent_id: &str,
+    expand_latest_charge: bool,
+) -> Result<PaymentIntentSummaryDto, StripePaymentError> {
+    get_payment_intent_at_version(
+        stripe_client,
+        payment_intent_id,
+        expand_latest_charge,
+        DEFAULT_API_VERSION,
+    )
+    .await
+}
+
+/// Like [`get_payment_intent`], for a client pinned to `api_version`, which
+/// decides where the latest charge is read from.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn get_payment_intent_at_version(
+    stripe_client: &Client,
+    payment_intent_id: &str,
+    expand_latest_charge: bool,
+    api_version: &str,
+) -> Result<PaymentIntentSummaryDto, StripePaymentError> {
+    let mut payment_intent = telemetry::observe(
+        "payment_intents.retrieve",
+        stripe_client.get_query::<RawPaymentIntent, _>(
+            &StripeUrl::new("/payment_intents")
+                .segment(payment_intent_id)
+                .build(),
+            ExpandParams {
+                expand: match expand_latest_charge {
+                    true => latest_charge_expand(api_version),
+                    false => &[],
+                },
+            },
+        ),
+    )
+    .await
+    .map(PaymentIntentSummaryDto::from)
+    .map_err(StripePaymentError::from_general)?;
+    if !expand_latest_charge {
+        payment_intent.latest_charge = None;
+    }
+    Ok(payment_intent)
+}
+
+#[derive(Serialize)]
+struct ListParams<'a> {
+    customer: &'a str,
+    limit: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    starting_after: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created: Option<CreatedRange>,
+}
+
+/// Lists a customer's payment intents, newest first. Stripe can't filter by
+/// status server side, so `status_filter` is applied to each fetched page and a
+/// page may contain fewer than `page.limit` items while `has_more` is still true.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn list_payment_intents(
+    stripe_client: &Client,
+    stripe_customer_id: &str,
+    status_filter: &[PaymentIntentStatus],
+    created_range: Option<CreatedRange>,
+    page: &PageRequest,
+) -> Result<Page<PaymentIntentSummaryDto>, StripePaymentError> {
+    let list = telemetry::observe(
+        "payment_intents.list",
+        stripe_client.get_query::<RawList<RawPaymentIntent>, _>(
+            "/payment_intents",
+            ListParams {
+                customer: stripe_customer_id,
+                limit: page.limit,
+                starting_after: page.starting_after.as_deref(),
+                created: created_range,
+            },
+        ),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+    let next_cursor = list.data.last().map(|x| x.id.clone());
+    Ok(Page {
+        data: list
+            .data
+            .into_iter()
+            .filter(|x| status_filter.is_empty() || status_filter.contains(&x.status))
+            .map(PaymentIntentSummaryDto::from)
+            .collect(),
+        has_more: list.has_more,
+        next_cursor,
+    })
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CapturePaymentDto {
+    pub payment_intent_id: String,
+    pub amount_to_capture: Option<i64>,
+    pub application_fee_amount: Option<i64>,
+    pub statement_descriptor: Option<StatementDescriptor>,
+    pub final_capture: Option<bool>,
+}
+
+impl CapturePaymentDto {
+    pub fn new(payment_intent_id: impl Into<String>) -> Self {
+        CapturePaymentDto {
+            payment_intent_id: payment_intent_id.into(),
+            amount_to_capture: None,
+            application_fee_amount: None,
+            statement_descriptor: None,
+            final_capture: None,
+        }
+    }
+
+    pub fn with_amount_to_capture(mut self, amount_to_capture: i64) -> Self {
+        self.amount_to_capture = Some(amount_to_capture);
+        self
+    }
+
+    /// Overrides the application fee set at creation; only valid for intents
+    /// created on behalf of a connected account.
+    pub fn with_application_fee_amount(mut self, application_fee_amount: i64) -> Self {
+        self.application_fee_amount = Some(application_fee_amount);
+        self
+    }
+
+    pub fn with_statement_descriptor(mut self, statement_descriptor: StatementDescriptor) -> Self {
+        self.statement_descriptor = Some(statement_descriptor);
+        self
+    }
+
+    /// Captures `amount_to_capture` while keeping the remainder capturable for
+    /// later tranches. Requires an intent created with multicapture enabled.
+    pub fn with_partial_capture(mut self, amount_to_capture: i64) -> Self {
+        self.amount_to_capture = Some(amount_to_capture);
+        self.final_capture = Some(false);
+        self
+    }
+
+    pub fn with_final_capture(mut self, final_capture: bool) -> Self {
+        self.final_capture = Some(final_capture);
+        self
+    }
+}
+
+#[derive(Serialize)]
+struct CaptureParams<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    amount_to_capture: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    application_fee_amount: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    statement_descriptor: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    statement_descriptor_suffix: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    final_capture: Option<bool>,
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn capture_payment(
+    stripe_client: &Client,
+    dto: &CapturePaymentDto,
+) -> Result<PaymentIntentSummaryDto, StripePaymentError> {
+    if dto.final_capture == Some(false) && dto.amount_to_capture.is_none() {
+        return Err(StripePaymentError::from_general(
+            "a non-final capture needs amount_to_capture".to_string(),
+        ));
+    }
+    if let (Some(fee), Some(amount)) = (dto.application_fee_amount, dto.amount_to_capture) {
+        if fee > amount {
+            return Err(StripePaymentError::from_general(format!(
+                "application_fee_amount {} exceeds amount_to_capture {}",
+                fee, amount
+            )));
+        }
+    }
+    telemetry::observe(
+        "payment_intents.capture",
+        stripe_client.post_form::<RawPaymentIntent, _>(
+            &StripeUrl::new("/payment_intents")
+                .segment(&dto.payment_intent_id)
+                .segment("capture")
+                .build(),
+            CaptureParams {
+                amount_to_capture: dto.amount_to_capture,
+                application_fee_amount: dto.application_fee_amount,
+                statement_descriptor: dto
+                    .statement_descriptor
+                    .as_ref()
+                    .and_then(|x| x.statement_descriptor()),
+                statement_descriptor_suffix: dto
+                    .statement_descriptor
+                    .as_ref()
+                    .and_then(|x| x.statement_descriptor_suffix()),
+                final_capture: dto.final_capture,
+            },
+        ),
+    )
+    .await
+    .map(PaymentIntentSummaryDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+/// Payment method types that send the customer to their bank to approve the
+/// payment, and so need a `return_url` when confirmed server side.
+pub const REDIRECT_PAYMENT_METHODS: [&str; 4] = ["ideal", "bancontact", "giropay", "alipay"];
+
+pub fn is_redirect_payment_method(payment_method_type: &str) -> bool {
+    REDIRECT_PAYMENT_METHODS.contains(&payment_method_type)
+}
+
+/// QR code the customer scans with their wallet or banking app to pay
+/// (WeChat Pay, PayNow, PromptPay). `data` is the payload to render; the image
+/// URLs are ready-made renderings of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PaymentQrCode {
+    pub data: String,
+    pub image_data_url: Option<String>,
+    pub image_url_png: Option<String>,
+    pub hosted_instructions_url: Option<String>,
+}
+
+impl From<RawQrCode> for PaymentQrCode {
+    fn from(x: RawQrCode) -> Self {
+        PaymentQrCode {
+            data: x.data,
+            image_data_url: x.image_data_url,
+            image_url_png: x.image_url_png,
+            hosted_instructions_url: x.hosted_instructions_url,
+        }
+    }
+}
+
+fn confirmable_without_payment_method(payment_method_type: &str) -> bool {
+    is_redirect_payment_method(payment_method_type) || is_qr_payment_method(payment_method_type)
+}
+
+/// Payment method types paid by scanning a QR code. They complete
+/// asynchronously, see [`QrPaymentEvent`].
+pub const QR_PAYMENT_METHODS: [&str; 3] = ["wechat_pay", "paynow", "promptpay"];
+
+pub fn is_qr_payment_method(payment_method_type: &str) -> bool {
+    QR_PAYMENT_METHODS.contains(&payment_method_type)
+}
+
+#[derive(Debug, Clone)]
+pub enum QrPaymentEvent {
+    /// The customer scanned the code and paid.
+    Paid { payment_intent_id: String },
+    /// The payment failed or the code expired before it was scanned.
+    Failed { payment_intent_id: String },
+}
+
+#[derive(Deserialize)]
+struct RawQrPaymentIntent {
+    id: String,
+    #[serde(default)]
+    payment_method_types: Vec<String>,
+}
+
+impl QrPaymentEvent {
+    /// Maps `payment_intent.succeeded` and `payment_intent.payment_failed`
+    /// events of QR payments, returning `None` for any other event.
+    pub fn from_event(event: &WebhookEvent) -> Option<QrPaymentEvent> {
+        let x = event.object_as::<RawQrPaymentIntent>().ok()?;
+        if !x
+            .payment_method_types
+            .iter()
+            .any(|x| is_qr_payment_method(x))
+        {
+            return None;
+        }
+        match event.event_type.as_str() {
+            "payment_intent.succeeded" => Some(QrPaymentEvent::Paid {
+                payment_intent_id: x.id,
+            }),
+            "payment_intent.payment_failed" => Some(QrPaymentEvent::Failed {
+                payment_intent_id: x.id,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Result of confirming a payment server side. `RequiresAction` means the
+/// customer has to authenticate (e.g. 3D Secure): hand `client_secret` to the
+/// client SDK to finish the payment, send the customer to `redirect_url` for
+/// redirect-based methods, or show `qr_code` for QR payment methods.
+///
+/// Only `Succeeded` means the money moved; fulfil orders on it alone.
+#[derive(Clone)]
+pub enum ConfirmedPayment {
+    Succeeded(PaymentIntentSummaryDto),
+    /// Accepted but not settled yet, e.g. a SEPA, BACS or BECS debit, which
+    /// can still fail; the outcome arrives as a webhook.
+    Processing(PaymentIntentSummaryDto),
+    /// Authorized with manual capture; nothing is charged until the intent
+    /// is captured.
+    RequiresCapture(PaymentIntentSummaryDto),
+    RequiresAction {
+        payment_intent: PaymentIntentSummaryDto,
+        client_secret: SecretString,
+        redirect_url: Option<String>,
+        qr_code: Option<PaymentQrCode>,
+    },
+}
+
+impl Redacted for ConfirmedPayment {
+    fn fmt_redacted(&self, f: &mut std::fmt::Formatter<'_>, reveal: bool) -> std::fmt::Result {
+        match self {
+            ConfirmedPayment::Succeeded(x) => f.debug_tuple("Succeeded").field(x).finish(),
+            ConfirmedPayment::Processing(x) => f.debug_tuple("Processing").field(x).finish(),
+            ConfirmedPayment::RequiresCapture(x) => {
+                f.debug_tuple("RequiresCapture").field(x).finish()
+            }
+            ConfirmedPayment::RequiresAction {
+                payment_intent,
+                client_secret,
+                redirect_url,
+                qr_code,
+            } => f
+                .debug_struct("RequiresAction")
+                .field("payment_intent", payment_intent)
+                .field("client_secret", redact::secret(client_secret, reveal))
+                .field("redirect_url", redirect_url)
+                .field("qr_code", qr_code)
+                .finish(),
+        }
+    }
+}
+
+impl std::fmt::Debug for ConfirmedPayment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_redacted(f, false)
+    }
+}
+
+#[derive(Serialize)]
+struct WeChatPayOptions {
+    client: &'static str,
+}
+
+#[derive(Serialize)]
+struct ConfirmPaymentMethodOptions {
+    wechat_pay: WeChatPayOptions,
+}
+
+#[derive(Serialize)]
+struct TransferData<'a> {
+    destination: &'a str,
+}
+
+#[derive(Serialize)]
+struct PaymentMethodData<'a> {
+    #[serde(rename = "type")]
+    kind: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    billing_details: Option<&'a BillingDetailsDto>,
+}
+
+#[derive(Serialize)]
+struct ConfirmParams<'a> {
+    amount: i64,
+    currency: String,
+    customer: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payment_method: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payment_method_data: Option<PaymentMethodData<'a>>,
+    payment_method_types: &'a [String],
+    confirm: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    off_session: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    return_url: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    setup_future_usage: Option<PaymentIntentSetupFutureUsage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<&'a HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shipping: Option<&'a CreatePaymentIntentShipping>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    statement_descriptor: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    statement_descriptor_suffix: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    on_behalf_of: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transfer_data: Option<TransferData<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    level3: Option<&'a Level3Data>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payment_method_options: Option<ConfirmPaymentMethodOptions>,
+}
+
+fn confirm_params<'a>(
+    dto: &'a CreatePaymentIntentDto,
+    payment_method_data: Option<PaymentMethodData<'a>>,
+    shipping: Option<&'a CreatePaymentIntentShipping>,
+    return_url: Option<&'a str>,
+    off_session: bool,
+) -> ConfirmParams<'a> {
+    ConfirmParams {
+        amount: dto.amount,
+        currency: dto.currency.to_lowercase(),
+        customer: &dto.stripe_customer_id,
+        payment_method: dto.payment_method.as_deref(),
+        payment_method_data,
+        payment_method_types: &dto.payment_method_types,
+        confirm: true,
+        off_session,
+        return_url,
+        setup_future_usage: dto.setup_future_usage,
+        metadata: match dto.metadata.is_empty() {
+            true => None,
+            false => Some(&dto.metadata),
+        },
+        shipping,
+        statement_descriptor: dto
+            .statement_descriptor
+            .as_ref()
+            .and_then(|x| x.statement_descriptor()),
+        statement_descriptor_suffix: dto
+            .statement_descriptor
+            .as_ref()
+            .and_then(|x| x.statement_descriptor_suffix()),
+        on_behalf_of: dto.on_behalf_of.as_deref(),
+        transfer_data: dto
+            .transfer_destination
+            .as_deref()
+            .map(|destination| TransferData { destination }),
+        level3: dto.level3.as_ref(),
+        payment_method_options: dto
+            .payment_method_types
+            .iter()
+            .any(|x| x == "wechat_pay")
+            .then(|| ConfirmPaymentMethodOptions {
+                wechat_pay: WeChatPayOptions { client: "web" },
+            }),
+    }
+}
+
+/// Creates a payment intent with `dto.payment_method` and confirms it in the
+/// same request. `return_url` is where redirect-based authentication sends the
+/// customer back to; `off_session` marks a charge made while the customer is
+/// not present, in which case Stripe fails instead of requiring action.
+///
+/// Without a saved payment method, a single redirect method type (see
+/// [`REDIRECT_PAYMENT_METHODS`]) or QR method type (see [`QR_PAYMENT_METHODS`])
+/// can be confirmed directly; the customer then approves the payment at the
+/// returned `redirect_url` or by scanning the returned `qr_code`.
+///
+/// Billing details are saved on the customer, as for payment sheets, and also
+/// attached to a payment method created from its type.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn create_and_confirm_payment(
+    stripe_client: &Client,
+    dto: &CreatePaymentIntentDto,
+    return_url: Option<&str>,
+    off_session: bool,
+) -> Result<ConfirmedPayment, StripePaymentError> {
+    let shipping = address::checked_shipping(&dto.delivery_address)?;
+    let billing_details = address::checked_billing_details(&dto.billing_details)?;
+    let payment_method_data = match (dto.payment_method.as_deref(), &dto.payment_method_types[..]) {
+        (Some(_), _) => None,
+        (None, [x]) if confirmable_without_payment_method(x) => Some(PaymentMethodData {
+            kind: x,
+            billing_details: billing_details.as_ref(),
+        }),
+        (None, _) => return Err(StripePaymentError::from_general(
+            "confirming needs a payment_method or a single redirect or wallet payment method type"
+                .to_string(),
+        )),
+    };
+    if return_url.is_none()
+        && !off_session
+        && dto
+            .payment_method_types
+            .iter()
+            .any(|x| is_redirect_payment_method(x))
+    {
+        return Err(StripePaymentError::from_general(format!(
+            "{:?} need a return_url",
+            dto.payment_method_types
+        )));
+    }
+    dto.validate_settlement()?;
+    level3::checked_level3(&dto.level3, dto.amount)?;
+    if let Some(billing_details) = &billing_details {
+        telemetry::observe(
+            "customers.update",
+            stripe_client.post_form::<Customer, _>(
+                &StripeUrl::new("/customers")
+                    .segment(&dto.stripe_customer_id)
+                    .build(),
+                billing_details,
+            ),
+        )
+        .await
+        .map_err(StripePaymentError::from_general)?;
+    }
+    let payment_intent = telemetry::observe(
+        "payment_intents.create",
+        stripe_client.post_form::<RawPaymentIntent, _>(
+            "/payment_intents",
+            confirm_params(
+                dto,
+                payment_method_data,
+                shipping.as_ref(),
+                return_url,
+                off_session,
+            ),
+        ),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+    confirmed(payment_intent)
+}
+
+fn confirmed(mut x: RawPaymentIntent) -> Result<ConfirmedPayment, StripePaymentError> {
+    match x.status {
+        PaymentIntentStatus::Succeeded => Ok(ConfirmedPayment::Succeeded(x.into())),
+        PaymentIntentStatus::Processing => Ok(ConfirmedPayment::Processing(x.into())),
+        PaymentIntentStatus::RequiresCapture => Ok(ConfirmedPayment::RequiresCapture(x.into())),
+        PaymentIntentStatus::RequiresAction => match x.client_secret.clone() {
+            Some(client_secret) => {
+                let next_action = x.next_action.take();
+                let (redirect_url, qr_code) = match next_action {
+                    Some(next_action) => (
+                        next_action
+                            .redirect_to_url
+                            .or(next_action.alipay_handle_redirect)
+                            .and_then(|x| x.url),
+                        next_action
+                            .wechat_pay_display_qr_code
+                            .or(next_action.paynow_display_qr_code)
+                            .or(next_action.promptpay_display_qr_code)
+                            .map(PaymentQrCode::from),
+                    ),
+                    None => (None, None),
+                };
+                Ok(ConfirmedPayment::RequiresAction {
+                    payment_intent: x.into(),
+                    client_secret: client_secret.into(),
+                    redirect_url,
+                    qr_code,
+                })
+            }
+            None => Err(StripePaymentError::from_general(
+                "no payment_client_secret".to_string(),
+            )),
+        },
+        status => Err(StripePaymentError::from_general(format!(
+            "payment intent {} was not confirmed: {:?}",
+            x.id, status
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_unsettled_payments_apart_from_succeeded() {
+        let raw = |status: &str| {
+            serde_json::from_str::<RawPaymentIntent>(&format!(
+                r#"{{"id":"pi_1","amount":500,"currency":"eur","status":"{}","created":1,
+                    "description":null,"customer":"cus_1"}}"#,
+                status
+            ))
+            .unwrap()
+        };
+        assert!(matches!(
+            confirmed(raw("succeeded")),
+            Ok(ConfirmedPayment::Succeeded(_))
+        ));
+        assert!(matches!(
+            confirmed(raw("processing")),
+            Ok(ConfirmedPayment::Processing(_))
+        ));
+        assert!(matches!(
+            confirmed(raw("requires_capture")),
+            Ok(ConfirmedPayment::RequiresCapture(_))
+        ));
+        assert!(confirmed(raw("requires_payment_method")).is_err());
+    }
+
+    #[test]
+    fn masks_charge_details() {
+        let charge = serde_json::from_str::<RawCharge>(
+            r#"{"id":"ch_1","paid":true,"receipt_url":"https://x.io/r",
+                "outcome":{"type":"authorized","risk_level":"normal"},
+                "payment_method_details":{"type":"card","card":{"brand":"visa","last4":"4242"}}}"#,
+        )
+        .map(ChargeDetailsDto::from)
+        .unwrap();
+        let charge = masked_charge(charge, &FieldMask::only(["outcome"]));
+        assert!(charge.outcome.is_some());
+        assert_eq!(charge.card_last4, None);
+        assert_eq!(charge.receipt_url, None);
+        assert!(charge.paid);
+    }
+
+    #[test]
+    fn reads_expanded_latest_charge() {
+        let dto = serde_json::from_str::<RawPaymentIntent>(
+            r#"{"id":"pi_1","amount":500,"currency":"eur","status":"succeeded","created":1,
+                "description":null,"customer":null,"latest_charge":{"id":"ch_1","paid":true,
+                "receipt_url":"https://pay.stripe.com/receipts/1",
+                "outcome":{"type":"authorized","network_status":"approved_by_network"},
+                "payment_method_details":{"type":"card","card":{"brand":"visa","last4":"4242"}}}}"#,
+        )
+        .map(PaymentIntentSummaryDto::from)
+        .unwrap();
+        let charge = dto.latest_charge.unwrap();
+        assert_eq!(charge.card_last4.as_deref(), Some("4242"));
+        assert_eq!(charge.outcome.unwrap().outcome_type, "authorized");
+
+        let unexpanded = serde_json::from_str::<RawPaymentIntent>(
+            r#"{"id":"pi_1","amount":500,"currency":"eur","status":"succeeded","created":1,
+                "description":null,"customer":null,"latest_charge":"ch_1"}"#,
+        )
+        .map(PaymentIntentSummaryDto::from)
+        .unwrap();
+        assert!(unexpanded.latest_charge.is_none());
+    }
+
+    #[test]
+    fn reads_embedded_charges_before_2022_11_15() {
+        assert!(latest_charge_expand(DEFAULT_API_VERSION).is_empty());
+        assert_eq!(latest_charge_expand("2022-11-15"), ["latest_charge"]);
+        let dto = serde_json::from_str::<RawPaymentIntent>(
+            r#"{"id":"pi_1","amount":500,"currency":"eur","status":"succeeded","created":1,
+                "description":null,"customer":null,"charges":{"object":"list","has_more":false,
+                "data":[{"id":"ch_2","paid":true,"receipt_url":"https://pay.stripe.com/receipts/2",
+                "payment_method_details":{"type":"card","card":{"brand":"visa","last4":"4242"}}},
+                {"id":"ch_1","paid":false}]}}"#,
+        )
+        .map(PaymentIntentSummaryDto::from)
+        .unwrap();
+        let charge = dto.latest_charge.unwrap();
+        assert_eq!(charge.id, "ch_2");
+        assert_eq!(charge.card_last4.as_deref(), Some("4242"));
+    }
+
+    #[test]
+    fn requires_action_carries_client_secret() {
+        let raw = serde_json::from_str::<RawPaymentIntent>(
+            r#"{"id":"pi_1","amount":500,"currency":"eur","status":"requires_action",
+                "created":1,"description":null,"customer":"cus_1","client_secret":"pi_1_secret",
+                "next_action":{"type":"redirect_to_url",
+                "redirect_to_url":{"url":"https://hooks.stripe.com/redirect/1","return_url":null}}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            confirmed(raw),
+            Ok(ConfirmedPayment::RequiresAction { client_secret, redirect_url: Some(url), .. })
+                if client_secret.expose() == "pi_1_secret" && url.starts_with("https://hooks.stripe.com")
+        ));
+
+        let wechat = serde_json::from_str::<RawPaymentIntent>(
+            r#"{"id":"pi_2","amount":500,"currency":"cny","status":"requires_action",
+                "created":1,"description":null,"customer":"cus_1","client_secret":"pi_2_secret",
+                "next_action":{"type":"wechat_pay_display_qr_code",
+                "wechat_pay_display_qr_code":{"data":"weixin://wxpay/bizpayurl?pr=1"}}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            confirmed(wechat),
+            Ok(ConfirmedPayment::RequiresAction { redirect_url: None, qr_code: Some(x), .. })
+                if x.data.starts_with("weixin://")
+        ));
+
+        let paid = serde_json::from_str::<WebhookEvent>(
+            r#"{"id":"evt_1","type":"payment_intent.succeeded","created":1,"livemode":false,
+                "data":{"object":{"id":"pi_3","payment_method_types":["paynow"]}}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            QrPaymentEvent::from_event(&paid),
+            Some(QrPaymentEvent::Paid { payment_intent_id }) if payment_intent_id == "pi_3"
+        ));
+    }
+
+    #[test]
+    fn confirms_with_metadata_and_billing_details() {
+        let mut dto = CreatePaymentIntentDto::new(500, "cus_1", "EUR")
+            .with_setup_future_usage(PaymentIntentSetupFutureUsage::OffSession);
+        dto.metadata
+            .insert("order_id".to_string(), "ord_1".to_string());
+        let billing_details =
+            BillingDetailsDto::new(address::AddressDto::new("Hauptstr. 1", "Berlin", "DE"));
+        let params = serde_json::to_value(confirm_params(
+            &dto,
+            Some(PaymentMethodData {
+                kind: "sepa_debit",
+                billing_details: Some(&billing_details),
+            }),
+            None,
+            None,
+            true,
+        ))
+        .unwrap();
+        assert_eq!(params["setup_future_usage"], "off_session");
+        assert_eq!(params["metadata"]["order_id"], "ord_1");
+        assert_eq!(
+            params["payment_method_data"]["billing_details"]["address"]["city"],
+            "Berlin"
+        );
+
+        let bare = serde_json::to_value(confirm_params(
+            &CreatePaymentIntentDto::new(500, "cus_1", "EUR"),
+            None,
+            None,
+            None,
+            false,
+        ))
+        .unwrap();
+        assert!(bare.get("metadata").is_none());
+        assert!(bare.get("setup_future_usage").is_none());
+    }
+}
@@ -0,0 +1,306 @@
+use serde::{Deserialize, Serialize};
+use stripe::Client;
+
+pub use crate::localization::recommended_payment_methods;
+use crate::mandate::MandateStatus;
+use crate::pagination::RawList;
+use crate::telemetry;
+use crate::url::StripeUrl;
+use crate::webhook::WebhookEvent;
+use crate::StripePaymentError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WalletType {
+    ApplePay,
+    GooglePay,
+    SamsungPay,
+    Link,
+    Other(String),
+}
+
+impl WalletType {
+    pub fn parse(wallet: &str) -> WalletType {
+        match wallet {
+            "apple_pay" => WalletType::ApplePay,
+            "google_pay" => WalletType::GooglePay,
+            "samsung_pay" => WalletType::SamsungPay,
+            "link" => WalletType::Link,
+            other => WalletType::Other(other.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardFunding {
+    Credit,
+    Debit,
+    Prepaid,
+    Unknown,
+}
+
+impl CardFunding {
+    pub fn parse(funding: &str) -> CardFunding {
+        match funding {
+            "credit" => CardFunding::Credit,
+            "debit" => CardFunding::Debit,
+            "prepaid" => CardFunding::Prepaid,
+            _ => CardFunding::Unknown,
+        }
+    }
+}
+
+/// A saved card as a checkout UI needs to render it. `network` is the network
+/// the card will be charged on, which differs from `brand` for co-branded cards
+/// when the customer picked a preferred network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PaymentMethodDto {
+    pub id: String,
+    pub brand: String,
+    pub network: String,
+    pub last4: String,
+    pub exp_month: u32,
+    pub exp_year: u32,
+    pub funding: CardFunding,
+    pub wallet: Option<WalletType>,
+    pub three_d_secure_supported: bool,
+    pub stripe_customer_id: Option<String>,
+    /// Status of the payment method's mandate, when one exists. Not part of
+    /// the payment method object; set by [`crate::mandate::apply_mandate_status`].
+    pub mandate_status: Option<MandateStatus>,
+}
+
+#[derive(Deserialize)]
+struct RawWallet {
+    #[serde(rename = "type")]
+    wallet_type: String,
+}
+
+#[derive(Deserialize)]
+struct RawNetworks {
+    preferred: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawThreeDSecureUsage {
+    supported: bool,
+}
+
+#[derive(Deserialize)]
+struct RawCard {
+    brand: String,
+    last4: String,
+    exp_month: u32,
+    exp_year: u32,
+    funding: Option<String>,
+    networks: Option<RawNetworks>,
+    three_d_secure_usage: Option<RawThreeDSecureUsage>,
+    wallet: Option<RawWallet>,
+}
+
+#[derive(Deserialize)]
+struct RawPaymentMethod {
+    id: String,
+    card: RawCard,
+    customer: Option<String>,
+}
+
+impl From<RawPaymentMethod> for PaymentMethodDto {
+    fn from(x: RawPaymentMethod) -> Self {
+        PaymentMethodDto {
+            id: x.id,
+            network: x
+                .card
+                .networks
+                .and_then(|x| x.preferred)
+                .unwrap_or_else(|| x.card.brand.clone()),
+            brand: x.card.brand,
+            last4: x.card.last4,
+            exp_month: x.card.exp_month,
+            exp_year: x.card.exp_year,
+            funding: CardFunding::parse(x.card.funding.as_deref().unwrap_or_default()),
+            wallet: x.card.wallet.map(|x| WalletType::parse(&x.wallet_type)),
+            three_d_secure_supported: x
+                .card
+                .three_d_secure_usage
+                .map(|x| x.supported)
+                .unwrap_or(false),
+            stripe_customer_id: x.customer,
+            mandate_status: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PaymentMethodEvent {
+    /// The card network replaced the card details, e.g. after a reissue with a
+    /// new expiry date or number. The `previous_*` fields hold the values that
+    /// changed, so stored copies can be matched and updated.
+    AutomaticallyUpdated {
+        payment_method: PaymentMethodDto,
+        previous_last4: Option<String>,
+        previous_exp_month: Option<u32>,
+        previous_exp_year: Option<u32>,
+    },
+}
+
+#[derive(Deserialize)]
+struct RawPreviousCard {
+    last4: Option<String>,
+    exp_month: Option<u32>,
+    exp_year: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct RawPreviousAttributes {
+    card: Option<RawPreviousCard>,
+}
+
+impl PaymentMethodEvent {
+    /// Maps `payment_method.automatically_updated` events, returning `None` for
+    /// any other event type.
+    pub fn from_event(event: &WebhookEvent) -> Option<PaymentMethodEvent> {
+        match event.event_type.as_str() {
+            "payment_method.automatically_updated" => {
+                let payment_method = event.object_as::<RawPaymentMethod>().ok()?.into();
+                let previous = event
+                    .data
+                    .previous_attributes
+                    .clone()
+                    .and_then(|x| serde_json::from_value::<RawPreviousAttributes>(x).ok())
+                    .and_then(|x| x.card);
+                Some(PaymentMethodEvent::AutomaticallyUpdated {
+                    payment_method,
+                    previous_last4: previous.as_ref().and_then(|x| x.last4.clone()),
+                    previous_exp_month: previous.as_ref().and_then(|x| x.exp_month),
+                    previous_exp_year: previous.and_then(|x| x.exp_year),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ListParams<'a> {
+    customer: &'a str,
+    #[serde(rename = "type")]
+    method_type: &'static str,
+    limit: u64,
+}
+
+/// Direct debit schemes only settle in their own currency.
+pub fn validate_payment_method_currency(
+    payment_method_type: &str,
+    currency: &str,
+) -> Result<(), StripePaymentError> {
+    let required = match payment_method_type {
+        "sepa_debit" => "eur",
+        "bacs_debit" => "gbp",
+        "au_becs_debit" => "aud",
+        _ => return Ok(()),
+    };
+    match currency.eq_ignore_ascii_case(required) {
+        true => Ok(()),
+        false => Err(StripePaymentError::from_general(format!(
+            "{} requires {}, not {}",
+            payment_method_type,
+            required,
+            currency.to_lowercase()
+        ))),
+    }
+}
+
+/// Lists the customer's saved cards, newest first.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn list_payment_methods(
+    stripe_client: &Client,
+    stripe_customer_id: &str,
+) -> Result<Vec<PaymentMethodDto>, StripePaymentError> {
+    telemetry::observe(
+        "payment_methods.list",
+        stripe_client.get_query::<RawList<RawPaymentMethod>, _>(
+            "/payment_methods",
+            ListParams {
+                customer: stripe_customer_id,
+                method_type: "card",
+                limit: 100,
+            },
+        ),
+    )
+    .await
+    .map(|x| x.data.into_iter().map(PaymentMethodDto::from).collect())
+    .map_err(StripePaymentError::from_general)
+}
+
+/// Fetches the current card details. Use it to resync a stored card when an
+/// update webhook was missed or only carried the payment method id.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn refresh_payment_method(
+    stripe_client: &Client,
+    payment_method_id: &str,
+) -> Result<PaymentMethodDto, StripePaymentError> {
+    telemetry::observe(
+        "payment_methods.retrieve",
+        stripe_client.get::<RawPaymentMethod>(
+            &StripeUrl::new("/payment_methods")
+                .segment(payment_method_id)
+                .build(),
+        ),
+    )
+    .await
+    .map(PaymentMethodDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_wallet_and_network() {
+        let card = serde_json::from_str::<RawPaymentMethod>(
+            r#"{"id":"pm_1","card":{"brand":"cartes_bancaires","last4":"4242","exp_month":12,
+                "exp_year":2030,"funding":"debit","networks":{"available":["visa","cartes_bancaires"],
+                "preferred":"visa"},"three_d_secure_usage":{"supported":true},
+                "wallet":{"type":"apple_pay"}}}"#,
+        )
+        .map(PaymentMethodDto::from)
+        .unwrap();
+        assert_eq!(card.network, "visa");
+        assert_eq!(card.funding, CardFunding::Debit);
+        assert_eq!(card.wallet, Some(WalletType::ApplePay));
+        assert!(card.three_d_secure_supported);
+    }
+
+    #[test]
+    fn checks_debit_currency() {
+        assert!(validate_payment_method_currency("bacs_debit", "GBP").is_ok());
+        assert!(validate_payment_method_currency("au_becs_debit", "gbp").is_err());
+    }
+
+    #[test]
+    fn parses_automatic_update() {
+        let event = serde_json::from_str::<WebhookEvent>(
+            r#"{"id":"evt_1","type":"payment_method.automatically_updated","created":1,
+                "livemode":false,"data":{"object":{"id":"pm_1","customer":"cus_1",
+                "card":{"brand":"visa","last4":"4242","exp_month":1,"exp_year":2031}},
+                "previous_attributes":{"card":{"exp_month":12,"exp_year":2026}}}}"#,
+        )
+        .unwrap();
+        match PaymentMethodEvent::from_event(&event) {
+            Some(PaymentMethodEvent::AutomaticallyUpdated {
+                payment_method,
+                previous_exp_year,
+                previous_last4,
+                ..
+            }) => {
+                assert_eq!(payment_method.exp_year, 2031);
+                assert_eq!(payment_method.stripe_customer_id.as_deref(), Some("cus_1"));
+                assert_eq!(previous_exp_year, Some(2026));
+                assert_eq!(previous_last4, None);
+            }
+            x => panic!("unexpected {:?}", x),
+        }
+    }
+}
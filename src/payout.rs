@@ -0,0 +1,342 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use stripe::{AccountId, Client};
+
+use crate::external_account;
+use crate::pagination::RawList;
+use crate::telemetry;
+use crate::StripePaymentError;
+
+#[derive(Debug)]
+pub enum PayoutError {
+    /// The account has no debit card or bank account for `currency` that
+    /// supports instant payouts.
+    NotEligible {
+        account_id: String,
+        currency: String,
+    },
+    InsufficientInstantBalance {
+        available: i64,
+        requested: i64,
+    },
+    Stripe(StripePaymentError),
+}
+
+impl Display for PayoutError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PayoutError::NotEligible {
+                account_id,
+                currency,
+            } => write!(
+                f,
+                "account {} has no {} external account eligible for instant payouts",
+                account_id, currency
+            ),
+            PayoutError::InsufficientInstantBalance {
+                available,
+                requested,
+            } => write!(
+                f,
+                "instant payout of {} exceeds the instantly available {}",
+                requested, available
+            ),
+            PayoutError::Stripe(x) => write!(f, "{:?}", x),
+        }
+    }
+}
+
+impl std::error::Error for PayoutError {}
+
+impl From<StripePaymentError> for PayoutError {
+    fn from(x: StripePaymentError) -> Self {
+        PayoutError::Stripe(x)
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PayoutDto {
+    pub id: String,
+    pub amount: i64,
+    pub currency: String,
+    pub status: String,
+    pub method: String,
+    pub arrival_date: i64,
+    pub destination: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawPayout {
+    id: String,
+    amount: i64,
+    currency: String,
+    status: String,
+    method: String,
+    arrival_date: i64,
+    destination: Option<String>,
+}
+
+impl From<RawPayout> for PayoutDto {
+    fn from(x: RawPayout) -> Self {
+        PayoutDto {
+            id: x.id,
+            amount: x.amount,
+            currency: x.currency,
+            status: x.status,
+            method: x.method,
+            arrival_date: x.arrival_date,
+            destination: x.destination,
+        }
+    }
+}
+
+/// What a balance transaction is for, taken from its `reporting_category`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BalanceTransactionCategory {
+    Charge,
+    Refund,
+    RefundFailure,
+    Dispute,
+    DisputeReversal,
+    Fee,
+    Transfer,
+    TransferReversal,
+    Payout,
+    PayoutReversal,
+    OtherAdjustment,
+    Other(String),
+}
+
+impl BalanceTransactionCategory {
+    pub fn parse(category: &str) -> BalanceTransactionCategory {
+        match category {
+            "charge" => BalanceTransactionCategory::Charge,
+            "refund" => BalanceTransactionCategory::Refund,
+            "refund_failure" => BalanceTransactionCategory::RefundFailure,
+            "dispute" => BalanceTransactionCategory::Dispute,
+            "dispute_reversal" => BalanceTransactionCategory::DisputeReversal,
+            "fee" => BalanceTransactionCategory::Fee,
+            "transfer" => BalanceTransactionCategory::Transfer,
+            "transfer_reversal" => BalanceTransactionCategory::TransferReversal,
+            "payout" => BalanceTransactionCategory::Payout,
+            "payout_reversal" => BalanceTransactionCategory::PayoutReversal,
+            "other_adjustment" => BalanceTransactionCategory::OtherAdjustment,
+            other => BalanceTransactionCategory::Other(other.to_string()),
+        }
+    }
+}
+
+/// One balance transaction settled by a payout. `amount` and `net` are signed:
+/// refunds, disputes and fees are negative.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PayoutTransactionDto {
+    pub id: String,
+    pub category: BalanceTransactionCategory,
+    pub amount: i64,
+    pub fee: i64,
+    pub net: i64,
+    pub currency: String,
+    /// The charge, refund, dispute etc. the transaction belongs to.
+    pub source: Option<String>,
+    pub description: Option<String>,
+    pub created: i64,
+    pub available_on: i64,
+}
+
+#[derive(Deserialize)]
+struct RawBalanceTransaction {
+    id: String,
+    reporting_category: String,
+    amount: i64,
+    fee: i64,
+    net: i64,
+    currency: String,
+    source: Option<String>,
+    description: Option<String>,
+    created: i64,
+    available_on: i64,
+}
+
+impl From<RawBalanceTransaction> for PayoutTransactionDto {
+    fn from(x: RawBalanceTransaction) -> Self {
+        PayoutTransactionDto {
+            id: x.id,
+            category: BalanceTransactionCategory::parse(&x.reporting_category),
+            amount: x.amount,
+            fee: x.fee,
+            net: x.net,
+            currency: x.currency,
+            source: x.source,
+            description: x.description,
+            created: x.created,
+            available_on: x.available_on,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PayoutTransactionParams<'a> {
+    payout: &'a str,
+    limit: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    starting_after: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawBalanceAmount {
+    amount: i64,
+    currency: String,
+}
+
+#[derive(Deserialize)]
+struct RawBalance {
+    #[serde(default)]
+    instant_available: Vec<RawBalanceAmount>,
+}
+
+#[derive(Serialize)]
+struct InstantPayoutParams<'a> {
+    amount: i64,
+    currency: String,
+    method: &'static str,
+    destination: &'a str,
+}
+
+fn connected(stripe_client: &Client, account_id: &str) -> Result<Client, StripePaymentError> {
+    let account_id = AccountId::from_str(account_id)
+        .map_err(|x| StripePaymentError::from_general(x.to_string()))?;
+    Ok(stripe_client.clone().with_stripe_account(account_id))
+}
+
+/// The external account instant payouts in `currency` would go to: the
+/// default one for the currency if it supports instant payouts, otherwise the
+/// first one that does.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn instant_payout_destination(
+    stripe_client: &Client,
+    account_id: &str,
+    currency: &str,
+) -> Result<Option<String>, StripePaymentError> {
+    let currency = currency.to_lowercase();
+    let mut eligible = external_account::list_external_accounts(stripe_client, account_id)
+        .await?
+        .into_iter()
+        .filter(|x| x.currency == currency && x.supports_instant_payouts())
+        .collect::<Vec<_>>();
+    eligible.sort_by_key(|x| !x.default_for_currency);
+    Ok(eligible.into_iter().next().map(|x| x.id))
+}
+
+/// Pays `amount` out of a connected account's balance to its debit card or
+/// bank account within minutes. Checks that an eligible external account
+/// exists and that enough balance is instantly available before creating the
+/// payout, so sellers get a specific reason instead of a Stripe error.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn create_instant_payout(
+    stripe_client: &Client,
+    account_id: &str,
+    amount: i64,
+    currency: &str,
+) -> Result<PayoutDto, PayoutError> {
+    let currency = currency.to_lowercase();
+    let destination = instant_payout_destination(stripe_client, account_id, &currency)
+        .await?
+        .ok_or_else(|| PayoutError::NotEligible {
+            account_id: account_id.to_string(),
+            currency: currency.clone(),
+        })?;
+    let client = connected(stripe_client, account_id)?;
+    let balance = telemetry::observe("balance.retrieve", client.get::<RawBalance>("/balance"))
+        .await
+        .map_err(StripePaymentError::from_general)?;
+    let available = balance
+        .instant_available
+        .iter()
+        .filter(|x| x.currency == currency)
+        .map(|x| x.amount)
+        .sum::<i64>();
+    if amount > available {
+        return Err(PayoutError::InsufficientInstantBalance {
+            available,
+            requested: amount,
+        });
+    }
+    telemetry::observe(
+        "payouts.create",
+        client.post_form::<RawPayout, _>(
+            "/payouts",
+            InstantPayoutParams {
+                amount,
+                currency,
+                method: "instant",
+                destination: &destination,
+            },
+        ),
+    )
+    .await
+    .map(PayoutDto::from)
+    .map_err(|x| StripePaymentError::from_general(x).into())
+}
+
+/// Lists every balance transaction included in an automatic payout, following
+/// pagination, so the payout amount on a bank statement can be matched to the
+/// charges, refunds and fees it is made of. The payout's own transaction is
+/// part of the list with category [`BalanceTransactionCategory::Payout`].
+#[tracing::instrument(skip(stripe_client))]
+pub async fn list_payout_transactions(
+    stripe_client: &Client,
+    payout_id: &str,
+) -> Result<Vec<PayoutTransactionDto>, StripePaymentError> {
+    let mut transactions = Vec::new();
+    let mut starting_after = None;
+    loop {
+        let page = telemetry::observe(
+            "balance_transactions.list",
+            stripe_client.get_query::<RawList<RawBalanceTransaction>, _>(
+                "/balance_transactions",
+                PayoutTransactionParams {
+                    payout: payout_id,
+                    limit: 100,
+                    starting_after: starting_after.take(),
+                },
+            ),
+        )
+        .await
+        .map_err(StripePaymentError::from_general)?;
+        starting_after = page
+            .data
+            .last()
+            .filter(|_| page.has_more)
+            .map(|x| x.id.clone());
+        transactions.extend(page.data.into_iter().map(PayoutTransactionDto::from));
+        if starting_after.is_none() {
+            return Ok(transactions);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_balance_transaction_category() {
+        let x = serde_json::from_str::<RawBalanceTransaction>(
+            r#"{"id":"txn_1","object":"balance_transaction","reporting_category":"refund",
+                "amount":-500,"fee":0,"net":-500,"currency":"usd","source":"re_1",
+                "description":null,"created":1,"available_on":2}"#,
+        )
+        .map(PayoutTransactionDto::from)
+        .unwrap();
+        assert_eq!(x.category, BalanceTransactionCategory::Refund);
+        assert_eq!(x.source.as_deref(), Some("re_1"));
+        assert_eq!(
+            BalanceTransactionCategory::parse("climate_order_purchase"),
+            BalanceTransactionCategory::Other("climate_order_purchase".to_string())
+        );
+    }
+}
@@ -0,0 +1,62 @@
+use stripe::StripeError;
+
+/// Reads the scope out of Stripe's 403 message, which names it as e.g.
+/// `rak_payment_intent_write`, and splits it into resource and permission.
+pub fn missing_permission(message: &str) -> Option<(String, String)> {
+    let scope = message
+        .split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .find_map(|x| x.strip_prefix("rak_"))?;
+    let (resource, permission) = scope.rsplit_once('_')?;
+    match (resource.is_empty(), permission) {
+        (false, "read" | "write") => Some((resource.to_string(), permission.to_string())),
+        _ => None,
+    }
+}
+
+/// The same check on the raw error; converting it into a
+/// [`StripePaymentError`](crate::StripePaymentError) applies it.
+pub fn missing_permission_of(error: &StripeError) -> Option<(String, String)> {
+    match error {
+        StripeError::Stripe(x) if x.http_status == 403 => {
+            missing_permission(x.message.as_deref().unwrap_or_default())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StripePaymentError;
+
+    #[test]
+    fn parses_restricted_key_scope() {
+        let message = "The provided key 'rk_test_***123' does not have the required permissions \
+            for this endpoint on account 'acct_1'. Having the 'rak_payment_intent_write' \
+            permission would allow this request to continue.";
+        assert_eq!(
+            missing_permission(message),
+            Some(("payment_intent".to_string(), "write".to_string()))
+        );
+        assert_eq!(missing_permission("No such customer: 'cus_1'"), None);
+        assert_eq!(missing_permission("rak_"), None);
+    }
+
+    #[test]
+    fn converts_refusals_into_missing_permission() {
+        let error = StripeError::Stripe(stripe::RequestError {
+            http_status: 403,
+            message: Some("Having the 'rak_refund_write' permission would allow this".to_string()),
+            ..Default::default()
+        });
+        let error = StripePaymentError::from(error);
+        assert!(matches!(
+            &error,
+            StripePaymentError::MissingPermission { resource, .. } if resource == "refund"
+        ));
+        assert_eq!(
+            error.to_string(),
+            "the restricted key lacks write permission on refund"
+        );
+    }
+}
@@ -0,0 +1,328 @@
+use serde::{Deserialize, Serialize};
+use stripe::Client;
+
+use crate::telemetry;
+use crate::StripePaymentError;
+
+/// A portal action to deep-link the customer to, instead of the portal
+/// homepage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortalFlow {
+    PaymentMethodUpdate,
+    /// Asks the customer to confirm cancelling `subscription_id`.
+    SubscriptionCancel {
+        subscription_id: String,
+    },
+    /// Lets the customer pick a new plan for `subscription_id`.
+    SubscriptionUpdate {
+        subscription_id: String,
+    },
+    /// Asks the customer to confirm a plan change the application already
+    /// chose, e.g. from an upgrade button.
+    SubscriptionUpdateConfirm {
+        subscription_id: String,
+        items: Vec<PortalSubscriptionItem>,
+    },
+}
+
+impl PortalFlow {
+    fn kind(&self) -> &'static str {
+        match self {
+            PortalFlow::PaymentMethodUpdate => "payment_method_update",
+            PortalFlow::SubscriptionCancel { .. } => "subscription_cancel",
+            PortalFlow::SubscriptionUpdate { .. } => "subscription_update",
+            PortalFlow::SubscriptionUpdateConfirm { .. } => "subscription_update_confirm",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PortalSubscriptionItem {
+    /// The subscription item being changed.
+    pub id: String,
+    pub price_id: String,
+    pub quantity: Option<u64>,
+}
+
+impl PortalSubscriptionItem {
+    pub fn new(id: impl Into<String>, price_id: impl Into<String>) -> Self {
+        PortalSubscriptionItem {
+            id: id.into(),
+            price_id: price_id.into(),
+            quantity: None,
+        }
+    }
+
+    pub fn with_quantity(mut self, quantity: u64) -> Self {
+        self.quantity = Some(quantity);
+        self
+    }
+}
+
+/// Where the customer goes once a [`PortalFlow`] is completed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortalAfterCompletion {
+    PortalHomepage,
+    HostedConfirmation { custom_message: Option<String> },
+    Redirect { return_url: String },
+}
+
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct CreatePortalSessionDto {
+    pub stripe_customer_id: String,
+    pub return_url: Option<String>,
+    /// Portal configuration id; the account's default configuration otherwise.
+    pub configuration: Option<String>,
+    pub flow: Option<PortalFlow>,
+    pub after_completion: Option<PortalAfterCompletion>,
+}
+
+impl CreatePortalSessionDto {
+    pub fn new(stripe_customer_id: impl Into<String>) -> Self {
+        CreatePortalSessionDto {
+            stripe_customer_id: stripe_customer_id.into(),
+            ..CreatePortalSessionDto::default()
+        }
+    }
+
+    pub fn with_return_url(mut self, return_url: impl Into<String>) -> Self {
+        self.return_url = Some(return_url.into());
+        self
+    }
+
+    pub fn with_configuration(mut self, configuration: impl Into<String>) -> Self {
+        self.configuration = Some(configuration.into());
+        self
+    }
+
+    pub fn with_flow(mut self, flow: PortalFlow) -> Self {
+        self.flow = Some(flow);
+        self
+    }
+
+    /// Only used together with a flow.
+    pub fn with_after_completion(mut self, after_completion: PortalAfterCompletion) -> Self {
+        self.after_completion = Some(after_completion);
+        self
+    }
+
+    /// Checks the combinations Stripe rejects, before a session is created.
+    pub fn validate(&self) -> Result<(), StripePaymentError> {
+        let invalid = |x: &str| Err(StripePaymentError::from_general(x.to_string()));
+        if self.after_completion.is_some() && self.flow.is_none() {
+            return invalid("after_completion requires a flow");
+        }
+        match &self.flow {
+            Some(PortalFlow::SubscriptionUpdateConfirm { items, .. }) if items.is_empty() => {
+                invalid("subscription_update_confirm requires at least one item")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PortalSessionDto {
+    pub id: String,
+    /// Short-lived link that logs the customer into the portal; send it as a
+    /// redirect rather than storing it.
+    pub url: String,
+}
+
+#[derive(Deserialize)]
+struct RawPortalSession {
+    id: String,
+    url: String,
+}
+
+#[derive(Serialize)]
+struct SubscriptionParams<'a> {
+    subscription: &'a str,
+}
+
+#[derive(Serialize)]
+struct ItemParams<'a> {
+    id: &'a str,
+    price: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quantity: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct UpdateConfirmParams<'a> {
+    subscription: &'a str,
+    items: Vec<ItemParams<'a>>,
+}
+
+#[derive(Serialize)]
+struct HostedConfirmationParams<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    custom_message: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct RedirectParams<'a> {
+    return_url: &'a str,
+}
+
+#[derive(Serialize)]
+struct AfterCompletionParams<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hosted_confirmation: Option<HostedConfirmationParams<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redirect: Option<RedirectParams<'a>>,
+}
+
+#[derive(Serialize)]
+struct FlowDataParams<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subscription_cancel: Option<SubscriptionParams<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subscription_update: Option<SubscriptionParams<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subscription_update_confirm: Option<UpdateConfirmParams<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    after_completion: Option<AfterCompletionParams<'a>>,
+}
+
+#[derive(Serialize)]
+struct CreateParams<'a> {
+    customer: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    return_url: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    configuration: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flow_data: Option<FlowDataParams<'a>>,
+}
+
+fn after_completion_params(x: &PortalAfterCompletion) -> AfterCompletionParams<'_> {
+    match x {
+        PortalAfterCompletion::PortalHomepage => AfterCompletionParams {
+            kind: "portal_homepage",
+            hosted_confirmation: None,
+            redirect: None,
+        },
+        PortalAfterCompletion::HostedConfirmation { custom_message } => AfterCompletionParams {
+            kind: "hosted_confirmation",
+            hosted_confirmation: Some(HostedConfirmationParams {
+                custom_message: custom_message.as_deref(),
+            }),
+            redirect: None,
+        },
+        PortalAfterCompletion::Redirect { return_url } => AfterCompletionParams {
+            kind: "redirect",
+            hosted_confirmation: None,
+            redirect: Some(RedirectParams { return_url }),
+        },
+    }
+}
+
+fn flow_data_params<'a>(
+    flow: &'a PortalFlow,
+    after_completion: Option<&'a PortalAfterCompletion>,
+) -> FlowDataParams<'a> {
+    let mut params = FlowDataParams {
+        kind: flow.kind(),
+        subscription_cancel: None,
+        subscription_update: None,
+        subscription_update_confirm: None,
+        after_completion: after_completion.map(after_completion_params),
+    };
+    match flow {
+        PortalFlow::PaymentMethodUpdate => {}
+        PortalFlow::SubscriptionCancel { subscription_id } => {
+            params.subscription_cancel = Some(SubscriptionParams {
+                subscription: subscription_id,
+            })
+        }
+        PortalFlow::SubscriptionUpdate { subscription_id } => {
+            params.subscription_update = Some(SubscriptionParams {
+                subscription: subscription_id,
+            })
+        }
+        PortalFlow::SubscriptionUpdateConfirm {
+            subscription_id,
+            items,
+        } => {
+            params.subscription_update_confirm = Some(UpdateConfirmParams {
+                subscription: subscription_id,
+                items: items
+                    .iter()
+                    .map(|x| ItemParams {
+                        id: &x.id,
+                        price: &x.price_id,
+                        quantity: x.quantity,
+                    })
+                    .collect(),
+            })
+        }
+    }
+    params
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn create_portal_session(
+    stripe_client: &Client,
+    dto: &CreatePortalSessionDto,
+) -> Result<PortalSessionDto, StripePaymentError> {
+    dto.validate()?;
+    telemetry::observe(
+        "billing_portal.sessions.create",
+        stripe_client.post_form::<RawPortalSession, _>(
+            "/billing_portal/sessions",
+            CreateParams {
+                customer: &dto.stripe_customer_id,
+                return_url: dto.return_url.as_deref(),
+                configuration: dto.configuration.as_deref(),
+                flow_data: dto
+                    .flow
+                    .as_ref()
+                    .map(|x| flow_data_params(x, dto.after_completion.as_ref())),
+            },
+        ),
+    )
+    .await
+    .map(|x| PortalSessionDto {
+        id: x.id,
+        url: x.url,
+    })
+    .map_err(StripePaymentError::from_general)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_flow_data() {
+        let flow = PortalFlow::SubscriptionUpdateConfirm {
+            subscription_id: "sub_1".to_string(),
+            items: vec![PortalSubscriptionItem::new("si_1", "price_pro").with_quantity(2)],
+        };
+        let after_completion = PortalAfterCompletion::Redirect {
+            return_url: "https://example.com/account".to_string(),
+        };
+        let params =
+            serde_json::to_value(flow_data_params(&flow, Some(&after_completion))).unwrap();
+        assert_eq!(params["type"], "subscription_update_confirm");
+        assert_eq!(
+            params["subscription_update_confirm"]["items"][0]["price"],
+            "price_pro"
+        );
+        assert_eq!(params["after_completion"]["type"], "redirect");
+        assert!(params.get("subscription_cancel").is_none());
+
+        assert!(CreatePortalSessionDto::new("cus_1")
+            .with_after_completion(PortalAfterCompletion::PortalHomepage)
+            .validate()
+            .is_err());
+    }
+}
@@ -0,0 +1,19 @@
+pub use crate::address::{AddressDto, BillingDetailsDto};
+#[cfg(feature = "blocking")]
+pub use crate::blocking::BlockingClient;
+pub use crate::config::{ConfigError, LibStripeConfig};
+#[cfg(feature = "runtime-tokio-hyper")]
+pub use crate::facade::LibStripe;
+#[cfg(feature = "actix")]
+pub use crate::webhook::StripeEvent;
+#[cfg(feature = "axum")]
+pub use crate::webhook::StripeWebhook;
+pub use crate::webhook::{EventHandler, WebhookError, WebhookEvent, WebhookVerifier};
+#[cfg(feature = "runtime-tokio-hyper")]
+pub use crate::Client;
+pub use crate::{
+    CreateCustomerDto, CreatePaymentIntentDto, CreatePaymentIntentShipping,
+    CreatePaymentIntentShippingAddress, CustomerDto, GuestPaymentIntentDto, GuestPaymentOptions,
+    PaymentIntentDto, PaymentIntentSetupFutureUsage, PaymentIntentStatus, PaymentSheetResult,
+    StripeError, StripePaymentError,
+};
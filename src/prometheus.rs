@@ -0,0 +1,154 @@
+use std::fmt::{Display, Formatter};
+
+use metrics::{Counter, Gauge, Histogram, Key, KeyName, Recorder, SharedString, Unit};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+pub const PREFIX: &str = "lib_";
+
+/// Upper bounds of the request duration histogram, in seconds.
+pub const DURATION_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+#[derive(Debug)]
+pub enum PrometheusError {
+    Build(String),
+    /// Another `metrics` recorder is already installed in this process.
+    AlreadyInstalled,
+}
+
+impl Display for PrometheusError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrometheusError::Build(x) => write!(f, "failed to build prometheus recorder: {}", x),
+            PrometheusError::AlreadyInstalled => {
+                f.write_str("a metrics recorder is already installed")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PrometheusError {}
+
+/// Renames the crate's `stripe_*` metrics to `lib_stripe_*` and passes every
+/// other metric through unchanged, so the application's own metrics recorded
+/// through the same global recorder keep their names.
+struct LibStripeNames<R>(R);
+
+fn rename(name: &str) -> Option<String> {
+    name.starts_with("stripe_")
+        .then(|| format!("{}{}", PREFIX, name))
+}
+
+fn renamed_key(key: &Key) -> Key {
+    match rename(key.name()) {
+        Some(name) => Key::from_parts(name, key.labels().cloned().collect::<Vec<_>>()),
+        None => key.clone(),
+    }
+}
+
+fn renamed_key_name(key: KeyName) -> KeyName {
+    match rename(key.as_str()) {
+        Some(name) => KeyName::from(name),
+        None => key,
+    }
+}
+
+impl<R: Recorder> Recorder for LibStripeNames<R> {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.0
+            .describe_counter(renamed_key_name(key), unit, description)
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.0
+            .describe_gauge(renamed_key_name(key), unit, description)
+    }
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.0
+            .describe_histogram(renamed_key_name(key), unit, description)
+    }
+
+    fn register_counter(&self, key: &Key) -> Counter {
+        self.0.register_counter(&renamed_key(key))
+    }
+
+    fn register_gauge(&self, key: &Key) -> Gauge {
+        self.0.register_gauge(&renamed_key(key))
+    }
+
+    fn register_histogram(&self, key: &Key) -> Histogram {
+        self.0.register_histogram(&renamed_key(key))
+    }
+}
+
+/// Batteries-included Prometheus export for services that don't run their own
+/// `metrics` recorder. The request metrics are exposed as
+/// `lib_stripe_requests_total`, `lib_stripe_request_duration` (seconds) and
+/// `lib_stripe_errors_total`:
+///
+/// ```ignore
+/// let recorder = PrometheusRecorder::install()?;
+/// let lib_stripe = LibStripe::new(secret_key).with_prometheus_recorder(recorder);
+/// // In the `/metrics` handler:
+/// let body = lib_stripe.render_metrics().unwrap_or_default();
+/// ```
+#[derive(Clone)]
+pub struct PrometheusRecorder {
+    handle: PrometheusHandle,
+}
+
+impl std::fmt::Debug for PrometheusRecorder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PrometheusRecorder").finish_non_exhaustive()
+    }
+}
+
+impl PrometheusRecorder {
+    /// Installs the recorder as the global `metrics` recorder. This can only
+    /// happen once per process.
+    pub fn install() -> Result<Self, PrometheusError> {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets_for_metric(
+                Matcher::Full(format!("{}stripe_request_duration", PREFIX)),
+                DURATION_BUCKETS,
+            )
+            .map_err(|x| PrometheusError::Build(x.to_string()))?
+            .build_recorder();
+        let handle = recorder.handle();
+        metrics::set_boxed_recorder(Box::new(LibStripeNames(recorder)))
+            .map_err(|_| PrometheusError::AlreadyInstalled)?;
+        metrics::describe_counter!("stripe_requests_total", "Requests sent to Stripe");
+        metrics::describe_histogram!(
+            "stripe_request_duration",
+            Unit::Seconds,
+            "Duration of requests sent to Stripe"
+        );
+        metrics::describe_counter!("stripe_errors_total", "Failed requests by error class");
+        Ok(PrometheusRecorder { handle })
+    }
+
+    /// The registry in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        self.handle.render()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renames_only_crate_metrics() {
+        let key = Key::from_parts(
+            "stripe_errors_total",
+            vec![metrics::Label::new("endpoint", "refunds.create")],
+        );
+        let renamed = renamed_key(&key);
+        assert_eq!(renamed.name(), "lib_stripe_errors_total");
+        assert_eq!(renamed.labels().count(), 1);
+        assert_eq!(
+            renamed_key(&Key::from_name("http_requests")).name(),
+            "http_requests"
+        );
+    }
+}
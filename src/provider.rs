@@ -0,0 +1,202 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::command::{execute_command, OutboxEntry, RefundReason, StripeCommand};
+use crate::facade::LibStripe;
+use crate::redact::{self, Redacted, SecretString};
+use crate::telemetry;
+use crate::url::StripeUrl;
+use crate::{GuestPaymentOptions, PaymentIntentStatus, StripePaymentError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProviderPaymentStatus {
+    Pending,
+    RequiresAction,
+    Authorized,
+    Succeeded,
+    Canceled,
+}
+
+impl From<PaymentIntentStatus> for ProviderPaymentStatus {
+    fn from(x: PaymentIntentStatus) -> Self {
+        match x {
+            PaymentIntentStatus::RequiresAction => ProviderPaymentStatus::RequiresAction,
+            PaymentIntentStatus::RequiresCapture => ProviderPaymentStatus::Authorized,
+            PaymentIntentStatus::Succeeded => ProviderPaymentStatus::Succeeded,
+            PaymentIntentStatus::Canceled => ProviderPaymentStatus::Canceled,
+            PaymentIntentStatus::RequiresPaymentMethod
+            | PaymentIntentStatus::RequiresConfirmation
+            | PaymentIntentStatus::Processing => ProviderPaymentStatus::Pending,
+        }
+    }
+}
+
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct ProviderPayment {
+    pub provider: String,
+    pub id: String,
+    pub client_secret: Option<SecretString>,
+}
+
+impl ProviderPayment {
+    pub fn new(provider: impl Into<String>, id: impl Into<String>) -> Self {
+        ProviderPayment {
+            provider: provider.into(),
+            id: id.into(),
+            client_secret: None,
+        }
+    }
+
+    pub fn with_client_secret(mut self, client_secret: impl Into<SecretString>) -> Self {
+        self.client_secret = Some(client_secret.into());
+        self
+    }
+}
+
+impl Redacted for ProviderPayment {
+    fn fmt_redacted(&self, f: &mut std::fmt::Formatter<'_>, reveal: bool) -> std::fmt::Result {
+        f.debug_struct("ProviderPayment")
+            .field("provider", &self.provider)
+            .field("id", &self.id)
+            .field(
+                "client_secret",
+                &self
+                    .client_secret
+                    .as_ref()
+                    .map(|x| redact::secret(x, reveal)),
+            )
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for ProviderPayment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_redacted(f, false)
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ProviderRefund {
+    pub id: String,
+    pub status: Option<String>,
+}
+
+impl ProviderRefund {
+    pub fn new(id: impl Into<String>, status: Option<String>) -> Self {
+        ProviderRefund {
+            id: id.into(),
+            status,
+        }
+    }
+}
+
+/// The operations an application needs from a payment provider. Stripe, via
+/// [`LibStripe`], is the canonical implementation; a secondary provider can
+/// implement this trait so callers can fall back to it behind the same interface.
+#[async_trait]
+pub trait PaymentProvider: Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn create_payment(
+        &self,
+        amount: i64,
+        currency: &str,
+        options: &GuestPaymentOptions,
+    ) -> Result<ProviderPayment, StripePaymentError>;
+
+    /// Refunds `amount`, or the full payment when `None`. Retrying with the
+    /// same `idempotency_key` must not refund twice.
+    async fn refund(
+        &self,
+        payment_id: &str,
+        amount: Option<i64>,
+        idempotency_key: &str,
+    ) -> Result<ProviderRefund, StripePaymentError>;
+
+    async fn get_status(
+        &self,
+        payment_id: &str,
+    ) -> Result<ProviderPaymentStatus, StripePaymentError>;
+}
+
+#[derive(Deserialize)]
+struct RawPaymentIntentStatus {
+    status: PaymentIntentStatus,
+}
+
+#[async_trait]
+impl PaymentProvider for LibStripe {
+    fn name(&self) -> &str {
+        "stripe"
+    }
+
+    async fn create_payment(
+        &self,
+        amount: i64,
+        currency: &str,
+        options: &GuestPaymentOptions,
+    ) -> Result<ProviderPayment, StripePaymentError> {
+        self.create_guest_payment_sheet(amount, currency, options)
+            .await
+            .map(|x| ProviderPayment::new(self.name(), x.id).with_client_secret(x.client_secret))
+    }
+
+    async fn refund(
+        &self,
+        payment_id: &str,
+        amount: Option<i64>,
+        idempotency_key: &str,
+    ) -> Result<ProviderRefund, StripePaymentError> {
+        let entry = OutboxEntry::new(
+            idempotency_key,
+            StripeCommand::CreateRefund {
+                payment_intent_id: payment_id.to_string(),
+                amount,
+                reason: Some(RefundReason::RequestedByCustomer),
+            },
+        );
+        self.run(|client| execute_command(client, &entry))
+            .await
+            .map(|x| ProviderRefund::new(x.object_id, x.status))
+    }
+
+    async fn get_status(
+        &self,
+        payment_id: &str,
+    ) -> Result<ProviderPaymentStatus, StripePaymentError> {
+        let url = &StripeUrl::new("/payment_intents")
+            .segment(payment_id)
+            .build();
+        self.run(|client| async move {
+            telemetry::observe(
+                "payment_intents.retrieve",
+                client.get::<RawPaymentIntentStatus>(url),
+            )
+            .await
+            .map(|x| ProviderPaymentStatus::from(x.status))
+            .map_err(StripePaymentError::from_general)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_intent_status() {
+        assert_eq!(
+            ProviderPaymentStatus::from(PaymentIntentStatus::RequiresCapture),
+            ProviderPaymentStatus::Authorized
+        );
+        assert_eq!(
+            ProviderPaymentStatus::from(PaymentIntentStatus::Processing),
+            ProviderPaymentStatus::Pending
+        );
+        let provider: Box<dyn PaymentProvider> = Box::new(LibStripe::new("sk_test_1"));
+        assert_eq!(provider.name(), "stripe");
+    }
+}
@@ -0,0 +1,93 @@
+use std::fmt::{Debug, Formatter};
+
+struct Mask;
+
+impl Debug for Mask {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+/// A client secret, ephemeral key or API key. `Debug` never prints the value;
+/// with the `zeroize` feature the memory is overwritten when it is dropped.
+#[derive(Clone, Default)]
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(x: String) -> Self {
+        SecretString(x)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(x: &str) -> Self {
+        SecretString(x.to_string())
+    }
+}
+
+impl Debug for SecretString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Mask.fmt(f)
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(&mut self.0);
+    }
+}
+
+/// `value` when revealing, otherwise a `[REDACTED]` placeholder.
+pub(crate) fn secret(value: &SecretString, reveal: bool) -> &dyn Debug {
+    match reveal {
+        true => &value.0,
+        false => &Mask,
+    }
+}
+
+/// DTOs carrying client secrets, ephemeral keys or signing secrets. Their
+/// `Debug` output masks those fields so they don't end up in logs; use
+/// [`Redacted::reveal`] where the full value is genuinely needed.
+pub trait Redacted {
+    fn fmt_redacted(&self, f: &mut Formatter<'_>, reveal: bool) -> std::fmt::Result;
+
+    /// Wraps `self` so that `{:?}` prints the secret fields as well.
+    fn reveal(&self) -> Revealed<'_, Self> {
+        Revealed(self)
+    }
+}
+
+pub struct Revealed<'a, T: ?Sized>(&'a T);
+
+impl<T: Redacted + ?Sized> Debug for Revealed<'_, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt_redacted(f, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PaymentIntentDto;
+
+    #[test]
+    fn masks_secrets_unless_revealed() {
+        let dto = PaymentIntentDto::new("pi_1", "ek_test_1", "pi_1_secret_1", "cus_1");
+        let masked = format!("{:?}", dto);
+        assert!(masked.contains("pi_1") && masked.contains("cus_1"));
+        assert!(!masked.contains("ek_test_1") && !masked.contains("pi_1_secret_1"));
+        assert!(masked.contains("[REDACTED]"));
+        assert!(format!("{:?}", dto.reveal()).contains("pi_1_secret_1"));
+    }
+}
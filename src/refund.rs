@@ -0,0 +1,360 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use stripe::Client;
+
+use crate::pagination::{CreatedRange, RawList};
+use crate::telemetry;
+use crate::url::StripeUrl;
+use crate::webhook::WebhookEvent;
+use crate::StripePaymentError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefundStatus {
+    Pending,
+    RequiresAction,
+    Succeeded,
+    Failed,
+    Canceled,
+    Other(String),
+}
+
+impl RefundStatus {
+    pub fn parse(status: &str) -> RefundStatus {
+        match status {
+            "pending" => RefundStatus::Pending,
+            "requires_action" => RefundStatus::RequiresAction,
+            "succeeded" => RefundStatus::Succeeded,
+            "failed" => RefundStatus::Failed,
+            "canceled" => RefundStatus::Canceled,
+            other => RefundStatus::Other(other.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefundFailureReason {
+    ExpiredOrCanceledCard,
+    LostOrStolenCard,
+    ChargeForPendingRefundDisputed,
+    InsufficientFunds,
+    Declined,
+    MerchantRequest,
+    Unknown(String),
+}
+
+impl RefundFailureReason {
+    pub fn parse(reason: &str) -> RefundFailureReason {
+        match reason {
+            "expired_or_canceled_card" => RefundFailureReason::ExpiredOrCanceledCard,
+            "lost_or_stolen_card" => RefundFailureReason::LostOrStolenCard,
+            "charge_for_pending_refund_disputed" => {
+                RefundFailureReason::ChargeForPendingRefundDisputed
+            }
+            "insufficient_funds" => RefundFailureReason::InsufficientFunds,
+            "declined" => RefundFailureReason::Declined,
+            "merchant_request" => RefundFailureReason::MerchantRequest,
+            other => RefundFailureReason::Unknown(other.to_string()),
+        }
+    }
+
+    pub fn action(&self) -> RefundAction {
+        match self {
+            RefundFailureReason::ExpiredOrCanceledCard
+            | RefundFailureReason::LostOrStolenCard
+            | RefundFailureReason::Declined => RefundAction::RefundOutOfBand,
+            RefundFailureReason::InsufficientFunds => RefundAction::TopUpBalanceAndRetry,
+            RefundFailureReason::ChargeForPendingRefundDisputed => RefundAction::ResolveDispute,
+            RefundFailureReason::MerchantRequest => RefundAction::None,
+            RefundFailureReason::Unknown(_) => RefundAction::ContactSupport,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefundAction {
+    None,
+    RefundOutOfBand,
+    TopUpBalanceAndRetry,
+    ResolveDispute,
+    ContactSupport,
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct RefundStatusDto {
+    pub id: String,
+    pub payment_intent_id: Option<String>,
+    pub charge_id: Option<String>,
+    pub amount: i64,
+    pub currency: String,
+    pub status: RefundStatus,
+    pub failure_reason: Option<RefundFailureReason>,
+    pub action: RefundAction,
+}
+
+#[derive(Deserialize)]
+struct RawRefund {
+    id: String,
+    amount: i64,
+    currency: String,
+    status: Option<String>,
+    failure_reason: Option<String>,
+    payment_intent: Option<String>,
+    charge: Option<String>,
+}
+
+impl From<RawRefund> for RefundStatusDto {
+    fn from(x: RawRefund) -> Self {
+        let failure_reason = x.failure_reason.as_deref().map(RefundFailureReason::parse);
+        RefundStatusDto {
+            id: x.id,
+            payment_intent_id: x.payment_intent,
+            charge_id: x.charge,
+            amount: x.amount,
+            currency: x.currency,
+            status: RefundStatus::parse(x.status.as_deref().unwrap_or("pending")),
+            action: failure_reason
+                .as_ref()
+                .map(|x| x.action())
+                .unwrap_or(RefundAction::None),
+            failure_reason,
+        }
+    }
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn sync_refund_status(
+    stripe_client: &Client,
+    refund_id: &str,
+) -> Result<RefundStatusDto, StripePaymentError> {
+    telemetry::observe(
+        "refunds.retrieve",
+        stripe_client.get::<RawRefund>(&StripeUrl::new("/refunds").segment(refund_id).build()),
+    )
+    .await
+    .map(RefundStatusDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+/// Maps `refund.*` and `charge.refund.updated` events into a [`RefundStatusDto`].
+pub fn refund_status_from_event(event: &WebhookEvent) -> Option<RefundStatusDto> {
+    match event.event_type.as_str() {
+        "refund.created" | "refund.updated" | "refund.failed" | "charge.refund.updated" => event
+            .object_as::<RawRefund>()
+            .ok()
+            .map(RefundStatusDto::from),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RefundReportReason {
+    Duplicate,
+    Fraudulent,
+    RequestedByCustomer,
+    ExpiredUncapturedCharge,
+    /// Refunds created without a reason.
+    Unspecified,
+    Other(String),
+}
+
+impl RefundReportReason {
+    pub fn parse(reason: Option<&str>) -> RefundReportReason {
+        match reason {
+            None => RefundReportReason::Unspecified,
+            Some("duplicate") => RefundReportReason::Duplicate,
+            Some("fraudulent") => RefundReportReason::Fraudulent,
+            Some("requested_by_customer") => RefundReportReason::RequestedByCustomer,
+            Some("expired_uncaptured_charge") => RefundReportReason::ExpiredUncapturedCharge,
+            Some(other) => RefundReportReason::Other(other.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RefundReportGroup {
+    pub reason: RefundReportReason,
+    /// `card`, `sepa_debit`, ...; `unknown` when the charge has no payment
+    /// method details.
+    pub payment_method_type: String,
+    pub currency: String,
+    pub count: u64,
+    pub amount: i64,
+}
+
+/// Refunds within a period grouped by reason, payment method type and
+/// currency. Failed and canceled refunds are left out.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RefundReport {
+    pub period: CreatedRange,
+    /// Ordered by currency, reason and payment method type.
+    pub groups: Vec<RefundReportGroup>,
+}
+
+impl RefundReport {
+    pub fn count(&self) -> u64 {
+        self.groups.iter().map(|x| x.count).sum()
+    }
+
+    /// Refunded amount in `currency` for `reason`.
+    pub fn amount_for_reason(&self, currency: &str, reason: &RefundReportReason) -> i64 {
+        self.groups
+            .iter()
+            .filter(|x| x.currency.eq_ignore_ascii_case(currency) && &x.reason == reason)
+            .map(|x| x.amount)
+            .sum()
+    }
+
+    fn from_refunds(period: CreatedRange, refunds: Vec<RawReportRefund>) -> Self {
+        let mut groups = BTreeMap::<(String, RefundReportReason, String), (u64, i64)>::new();
+        for x in refunds {
+            if matches!(x.status.as_deref(), Some("failed") | Some("canceled")) {
+                continue;
+            }
+            let payment_method_type = match x.charge {
+                Some(RawReportCharge::Expanded {
+                    payment_method_details: Some(details),
+                }) => details.kind,
+                _ => "unknown".to_string(),
+            };
+            let group = groups
+                .entry((
+                    x.currency,
+                    RefundReportReason::parse(x.reason.as_deref()),
+                    payment_method_type,
+                ))
+                .or_default();
+            group.0 += 1;
+            group.1 += x.amount;
+        }
+        RefundReport {
+            period,
+            groups: groups
+                .into_iter()
+                .map(
+                    |((currency, reason, payment_method_type), (count, amount))| {
+                        RefundReportGroup {
+                            reason,
+                            payment_method_type,
+                            currency,
+                            count,
+                            amount,
+                        }
+                    },
+                )
+                .collect(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawPaymentMethodDetails {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawReportCharge {
+    Expanded {
+        payment_method_details: Option<RawPaymentMethodDetails>,
+    },
+    #[allow(dead_code)]
+    Id(String),
+}
+
+#[derive(Deserialize)]
+struct RawReportRefund {
+    id: String,
+    amount: i64,
+    currency: String,
+    status: Option<String>,
+    reason: Option<String>,
+    charge: Option<RawReportCharge>,
+}
+
+#[derive(Serialize)]
+struct ReportListParams<'a> {
+    created: CreatedRange,
+    limit: u64,
+    expand: &'a [&'a str],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    starting_after: Option<&'a str>,
+}
+
+/// Groups the refunds created within `period` for monthly ops reviews. The
+/// charge is expanded on each refund to read its payment method type.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn refund_report(
+    stripe_client: &Client,
+    period: CreatedRange,
+) -> Result<RefundReport, StripePaymentError> {
+    let mut refunds = Vec::<RawReportRefund>::new();
+    loop {
+        let list = telemetry::observe(
+            "refunds.list",
+            stripe_client.get_query::<RawList<RawReportRefund>, _>(
+                "/refunds",
+                ReportListParams {
+                    created: period,
+                    limit: 100,
+                    expand: &["data.charge"],
+                    starting_after: refunds.last().map(|x| x.id.as_str()),
+                },
+            ),
+        )
+        .await
+        .map_err(StripePaymentError::from_general)?;
+        let done = !list.has_more || list.data.is_empty();
+        refunds.extend(list.data);
+        if done {
+            return Ok(RefundReport::from_refunds(period, refunds));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_refund_requires_out_of_band_refund() {
+        let refund = RefundStatusDto::from(RawRefund {
+            id: "re_1".to_string(),
+            amount: 500,
+            currency: "eur".to_string(),
+            status: Some("failed".to_string()),
+            failure_reason: Some("expired_or_canceled_card".to_string()),
+            payment_intent: Some("pi_1".to_string()),
+            charge: None,
+        });
+        assert_eq!(refund.status, RefundStatus::Failed);
+        assert_eq!(refund.action, RefundAction::RefundOutOfBand);
+    }
+
+    #[test]
+    fn groups_refunds_by_reason_method_and_currency() {
+        let refunds = serde_json::from_str::<Vec<RawReportRefund>>(
+            r#"[{"id":"re_1","amount":500,"currency":"eur","status":"succeeded",
+                "reason":"duplicate","charge":{"payment_method_details":{"type":"card"}}},
+               {"id":"re_2","amount":300,"currency":"eur","status":"succeeded",
+                "reason":"duplicate","charge":{"payment_method_details":{"type":"card"}}},
+               {"id":"re_3","amount":700,"currency":"eur","status":"pending",
+                "reason":null,"charge":"ch_3"},
+               {"id":"re_4","amount":900,"currency":"eur","status":"failed",
+                "reason":"duplicate","charge":null}]"#,
+        )
+        .unwrap();
+        let report = RefundReport::from_refunds(CreatedRange::default(), refunds);
+        assert_eq!(report.count(), 3);
+        assert_eq!(
+            report.amount_for_reason("EUR", &RefundReportReason::Duplicate),
+            800
+        );
+        assert_eq!(report.groups[1].reason, RefundReportReason::Unspecified);
+        assert_eq!(report.groups[1].payment_method_type, "unknown");
+    }
+}
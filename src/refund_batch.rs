@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+
+use futures_util::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use stripe::{Client, RequestStrategy};
+
+use crate::cancel::{self, CancellableError, CancellationToken};
+use crate::command::{CommandOutcome, RefundReason};
+use crate::telemetry;
+use crate::StripePaymentError;
+
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RefundRequest {
+    /// Caller-supplied id of the row, e.g. the support ticket number. Unique
+    /// per refund: it is what makes the idempotency key unique, so two equal
+    /// partial refunds of one charge need different row ids.
+    pub row_id: String,
+    pub charge_id: String,
+    /// `None` refunds the remaining amount of the charge.
+    pub amount: Option<i64>,
+    pub reason: Option<RefundReason>,
+}
+
+impl RefundRequest {
+    pub fn new(row_id: impl Into<String>, charge_id: impl Into<String>) -> Self {
+        RefundRequest {
+            row_id: row_id.into(),
+            charge_id: charge_id.into(),
+            amount: None,
+            reason: None,
+        }
+    }
+
+    pub fn with_amount(mut self, amount: i64) -> Self {
+        self.amount = Some(amount);
+        self
+    }
+
+    pub fn with_reason(mut self, reason: RefundReason) -> Self {
+        self.reason = Some(reason);
+        self
+    }
+
+    /// Derived from the row id and charge, so re-running a batch within
+    /// Stripe's 24 hour idempotency window doesn't refund twice.
+    pub fn idempotency_key(&self) -> String {
+        format!("refund-batch-{}-{}", self.row_id, self.charge_id)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefundCsvError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for RefundCsvError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for RefundCsvError {}
+
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(x) = chars.next() {
+        match (x, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            ('"', _) => quoted = !quoted,
+            (',', false) => fields.push(String::new()),
+            (x, _) => fields.last_mut().unwrap().push(x),
+        }
+    }
+    fields.into_iter().map(|x| x.trim().to_string()).collect()
+}
+
+/// Parses `row_id,charge_id,amount,reason` rows. A header row starting with
+/// `row_id` is skipped; an empty amount refunds the remaining amount and
+/// reason is one of `duplicate`, `fraudulent` or `requested_by_customer`.
+pub fn parse_refund_csv(input: &str) -> Result<Vec<RefundRequest>, RefundCsvError> {
+    let mut requests = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line_number = i + 1;
+        if line.trim().is_empty() || (i == 0 && line.trim_start().starts_with("row_id")) {
+            continue;
+        }
+        let error = |message: String| RefundCsvError {
+            line: line_number,
+            message,
+        };
+        let fields = split_csv_line(line);
+        if !(2..=4).contains(&fields.len()) {
+            return Err(error(format!("expected 4 columns, got {}", fields.len())));
+        }
+        let row_id = &fields[0];
+        if row_id.is_empty() {
+            return Err(error("row id is empty".to_string()));
+        }
+        let charge_id = &fields[1];
+        if !(charge_id.starts_with("ch_") || charge_id.starts_with("py_")) {
+            return Err(error(format!("{} is not a charge id", charge_id)));
+        }
+        let mut request = RefundRequest::new(row_id.as_str(), charge_id.as_str());
+        match fields.get(2).map(|x| x.as_str()) {
+            None | Some("") => {}
+            Some(x) => match x.parse::<i64>() {
+                Ok(amount) if amount > 0 => request = request.with_amount(amount),
+                _ => return Err(error(format!("invalid amount {}", x))),
+            },
+        }
+        match fields.get(3).map(|x| x.as_str()) {
+            None | Some("") => {}
+            Some(x) => {
+                let reason = serde_json::from_value::<RefundReason>(x.into())
+                    .map_err(|_| error(format!("invalid reason {}", x)))?;
+                request = request.with_reason(reason);
+            }
+        }
+        requests.push(request);
+    }
+    Ok(requests)
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct RefundBatchRow {
+    /// 1-based position in the submitted requests.
+    pub row: usize,
+    pub request: RefundRequest,
+    /// [`CancellableError::Cancelled`] for rows not sent because the batch
+    /// was cancelled.
+    pub result: Result<CommandOutcome, CancellableError>,
+}
+
+#[derive(Debug, Default)]
+#[non_exhaustive]
+pub struct RefundBatchReport {
+    pub rows: Vec<RefundBatchRow>,
+}
+
+impl RefundBatchReport {
+    pub fn succeeded(&self) -> usize {
+        self.rows.iter().filter(|x| x.result.is_ok()).count()
+    }
+
+    pub fn cancelled(&self) -> usize {
+        self.rows
+            .iter()
+            .filter(|x| matches!(x.result, Err(CancellableError::Cancelled)))
+            .count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.rows.len() - self.succeeded() - self.cancelled()
+    }
+
+    /// `row,row_id,charge_id,amount,refund_id,status,error` lines for the
+    /// incident log.
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("row,row_id,charge_id,amount,refund_id,status,error\n");
+        for x in &self.rows {
+            let (refund_id, status, error) = match &x.result {
+                Ok(outcome) => (
+                    outcome.object_id.clone(),
+                    outcome.status.clone().unwrap_or_default(),
+                    String::new(),
+                ),
+                Err(CancellableError::Cancelled) => {
+                    (String::new(), "cancelled".to_string(), String::new())
+                }
+                Err(CancellableError::Stripe(e)) => {
+                    let error = format!("{:?}", e).replace('"', "\"\"");
+                    (
+                        String::new(),
+                        "failed".to_string(),
+                        format!("\"{}\"", error),
+                    )
+                }
+            };
+            out.push_str(&format!(
+                "{},{},{},{},{},{},{}\n",
+                x.row,
+                x.request.row_id,
+                x.request.charge_id,
+                x.request.amount.map(|x| x.to_string()).unwrap_or_default(),
+                refund_id,
+                status,
+                error
+            ));
+        }
+        out
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct RefundParams<'a> {
+    pub(crate) charge: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) amount: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) reason: Option<RefundReason>,
+}
+
+#[derive(Deserialize)]
+struct RawRefund {
+    id: String,
+    status: Option<String>,
+}
+
+pub(crate) async fn refund(
+    stripe_client: &Client,
+    request: &RefundRequest,
+) -> Result<CommandOutcome, StripePaymentError> {
+    let idempotency_key = request.idempotency_key();
+    let client = stripe_client
+        .clone()
+        .with_strategy(RequestStrategy::Idempotent(idempotency_key.clone()));
+    let refund = telemetry::observe(
+        "refunds.create",
+        client.post_form::<RawRefund, _>(
+            "/refunds",
+            RefundParams {
+                charge: &request.charge_id,
+                amount: request.amount,
+                reason: request.reason,
+            },
+        ),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+    Ok(CommandOutcome {
+        idempotency_key,
+        object_id: refund.id,
+        status: refund.status,
+    })
+}
+
+/// Refunds each request with at most `concurrency` requests in flight and
+/// reports the outcome per row, in input order. A failed row doesn't stop the
+/// batch. Rows repeating an earlier row's row id and charge are reported as
+/// failed instead of being sent, since their idempotency key would return the
+/// earlier refund. [`crate::LibStripe::refund_batch`] adds the live-mode
+/// guard, dry-run and key failover.
+#[tracing::instrument(skip(stripe_client, requests), fields(rows = requests.len()))]
+pub async fn refund_batch(
+    stripe_client: &Client,
+    requests: Vec<RefundRequest>,
+    concurrency: usize,
+) -> RefundBatchReport {
+    refund_batch_cancellable(stripe_client, requests, concurrency, None).await
+}
+
+/// Like [`refund_batch`], but once `cancel` fires the rows not yet sent are
+/// reported as cancelled. Refunds already in flight are always completed.
+#[tracing::instrument(skip(stripe_client, requests, cancel), fields(rows = requests.len()))]
+pub async fn refund_batch_cancellable(
+    stripe_client: &Client,
+    requests: Vec<RefundRequest>,
+    concurrency: usize,
+    cancel: Option<&CancellationToken>,
+) -> RefundBatchReport {
+    run_batch(requests, concurrency, cancel, |request| async move {
+        refund(stripe_client, &request).await
+    })
+    .await
+}
+
+pub(crate) async fn run_batch<F, Fut>(
+    requests: Vec<RefundRequest>,
+    concurrency: usize,
+    cancel: Option<&CancellationToken>,
+    refund: F,
+) -> RefundBatchReport
+where
+    F: Fn(RefundRequest) -> Fut,
+    Fut: Future<Output = Result<CommandOutcome, StripePaymentError>>,
+{
+    let mut first_rows = HashMap::new();
+    let rows = stream::iter(requests.into_iter().enumerate())
+        .map(|(i, request)| {
+            let key = request.idempotency_key();
+            let duplicate_of = first_rows.get(&key).copied();
+            if duplicate_of.is_none() {
+                first_rows.insert(key, i + 1);
+            }
+            let refund = &refund;
+            async move {
+                let result = match (duplicate_of, cancel::check(cancel)) {
+                    (_, Err(x)) => Err(x),
+                    (Some(x), Ok(())) => Err(CancellableError::Stripe(
+                        StripePaymentError::from_general(format!("duplicate of row {}", x)),
+                    )),
+                    (None, Ok(())) => refund(request.clone())
+                        .await
+                        .map_err(CancellableError::Stripe),
+                };
+                if let Err(CancellableError::Stripe(x)) = &result {
+                    tracing::warn!("refund of {} failed: {:?}", request.charge_id, x);
+                }
+                RefundBatchRow {
+                    row: i + 1,
+                    request,
+                    result,
+                }
+            }
+        })
+        .buffered(concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    RefundBatchReport { rows }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_refund_csv() {
+        let requests = parse_refund_csv(
+            "row_id,charge_id,amount,reason\nT-1,ch_1,500,requested_by_customer\nT-2,\"ch_2\",,\nT-3,py_3\n",
+        )
+        .unwrap();
+        assert_eq!(
+            requests,
+            vec![
+                RefundRequest::new("T-1", "ch_1")
+                    .with_amount(500)
+                    .with_reason(RefundReason::RequestedByCustomer),
+                RefundRequest::new("T-2", "ch_2"),
+                RefundRequest::new("T-3", "py_3"),
+            ]
+        );
+        assert_ne!(
+            RefundRequest::new("T-1", "ch_1")
+                .with_amount(500)
+                .idempotency_key(),
+            RefundRequest::new("T-2", "ch_1")
+                .with_amount(500)
+                .idempotency_key()
+        );
+        assert_eq!(
+            parse_refund_csv("T-1,ch_1,-5\n").unwrap_err(),
+            RefundCsvError {
+                line: 1,
+                message: "invalid amount -5".to_string()
+            }
+        );
+        assert!(parse_refund_csv("T-1,ch_1,5,oops\n").is_err());
+        assert!(parse_refund_csv(",ch_1,5\n").is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+
+use crate::descriptor::StatementDescriptor;
+use crate::localization::PaymentSheetLocalization;
+use crate::{CreateCustomerDto, CreatePaymentIntentDto, GuestPaymentOptions};
+
+/// Defaults for payments in one currency, optionally narrowed to a country.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct RegionSettings {
+    pub statement_descriptor: Option<StatementDescriptor>,
+    pub locale: Option<String>,
+    pub payment_method_types: Option<Vec<String>>,
+}
+
+impl RegionSettings {
+    pub fn new() -> Self {
+        RegionSettings::default()
+    }
+
+    pub fn with_statement_descriptor(mut self, statement_descriptor: StatementDescriptor) -> Self {
+        self.statement_descriptor = Some(statement_descriptor);
+        self
+    }
+
+    pub fn with_locale(mut self, locale: impl Into<String>) -> Self {
+        self.locale = Some(locale.into());
+        self
+    }
+
+    pub fn with_payment_method_types(mut self, payment_method_types: Vec<String>) -> Self {
+        self.payment_method_types = Some(payment_method_types);
+        self
+    }
+}
+
+/// The country of the billing address, else the delivery address.
+fn country_of(dto: &CreatePaymentIntentDto) -> Option<String> {
+    dto.billing_details
+        .as_ref()
+        .map(|x| x.address.country.clone())
+        .or_else(|| {
+            dto.delivery_address
+                .as_ref()
+                .and_then(|x| x.address.country.clone())
+        })
+}
+
+/// Per-region defaults consulted by [`crate::facade::LibStripe`] before a
+/// customer or payment sheet is created, and by
+/// [`crate::checkout::CreateCheckoutSessionDto::with_region_config`].
+/// Settings for a country and currency win over settings for the currency
+/// alone; values set explicitly on a DTO are never overridden.
+#[derive(Debug, Clone, Default)]
+pub struct RegionConfig {
+    currencies: HashMap<String, RegionSettings>,
+    countries: HashMap<(String, String), RegionSettings>,
+}
+
+impl RegionConfig {
+    pub fn new() -> Self {
+        RegionConfig::default()
+    }
+
+    pub fn with_currency(mut self, currency: &str, settings: RegionSettings) -> Self {
+        self.currencies.insert(currency.to_lowercase(), settings);
+        self
+    }
+
+    pub fn with_country(mut self, country: &str, currency: &str, settings: RegionSettings) -> Self {
+        self.countries
+            .insert((country.to_uppercase(), currency.to_lowercase()), settings);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.currencies.is_empty() && self.countries.is_empty()
+    }
+
+    pub fn settings(&self, country: Option<&str>, currency: &str) -> Option<&RegionSettings> {
+        let currency = currency.to_lowercase();
+        country
+            .and_then(|x| self.countries.get(&(x.to_uppercase(), currency.clone())))
+            .or_else(|| self.currencies.get(&currency))
+    }
+
+    /// Fills in the statement descriptor and payment method types. The
+    /// country is taken from the billing address, else the delivery address.
+    /// Payment method types are only replaced while they are still the
+    /// card-only default.
+    pub fn apply_to_payment_sheet(
+        &self,
+        mut dto: CreatePaymentIntentDto,
+    ) -> CreatePaymentIntentDto {
+        if let Some(settings) = self.settings(country_of(&dto).as_deref(), &dto.currency) {
+            if dto.statement_descriptor.is_none() {
+                dto.statement_descriptor = settings.statement_descriptor.clone();
+            }
+            if let Some(x) = &settings.payment_method_types {
+                if dto.payment_method_types == ["card"] {
+                    dto.payment_method_types = x.clone();
+                }
+            }
+        }
+        dto
+    }
+
+    /// Locale and currency formatting for the payment sheet of `dto`, looked
+    /// up the same way as [`RegionConfig::apply_to_payment_sheet`].
+    pub fn payment_sheet_localization(
+        &self,
+        dto: &CreatePaymentIntentDto,
+    ) -> PaymentSheetLocalization {
+        let localization = PaymentSheetLocalization::new(&dto.currency);
+        match self
+            .settings(country_of(dto).as_deref(), &dto.currency)
+            .and_then(|x| x.locale.as_ref())
+        {
+            Some(x) => localization.with_locale(x),
+            None => localization,
+        }
+    }
+
+    pub fn apply_to_guest_payment(
+        &self,
+        currency: &str,
+        mut options: GuestPaymentOptions,
+    ) -> GuestPaymentOptions {
+        let country = options
+            .delivery_address
+            .as_ref()
+            .and_then(|x| x.address.country.clone());
+        if let Some(settings) = self.settings(country.as_deref(), currency) {
+            if options.statement_descriptor.is_none() {
+                options.statement_descriptor = settings.statement_descriptor.clone();
+            }
+        }
+        options
+    }
+
+    /// Sets the customer's preferred locale for receipts and invoices when
+    /// none was given, looked up by the DTO's currency and country.
+    pub fn apply_to_customer(&self, mut dto: CreateCustomerDto) -> CreateCustomerDto {
+        let currency = match &dto.currency {
+            Some(x) => x,
+            None => return dto,
+        };
+        if dto.preferred_locales.is_none() {
+            dto.preferred_locales = self
+                .settings(dto.country.as_deref(), currency)
+                .and_then(|x| x.locale.clone())
+                .map(|x| vec![x]);
+        }
+        dto
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::{AddressDto, BillingDetailsDto};
+
+    #[test]
+    fn country_settings_win_over_currency() {
+        let config = RegionConfig::new()
+            .with_currency(
+                "EUR",
+                RegionSettings::new()
+                    .with_payment_method_types(vec!["card".into(), "sepa_debit".into()]),
+            )
+            .with_country(
+                "nl",
+                "eur",
+                RegionSettings::new()
+                    .with_payment_method_types(vec!["ideal".into(), "card".into()]),
+            );
+        let dto = config.apply_to_payment_sheet(
+            CreatePaymentIntentDto::new(500, "cus_1", "EUR").with_billing_details(
+                BillingDetailsDto::new(AddressDto::new("Damrak 1", "Amsterdam", "NL")),
+            ),
+        );
+        assert_eq!(dto.payment_method_types, ["ideal", "card"]);
+        let dto = config.apply_to_payment_sheet(CreatePaymentIntentDto::new(500, "cus_1", "eur"));
+        assert_eq!(dto.payment_method_types, ["card", "sepa_debit"]);
+    }
+
+    #[test]
+    fn localizes_payment_sheet_by_region() {
+        let config =
+            RegionConfig::new().with_currency("jpy", RegionSettings::new().with_locale("ja"));
+        let localization =
+            config.payment_sheet_localization(&CreatePaymentIntentDto::new(500, "cus_1", "JPY"));
+        assert_eq!(localization.locale.as_deref(), Some("ja"));
+        assert_eq!(localization.currency_format.decimal_digits, 0);
+        let localization =
+            config.payment_sheet_localization(&CreatePaymentIntentDto::new(500, "cus_1", "eur"));
+        assert_eq!(localization.locale, None);
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use stripe::{Client, StripeError};
+
+use crate::{
+    CreateCustomerDto, CreatePaymentIntentDto, CustomerDto, GuestPaymentIntentDto,
+    GuestPaymentOptions, PaymentIntentDto, PaymentSheetResult, StripePaymentError,
+};
+
+/// Named clients for setups with several Stripe accounts, e.g. one per tenant
+/// or region. The crate-root customer and payment sheet helpers are mirrored
+/// here and take the tenant name; for the helpers in the other modules, pass
+/// the client returned by [`ClientRegistry::client`].
+#[derive(Clone, Default)]
+pub struct ClientRegistry {
+    clients: HashMap<String, Client>,
+    default_tenant: Option<String>,
+}
+
+impl std::fmt::Debug for ClientRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientRegistry")
+            .field("tenants", &self.tenants())
+            .field("default_tenant", &self.default_tenant)
+            .finish()
+    }
+}
+
+impl ClientRegistry {
+    pub fn new() -> Self {
+        ClientRegistry::default()
+    }
+
+    pub fn with_client(mut self, tenant: impl Into<String>, client: Client) -> Self {
+        self.clients.insert(tenant.into(), client);
+        self
+    }
+
+    pub fn with_secret_key(self, tenant: impl Into<String>, secret_key: impl Into<String>) -> Self {
+        self.with_client(tenant, Client::new(secret_key))
+    }
+
+    /// The tenant used when the selector passed to a helper is `None`.
+    pub fn with_default_tenant(mut self, tenant: impl Into<String>) -> Self {
+        self.default_tenant = Some(tenant.into());
+        self
+    }
+
+    pub fn tenants(&self) -> Vec<&str> {
+        let mut tenants = self.clients.keys().map(|x| x.as_str()).collect::<Vec<_>>();
+        tenants.sort_unstable();
+        tenants
+    }
+
+    fn resolve(&self, tenant: Option<&str>) -> Result<&Client, String> {
+        let tenant = tenant
+            .or(self.default_tenant.as_deref())
+            .ok_or_else(|| "no tenant selected and no default tenant".to_string())?;
+        self.clients
+            .get(tenant)
+            .ok_or_else(|| format!("no stripe client registered for tenant {}", tenant))
+    }
+
+    pub fn client(&self, tenant: Option<&str>) -> Result<&Client, StripePaymentError> {
+        self.resolve(tenant)
+            .map_err(StripePaymentError::from_general)
+    }
+
+    pub async fn get_customer(
+        &self,
+        tenant: Option<&str>,
+        account_id: String,
+    ) -> Result<CustomerDto, StripeError> {
+        let client = self.resolve(tenant).map_err(StripeError::ClientError)?;
+        crate::get_customer(client, account_id).await
+    }
+
+    pub async fn create_customer(
+        &self,
+        tenant: Option<&str>,
+        dto: &CreateCustomerDto,
+    ) -> Result<CustomerDto, StripePaymentError> {
+        crate::create_customer(self.client(tenant)?, dto).await
+    }
+
+    pub async fn update_customer_locales(
+        &self,
+        tenant: Option<&str>,
+        stripe_customer_id: &str,
+        preferred_locales: &[String],
+    ) -> Result<CustomerDto, StripePaymentError> {
+        crate::update_customer_locales(self.client(tenant)?, stripe_customer_id, preferred_locales)
+            .await
+    }
+
+    pub async fn set_customer_locale(
+        &self,
+        tenant: Option<&str>,
+        stripe_customer_id: &str,
+        locale: &str,
+    ) -> Result<CustomerDto, StripePaymentError> {
+        crate::set_customer_locale(self.client(tenant)?, stripe_customer_id, locale).await
+    }
+
+    pub async fn create_payment_sheet(
+        &self,
+        tenant: Option<&str>,
+        dto: &CreatePaymentIntentDto,
+    ) -> Result<PaymentIntentDto, StripePaymentError> {
+        crate::create_payment_sheet(self.client(tenant)?, dto).await
+    }
+
+    pub async fn create_payment_sheet_allowing_guest_fallback(
+        &self,
+        tenant: Option<&str>,
+        dto: &CreatePaymentIntentDto,
+    ) -> Result<PaymentSheetResult, StripePaymentError> {
+        crate::create_payment_sheet_allowing_guest_fallback(self.client(tenant)?, dto).await
+    }
+
+    pub async fn create_guest_payment_sheet(
+        &self,
+        tenant: Option<&str>,
+        amount: i64,
+        currency: &str,
+        options: &GuestPaymentOptions,
+    ) -> Result<GuestPaymentIntentDto, StripePaymentError> {
+        crate::create_guest_payment_sheet(self.client(tenant)?, amount, currency, options).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_named_and_default_tenants() {
+        let registry = ClientRegistry::new()
+            .with_secret_key("eu", "sk_test_eu")
+            .with_secret_key("us", "sk_test_us")
+            .with_default_tenant("eu");
+        assert_eq!(registry.tenants(), vec!["eu", "us"]);
+        assert!(registry.resolve(Some("us")).is_ok());
+        assert!(registry.resolve(None).is_ok());
+        assert!(registry.resolve(Some("apac")).is_err());
+        assert!(ClientRegistry::new().resolve(None).is_err());
+    }
+}
@@ -0,0 +1,283 @@
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use serde::Serialize;
+use stripe::{CreateEphemeralKey, CustomerId};
+
+use crate::address::{checked_billing_details, checked_shipping};
+use crate::command::{
+    self, CancelParams, CaptureParams, OutboxEntry, RefundParams, StripeCommand, TransferParams,
+};
+use crate::dry_run;
+use crate::level3::checked_level3;
+use crate::refund_batch::{self, RefundRequest};
+use crate::url::StripeUrl;
+use crate::{
+    customer_params, guest_payment_intent_params, payment_intent_params, CreateCustomerDto,
+    CreatePaymentIntentDto, GuestPaymentOptions, StripePaymentError,
+};
+
+/// A request the crate would have sent to Stripe, with its form parameters as
+/// JSON.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[non_exhaustive]
+pub struct ShadowRequest {
+    /// Same names as the telemetry endpoint label, e.g.
+    /// `payment_intents.create`.
+    pub endpoint: &'static str,
+    pub method: &'static str,
+    pub path: String,
+    pub params: serde_json::Value,
+}
+
+fn shadow_request(
+    endpoint: &'static str,
+    path: String,
+    params: impl Serialize,
+) -> Result<ShadowRequest, StripePaymentError> {
+    Ok(ShadowRequest {
+        endpoint,
+        method: "POST",
+        path,
+        params: serde_json::to_value(params)
+            .map_err(|x| StripePaymentError::from_general(x.to_string()))?,
+    })
+}
+
+/// The request `create_customer` would make for `dto`, after the same local
+/// validation as in dry-run mode.
+pub fn customer_requests(
+    dto: &CreateCustomerDto,
+) -> Result<Vec<ShadowRequest>, StripePaymentError> {
+    dry_run::create_customer(dto)?;
+    Ok(vec![shadow_request(
+        "customers.create",
+        "/customers".to_string(),
+        customer_params(dto),
+    )?])
+}
+
+/// The requests `create_payment_sheet` would make for `dto`, in order, after
+/// the same local validation.
+pub fn payment_sheet_requests(
+    dto: &CreatePaymentIntentDto,
+) -> Result<Vec<ShadowRequest>, StripePaymentError> {
+    let shipping = checked_shipping(&dto.delivery_address)?;
+    let billing_details = checked_billing_details(&dto.billing_details)?;
+    dto.validate_settlement()?;
+    checked_level3(&dto.level3, dto.amount)?;
+    let stripe_customer_id = CustomerId::from_str(dto.stripe_customer_id.as_str())
+        .map_err(|x| StripePaymentError::from_general(x.to_string()))?;
+    let mut requests = Vec::new();
+    if let Some(billing_details) = &billing_details {
+        requests.push(shadow_request(
+            "customers.update",
+            StripeUrl::new("/customers")
+                .segment(&dto.stripe_customer_id)
+                .build(),
+            billing_details,
+        )?);
+    }
+    requests.push(shadow_request(
+        "ephemeral_keys.create",
+        "/ephemeral_keys".to_string(),
+        CreateEphemeralKey {
+            customer: Some(stripe_customer_id.clone()),
+            expand: &[],
+            issuing_card: None,
+        },
+    )?);
+    requests.push(shadow_request(
+        "payment_intents.create",
+        "/payment_intents".to_string(),
+        payment_intent_params(dto, stripe_customer_id, shipping)?,
+    )?);
+    if let Some(level3) = &dto.level3 {
+        // The intent id is only known once Stripe created it.
+        requests.push(shadow_request(
+            "payment_intents.update",
+            "/payment_intents/{id}".to_string(),
+            serde_json::json!({ "level3": level3 }),
+        )?);
+    }
+    Ok(requests)
+}
+
+/// The request `create_guest_payment_sheet` would make.
+pub fn guest_payment_sheet_requests(
+    amount: i64,
+    currency: &str,
+    options: &GuestPaymentOptions,
+) -> Result<Vec<ShadowRequest>, StripePaymentError> {
+    let shipping = checked_shipping(&options.delivery_address)?;
+    Ok(vec![shadow_request(
+        "payment_intents.create",
+        "/payment_intents".to_string(),
+        guest_payment_intent_params(amount, currency, options, shipping)?,
+    )?])
+}
+
+/// The request `execute_command` would make for `entry`. The idempotency key
+/// is sent as a header, so it is not part of the parameters.
+pub fn command_requests(entry: &OutboxEntry) -> Result<Vec<ShadowRequest>, StripePaymentError> {
+    let endpoint = command::endpoint(&entry.command);
+    let request = match &entry.command {
+        StripeCommand::CreateRefund {
+            payment_intent_id,
+            amount,
+            reason,
+        } => shadow_request(
+            endpoint,
+            "/refunds".to_string(),
+            RefundParams {
+                payment_intent: payment_intent_id,
+                amount: *amount,
+                reason: *reason,
+            },
+        ),
+        StripeCommand::CancelIntent {
+            payment_intent_id,
+            reason,
+        } => shadow_request(
+            endpoint,
+            StripeUrl::new("/payment_intents")
+                .segment(payment_intent_id)
+                .segment("cancel")
+                .build(),
+            CancelParams {
+                cancellation_reason: *reason,
+            },
+        ),
+        StripeCommand::CapturePayment {
+            payment_intent_id,
+            amount_to_capture,
+        } => shadow_request(
+            endpoint,
+            StripeUrl::new("/payment_intents")
+                .segment(payment_intent_id)
+                .segment("capture")
+                .build(),
+            CaptureParams {
+                amount_to_capture: *amount_to_capture,
+            },
+        ),
+        StripeCommand::CreateTransfer {
+            amount,
+            currency,
+            destination,
+            transfer_group,
+        } => shadow_request(
+            endpoint,
+            "/transfers".to_string(),
+            TransferParams {
+                amount: *amount,
+                currency: currency.to_lowercase(),
+                destination,
+                transfer_group: transfer_group.as_deref(),
+            },
+        ),
+    }?;
+    Ok(vec![request])
+}
+
+/// The request `refund_batch` would make for `request`.
+pub fn refund_requests(request: &RefundRequest) -> Result<Vec<ShadowRequest>, StripePaymentError> {
+    Ok(vec![shadow_request(
+        "refunds.create",
+        "/refunds".to_string(),
+        refund_batch::RefundParams {
+            charge: &request.charge_id,
+            amount: request.amount,
+            reason: request.reason,
+        },
+    )?])
+}
+
+/// Receives shadow requests instead of Stripe. Implement it to persist them
+/// next to the request sent to the current provider, so the mapping can be
+/// compared on production traffic before switching over.
+#[async_trait]
+pub trait ShadowSink: Send + Sync {
+    async fn write(&self, requests: &[ShadowRequest]) -> Result<(), StripePaymentError>;
+}
+
+/// Logs each request at info level.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TracingShadowSink;
+
+#[async_trait]
+impl ShadowSink for TracingShadowSink {
+    async fn write(&self, requests: &[ShadowRequest]) -> Result<(), StripePaymentError> {
+        for x in requests {
+            tracing::info!(
+                endpoint = x.endpoint,
+                "shadow {} {} {}",
+                x.method,
+                x.path,
+                x.params
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct InMemoryShadowSink {
+    requests: Mutex<Vec<ShadowRequest>>,
+}
+
+impl InMemoryShadowSink {
+    pub fn new() -> Self {
+        InMemoryShadowSink::default()
+    }
+
+    pub fn requests(&self) -> Vec<ShadowRequest> {
+        self.requests.lock().map(|x| x.clone()).unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl ShadowSink for InMemoryShadowSink {
+    async fn write(&self, requests: &[ShadowRequest]) -> Result<(), StripePaymentError> {
+        self.requests
+            .lock()
+            .map_err(|x| StripePaymentError::from_general(x.to_string()))?
+            .extend_from_slice(requests);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_payment_sheet_payloads() {
+        let requests =
+            payment_sheet_requests(&CreatePaymentIntentDto::new(500, "cus_ABC123", "EUR")).unwrap();
+        let endpoints = requests.iter().map(|x| x.endpoint).collect::<Vec<_>>();
+        assert_eq!(
+            endpoints,
+            vec!["ephemeral_keys.create", "payment_intents.create"]
+        );
+        assert_eq!(requests[1].params["amount"], 500);
+        assert_eq!(requests[1].params["currency"], "eur");
+        assert_eq!(requests[1].params["customer"], "cus_ABC123");
+    }
+
+    #[test]
+    fn builds_command_payloads() {
+        let requests = command_requests(&OutboxEntry::new(
+            "order-1-cancel",
+            StripeCommand::CancelIntent {
+                payment_intent_id: "pi_1".to_string(),
+                reason: None,
+            },
+        ))
+        .unwrap();
+        assert_eq!(requests[0].endpoint, "payment_intents.cancel");
+        assert_eq!(requests[0].path, "/payment_intents/pi_1/cancel");
+        assert_eq!(requests[0].params, serde_json::json!({}));
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+#[cfg(feature = "runtime-tokio-hyper")]
+use stripe::Client;
+
+#[cfg(feature = "runtime-tokio-hyper")]
+use crate::PaymentIntentDto;
+use crate::{CreatePaymentIntentDto, StripePaymentError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpendingViolation {
+    MaxAmountPerIntent {
+        limit: i64,
+        requested: i64,
+    },
+    DailyLimit {
+        customer_id: String,
+        currency: String,
+        limit: i64,
+        spent: i64,
+        requested: i64,
+    },
+    /// Raised by application policies that don't fit the built-in variants.
+    Other(String),
+}
+
+impl Display for SpendingViolation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpendingViolation::MaxAmountPerIntent { limit, requested } => write!(
+                f,
+                "amount {} exceeds the per payment limit of {}",
+                requested, limit
+            ),
+            SpendingViolation::DailyLimit {
+                customer_id,
+                currency,
+                limit,
+                spent,
+                requested,
+            } => write!(
+                f,
+                "amount {} would exceed the daily {} limit of {} for {}, {} already spent",
+                requested, currency, limit, customer_id, spent
+            ),
+            SpendingViolation::Other(x) => f.write_str(x),
+        }
+    }
+}
+
+impl std::error::Error for SpendingViolation {}
+
+/// The payment a policy is asked about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SpendingRequest<'a> {
+    pub amount: i64,
+    pub currency: &'a str,
+    /// `None` for guest payments.
+    pub customer_id: Option<&'a str>,
+}
+
+impl<'a> SpendingRequest<'a> {
+    pub fn guest(amount: i64, currency: &'a str) -> Self {
+        SpendingRequest {
+            amount,
+            currency,
+            customer_id: None,
+        }
+    }
+}
+
+impl<'a> From<&'a CreatePaymentIntentDto> for SpendingRequest<'a> {
+    fn from(x: &'a CreatePaymentIntentDto) -> Self {
+        SpendingRequest {
+            amount: x.amount,
+            currency: &x.currency,
+            customer_id: Some(&x.stripe_customer_id),
+        }
+    }
+}
+
+/// Consulted before a payment intent is created. Policies that track spending
+/// reserve the amount in `check`, in the same step as the comparison, so
+/// concurrent payments can't both pass; `release` hands the reservation back
+/// when the intent could not be created.
+#[async_trait]
+pub trait SpendingPolicy: Send + Sync {
+    async fn check(&self, request: SpendingRequest<'_>) -> Result<(), SpendingViolation>;
+
+    async fn release(&self, _request: SpendingRequest<'_>) {}
+}
+
+/// Caps the amount of a single payment intent, in the currency's smallest
+/// unit.
+#[derive(Debug, Clone, Copy)]
+pub struct MaxAmountPolicy {
+    limit: i64,
+}
+
+impl MaxAmountPolicy {
+    pub fn new(limit: i64) -> Self {
+        MaxAmountPolicy { limit }
+    }
+}
+
+#[async_trait]
+impl SpendingPolicy for MaxAmountPolicy {
+    async fn check(&self, request: SpendingRequest<'_>) -> Result<(), SpendingViolation> {
+        match request.amount > self.limit {
+            true => Err(SpendingViolation::MaxAmountPerIntent {
+                limit: self.limit,
+                requested: request.amount,
+            }),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Caps what a customer can spend per UTC day and currency. Guest payments
+/// have no customer and are not limited. Spending is kept in memory, so the
+/// limit is per process; implement [`SpendingPolicy`] on the application
+/// database to enforce it across instances.
+#[derive(Debug)]
+pub struct DailyLimitPolicy {
+    limit: i64,
+    spent: Mutex<HashMap<(String, String), (u64, i64)>>,
+}
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() / 86_400)
+        .unwrap_or_default()
+}
+
+impl DailyLimitPolicy {
+    pub fn new(limit: i64) -> Self {
+        DailyLimitPolicy {
+            limit,
+            spent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn spent_on(&self, customer_id: &str, currency: &str, day: u64) -> i64 {
+        self.spent
+            .lock()
+            .ok()
+            .and_then(|x| {
+                x.get(&(customer_id.to_string(), currency.to_lowercase()))
+                    .copied()
+            })
+            .filter(|(x, _)| *x == day)
+            .map(|(_, spent)| spent)
+            .unwrap_or_default()
+    }
+
+    /// Reserves the amount unless it would exceed the limit. `day` counts
+    /// days since the Unix epoch.
+    pub fn check_on(
+        &self,
+        request: SpendingRequest<'_>,
+        day: u64,
+    ) -> Result<(), SpendingViolation> {
+        let customer_id = match request.customer_id {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let currency = request.currency.to_lowercase();
+        let mut spent = self
+            .spent
+            .lock()
+            .map_err(|x| SpendingViolation::Other(x.to_string()))?;
+        let entry = spent
+            .entry((customer_id.to_string(), currency.clone()))
+            .or_insert((day, 0));
+        if entry.0 != day {
+            *entry = (day, 0);
+        }
+        let total = entry.1.checked_add(request.amount);
+        if total.map_or(true, |x| x > self.limit) {
+            return Err(SpendingViolation::DailyLimit {
+                customer_id: customer_id.to_string(),
+                currency,
+                limit: self.limit,
+                spent: entry.1,
+                requested: request.amount,
+            });
+        }
+        entry.1 = total.unwrap_or_default();
+        Ok(())
+    }
+
+    pub fn release_on(&self, request: SpendingRequest<'_>, day: u64) {
+        let customer_id = match request.customer_id {
+            Some(x) => x,
+            None => return,
+        };
+        if let Ok(mut spent) = self.spent.lock() {
+            let key = (customer_id.to_string(), request.currency.to_lowercase());
+            if let Some(entry) = spent.get_mut(&key).filter(|x| x.0 == day) {
+                entry.1 = (entry.1 - request.amount).max(0);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl SpendingPolicy for DailyLimitPolicy {
+    async fn check(&self, request: SpendingRequest<'_>) -> Result<(), SpendingViolation> {
+        self.check_on(request, today())
+    }
+
+    async fn release(&self, request: SpendingRequest<'_>) {
+        self.release_on(request, today())
+    }
+}
+
+#[cfg(feature = "runtime-tokio-hyper")]
+/// Like [`crate::create_payment_sheet`], but refuses to create the intent
+/// when `policy` rejects it.
+#[tracing::instrument(skip(stripe_client, policy))]
+pub async fn create_payment_sheet_with_policy(
+    stripe_client: &Client,
+    dto: &CreatePaymentIntentDto,
+    policy: &dyn SpendingPolicy,
+) -> Result<PaymentIntentDto, StripePaymentError> {
+    policy.check(dto.into()).await?;
+    match crate::create_payment_sheet(stripe_client, dto).await {
+        Ok(x) => Ok(x),
+        Err(x) => {
+            policy.release(dto.into()).await;
+            Err(x)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn daily_limit_resets_each_day() {
+        let policy = DailyLimitPolicy::new(1000);
+        let dto = CreatePaymentIntentDto::new(600, "cus_1", "EUR");
+        assert!(policy.check_on((&dto).into(), 1).is_ok());
+        assert_eq!(policy.spent_on("cus_1", "eur", 1), 600);
+        assert_eq!(
+            policy.check_on((&dto).into(), 1),
+            Err(SpendingViolation::DailyLimit {
+                customer_id: "cus_1".to_string(),
+                currency: "eur".to_string(),
+                limit: 1000,
+                spent: 600,
+                requested: 600,
+            })
+        );
+        assert_eq!(policy.spent_on("cus_1", "eur", 1), 600);
+        assert!(policy
+            .check_on(
+                (&CreatePaymentIntentDto::new(600, "cus_1", "usd")).into(),
+                1
+            )
+            .is_ok());
+        assert!(policy.check_on((&dto).into(), 2).is_ok());
+        policy.release_on((&dto).into(), 2);
+        assert_eq!(policy.spent_on("cus_1", "eur", 2), 0);
+        assert!(policy
+            .check_on(SpendingRequest::guest(5000, "eur"), 2)
+            .is_ok());
+    }
+
+    #[test]
+    fn daily_limit_treats_overflow_as_a_violation() {
+        let policy = DailyLimitPolicy::new(i64::MAX);
+        let dto = CreatePaymentIntentDto::new(i64::MAX - 10, "cus_1", "eur");
+        assert!(policy.check_on((&dto).into(), 1).is_ok());
+        assert!(matches!(
+            policy.check_on((&CreatePaymentIntentDto::new(20, "cus_1", "eur")).into(), 1),
+            Err(SpendingViolation::DailyLimit { spent, .. }) if spent == i64::MAX - 10
+        ));
+        assert_eq!(policy.spent_on("cus_1", "eur", 1), i64::MAX - 10);
+        assert_eq!(
+            StripePaymentError::from(SpendingViolation::MaxAmountPerIntent {
+                limit: 100,
+                requested: 200,
+            })
+            .to_string(),
+            "spending policy violated: amount 200 exceeds the per payment limit of 100"
+        );
+    }
+}
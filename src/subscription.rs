@@ -0,0 +1,722 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use stripe::Client;
+
+use crate::invoice::{check_collection, CollectionMethod};
+use crate::pagination::RawList;
+use crate::telemetry;
+use crate::url::StripeUrl;
+use crate::StripePaymentError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PauseBehavior {
+    KeepAsDraft,
+    MarkUncollectible,
+    Void,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProrationBehavior {
+    CreateProrations,
+    AlwaysInvoice,
+    None,
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct SubscriptionItemDto {
+    pub id: String,
+    pub price_id: String,
+    pub quantity: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct SubscriptionDto {
+    pub id: String,
+    pub stripe_customer_id: String,
+    pub status: String,
+    pub cancel_at_period_end: bool,
+    pub current_period_start: i64,
+    pub current_period_end: i64,
+    pub pause_behavior: Option<String>,
+    pub resumes_at: Option<i64>,
+    pub latest_invoice_status: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawPauseCollection {
+    behavior: String,
+    resumes_at: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RawSubscription {
+    id: String,
+    customer: String,
+    status: String,
+    #[serde(default)]
+    cancel_at_period_end: bool,
+    current_period_start: i64,
+    current_period_end: i64,
+    pause_collection: Option<RawPauseCollection>,
+    latest_invoice: Option<serde_json::Value>,
+}
+
+impl From<RawSubscription> for SubscriptionDto {
+    fn from(x: RawSubscription) -> Self {
+        SubscriptionDto {
+            id: x.id,
+            stripe_customer_id: x.customer,
+            status: x.status,
+            cancel_at_period_end: x.cancel_at_period_end,
+            current_period_start: x.current_period_start,
+            current_period_end: x.current_period_end,
+            resumes_at: x.pause_collection.as_ref().and_then(|x| x.resumes_at),
+            pause_behavior: x.pause_collection.map(|x| x.behavior),
+            latest_invoice_status: x
+                .latest_invoice
+                .as_ref()
+                .and_then(|x| x.get("status"))
+                .and_then(|x| x.as_str())
+                .map(|x| x.to_string()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawPrice {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct RawSubscriptionItem {
+    id: String,
+    price: RawPrice,
+    quantity: Option<u64>,
+}
+
+impl From<RawSubscriptionItem> for SubscriptionItemDto {
+    fn from(x: RawSubscriptionItem) -> Self {
+        SubscriptionItemDto {
+            id: x.id,
+            price_id: x.price.id,
+            quantity: x.quantity,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SubscriptionItemsParams<'a> {
+    subscription: &'a str,
+    limit: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    starting_after: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct QuantityParams {
+    quantity: u64,
+    proration_behavior: ProrationBehavior,
+}
+
+#[derive(Serialize)]
+struct AddItemParams<'a> {
+    subscription: &'a str,
+    price: &'a str,
+    quantity: u64,
+    proration_behavior: ProrationBehavior,
+}
+
+#[derive(Serialize)]
+struct RemoveItemParams {
+    proration_behavior: ProrationBehavior,
+}
+
+#[derive(Deserialize)]
+struct RawDeleted {
+    id: String,
+}
+
+#[derive(Serialize)]
+struct PauseCollection {
+    behavior: PauseBehavior,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resumes_at: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct PauseParams {
+    pause_collection: PauseCollection,
+}
+
+#[derive(Serialize)]
+struct ResumeParams {
+    pause_collection: &'static str,
+}
+
+#[derive(Serialize)]
+struct ExpandParams<'a> {
+    expand: &'a [&'a str],
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn get_subscription(
+    stripe_client: &Client,
+    subscription_id: &str,
+) -> Result<SubscriptionDto, StripePaymentError> {
+    telemetry::observe(
+        "subscriptions.retrieve",
+        stripe_client.get_query::<RawSubscription, _>(
+            &StripeUrl::new("/subscriptions")
+                .segment(subscription_id)
+                .build(),
+            ExpandParams {
+                expand: &["latest_invoice"],
+            },
+        ),
+    )
+    .await
+    .map(SubscriptionDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CreateSubscriptionDto {
+    pub stripe_customer_id: String,
+    pub items: Vec<(String, u64)>,
+    pub collection_method: CollectionMethod,
+    pub days_until_due: Option<u32>,
+    /// Restricts how renewals may be paid; the customer's invoice settings
+    /// decide otherwise.
+    pub payment_method_types: Option<Vec<String>>,
+}
+
+impl CreateSubscriptionDto {
+    pub fn new(stripe_customer_id: impl Into<String>, price_id: impl Into<String>) -> Self {
+        CreateSubscriptionDto {
+            stripe_customer_id: stripe_customer_id.into(),
+            items: vec![(price_id.into(), 1)],
+            collection_method: CollectionMethod::ChargeAutomatically,
+            days_until_due: None,
+            payment_method_types: None,
+        }
+    }
+
+    pub fn with_item(mut self, price_id: impl Into<String>, quantity: u64) -> Self {
+        self.items.push((price_id.into(), quantity));
+        self
+    }
+
+    pub fn with_collection_method(mut self, collection_method: CollectionMethod) -> Self {
+        self.collection_method = collection_method;
+        self
+    }
+
+    /// Invoices each renewal instead of charging the default payment method,
+    /// due `days_until_due` days after the invoice is finalized.
+    pub fn with_send_invoice(mut self, days_until_due: u32) -> Self {
+        self.collection_method = CollectionMethod::SendInvoice;
+        self.days_until_due = Some(days_until_due);
+        self
+    }
+
+    /// E.g. `bacs_debit` or `au_becs_debit` for UK and Australian customers
+    /// paying by direct debit; the customer needs a payment method with an
+    /// active mandate, see [`crate::mandate`].
+    pub fn with_payment_method_types(mut self, payment_method_types: Vec<String>) -> Self {
+        self.payment_method_types = Some(payment_method_types);
+        self
+    }
+}
+
+#[derive(Serialize)]
+struct CreateItemParams<'a> {
+    price: &'a str,
+    quantity: u64,
+}
+
+#[derive(Serialize)]
+struct PaymentSettingsParams<'a> {
+    payment_method_types: &'a [String],
+}
+
+#[derive(Serialize)]
+struct CreateParams<'a> {
+    customer: &'a str,
+    items: Vec<CreateItemParams<'a>>,
+    collection_method: CollectionMethod,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    days_until_due: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payment_settings: Option<PaymentSettingsParams<'a>>,
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn create_subscription(
+    stripe_client: &Client,
+    dto: &CreateSubscriptionDto,
+) -> Result<SubscriptionDto, StripePaymentError> {
+    check_collection(dto.collection_method, dto.days_until_due)?;
+    telemetry::observe(
+        "subscriptions.create",
+        stripe_client.post_form::<RawSubscription, _>(
+            "/subscriptions",
+            CreateParams {
+                customer: &dto.stripe_customer_id,
+                items: dto
+                    .items
+                    .iter()
+                    .map(|(price, quantity)| CreateItemParams {
+                        price,
+                        quantity: *quantity,
+                    })
+                    .collect(),
+                collection_method: dto.collection_method,
+                days_until_due: dto.days_until_due,
+                payment_settings: dto.payment_method_types.as_ref().map(|x| {
+                    PaymentSettingsParams {
+                        payment_method_types: x,
+                    }
+                }),
+            },
+        ),
+    )
+    .await
+    .map(SubscriptionDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn pause_subscription(
+    stripe_client: &Client,
+    subscription_id: &str,
+    behavior: PauseBehavior,
+    resumes_at: Option<i64>,
+) -> Result<SubscriptionDto, StripePaymentError> {
+    telemetry::observe(
+        "subscriptions.update",
+        stripe_client.post_form::<RawSubscription, _>(
+            &StripeUrl::new("/subscriptions")
+                .segment(subscription_id)
+                .build(),
+            PauseParams {
+                pause_collection: PauseCollection {
+                    behavior,
+                    resumes_at,
+                },
+            },
+        ),
+    )
+    .await
+    .map(SubscriptionDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn resume_subscription(
+    stripe_client: &Client,
+    subscription_id: &str,
+) -> Result<SubscriptionDto, StripePaymentError> {
+    telemetry::observe(
+        "subscriptions.update",
+        stripe_client.post_form::<RawSubscription, _>(
+            &StripeUrl::new("/subscriptions")
+                .segment(subscription_id)
+                .build(),
+            ResumeParams {
+                pause_collection: "",
+            },
+        ),
+    )
+    .await
+    .map(SubscriptionDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+/// Pages through `/subscription_items`, since the list embedded in the
+/// subscription stops after 10 items.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn list_subscription_items(
+    stripe_client: &Client,
+    subscription_id: &str,
+) -> Result<Vec<SubscriptionItemDto>, StripePaymentError> {
+    let mut items = Vec::<SubscriptionItemDto>::new();
+    loop {
+        let list = telemetry::observe(
+            "subscription_items.list",
+            stripe_client.get_query::<RawList<RawSubscriptionItem>, _>(
+                "/subscription_items",
+                SubscriptionItemsParams {
+                    subscription: subscription_id,
+                    limit: 100,
+                    starting_after: items.last().map(|x| x.id.as_str()),
+                },
+            ),
+        )
+        .await
+        .map_err(StripePaymentError::from_general)?;
+        let done = !list.has_more || list.data.is_empty();
+        items.extend(list.data.into_iter().map(SubscriptionItemDto::from));
+        if done {
+            return Ok(items);
+        }
+    }
+}
+
+/// Sets the quantity of the item billing `price_id`, e.g. the number of seats.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn update_subscription_quantity(
+    stripe_client: &Client,
+    subscription_id: &str,
+    price_id: &str,
+    quantity: u64,
+    proration: ProrationBehavior,
+) -> Result<SubscriptionItemDto, StripePaymentError> {
+    let item = list_subscription_items(stripe_client, subscription_id)
+        .await?
+        .into_iter()
+        .find(|x| x.price_id == price_id)
+        .ok_or_else(|| {
+            StripePaymentError::from_general(format!(
+                "subscription {} has no item for price {}",
+                subscription_id, price_id
+            ))
+        })?;
+    telemetry::observe(
+        "subscription_items.update",
+        stripe_client.post_form::<RawSubscriptionItem, _>(
+            &StripeUrl::new("/subscription_items")
+                .segment(&item.id)
+                .build(),
+            QuantityParams {
+                quantity,
+                proration_behavior: proration,
+            },
+        ),
+    )
+    .await
+    .map(SubscriptionItemDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+/// Attaches an add-on price to a running subscription. With
+/// [`ProrationBehavior::AlwaysInvoice`] the prorated amount is charged right
+/// away instead of on the next invoice.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn add_subscription_item(
+    stripe_client: &Client,
+    subscription_id: &str,
+    price_id: &str,
+    quantity: u64,
+    proration: ProrationBehavior,
+) -> Result<SubscriptionItemDto, StripePaymentError> {
+    telemetry::observe(
+        "subscription_items.create",
+        stripe_client.post_form::<RawSubscriptionItem, _>(
+            "/subscription_items",
+            AddItemParams {
+                subscription: subscription_id,
+                price: price_id,
+                quantity,
+                proration_behavior: proration,
+            },
+        ),
+    )
+    .await
+    .map(SubscriptionItemDto::from)
+    .map_err(StripePaymentError::from_general)
+}
+
+fn removable_item(
+    items: Vec<SubscriptionItemDto>,
+    subscription_id: &str,
+    price_id: &str,
+) -> Result<SubscriptionItemDto, StripePaymentError> {
+    if items.len() < 2 {
+        return Err(StripePaymentError::from_general(format!(
+            "can't remove the last item of subscription {}; cancel it instead",
+            subscription_id
+        )));
+    }
+    items
+        .into_iter()
+        .find(|x| x.price_id == price_id)
+        .ok_or_else(|| {
+            StripePaymentError::from_general(format!(
+                "subscription {} has no item for price {}",
+                subscription_id, price_id
+            ))
+        })
+}
+
+/// Detaches the item billing `price_id`. A subscription needs at least one
+/// item, so removing the last one is refused; cancel the subscription instead.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn remove_subscription_item(
+    stripe_client: &Client,
+    subscription_id: &str,
+    price_id: &str,
+    proration: ProrationBehavior,
+) -> Result<SubscriptionItemDto, StripePaymentError> {
+    let items = list_subscription_items(stripe_client, subscription_id).await?;
+    let item = removable_item(items, subscription_id, price_id)?;
+    let deleted = telemetry::observe(
+        "subscription_items.delete",
+        stripe_client.delete_query::<RawDeleted, _>(
+            &StripeUrl::new("/subscription_items")
+                .segment(&item.id)
+                .build(),
+            RemoveItemParams {
+                proration_behavior: proration,
+            },
+        ),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+    tracing::debug!("removed subscription item {}", deleted.id);
+    Ok(item)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CancellationFeedback {
+    CustomerService,
+    LowQuality,
+    MissingFeatures,
+    Other,
+    SwitchedService,
+    TooComplex,
+    TooExpensive,
+    Unused,
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CancelSubscriptionDto {
+    pub subscription_id: String,
+    pub feedback: Option<CancellationFeedback>,
+    pub comment: Option<String>,
+    pub at_period_end: bool,
+    pub retention_coupon: Option<String>,
+}
+
+impl CancelSubscriptionDto {
+    pub fn new(subscription_id: impl Into<String>) -> Self {
+        CancelSubscriptionDto {
+            subscription_id: subscription_id.into(),
+            feedback: None,
+            comment: None,
+            at_period_end: false,
+            retention_coupon: None,
+        }
+    }
+
+    pub fn with_feedback(mut self, feedback: CancellationFeedback) -> Self {
+        self.feedback = Some(feedback);
+        self
+    }
+
+    pub fn with_comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn with_at_period_end(mut self, at_period_end: bool) -> Self {
+        self.at_period_end = at_period_end;
+        self
+    }
+
+    /// Applies the coupon and keeps the subscription instead of cancelling.
+    /// Set this when the customer accepted a retention offer.
+    pub fn with_retention_coupon(mut self, coupon: impl Into<String>) -> Self {
+        self.retention_coupon = Some(coupon.into());
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum CancellationOutcome {
+    Canceled(SubscriptionDto),
+    CancelsAtPeriodEnd(SubscriptionDto),
+    OfferApplied(SubscriptionDto),
+}
+
+#[derive(Serialize)]
+struct CancellationDetails<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    feedback: Option<CancellationFeedback>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct CancelParams<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cancel_at_period_end: Option<bool>,
+    cancellation_details: CancellationDetails<'a>,
+}
+
+#[derive(Serialize)]
+struct CouponParams<'a> {
+    coupon: &'a str,
+}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn cancel_subscription(
+    stripe_client: &Client,
+    dto: &CancelSubscriptionDto,
+) -> Result<CancellationOutcome, StripePaymentError> {
+    let url = StripeUrl::new("/subscriptions")
+        .segment(&dto.subscription_id)
+        .build();
+    if let Some(coupon) = &dto.retention_coupon {
+        return telemetry::observe(
+            "subscriptions.update",
+            stripe_client.post_form::<RawSubscription, _>(&url, CouponParams { coupon }),
+        )
+        .await
+        .map(|x| CancellationOutcome::OfferApplied(SubscriptionDto::from(x)))
+        .map_err(StripePaymentError::from_general);
+    }
+    let details = CancellationDetails {
+        feedback: dto.feedback,
+        comment: dto.comment.as_deref(),
+    };
+    match dto.at_period_end {
+        true => telemetry::observe(
+            "subscriptions.update",
+            stripe_client.post_form::<RawSubscription, _>(
+                &url,
+                CancelParams {
+                    cancel_at_period_end: Some(true),
+                    cancellation_details: details,
+                },
+            ),
+        )
+        .await
+        .map(|x| CancellationOutcome::CancelsAtPeriodEnd(SubscriptionDto::from(x))),
+        false => telemetry::observe(
+            "subscriptions.cancel",
+            stripe_client.delete_query::<RawSubscription, _>(
+                &url,
+                CancelParams {
+                    cancel_at_period_end: None,
+                    cancellation_details: details,
+                },
+            ),
+        )
+        .await
+        .map(|x| CancellationOutcome::Canceled(SubscriptionDto::from(x))),
+    }
+    .map_err(StripePaymentError::from_general)
+}
+
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Entitlement {
+    pub active: bool,
+    pub expires_at: Option<i64>,
+    pub in_grace_period: bool,
+}
+
+pub fn entitlement_for(subscription: &SubscriptionDto) -> Entitlement {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64)
+        .unwrap_or_default();
+    entitlement_at(subscription, DEFAULT_GRACE_PERIOD, now)
+}
+
+/// Access is kept while a renewal payment is being retried, for at most
+/// `grace_period` after the start of the unpaid period.
+pub fn entitlement_at(
+    subscription: &SubscriptionDto,
+    grace_period: Duration,
+    now: i64,
+) -> Entitlement {
+    let grace_ends_at = subscription.current_period_start + grace_period.as_secs() as i64;
+    let renewal_unpaid = subscription.latest_invoice_status.as_deref() == Some("open");
+    match subscription.status.as_str() {
+        "active" | "trialing" if renewal_unpaid => Entitlement {
+            active: now < grace_ends_at,
+            expires_at: Some(grace_ends_at),
+            in_grace_period: now < grace_ends_at,
+        },
+        "active" | "trialing" => Entitlement {
+            active: true,
+            expires_at: match subscription.cancel_at_period_end {
+                true => Some(subscription.current_period_end),
+                false => None,
+            },
+            in_grace_period: false,
+        },
+        "past_due" => Entitlement {
+            active: now < grace_ends_at,
+            expires_at: Some(grace_ends_at),
+            in_grace_period: now < grace_ends_at,
+        },
+        _ => Entitlement {
+            active: false,
+            expires_at: None,
+            in_grace_period: false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscription(status: &str, latest_invoice_status: Option<&str>) -> SubscriptionDto {
+        SubscriptionDto {
+            id: "sub_1".to_string(),
+            stripe_customer_id: "cus_1".to_string(),
+            status: status.to_string(),
+            cancel_at_period_end: false,
+            current_period_start: 1_000,
+            current_period_end: 2_000,
+            pause_behavior: None,
+            resumes_at: None,
+            latest_invoice_status: latest_invoice_status.map(|x| x.to_string()),
+        }
+    }
+
+    #[test]
+    fn past_due_is_active_within_grace_period() {
+        let grace = Duration::from_secs(100);
+        let past_due = subscription("past_due", Some("open"));
+        assert_eq!(
+            entitlement_at(&past_due, grace, 1_050),
+            Entitlement {
+                active: true,
+                expires_at: Some(1_100),
+                in_grace_period: true
+            }
+        );
+        assert!(!entitlement_at(&past_due, grace, 1_100).active);
+        assert!(entitlement_at(&subscription("active", Some("paid")), grace, 5_000).active);
+        assert!(!entitlement_at(&subscription("canceled", None), grace, 1_050).active);
+    }
+
+    #[test]
+    fn refuses_to_remove_last_item() {
+        let item = |id: &str, price_id: &str| SubscriptionItemDto {
+            id: id.to_string(),
+            price_id: price_id.to_string(),
+            quantity: Some(1),
+        };
+        assert!(removable_item(vec![item("si_1", "price_base")], "sub_1", "price_base").is_err());
+        let items = vec![item("si_1", "price_base"), item("si_2", "price_addon")];
+        assert_eq!(
+            removable_item(items, "sub_1", "price_addon").unwrap().id,
+            "si_2"
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use std::future::Future;
+
+use stripe::StripeError;
+
+use crate::permission;
+use crate::StripePaymentError;
+
+/// Coarse error class used as a metrics label.
+pub fn error_class(error: &StripeError) -> &'static str {
+    match error {
+        StripeError::Stripe(x) => match x.http_status {
+            401 | 403 => "authentication",
+            402 => "card",
+            409 => "idempotency",
+            429 => "rate_limit",
+            400..=499 => "invalid_request",
+            _ => "api",
+        },
+        StripeError::Timeout => "timeout",
+        StripeError::JSONSerialize(_) => "serialization",
+        _ => "client",
+    }
+}
+
+/// Runs `request` through [`measure`] and converts its error.
+pub(crate) async fn observe<T, F>(
+    endpoint: &'static str,
+    request: F,
+) -> Result<T, StripePaymentError>
+where
+    F: Future<Output = Result<T, StripeError>>,
+{
+    measure(endpoint, request).await.map_err(Into::into)
+}
+
+/// Records `stripe_requests_total`, `stripe_request_duration` (seconds) and
+/// `stripe_errors_total` labeled by endpoint and error class when the `metrics`
+/// feature is enabled; otherwise just awaits the request.
+async fn measure<T, F>(endpoint: &'static str, request: F) -> Result<T, StripeError>
+where
+    F: Future<Output = Result<T, StripeError>>,
+{
+    #[cfg(feature = "metrics")]
+    {
+        let started = std::time::Instant::now();
+        let result = request.await;
+        metrics::counter!("stripe_requests_total", 1, "endpoint" => endpoint);
+        metrics::histogram!(
+            "stripe_request_duration",
+            started.elapsed().as_secs_f64(),
+            "endpoint" => endpoint
+        );
+        if let Err(x) = &result {
+            metrics::counter!(
+                "stripe_errors_total",
+                1,
+                "endpoint" => endpoint,
+                "error_class" => error_class(x)
+            );
+        }
+        warn_missing_permission(endpoint, &result);
+        result
+    }
+    #[cfg(not(feature = "metrics"))]
+    {
+        let result = request.await;
+        warn_missing_permission(endpoint, &result);
+        result
+    }
+}
+
+/// Names the scope to grant when a restricted key is refused, since Stripe's
+/// message is easy to miss in a wrapped error.
+fn warn_missing_permission<T>(endpoint: &'static str, result: &Result<T, StripeError>) {
+    if let Some((resource, permission)) = result
+        .as_ref()
+        .err()
+        .and_then(permission::missing_permission_of)
+    {
+        tracing::error!(
+            "{}: restricted stripe key lacks {} permission on {}",
+            endpoint,
+            permission,
+            resource
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_errors() {
+        assert_eq!(error_class(&StripeError::Timeout), "timeout");
+        assert_eq!(
+            error_class(&StripeError::ClientError("x".to_string())),
+            "client"
+        );
+    }
+}
@@ -0,0 +1,3 @@
+pub mod cards;
+pub mod seed;
+pub mod webhook;
@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+use stripe::{Client, StripeError};
+
+use crate::decline::DeclineCode;
+use crate::telemetry;
+use crate::{PaymentIntentStatus, StripePaymentError};
+
+/// One of Stripe's documented test cards. `payment_method` is the matching
+/// test PaymentMethod, usable without collecting card details.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TestCard {
+    pub number: &'static str,
+    pub payment_method: &'static str,
+    pub decline_code: Option<&'static str>,
+    pub requires_action: bool,
+}
+
+impl TestCard {
+    pub fn expected_decline(&self) -> Option<DeclineCode> {
+        self.decline_code.map(DeclineCode::parse)
+    }
+}
+
+pub const SUCCESS: TestCard = TestCard {
+    number: "4242424242424242",
+    payment_method: "pm_card_visa",
+    decline_code: None,
+    requires_action: false,
+};
+
+pub const THREE_DS_REQUIRED: TestCard = TestCard {
+    number: "4000002760003184",
+    payment_method: "pm_card_authenticationRequired",
+    decline_code: None,
+    requires_action: true,
+};
+
+pub const DECLINED: TestCard = TestCard {
+    number: "4000000000000002",
+    payment_method: "pm_card_visa_chargeDeclined",
+    decline_code: Some("generic_decline"),
+    requires_action: false,
+};
+
+pub const INSUFFICIENT_FUNDS: TestCard = TestCard {
+    number: "4000000000009995",
+    payment_method: "pm_card_visa_chargeDeclinedInsufficientFunds",
+    decline_code: Some("insufficient_funds"),
+    requires_action: false,
+};
+
+pub const FRAUDULENT: TestCard = TestCard {
+    number: "4100000000000019",
+    payment_method: "pm_card_radarBlock",
+    decline_code: Some("fraudulent"),
+    requires_action: false,
+};
+
+pub const ALL: [TestCard; 5] = [
+    SUCCESS,
+    THREE_DS_REQUIRED,
+    DECLINED,
+    INSUFFICIENT_FUNDS,
+    FRAUDULENT,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[non_exhaustive]
+pub struct TestPaymentIntent {
+    pub id: String,
+    pub status: PaymentIntentStatus,
+    pub amount: i64,
+}
+
+#[derive(Serialize)]
+struct ConfirmParams<'a> {
+    amount: i64,
+    currency: String,
+    payment_method: &'a str,
+    payment_method_types: [&'a str; 1],
+    confirm: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    customer: Option<&'a str>,
+}
+
+/// Creates and confirms a PaymentIntent paid with `card`. Declines come back as
+/// the raw [`StripeError`] so tests can assert on
+/// [`DeclineCode::from_stripe_error`].
+#[tracing::instrument(skip(stripe_client))]
+pub async fn create_confirmed_payment_intent(
+    stripe_client: &Client,
+    card: &TestCard,
+    amount: i64,
+    currency: &str,
+    stripe_customer_id: Option<&str>,
+) -> Result<TestPaymentIntent, StripeError> {
+    telemetry::observe(
+        "payment_intents.create",
+        stripe_client.post_form::<TestPaymentIntent, _>(
+            "/payment_intents",
+            ConfirmParams {
+                amount,
+                currency: currency.to_lowercase(),
+                payment_method: card.payment_method,
+                payment_method_types: ["card"],
+                confirm: true,
+                customer: stripe_customer_id,
+            },
+        ),
+    )
+    .await
+    .map_err(StripePaymentError::into_stripe_error)
+}
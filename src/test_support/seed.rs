@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use stripe::Client;
+
+use crate::metadata::{MetadataNamespace, ACCOUNT_ID};
+use crate::telemetry;
+use crate::url::StripeUrl;
+use crate::StripePaymentError;
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct SeedOptions {
+    pub account_id: Option<String>,
+    pub test_payment_method: String,
+    pub product_name: String,
+    pub unit_amount: i64,
+    pub currency: String,
+    pub interval: String,
+}
+
+impl Default for SeedOptions {
+    fn default() -> Self {
+        SeedOptions {
+            account_id: None,
+            test_payment_method: "pm_card_visa".to_string(),
+            product_name: "lib_stripe test product".to_string(),
+            unit_amount: 1000,
+            currency: "usd".to_string(),
+            interval: "month".to_string(),
+        }
+    }
+}
+
+impl SeedOptions {
+    pub fn new() -> Self {
+        SeedOptions::default()
+    }
+
+    pub fn with_account_id(mut self, account_id: impl Into<String>) -> Self {
+        self.account_id = Some(account_id.into());
+        self
+    }
+
+    pub fn with_test_payment_method(mut self, test_payment_method: impl Into<String>) -> Self {
+        self.test_payment_method = test_payment_method.into();
+        self
+    }
+
+    pub fn with_price(mut self, unit_amount: i64, currency: impl Into<String>) -> Self {
+        self.unit_amount = unit_amount;
+        self.currency = currency.into();
+        self
+    }
+
+    pub fn with_interval(mut self, interval: impl Into<String>) -> Self {
+        self.interval = interval.into();
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SeededAccount {
+    pub customer_id: String,
+    pub payment_method_id: String,
+    pub product_id: String,
+    pub price_id: String,
+    pub subscription_id: String,
+}
+
+#[derive(Deserialize)]
+struct Created {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct Balance {
+    livemode: bool,
+}
+
+#[derive(Serialize)]
+struct CustomerParams {
+    metadata: HashMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct AttachParams<'a> {
+    customer: &'a str,
+}
+
+#[derive(Serialize)]
+struct InvoiceSettings<'a> {
+    default_payment_method: &'a str,
+}
+
+#[derive(Serialize)]
+struct CustomerUpdateParams<'a> {
+    invoice_settings: InvoiceSettings<'a>,
+}
+
+#[derive(Serialize)]
+struct ProductParams<'a> {
+    name: &'a str,
+}
+
+#[derive(Serialize)]
+struct Recurring<'a> {
+    interval: &'a str,
+}
+
+#[derive(Serialize)]
+struct PriceParams<'a> {
+    currency: String,
+    unit_amount: i64,
+    product: &'a str,
+    recurring: Recurring<'a>,
+}
+
+#[derive(Serialize)]
+struct SubscriptionItem<'a> {
+    price: &'a str,
+}
+
+#[derive(Serialize)]
+struct SubscriptionParams<'a> {
+    customer: &'a str,
+    default_payment_method: &'a str,
+    items: Vec<SubscriptionItem<'a>>,
+}
+
+/// Creates a customer with an attached test card, a product, a recurring price
+/// and an active subscription. Refuses to run against a live-mode key.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn seed(
+    stripe_client: &Client,
+    options: &SeedOptions,
+) -> Result<SeededAccount, StripePaymentError> {
+    let balance = telemetry::observe("balance.retrieve", stripe_client.get::<Balance>("/balance"))
+        .await
+        .map_err(StripePaymentError::from_general)?;
+    if balance.livemode {
+        return Err(StripePaymentError::from_general(
+            "refusing to seed with a live mode key".to_string(),
+        ));
+    }
+
+    let mut metadata = HashMap::new();
+    if let Some(account_id) = &options.account_id {
+        MetadataNamespace::default().insert(&mut metadata, ACCOUNT_ID, account_id.clone());
+    }
+    let customer = telemetry::observe(
+        "customers.create",
+        stripe_client.post_form::<Created, _>("/customers", CustomerParams { metadata }),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+
+    let payment_method = telemetry::observe(
+        "payment_methods.attach",
+        stripe_client.post_form::<Created, _>(
+            &StripeUrl::new("/payment_methods")
+                .segment(&options.test_payment_method)
+                .segment("attach")
+                .build(),
+            AttachParams {
+                customer: &customer.id,
+            },
+        ),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+    telemetry::observe(
+        "customers.update",
+        stripe_client.post_form::<Created, _>(
+            &StripeUrl::new("/customers").segment(&customer.id).build(),
+            CustomerUpdateParams {
+                invoice_settings: InvoiceSettings {
+                    default_payment_method: &payment_method.id,
+                },
+            },
+        ),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+
+    let product = telemetry::observe(
+        "products.create",
+        stripe_client.post_form::<Created, _>(
+            "/products",
+            ProductParams {
+                name: &options.product_name,
+            },
+        ),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+    let price = telemetry::observe(
+        "prices.create",
+        stripe_client.post_form::<Created, _>(
+            "/prices",
+            PriceParams {
+                currency: options.currency.to_lowercase(),
+                unit_amount: options.unit_amount,
+                product: &product.id,
+                recurring: Recurring {
+                    interval: &options.interval,
+                },
+            },
+        ),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+
+    let subscription = telemetry::observe(
+        "subscriptions.create",
+        stripe_client.post_form::<Created, _>(
+            "/subscriptions",
+            SubscriptionParams {
+                customer: &customer.id,
+                default_payment_method: &payment_method.id,
+                items: vec![SubscriptionItem { price: &price.id }],
+            },
+        ),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+
+    Ok(SeededAccount {
+        customer_id: customer.id,
+        payment_method_id: payment_method.id,
+        product_id: product.id,
+        price_id: price.id,
+        subscription_id: subscription.id,
+    })
+}
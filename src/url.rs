@@ -0,0 +1,82 @@
+use std::fmt::{Display, Formatter};
+
+use crate::StripePaymentError;
+
+fn encode(value: &str, out: &mut String) {
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(byte as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+}
+
+/// Builds a path relative to the client's base URL, percent-encoding path
+/// segments. Use it with `Client::get` for endpoints this crate doesn't wrap.
+/// Query parameters can't be part of the path, since the client escapes `?`;
+/// pass them as a params struct to `Client::get_query` instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StripeUrl {
+    path: String,
+}
+
+impl StripeUrl {
+    pub fn new(path: &str) -> Self {
+        StripeUrl {
+            path: path.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn segment(mut self, segment: &str) -> Self {
+        self.path.push('/');
+        encode(segment, &mut self.path);
+        self
+    }
+
+    pub fn build(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl Display for StripeUrl {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.path)
+    }
+}
+
+/// Checks that `id` looks like a Stripe id with `prefix`, e.g. `acct_1A2b`,
+/// before it's sent as a path segment or parameter.
+pub fn validate_id(id: &str, prefix: &str) -> Result<(), StripePaymentError> {
+    let valid = id
+        .strip_prefix(prefix)
+        .and_then(|x| x.strip_prefix('_'))
+        .map(|x| !x.is_empty() && x.chars().all(|x| x.is_ascii_alphanumeric()))
+        .unwrap_or(false);
+    match valid {
+        true => Ok(()),
+        false => Err(StripePaymentError::from_general(format!(
+            "{} is not a valid {}_ id",
+            id, prefix
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_segments() {
+        assert_eq!(
+            StripeUrl::new("/subscriptions/")
+                .segment("sub_1/../x?expand[]=y")
+                .build(),
+            "/subscriptions/sub_1%2F..%2Fx%3Fexpand%5B%5D%3Dy"
+        );
+        assert!(validate_id("acct_1A2b", "acct").is_ok());
+        assert!(validate_id("acct_", "acct").is_err());
+        assert!(validate_id("acct_1/../x", "acct").is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::edge::{HttpExecutor, HttpMethod, HttpRequest, HttpResponse};
+
+const SECRET_PREFIXES: [&str; 6] = [
+    "sk_live_", "sk_test_", "rk_live_", "rk_test_", "whsec_", "ek_",
+];
+const SECRET_INFIXES: [&str; 1] = ["_secret_"];
+
+fn is_token_char(x: char) -> bool {
+    x.is_ascii_alphanumeric() || x == '_'
+}
+
+/// Replaces API keys, webhook secrets, ephemeral keys and client secrets with
+/// placeholders so cassettes can be committed.
+pub fn redact(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    'outer: while !rest.is_empty() {
+        let at_boundary = !out.ends_with(is_token_char);
+        for prefix in SECRET_PREFIXES
+            .iter()
+            .filter(|_| at_boundary)
+            .chain(SECRET_INFIXES.iter())
+        {
+            if rest.starts_with(prefix) {
+                out.push_str(prefix);
+                out.push_str("REDACTED");
+                rest = rest[prefix.len()..].trim_start_matches(is_token_char);
+                continue 'outer;
+            }
+        }
+        let next = rest.chars().next().unwrap();
+        out.push(next);
+        rest = &rest[next.len_utf8()..];
+    }
+    out
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Interaction {
+    pub method: String,
+    pub url: String,
+    pub body: Option<String>,
+    pub status: u16,
+    pub response: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VcrMode {
+    Record,
+    Replay,
+    /// Replays when the cassette file exists, records otherwise.
+    Auto,
+}
+
+fn method_name(method: HttpMethod) -> &'static str {
+    match method {
+        HttpMethod::Get => "GET",
+        HttpMethod::Post => "POST",
+        HttpMethod::Delete => "DELETE",
+    }
+}
+
+#[derive(Default)]
+struct Cassette {
+    interactions: Vec<Interaction>,
+    cursor: usize,
+}
+
+/// Wraps an [`HttpExecutor`], recording interactions to a JSON cassette on the
+/// first run and replaying them afterwards, so tests of code built on the edge
+/// client are fast and deterministic. Call [`VcrExecutor::save`] after recording.
+pub struct VcrExecutor<E> {
+    inner: Option<E>,
+    path: PathBuf,
+    recording: bool,
+    cassette: Mutex<Cassette>,
+}
+
+impl<E> std::fmt::Debug for VcrExecutor<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VcrExecutor")
+            .field("path", &self.path)
+            .field("recording", &self.recording)
+            .finish()
+    }
+}
+
+impl<E: HttpExecutor> VcrExecutor<E> {
+    /// `inner` may be `None` when only replaying.
+    pub fn new(path: impl Into<PathBuf>, mode: VcrMode, inner: Option<E>) -> Result<Self, String> {
+        let path = path.into();
+        let recording = match mode {
+            VcrMode::Record => true,
+            VcrMode::Replay => false,
+            VcrMode::Auto => !path.exists(),
+        };
+        let interactions = match recording {
+            true => vec![],
+            false => {
+                let data = std::fs::read_to_string(&path).map_err(|x| x.to_string())?;
+                serde_json::from_str(&data).map_err(|x| x.to_string())?
+            }
+        };
+        if recording && inner.is_none() {
+            return Err("recording needs an inner executor".to_string());
+        }
+        Ok(VcrExecutor {
+            inner,
+            path,
+            recording,
+            cassette: Mutex::new(Cassette {
+                interactions,
+                cursor: 0,
+            }),
+        })
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        if !self.recording {
+            return Ok(());
+        }
+        let cassette = self.cassette.lock().unwrap();
+        let data =
+            serde_json::to_string_pretty(&cassette.interactions).map_err(|x| x.to_string())?;
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).map_err(|x| x.to_string())?;
+        }
+        std::fs::write(&self.path, data).map_err(|x| x.to_string())
+    }
+}
+
+#[async_trait(?Send)]
+impl<E: HttpExecutor> HttpExecutor for VcrExecutor<E> {
+    async fn execute(&self, request: HttpRequest) -> Result<HttpResponse, String> {
+        let method = method_name(request.method).to_string();
+        let url = redact(&request.url);
+        let body = request.body.as_deref().map(redact);
+        if !self.recording {
+            let mut cassette = self.cassette.lock().unwrap();
+            let cursor = cassette.cursor;
+            let interaction = cassette
+                .interactions
+                .get(cursor)
+                .filter(|x| x.method == method && x.url == url && x.body == body)
+                .cloned()
+                .ok_or_else(|| {
+                    format!("no recorded interaction #{} for {} {}", cursor, method, url)
+                })?;
+            cassette.cursor += 1;
+            return Ok(HttpResponse::new(interaction.status, interaction.response));
+        }
+        let response = self.inner.as_ref().unwrap().execute(request).await?;
+        self.cassette
+            .lock()
+            .unwrap()
+            .interactions
+            .push(Interaction {
+                method,
+                url,
+                body,
+                status: response.status,
+                response: redact(&response.body),
+            });
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_secrets() {
+        assert_eq!(
+            redact(r#"{"id":"pi_1","client_secret":"pi_1_secret_abc","key":"sk_test_123"}"#),
+            r#"{"id":"pi_1","client_secret":"pi_1_secret_REDACTED","key":"sk_test_REDACTED"}"#
+        );
+        assert_eq!(redact("per_week_plan"), "per_week_plan");
+    }
+}
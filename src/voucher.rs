@@ -0,0 +1,305 @@
+use serde::{Deserialize, Serialize};
+use stripe::Client;
+
+use crate::address::{self, BillingDetailsDto};
+use crate::telemetry;
+use crate::webhook::WebhookEvent;
+use crate::StripePaymentError;
+
+pub const OXXO_MAX_EXPIRES_AFTER_DAYS: u32 = 7;
+pub const BOLETO_MAX_EXPIRES_AFTER_DAYS: u32 = 60;
+
+/// A cash voucher the customer pays at a store or bank. The payment stays
+/// `requires_action` until the voucher is paid or expires, which is reported
+/// by webhook (see [`VoucherEvent`]).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VoucherType {
+    /// Paid at OXXO stores in Mexico, in MXN. Expires after 1 to 7 days.
+    Oxxo { expires_after_days: u32 },
+    /// Paid at Brazilian banks and lottery agencies, in BRL. Needs the
+    /// customer's CPF or CNPJ. Expires after 0 to 60 days.
+    Boleto {
+        expires_after_days: u32,
+        tax_id: String,
+    },
+}
+
+impl VoucherType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VoucherType::Oxxo { .. } => "oxxo",
+            VoucherType::Boleto { .. } => "boleto",
+        }
+    }
+
+    pub fn currency(&self) -> &'static str {
+        match self {
+            VoucherType::Oxxo { .. } => "mxn",
+            VoucherType::Boleto { .. } => "brl",
+        }
+    }
+
+    fn validate(&self, currency: &str) -> Result<(), StripePaymentError> {
+        if !currency.eq_ignore_ascii_case(self.currency()) {
+            return Err(StripePaymentError::from_general(format!(
+                "{} vouchers must be paid in {}, not {}",
+                self.as_str(),
+                self.currency(),
+                currency
+            )));
+        }
+        let valid = match self {
+            VoucherType::Oxxo { expires_after_days } => {
+                (1..=OXXO_MAX_EXPIRES_AFTER_DAYS).contains(expires_after_days)
+            }
+            VoucherType::Boleto {
+                expires_after_days,
+                tax_id,
+            } => {
+                *expires_after_days <= BOLETO_MAX_EXPIRES_AFTER_DAYS
+                    && matches!(
+                        tax_id.chars().filter(|x| x.is_ascii_digit()).count(),
+                        11 | 14
+                    )
+            }
+        };
+        match valid {
+            true => Ok(()),
+            false => Err(StripePaymentError::from_general(format!(
+                "invalid voucher configuration {:?}",
+                self
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct VoucherPaymentDto {
+    pub id: String,
+    pub status: String,
+    pub voucher_type: String,
+    pub number: Option<String>,
+    pub hosted_voucher_url: Option<String>,
+    /// Boleto only: link to the printable voucher.
+    pub pdf_url: Option<String>,
+    /// Unix timestamp after which the voucher can no longer be paid.
+    pub expires_at: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub enum VoucherEvent {
+    /// The voucher was paid; this arrives hours or days after creation.
+    Paid { payment_intent_id: String },
+    /// The voucher expired unpaid.
+    Expired { payment_intent_id: String },
+}
+
+impl VoucherEvent {
+    /// Maps `payment_intent.succeeded` and `payment_intent.payment_failed`
+    /// events of voucher payments, returning `None` for any other event.
+    pub fn from_event(event: &WebhookEvent) -> Option<VoucherEvent> {
+        let x = event.object_as::<RawPaymentIntent>().ok()?;
+        if !x
+            .payment_method_types
+            .iter()
+            .any(|x| x == "oxxo" || x == "boleto")
+        {
+            return None;
+        }
+        match event.event_type.as_str() {
+            "payment_intent.succeeded" => Some(VoucherEvent::Paid {
+                payment_intent_id: x.id,
+            }),
+            "payment_intent.payment_failed" => Some(VoucherEvent::Expired {
+                payment_intent_id: x.id,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ExpiryOptions {
+    expires_after_days: u32,
+}
+
+#[derive(Serialize)]
+struct PaymentMethodOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    oxxo: Option<ExpiryOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    boleto: Option<ExpiryOptions>,
+}
+
+#[derive(Serialize)]
+struct BoletoData<'a> {
+    tax_id: &'a str,
+}
+
+#[derive(Serialize)]
+struct PaymentMethodData<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    billing_details: &'a BillingDetailsDto,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    boleto: Option<BoletoData<'a>>,
+}
+
+#[derive(Serialize)]
+struct VoucherPaymentParams<'a> {
+    amount: i64,
+    currency: String,
+    customer: &'a str,
+    confirm: bool,
+    payment_method_types: [&'static str; 1],
+    payment_method_data: PaymentMethodData<'a>,
+    payment_method_options: PaymentMethodOptions,
+}
+
+#[derive(Deserialize)]
+struct RawDisplayDetails {
+    number: Option<String>,
+    hosted_voucher_url: Option<String>,
+    pdf: Option<String>,
+    expires_after: Option<i64>,
+    expires_at: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct RawNextAction {
+    oxxo_display_details: Option<RawDisplayDetails>,
+    boleto_display_details: Option<RawDisplayDetails>,
+}
+
+#[derive(Deserialize)]
+struct RawPaymentIntent {
+    id: String,
+    status: String,
+    #[serde(default)]
+    payment_method_types: Vec<String>,
+    next_action: Option<RawNextAction>,
+}
+
+/// Creates and confirms a voucher payment. Both voucher types need the
+/// customer's name and email in `billing_details`; Boleto also needs the
+/// address.
+#[tracing::instrument(skip(stripe_client))]
+pub async fn create_voucher_payment(
+    stripe_client: &Client,
+    stripe_customer_id: &str,
+    amount: i64,
+    currency: &str,
+    voucher_type: &VoucherType,
+    billing_details: &BillingDetailsDto,
+) -> Result<VoucherPaymentDto, StripePaymentError> {
+    voucher_type.validate(currency)?;
+    if billing_details.name.is_none() || billing_details.email.is_none() {
+        return Err(StripePaymentError::from_general(
+            "voucher payments need the customer's name and email".to_string(),
+        ));
+    }
+    let billing_details = match voucher_type {
+        VoucherType::Boleto { .. } => {
+            address::checked_billing_details(&Some(billing_details.clone()))?
+                .unwrap_or_else(|| billing_details.clone())
+        }
+        VoucherType::Oxxo { .. } => billing_details.clone(),
+    };
+    let (expiry, boleto) = match voucher_type {
+        VoucherType::Oxxo { expires_after_days } => (
+            ExpiryOptions {
+                expires_after_days: *expires_after_days,
+            },
+            None,
+        ),
+        VoucherType::Boleto {
+            expires_after_days,
+            tax_id,
+        } => (
+            ExpiryOptions {
+                expires_after_days: *expires_after_days,
+            },
+            Some(BoletoData { tax_id }),
+        ),
+    };
+    let payment_intent = telemetry::observe(
+        "payment_intents.create",
+        stripe_client.post_form::<RawPaymentIntent, _>(
+            "/payment_intents",
+            VoucherPaymentParams {
+                amount,
+                currency: currency.to_lowercase(),
+                customer: stripe_customer_id,
+                confirm: true,
+                payment_method_types: [voucher_type.as_str()],
+                payment_method_data: PaymentMethodData {
+                    kind: voucher_type.as_str(),
+                    billing_details: &billing_details,
+                    boleto,
+                },
+                payment_method_options: match voucher_type {
+                    VoucherType::Oxxo { .. } => PaymentMethodOptions {
+                        oxxo: Some(expiry),
+                        boleto: None,
+                    },
+                    VoucherType::Boleto { .. } => PaymentMethodOptions {
+                        oxxo: None,
+                        boleto: Some(expiry),
+                    },
+                },
+            },
+        ),
+    )
+    .await
+    .map_err(StripePaymentError::from_general)?;
+    Ok(voucher_payment(payment_intent, voucher_type))
+}
+
+fn voucher_payment(x: RawPaymentIntent, voucher_type: &VoucherType) -> VoucherPaymentDto {
+    let details = x.next_action.and_then(|x| match voucher_type {
+        VoucherType::Oxxo { .. } => x.oxxo_display_details,
+        VoucherType::Boleto { .. } => x.boleto_display_details,
+    });
+    VoucherPaymentDto {
+        id: x.id,
+        status: x.status,
+        voucher_type: voucher_type.as_str().to_string(),
+        number: details.as_ref().and_then(|x| x.number.clone()),
+        hosted_voucher_url: details.as_ref().and_then(|x| x.hosted_voucher_url.clone()),
+        pdf_url: details.as_ref().and_then(|x| x.pdf.clone()),
+        expires_at: details.and_then(|x| x.expires_at.or(x.expires_after)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_oxxo_voucher() {
+        let raw = serde_json::from_str::<RawPaymentIntent>(
+            r#"{"id":"pi_1","status":"requires_action","payment_method_types":["oxxo"],
+                "next_action":{"type":"oxxo_display_details","oxxo_display_details":{
+                "expires_after":1700000000,"hosted_voucher_url":"https://payments.stripe.com/oxxo/1",
+                "number":"12345678901234657890123456789012"}}}"#,
+        )
+        .unwrap();
+        let voucher = voucher_payment(
+            raw,
+            &VoucherType::Oxxo {
+                expires_after_days: 3,
+            },
+        );
+        assert_eq!(voucher.expires_at, Some(1700000000));
+        assert_eq!(
+            voucher.number.as_deref(),
+            Some("12345678901234657890123456789012")
+        );
+        assert!(VoucherType::Oxxo {
+            expires_after_days: 8
+        }
+        .validate("mxn")
+        .is_err());
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+#[cfg(feature = "actix")]
+pub mod actix;
+#[cfg(feature = "axum")]
+pub mod axum;
+pub mod domain;
+#[cfg(feature = "runtime-tokio-hyper")]
+pub mod endpoints;
+pub mod fulfillment;
+#[cfg(feature = "sqlx-postgres")]
+pub mod postgres;
+pub mod retry;
+pub mod sequential;
+
+#[cfg(feature = "actix")]
+pub use self::actix::StripeEvent;
+#[cfg(feature = "axum")]
+pub use self::axum::{webhook_router, StripeWebhook};
+pub use domain::{DomainEvent, PaymentDetails};
+#[cfg(feature = "runtime-tokio-hyper")]
+pub use endpoints::{
+    sync_webhook_configuration, DesiredEndpoint, WebhookEndpointDto, WebhookSyncPlan,
+    WebhookSyncReport,
+};
+pub use fulfillment::{
+    FulfillmentCallback, FulfillmentHandler, FulfillmentOrder, FulfillmentSource, FulfillmentStore,
+    InMemoryFulfillmentStore,
+};
+#[cfg(feature = "sqlx-postgres")]
+pub use postgres::{PostgresReplayCache, PostgresRetryStore};
+pub use retry::{InMemoryRetryStore, RetryEntry, RetryReport, RetryStore, WebhookRetryQueue};
+pub use sequential::{EventHandler, SequentialEventProcessor};
+
+pub const DEFAULT_TOLERANCE: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+pub enum WebhookError {
+    BadKey,
+    BadHeader(String),
+    BadSignature,
+    BadTimestamp(i64),
+    BadParse(serde_json::Error),
+    DuplicateEvent(String),
+    ReplayCache(String),
+    RetryStore(String),
+    FulfillmentStore(String),
+    Handler(String),
+}
+
+impl Display for WebhookError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebhookError::BadKey => write!(f, "invalid webhook secret"),
+            WebhookError::BadHeader(x) => write!(f, "invalid Stripe-Signature header: {}", x),
+            WebhookError::BadSignature => write!(f, "webhook signature mismatch"),
+            WebhookError::BadTimestamp(x) => {
+                write!(f, "webhook timestamp {} outside of tolerance", x)
+            }
+            WebhookError::BadParse(x) => write!(f, "could not parse webhook event: {}", x),
+            WebhookError::DuplicateEvent(x) => write!(f, "event {} was already processed", x),
+            WebhookError::ReplayCache(x) => write!(f, "replay cache failure: {}", x),
+            WebhookError::RetryStore(x) => write!(f, "retry store failure: {}", x),
+            WebhookError::FulfillmentStore(x) => write!(f, "fulfillment store failure: {}", x),
+            WebhookError::Handler(x) => write!(f, "event handler failed: {}", x),
+        }
+    }
+}
+
+impl std::error::Error for WebhookError {}
+
+impl WebhookError {
+    /// Status a webhook endpoint should answer with. Duplicates answer 200 so
+    /// Stripe stops redelivering them; failures on our side answer 500 so
+    /// Stripe retries.
+    pub fn http_status(&self) -> u16 {
+        match self {
+            WebhookError::DuplicateEvent(_) => 200,
+            WebhookError::BadKey
+            | WebhookError::ReplayCache(_)
+            | WebhookError::RetryStore(_)
+            | WebhookError::FulfillmentStore(_)
+            | WebhookError::Handler(_) => 500,
+            _ => 400,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct WebhookEventData {
+    pub object: serde_json::Value,
+    #[serde(default)]
+    pub previous_attributes: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct WebhookEvent {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub created: i64,
+    pub livemode: bool,
+    pub data: WebhookEventData,
+}
+
+impl WebhookEvent {
+    /// An event with `created` at 0 and `livemode` off, e.g. for feeding a
+    /// handler in tests.
+    pub fn new(
+        id: impl Into<String>,
+        event_type: impl Into<String>,
+        object: serde_json::Value,
+    ) -> Self {
+        WebhookEvent {
+            id: id.into(),
+            event_type: event_type.into(),
+            created: 0,
+            livemode: false,
+            data: WebhookEventData {
+                object,
+                previous_attributes: None,
+            },
+        }
+    }
+
+    pub fn with_created(mut self, created: i64) -> Self {
+        self.created = created;
+        self
+    }
+
+    pub fn with_livemode(mut self, livemode: bool) -> Self {
+        self.livemode = livemode;
+        self
+    }
+
+    pub fn with_previous_attributes(mut self, previous_attributes: serde_json::Value) -> Self {
+        self.data.previous_attributes = Some(previous_attributes);
+        self
+    }
+
+    pub fn object_id(&self) -> Option<&str> {
+        self.data.object.get("id").and_then(|x| x.as_str())
+    }
+
+    pub fn object_as<T: serde::de::DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_value(self.data.object.clone())
+    }
+}
+
+/// Remembers which event ids were already delivered. `check_and_insert` returns
+/// `false` when the id has been seen before.
+#[async_trait]
+pub trait ReplayCache: Send + Sync {
+    async fn check_and_insert(&self, event_id: &str) -> Result<bool, WebhookError>;
+
+    async fn remove(&self, event_id: &str) -> Result<(), WebhookError>;
+}
+
+#[derive(Debug)]
+pub struct InMemoryReplayCache {
+    retention: Duration,
+    seen: Mutex<HashMap<String, Instant>>,
+}
+
+impl InMemoryReplayCache {
+    pub fn new(retention: Duration) -> Self {
+        InMemoryReplayCache {
+            retention,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl ReplayCache for InMemoryReplayCache {
+    async fn check_and_insert(&self, event_id: &str) -> Result<bool, WebhookError> {
+        let mut seen = self
+            .seen
+            .lock()
+            .map_err(|x| WebhookError::ReplayCache(x.to_string()))?;
+        let now = Instant::now();
+        let retention = self.retention;
+        seen.retain(|_, at| now.duration_since(*at) < retention);
+        if seen.contains_key(event_id) {
+            return Ok(false);
+        }
+        seen.insert(event_id.to_string(), now);
+        Ok(true)
+    }
+
+    async fn remove(&self, event_id: &str) -> Result<(), WebhookError> {
+        self.seen
+            .lock()
+            .map_err(|x| WebhookError::ReplayCache(x.to_string()))?
+            .remove(event_id);
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct WebhookVerifier {
+    secret: String,
+    tolerance: Duration,
+    replay_cache: Option<Arc<dyn ReplayCache>>,
+}
+
+impl std::fmt::Debug for WebhookVerifier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebhookVerifier")
+            .field("tolerance", &self.tolerance)
+            .field("replay_cache", &self.replay_cache.is_some())
+            .finish()
+    }
+}
+
+impl WebhookVerifier {
+    pub fn new(secret: impl Into<String>) -> Self {
+        WebhookVerifier {
+            secret: secret.into(),
+            tolerance: DEFAULT_TOLERANCE,
+            replay_cache: None,
+        }
+    }
+
+    pub fn with_tolerance(mut self, tolerance: Duration) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    pub fn with_replay_cache(mut self, replay_cache: Arc<dyn ReplayCache>) -> Self {
+        self.replay_cache = Some(replay_cache);
+        self
+    }
+
+    pub fn construct_event(
+        &self,
+        payload: &str,
+        signature_header: &str,
+    ) -> Result<WebhookEvent, WebhookError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs() as i64)
+            .unwrap_or_default();
+        self.construct_event_at(payload, signature_header, now)
+    }
+
+    pub fn construct_event_at(
+        &self,
+        payload: &str,
+        signature_header: &str,
+        now: i64,
+    ) -> Result<WebhookEvent, WebhookError> {
+        let signature = Signature::parse(signature_header)?;
+        if (now - signature.t).unsigned_abs() > self.tolerance.as_secs() {
+            return Err(WebhookError::BadTimestamp(signature.t));
+        }
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .map_err(|_| WebhookError::BadKey)?;
+        mac.update(format!("{}.{}", signature.t, payload).as_bytes());
+        let matched = signature.v1.iter().any(|x| match hex::decode(x) {
+            Ok(bytes) => mac.clone().verify_slice(&bytes).is_ok(),
+            Err(_) => false,
+        });
+        if !matched {
+            return Err(WebhookError::BadSignature);
+        }
+        serde_json::from_str(payload).map_err(WebhookError::BadParse)
+    }
+
+    #[tracing::instrument(skip(self, payload, signature_header))]
+    pub async fn verify(
+        &self,
+        payload: &str,
+        signature_header: &str,
+    ) -> Result<WebhookEvent, WebhookError> {
+        let event = self.construct_event(payload, signature_header)?;
+        if let Some(replay_cache) = &self.replay_cache {
+            if !replay_cache.check_and_insert(&event.id).await? {
+                tracing::debug!("duplicate delivery of {}", event.id);
+                return Err(WebhookError::DuplicateEvent(event.id));
+            }
+        }
+        Ok(event)
+    }
+
+    pub async fn release(&self, event_id: &str) -> Result<(), WebhookError> {
+        match &self.replay_cache {
+            Some(replay_cache) => replay_cache.remove(event_id).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Hands a verified event to `handler`. When the handler fails the event id
+    /// is released, so Stripe's retry is accepted instead of being rejected as
+    /// a duplicate.
+    pub async fn dispatch(
+        &self,
+        event: &WebhookEvent,
+        handler: &dyn EventHandler,
+    ) -> Result<(), WebhookError> {
+        let result = handler.handle(event).await;
+        if result.is_err() {
+            if let Err(x) = self.release(&event.id).await {
+                tracing::warn!("releasing {} failed: {}", event.id, x);
+            }
+        }
+        result
+    }
+}
+
+struct Signature {
+    t: i64,
+    v1: Vec<String>,
+}
+
+impl Signature {
+    fn parse(header: &str) -> Result<Signature, WebhookError> {
+        let mut t = None;
+        let mut v1 = vec![];
+        for part in header.split(',') {
+            let (key, value) = part
+                .trim()
+                .split_once('=')
+                .ok_or_else(|| WebhookError::BadHeader(part.to_string()))?;
+            match key {
+                "t" => {
+                    t = Some(
+                        value
+                            .parse::<i64>()
+                            .map_err(|x| WebhookError::BadHeader(x.to_string()))?,
+                    )
+                }
+                "v1" => v1.push(value.to_string()),
+                _ => {}
+            }
+        }
+        let t = t.ok_or_else(|| WebhookError::BadHeader("missing timestamp".to_string()))?;
+        if v1.is_empty() {
+            return Err(WebhookError::BadHeader("missing v1 signature".to_string()));
+        }
+        Ok(Signature { t, v1 })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAYLOAD: &str = r#"{"id":"evt_1","type":"payment_intent.succeeded","created":1,"livemode":false,"data":{"object":{"id":"pi_1"}}}"#;
+
+    struct FailsOnce(std::sync::atomic::AtomicBool);
+
+    #[async_trait]
+    impl EventHandler for FailsOnce {
+        async fn handle(&self, _: &WebhookEvent) -> Result<(), WebhookError> {
+            match self.0.swap(false, std::sync::atomic::Ordering::SeqCst) {
+                true => Err(WebhookError::Handler("failed".to_string())),
+                false => Ok(()),
+            }
+        }
+    }
+
+    fn sign(secret: &str, t: i64, payload: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("{}.{}", t, payload).as_bytes());
+        format!("t={},v1={}", t, hex::encode(mac.finalize().into_bytes()))
+    }
+
+    #[test]
+    fn verifies_signature_within_tolerance() {
+        let verifier = WebhookVerifier::new("whsec_test").with_tolerance(Duration::from_secs(10));
+        let header = sign("whsec_test", 1000, PAYLOAD);
+
+        let event = verifier.construct_event_at(PAYLOAD, &header, 1005).unwrap();
+        assert_eq!(event.object_id(), Some("pi_1"));
+        let built = WebhookEvent::new(
+            "evt_1",
+            "payment_intent.succeeded",
+            serde_json::json!({ "id": "pi_1" }),
+        )
+        .with_created(1);
+        assert_eq!(
+            serde_json::to_value(&built).unwrap(),
+            serde_json::to_value(&event).unwrap()
+        );
+
+        assert!(matches!(
+            verifier.construct_event_at(PAYLOAD, &header, 1011),
+            Err(WebhookError::BadTimestamp(1000))
+        ));
+        assert!(matches!(
+            verifier.construct_event_at(PAYLOAD, &sign("other", 1000, PAYLOAD), 1000),
+            Err(WebhookError::BadSignature)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_duplicates_until_the_handler_fails() {
+        let verifier = WebhookVerifier::new("whsec_test")
+            .with_replay_cache(Arc::new(InMemoryReplayCache::new(Duration::from_secs(60))));
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let header = sign("whsec_test", now, PAYLOAD);
+        let handler = FailsOnce(true.into());
+
+        let event = verifier.verify(PAYLOAD, &header).await.unwrap();
+        assert!(verifier.dispatch(&event, &handler).await.is_err());
+
+        let retried = verifier.verify(PAYLOAD, &header).await.unwrap();
+        assert!(verifier.dispatch(&retried, &handler).await.is_ok());
+        assert!(matches!(
+            verifier.verify(PAYLOAD, &header).await,
+            Err(WebhookError::DuplicateEvent(x)) if x == "evt_1"
+        ));
+    }
+}
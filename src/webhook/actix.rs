@@ -0,0 +1,70 @@
+use std::ops::Deref;
+
+use actix_web::dev::Payload;
+use actix_web::http::StatusCode;
+use actix_web::web::{Bytes, Data};
+use actix_web::{FromRequest, HttpRequest, ResponseError};
+use futures_util::future::LocalBoxFuture;
+
+use super::{DomainEvent, WebhookError, WebhookEvent, WebhookVerifier};
+
+pub const SIGNATURE_HEADER: &str = "Stripe-Signature";
+
+impl ResponseError for WebhookError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.http_status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+/// A webhook event whose signature was verified with the
+/// `web::Data<WebhookVerifier>` registered on the app, including its replay
+/// cache if one is configured. Handle it with [`WebhookVerifier::dispatch`]
+/// so a failed delivery is released for Stripe's retry.
+#[derive(Debug, Clone)]
+pub struct StripeEvent(pub WebhookEvent);
+
+impl StripeEvent {
+    pub fn into_inner(self) -> WebhookEvent {
+        self.0
+    }
+
+    pub fn domain_event(&self) -> Option<DomainEvent> {
+        DomainEvent::from_event(&self.0)
+    }
+}
+
+impl Deref for StripeEvent {
+    type Target = WebhookEvent;
+
+    fn deref(&self) -> &WebhookEvent {
+        &self.0
+    }
+}
+
+impl FromRequest for StripeEvent {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<StripeEvent, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let verifier = req.app_data::<Data<WebhookVerifier>>().cloned();
+        let signature = req
+            .headers()
+            .get(SIGNATURE_HEADER)
+            .and_then(|x| x.to_str().ok())
+            .map(String::from);
+        let body = Bytes::from_request(req, payload);
+        Box::pin(async move {
+            let verifier = verifier.ok_or_else(|| {
+                actix_web::error::ErrorInternalServerError("no WebhookVerifier app data")
+            })?;
+            let signature = signature.ok_or_else(|| {
+                WebhookError::BadHeader(format!("missing {} header", SIGNATURE_HEADER))
+            })?;
+            let body = body.await?;
+            let payload = std::str::from_utf8(&body)
+                .map_err(|x| WebhookError::BadHeader(format!("body is not utf-8: {}", x)))?;
+            let event = verifier.verify(payload, &signature).await?;
+            Ok(StripeEvent(event))
+        })
+    }
+}
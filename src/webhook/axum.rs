@@ -0,0 +1,115 @@
+use std::ops::Deref;
+use std::sync::Arc;
+
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::extract::{FromRef, FromRequest, Request, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::Router;
+
+use super::{DomainEvent, EventHandler, WebhookError, WebhookEvent, WebhookVerifier};
+
+pub const SIGNATURE_HEADER: &str = "Stripe-Signature";
+pub const WEBHOOK_PATH: &str = "/stripe/webhook";
+
+impl IntoResponse for WebhookError {
+    fn into_response(self) -> Response {
+        let status =
+            StatusCode::from_u16(self.http_status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        (status, self.to_string()).into_response()
+    }
+}
+
+/// A webhook event whose signature was verified with the
+/// `Arc<WebhookVerifier>` taken from the router state, including its replay
+/// cache if one is configured.
+#[derive(Debug, Clone)]
+pub struct StripeWebhook(pub WebhookEvent);
+
+impl StripeWebhook {
+    pub fn into_inner(self) -> WebhookEvent {
+        self.0
+    }
+
+    pub fn domain_event(&self) -> Option<DomainEvent> {
+        DomainEvent::from_event(&self.0)
+    }
+}
+
+impl Deref for StripeWebhook {
+    type Target = WebhookEvent;
+
+    fn deref(&self) -> &WebhookEvent {
+        &self.0
+    }
+}
+
+#[async_trait]
+impl<S> FromRequest<S> for StripeWebhook
+where
+    Arc<WebhookVerifier>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<StripeWebhook, Response> {
+        let verifier = Arc::<WebhookVerifier>::from_ref(state);
+        let signature = req
+            .headers()
+            .get(SIGNATURE_HEADER)
+            .and_then(|x| x.to_str().ok())
+            .map(String::from)
+            .ok_or_else(|| {
+                WebhookError::BadHeader(format!("missing {} header", SIGNATURE_HEADER))
+                    .into_response()
+            })?;
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let payload = std::str::from_utf8(&body).map_err(|x| {
+            WebhookError::BadHeader(format!("body is not utf-8: {}", x)).into_response()
+        })?;
+        verifier
+            .verify(payload, &signature)
+            .await
+            .map(StripeWebhook)
+            .map_err(IntoResponse::into_response)
+    }
+}
+
+#[derive(Clone)]
+struct WebhookState {
+    verifier: Arc<WebhookVerifier>,
+    handler: Arc<dyn EventHandler>,
+}
+
+impl FromRef<WebhookState> for Arc<WebhookVerifier> {
+    fn from_ref(state: &WebhookState) -> Self {
+        state.verifier.clone()
+    }
+}
+
+async fn receive(
+    State(state): State<WebhookState>,
+    event: StripeWebhook,
+) -> Result<StatusCode, WebhookError> {
+    state
+        .verifier
+        .dispatch(&event, state.handler.as_ref())
+        .await?;
+    Ok(StatusCode::OK)
+}
+
+/// Router answering `POST /stripe/webhook`: verifies the event and hands it
+/// to `handler`, e.g. a [`super::SequentialEventProcessor`]. Merge it into the
+/// service's router.
+pub fn webhook_router(verifier: WebhookVerifier, handler: impl EventHandler + 'static) -> Router {
+    Router::new()
+        .route(WEBHOOK_PATH, post(receive))
+        .with_state(WebhookState {
+            verifier: Arc::new(verifier),
+            handler: Arc::new(handler),
+        })
+}
@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use super::WebhookEvent;
+use crate::decline::{DeclineCategory, DeclineCode};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PaymentDetails {
+    /// Id of the Stripe object the event is about (`pi_`, `ch_` or `in_`).
+    pub object_id: String,
+    pub payment_intent_id: Option<String>,
+    pub amount: i64,
+    pub currency: String,
+    pub stripe_customer_id: Option<String>,
+    pub metadata: HashMap<String, String>,
+}
+
+/// Business-level events derived from Stripe webhooks, so application code
+/// doesn't need to know which Stripe event types mean what.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DomainEvent {
+    OrderPaid(PaymentDetails),
+    OrderRefunded {
+        payment: PaymentDetails,
+        amount_refunded: i64,
+        fully_refunded: bool,
+    },
+    SubscriptionRenewed {
+        subscription_id: String,
+        payment: PaymentDetails,
+    },
+    /// `retryable` is true when retrying the same payment method may still
+    /// succeed, e.g. after insufficient funds or when Stripe schedules another
+    /// invoice attempt.
+    PaymentFailed {
+        payment: PaymentDetails,
+        decline_code: Option<DeclineCode>,
+        retryable: bool,
+    },
+    /// The disputed amount was taken from the balance. `amount` and `fee` are
+    /// positive; `net` is the balance change (negative).
+    FundsWithdrawn {
+        dispute_id: String,
+        payment: PaymentDetails,
+        amount: i64,
+        fee: i64,
+        net: i64,
+    },
+    /// The dispute was won and the funds returned. `fee` is the dispute fee
+    /// refunded, if any, as a positive amount; `net` is the balance change.
+    FundsReinstated {
+        dispute_id: String,
+        payment: PaymentDetails,
+        amount: i64,
+        fee: i64,
+        net: i64,
+    },
+}
+
+#[derive(Deserialize)]
+struct RawPaymentError {
+    code: Option<String>,
+    decline_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawPaymentIntent {
+    id: String,
+    amount: i64,
+    currency: String,
+    customer: Option<String>,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+    last_payment_error: Option<RawPaymentError>,
+}
+
+#[derive(Deserialize)]
+struct RawCharge {
+    id: String,
+    payment_intent: Option<String>,
+    amount: i64,
+    amount_refunded: i64,
+    #[serde(default)]
+    refunded: bool,
+    currency: String,
+    customer: Option<String>,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct RawInvoice {
+    id: String,
+    subscription: Option<String>,
+    billing_reason: Option<String>,
+    payment_intent: Option<String>,
+    amount_paid: i64,
+    amount_due: i64,
+    currency: String,
+    customer: Option<String>,
+    next_payment_attempt: Option<i64>,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct RawBalanceTransaction {
+    amount: i64,
+    fee: i64,
+    net: i64,
+}
+
+#[derive(Deserialize)]
+struct RawDispute {
+    id: String,
+    charge: String,
+    payment_intent: Option<String>,
+    amount: i64,
+    currency: String,
+    #[serde(default)]
+    balance_transactions: Vec<RawBalanceTransaction>,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+}
+
+impl RawDispute {
+    /// Latest balance transaction moving funds in the given direction, with
+    /// its amount, fee and net as positive amounts relative to that direction.
+    fn movement(&self, withdrawn: bool) -> (i64, i64, i64) {
+        self.balance_transactions
+            .iter()
+            .rev()
+            .find(|x| (x.amount < 0) == withdrawn)
+            .map(|x| (x.amount.abs(), x.fee.abs(), x.net))
+            .unwrap_or((
+                self.amount,
+                0,
+                if withdrawn { -self.amount } else { self.amount },
+            ))
+    }
+
+    fn into_event(self, withdrawn: bool) -> DomainEvent {
+        let (amount, fee, net) = self.movement(withdrawn);
+        let payment = PaymentDetails {
+            object_id: self.charge,
+            payment_intent_id: self.payment_intent,
+            amount: self.amount,
+            currency: self.currency,
+            stripe_customer_id: None,
+            metadata: self.metadata,
+        };
+        match withdrawn {
+            true => DomainEvent::FundsWithdrawn {
+                dispute_id: self.id,
+                payment,
+                amount,
+                fee,
+                net,
+            },
+            false => DomainEvent::FundsReinstated {
+                dispute_id: self.id,
+                payment,
+                amount,
+                fee,
+                net,
+            },
+        }
+    }
+}
+
+impl DomainEvent {
+    /// Translates the Stripe events this crate knows about; returns `None` for
+    /// everything else, including events whose object can't be parsed.
+    pub fn from_event(event: &WebhookEvent) -> Option<DomainEvent> {
+        match event.event_type.as_str() {
+            "payment_intent.succeeded" => {
+                let x = event.object_as::<RawPaymentIntent>().ok()?;
+                Some(DomainEvent::OrderPaid(PaymentDetails {
+                    payment_intent_id: Some(x.id.clone()),
+                    object_id: x.id,
+                    amount: x.amount,
+                    currency: x.currency,
+                    stripe_customer_id: x.customer,
+                    metadata: x.metadata,
+                }))
+            }
+            "payment_intent.payment_failed" => {
+                let x = event.object_as::<RawPaymentIntent>().ok()?;
+                let decline_code = x
+                    .last_payment_error
+                    .and_then(|x| x.decline_code.or(x.code))
+                    .map(|x| DeclineCode::parse(&x));
+                Some(DomainEvent::PaymentFailed {
+                    retryable: matches!(
+                        decline_code.as_ref().map(|x| x.category()),
+                        Some(DeclineCategory::InsufficientFunds | DeclineCategory::RetryLater)
+                    ),
+                    decline_code,
+                    payment: PaymentDetails {
+                        payment_intent_id: Some(x.id.clone()),
+                        object_id: x.id,
+                        amount: x.amount,
+                        currency: x.currency,
+                        stripe_customer_id: x.customer,
+                        metadata: x.metadata,
+                    },
+                })
+            }
+            "charge.refunded" => {
+                let x = event.object_as::<RawCharge>().ok()?;
+                Some(DomainEvent::OrderRefunded {
+                    amount_refunded: x.amount_refunded,
+                    fully_refunded: x.refunded,
+                    payment: PaymentDetails {
+                        object_id: x.id,
+                        payment_intent_id: x.payment_intent,
+                        amount: x.amount,
+                        currency: x.currency,
+                        stripe_customer_id: x.customer,
+                        metadata: x.metadata,
+                    },
+                })
+            }
+            "invoice.paid" => {
+                let x = event.object_as::<RawInvoice>().ok()?;
+                match (x.billing_reason.as_deref(), x.subscription) {
+                    (Some("subscription_cycle"), Some(subscription_id)) => {
+                        Some(DomainEvent::SubscriptionRenewed {
+                            subscription_id,
+                            payment: PaymentDetails {
+                                object_id: x.id,
+                                payment_intent_id: x.payment_intent,
+                                amount: x.amount_paid,
+                                currency: x.currency,
+                                stripe_customer_id: x.customer,
+                                metadata: x.metadata,
+                            },
+                        })
+                    }
+                    _ => None,
+                }
+            }
+            "invoice.payment_failed" => {
+                let x = event.object_as::<RawInvoice>().ok()?;
+                Some(DomainEvent::PaymentFailed {
+                    decline_code: None,
+                    retryable: x.next_payment_attempt.is_some(),
+                    payment: PaymentDetails {
+                        object_id: x.id,
+                        payment_intent_id: x.payment_intent,
+                        amount: x.amount_due,
+                        currency: x.currency,
+                        stripe_customer_id: x.customer,
+                        metadata: x.metadata,
+                    },
+                })
+            }
+            "charge.dispute.funds_withdrawn" => event
+                .object_as::<RawDispute>()
+                .ok()
+                .map(|x| x.into_event(true)),
+            "charge.dispute.funds_reinstated" => event
+                .object_as::<RawDispute>()
+                .ok()
+                .map(|x| x.into_event(false)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(event_type: &str, object: &str) -> WebhookEvent {
+        WebhookEvent::new("evt_1", event_type, serde_json::from_str(object).unwrap())
+            .with_created(1)
+    }
+
+    #[test]
+    fn translates_stripe_events() {
+        let paid = event(
+            "payment_intent.succeeded",
+            r#"{"id":"pi_1","amount":500,"currency":"eur","customer":"cus_1","metadata":{"order":"42"}}"#,
+        );
+        assert!(matches!(
+            DomainEvent::from_event(&paid),
+            Some(DomainEvent::OrderPaid(x)) if x.amount == 500 && x.metadata["order"] == "42"
+        ));
+
+        let failed = event(
+            "payment_intent.payment_failed",
+            r#"{"id":"pi_1","amount":500,"currency":"eur","customer":null,
+                "last_payment_error":{"code":"card_declined","decline_code":"insufficient_funds"}}"#,
+        );
+        assert!(matches!(
+            DomainEvent::from_event(&failed),
+            Some(DomainEvent::PaymentFailed {
+                retryable: true,
+                ..
+            })
+        ));
+
+        let first_invoice = event(
+            "invoice.paid",
+            r#"{"id":"in_1","subscription":"sub_1","billing_reason":"subscription_create",
+                "amount_paid":1000,"amount_due":1000,"currency":"eur"}"#,
+        );
+        assert_eq!(DomainEvent::from_event(&first_invoice), None);
+    }
+
+    #[test]
+    fn translates_dispute_funds_events() {
+        let withdrawn = event(
+            "charge.dispute.funds_withdrawn",
+            r#"{"id":"du_1","charge":"ch_1","payment_intent":"pi_1","amount":1000,"currency":"usd",
+                "balance_transactions":[{"amount":-1000,"fee":1500,"net":-2500}]}"#,
+        );
+        assert_eq!(
+            DomainEvent::from_event(&withdrawn).map(|x| match x {
+                DomainEvent::FundsWithdrawn {
+                    amount, fee, net, ..
+                } => (amount, fee, net),
+                _ => (0, 0, 0),
+            }),
+            Some((1000, 1500, -2500))
+        );
+
+        let reinstated = event(
+            "charge.dispute.funds_reinstated",
+            r#"{"id":"du_1","charge":"ch_1","payment_intent":"pi_1","amount":1000,"currency":"usd",
+                "balance_transactions":[{"amount":-1000,"fee":1500,"net":-2500},
+                {"amount":1000,"fee":-1500,"net":2500}]}"#,
+        );
+        assert!(matches!(
+            DomainEvent::from_event(&reinstated),
+            Some(DomainEvent::FundsReinstated {
+                fee: 1500,
+                net: 2500,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+use stripe::Client;
+
+use crate::pagination::RawList;
+use crate::redact::{self, Redacted, SecretString};
+use crate::telemetry;
+use crate::url::StripeUrl;
+use crate::StripePaymentError;
+
+/// An endpoint as it should exist in Stripe. Endpoints are matched to
+/// existing ones by URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DesiredEndpoint {
+    pub url: String,
+    pub enabled_events: BTreeSet<String>,
+    pub description: Option<String>,
+}
+
+impl DesiredEndpoint {
+    pub fn new(url: impl Into<String>) -> Self {
+        DesiredEndpoint {
+            url: url.into(),
+            enabled_events: BTreeSet::new(),
+            description: None,
+        }
+    }
+
+    pub fn with_event(mut self, event_type: impl Into<String>) -> Self {
+        self.enabled_events.insert(event_type.into());
+        self
+    }
+
+    pub fn with_events<I, S>(mut self, event_types: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.enabled_events
+            .extend(event_types.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn with_description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+}
+
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct WebhookEndpointDto {
+    pub id: String,
+    pub url: String,
+    pub enabled_events: BTreeSet<String>,
+    pub description: Option<String>,
+    /// `enabled` or `disabled`.
+    pub status: String,
+    /// Signing secret; Stripe only returns it when the endpoint is created.
+    pub secret: Option<SecretString>,
+}
+
+impl Redacted for WebhookEndpointDto {
+    fn fmt_redacted(&self, f: &mut std::fmt::Formatter<'_>, reveal: bool) -> std::fmt::Result {
+        f.debug_struct("WebhookEndpointDto")
+            .field("id", &self.id)
+            .field("url", &self.url)
+            .field("enabled_events", &self.enabled_events)
+            .field("description", &self.description)
+            .field("status", &self.status)
+            .field(
+                "secret",
+                &self.secret.as_ref().map(|x| redact::secret(x, reveal)),
+            )
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for WebhookEndpointDto {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_redacted(f, false)
+    }
+}
+
+#[derive(Deserialize)]
+struct RawWebhookEndpoint {
+    id: String,
+    url: String,
+    #[serde(default)]
+    enabled_events: BTreeSet<String>,
+    description: Option<String>,
+    status: String,
+    secret: Option<String>,
+}
+
+impl From<RawWebhookEndpoint> for WebhookEndpointDto {
+    fn from(x: RawWebhookEndpoint) -> Self {
+        WebhookEndpointDto {
+            id: x.id,
+            url: x.url,
+            enabled_events: x.enabled_events,
+            description: x.description,
+            status: x.status,
+            secret: x.secret.map(SecretString::from),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawDeleted {
+    id: String,
+}
+
+/// The changes that make the endpoints in Stripe match the desired ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct WebhookSyncPlan {
+    pub create: Vec<DesiredEndpoint>,
+    /// Existing endpoint id and the state it should be brought to.
+    pub update: Vec<(String, DesiredEndpoint)>,
+    pub delete: Vec<String>,
+}
+
+impl WebhookSyncPlan {
+    pub fn is_empty(&self) -> bool {
+        self.create.is_empty() && self.update.is_empty() && self.delete.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct WebhookSyncReport {
+    /// Newly created endpoints, carrying their signing secrets.
+    pub created: Vec<WebhookEndpointDto>,
+    pub updated: Vec<WebhookEndpointDto>,
+    pub deleted: Vec<String>,
+}
+
+/// Diffs existing endpoints against the desired ones. An endpoint is updated
+/// when its events or description differ or it was disabled; endpoints whose
+/// URL is not desired are deleted, as are duplicates of a desired URL.
+pub fn plan_webhook_sync(
+    existing: &[WebhookEndpointDto],
+    desired: &[DesiredEndpoint],
+) -> WebhookSyncPlan {
+    let mut plan = WebhookSyncPlan::default();
+    let mut matched = BTreeSet::new();
+    for endpoint in desired {
+        match existing
+            .iter()
+            .find(|x| x.url == endpoint.url && !matched.contains(&x.id))
+        {
+            Some(x) => {
+                matched.insert(x.id.clone());
+                if x.enabled_events != endpoint.enabled_events
+                    || x.description != endpoint.description
+                    || x.status != "enabled"
+                {
+                    plan.update.push((x.id.clone(), endpoint.clone()));
+                }
+            }
+            None => plan.create.push(endpoint.clone()),
+        }
+    }
+    plan.delete = existing
+        .iter()
+        .filter(|x| !matched.contains(&x.id))
+        .map(|x| x.id.clone())
+        .collect();
+    plan
+}
+
+#[derive(Serialize)]
+struct ListParams {
+    limit: u64,
+}
+
+#[derive(Serialize)]
+struct CreateParams<'a> {
+    url: &'a str,
+    enabled_events: Vec<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_version: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct UpdateParams<'a> {
+    enabled_events: Vec<&'a str>,
+    description: &'a str,
+    disabled: bool,
+}
+
+#[derive(Serialize)]
+struct DeleteParams {}
+
+#[tracing::instrument(skip(stripe_client))]
+pub async fn list_webhook_endpoints(
+    stripe_client: &Client,
+) -> Result<Vec<WebhookEndpointDto>, StripePaymentError> {
+    telemetry::observe(
+        "webhook_endpoints.list",
+        stripe_client.get_query::<RawList<RawWebhookEndpoint>, _>(
+            "/webhook_endpoints",
+            ListParams { limit: 100 },
+        ),
+    )
+    .await
+    .map(|x| x.data.into_iter().map(WebhookEndpointDto::from).collect())
+    .map_err(StripePaymentError::from_general)
+}
+
+/// Converges the account's webhook endpoints to `desired_endpoints`, for
+/// deployments that keep the endpoint configuration in code. Created endpoints
+/// are pinned to `api_version` when given. Endpoints not listed are deleted,
+/// so `desired_endpoints` must cover every endpoint of the account.
+#[tracing::instrument(skip(stripe_client, desired_endpoints))]
+pub async fn sync_webhook_configuration(
+    stripe_client: &Client,
+    desired_endpoints: &[DesiredEndpoint],
+    api_version: Option<&str>,
+) -> Result<WebhookSyncReport, StripePaymentError> {
+    let existing = list_webhook_endpoints(stripe_client).await?;
+    let plan = plan_webhook_sync(&existing, desired_endpoints);
+    let mut report = WebhookSyncReport::default();
+    for endpoint in &plan.create {
+        let created = telemetry::observe(
+            "webhook_endpoints.create",
+            stripe_client.post_form::<RawWebhookEndpoint, _>(
+                "/webhook_endpoints",
+                CreateParams {
+                    url: &endpoint.url,
+                    enabled_events: endpoint.enabled_events.iter().map(|x| x.as_str()).collect(),
+                    description: endpoint.description.as_deref(),
+                    api_version,
+                },
+            ),
+        )
+        .await
+        .map_err(StripePaymentError::from_general)?;
+        tracing::info!(
+            "created webhook endpoint {} for {}",
+            created.id,
+            created.url
+        );
+        report.created.push(created.into());
+    }
+    for (id, endpoint) in &plan.update {
+        let updated = telemetry::observe(
+            "webhook_endpoints.update",
+            stripe_client.post_form::<RawWebhookEndpoint, _>(
+                &StripeUrl::new("/webhook_endpoints").segment(id).build(),
+                UpdateParams {
+                    enabled_events: endpoint.enabled_events.iter().map(|x| x.as_str()).collect(),
+                    description: endpoint.description.as_deref().unwrap_or_default(),
+                    disabled: false,
+                },
+            ),
+        )
+        .await
+        .map_err(StripePaymentError::from_general)?;
+        tracing::info!("updated webhook endpoint {}", updated.id);
+        report.updated.push(updated.into());
+    }
+    for id in &plan.delete {
+        let deleted = telemetry::observe(
+            "webhook_endpoints.delete",
+            stripe_client.delete_query::<RawDeleted, _>(
+                &StripeUrl::new("/webhook_endpoints").segment(id).build(),
+                DeleteParams {},
+            ),
+        )
+        .await
+        .map_err(StripePaymentError::from_general)?;
+        tracing::info!("deleted webhook endpoint {}", deleted.id);
+        report.deleted.push(deleted.id);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn existing(id: &str, url: &str, events: &[&str], status: &str) -> WebhookEndpointDto {
+        WebhookEndpointDto {
+            id: id.to_string(),
+            url: url.to_string(),
+            enabled_events: events.iter().map(|x| x.to_string()).collect(),
+            description: None,
+            status: status.to_string(),
+            secret: None,
+        }
+    }
+
+    #[test]
+    fn plans_create_update_and_delete() {
+        let existing = vec![
+            existing(
+                "we_1",
+                "https://a.test/hook",
+                &["charge.refunded"],
+                "enabled",
+            ),
+            existing("we_2", "https://b.test/hook", &["invoice.paid"], "enabled"),
+            existing(
+                "we_3",
+                "https://old.test/hook",
+                &["invoice.paid"],
+                "enabled",
+            ),
+        ];
+        let desired = vec![
+            DesiredEndpoint::new("https://a.test/hook").with_event("charge.refunded"),
+            DesiredEndpoint::new("https://b.test/hook")
+                .with_events(["invoice.paid", "invoice.payment_failed"]),
+            DesiredEndpoint::new("https://c.test/hook").with_event("payout.paid"),
+        ];
+        let plan = plan_webhook_sync(&existing, &desired);
+        assert_eq!(plan.create, vec![desired[2].clone()]);
+        assert_eq!(plan.update, vec![("we_2".to_string(), desired[1].clone())]);
+        assert_eq!(plan.delete, vec!["we_3".to_string()]);
+        assert!(plan_webhook_sync(&existing[..1], &desired[..1]).is_empty());
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use super::{EventHandler, WebhookError, WebhookEvent};
+use crate::metadata::MetadataNamespace;
+
+pub const ORDER_ID: &str = "order_id";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FulfillmentSource {
+    Invoice(String),
+    PaymentIntent(String),
+}
+
+/// A paid order, identified by the `order_id` metadata key the application
+/// set when creating the invoice or payment intent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FulfillmentOrder {
+    pub order_id: String,
+    pub source: FulfillmentSource,
+    pub amount: i64,
+    pub currency: String,
+    pub customer: Option<String>,
+    pub event_id: String,
+}
+
+#[derive(Deserialize)]
+struct RawInvoice {
+    id: String,
+    amount_paid: i64,
+    currency: String,
+    customer: Option<String>,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct RawPaymentIntent {
+    id: String,
+    #[serde(default)]
+    amount_received: i64,
+    currency: String,
+    customer: Option<String>,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+}
+
+impl FulfillmentOrder {
+    /// Maps `invoice.paid` and `payment_intent.succeeded` events carrying an
+    /// order id in `namespace`, returning `None` for any other event.
+    pub fn from_event(event: &WebhookEvent, namespace: &MetadataNamespace) -> Option<Self> {
+        let (source, amount, currency, customer, metadata) = match event.event_type.as_str() {
+            "invoice.paid" => {
+                let x = event.object_as::<RawInvoice>().ok()?;
+                (
+                    FulfillmentSource::Invoice(x.id),
+                    x.amount_paid,
+                    x.currency,
+                    x.customer,
+                    x.metadata,
+                )
+            }
+            "payment_intent.succeeded" => {
+                let x = event.object_as::<RawPaymentIntent>().ok()?;
+                (
+                    FulfillmentSource::PaymentIntent(x.id),
+                    x.amount_received,
+                    x.currency,
+                    x.customer,
+                    x.metadata,
+                )
+            }
+            _ => return None,
+        };
+        Some(FulfillmentOrder {
+            order_id: namespace.get(&metadata, ORDER_ID)?.to_string(),
+            source,
+            amount,
+            currency,
+            customer,
+            event_id: event.id.clone(),
+        })
+    }
+}
+
+/// Remembers fulfilled orders. Implement it on the application database so
+/// that a redelivered event, or an order paid through both an invoice and its
+/// payment intent, is only fulfilled once.
+#[async_trait]
+pub trait FulfillmentStore: Send + Sync {
+    async fn is_fulfilled(&self, order_id: &str) -> Result<bool, WebhookError>;
+
+    async fn mark_fulfilled(&self, order: &FulfillmentOrder) -> Result<(), WebhookError>;
+}
+
+#[derive(Debug, Default)]
+pub struct InMemoryFulfillmentStore {
+    fulfilled: Mutex<HashSet<String>>,
+}
+
+impl InMemoryFulfillmentStore {
+    pub fn new() -> Self {
+        InMemoryFulfillmentStore::default()
+    }
+}
+
+#[async_trait]
+impl FulfillmentStore for InMemoryFulfillmentStore {
+    async fn is_fulfilled(&self, order_id: &str) -> Result<bool, WebhookError> {
+        self.fulfilled
+            .lock()
+            .map(|x| x.contains(order_id))
+            .map_err(|x| WebhookError::FulfillmentStore(x.to_string()))
+    }
+
+    async fn mark_fulfilled(&self, order: &FulfillmentOrder) -> Result<(), WebhookError> {
+        self.fulfilled
+            .lock()
+            .map_err(|x| WebhookError::FulfillmentStore(x.to_string()))?
+            .insert(order.order_id.clone());
+        Ok(())
+    }
+}
+
+#[async_trait]
+pub trait FulfillmentCallback: Send + Sync {
+    async fn fulfill(&self, order: &FulfillmentOrder) -> Result<(), WebhookError>;
+}
+
+/// Calls `callback` once per paid order. Delivery is at-least-once: an order
+/// is marked fulfilled only after the callback succeeds, so a crash in between
+/// runs the callback again on redelivery, and a failed callback is returned as
+/// an error so Stripe (or a [`super::WebhookRetryQueue`]) retries the event.
+pub struct FulfillmentHandler<C> {
+    callback: C,
+    store: Arc<dyn FulfillmentStore>,
+    namespace: MetadataNamespace,
+}
+
+impl<C: FulfillmentCallback> FulfillmentHandler<C> {
+    pub fn new(callback: C) -> Self {
+        FulfillmentHandler {
+            callback,
+            store: Arc::new(InMemoryFulfillmentStore::new()),
+            namespace: MetadataNamespace::default(),
+        }
+    }
+
+    pub fn with_store(mut self, store: Arc<dyn FulfillmentStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    pub fn with_namespace(mut self, namespace: MetadataNamespace) -> Self {
+        self.namespace = namespace;
+        self
+    }
+}
+
+#[async_trait]
+impl<C: FulfillmentCallback> EventHandler for FulfillmentHandler<C> {
+    #[tracing::instrument(skip(self, event), fields(event_id = %event.id))]
+    async fn handle(&self, event: &WebhookEvent) -> Result<(), WebhookError> {
+        let order = match FulfillmentOrder::from_event(event, &self.namespace) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        if self.store.is_fulfilled(&order.order_id).await? {
+            tracing::debug!("order {} already fulfilled", order.order_id);
+            return Ok(());
+        }
+        self.callback.fulfill(&order).await?;
+        self.store.mark_fulfilled(&order).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_order_id_from_namespaced_metadata() {
+        let event = serde_json::from_str::<WebhookEvent>(
+            r#"{"id":"evt_1","type":"invoice.paid","created":1,"livemode":false,
+                "data":{"object":{"id":"in_1","amount_paid":1200,"currency":"eur",
+                "customer":"cus_1","metadata":{"libstripe:order_id":"ord_42"}}}}"#,
+        )
+        .unwrap();
+        let order = FulfillmentOrder::from_event(&event, &MetadataNamespace::default()).unwrap();
+        assert_eq!(order.order_id, "ord_42");
+        assert_eq!(order.source, FulfillmentSource::Invoice("in_1".to_string()));
+        assert!(FulfillmentOrder::from_event(&event, &MetadataNamespace::new("shop")).is_none());
+    }
+}
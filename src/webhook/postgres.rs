@@ -0,0 +1,168 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use sqlx::{Executor, PgPool};
+
+use super::retry::{RetryEntry, RetryStore};
+use super::{ReplayCache, WebhookError, WebhookEvent};
+
+/// Creates the tables used by [`PostgresReplayCache`] and
+/// [`PostgresRetryStore`]. Every statement is idempotent, so [`migrate`] can
+/// run on each start; services with their own migration tool can copy it
+/// instead.
+pub const MIGRATIONS: &str = "
+CREATE TABLE IF NOT EXISTS stripe_webhook_events (
+    event_id TEXT PRIMARY KEY,
+    seen_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS stripe_webhook_events_seen_at
+    ON stripe_webhook_events (seen_at);
+CREATE TABLE IF NOT EXISTS stripe_webhook_retries (
+    id BIGSERIAL PRIMARY KEY,
+    event TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    next_attempt_at BIGINT NOT NULL,
+    last_error TEXT NOT NULL,
+    dead BOOLEAN NOT NULL DEFAULT false
+);
+CREATE INDEX IF NOT EXISTS stripe_webhook_retries_due
+    ON stripe_webhook_retries (next_attempt_at) WHERE NOT dead;
+";
+
+pub async fn migrate(pool: &PgPool) -> Result<(), sqlx::Error> {
+    pool.execute(MIGRATIONS).await.map(|_| ())
+}
+
+/// [`ReplayCache`] on the `stripe_webhook_events` table. Ids older than the
+/// retention are deleted as new events arrive.
+#[derive(Debug, Clone)]
+pub struct PostgresReplayCache {
+    pool: PgPool,
+    retention: Duration,
+}
+
+impl PostgresReplayCache {
+    pub fn new(pool: PgPool, retention: Duration) -> Self {
+        PostgresReplayCache { pool, retention }
+    }
+}
+
+fn replay_error(x: sqlx::Error) -> WebhookError {
+    WebhookError::ReplayCache(x.to_string())
+}
+
+#[async_trait]
+impl ReplayCache for PostgresReplayCache {
+    async fn check_and_insert(&self, event_id: &str) -> Result<bool, WebhookError> {
+        sqlx::query(
+            "DELETE FROM stripe_webhook_events WHERE seen_at < now() - make_interval(secs => $1)",
+        )
+        .bind(self.retention.as_secs_f64())
+        .execute(&self.pool)
+        .await
+        .map_err(replay_error)?;
+        let inserted = sqlx::query(
+            "INSERT INTO stripe_webhook_events (event_id) VALUES ($1) ON CONFLICT DO NOTHING",
+        )
+        .bind(event_id)
+        .execute(&self.pool)
+        .await
+        .map_err(replay_error)?;
+        Ok(inserted.rows_affected() == 1)
+    }
+
+    async fn remove(&self, event_id: &str) -> Result<(), WebhookError> {
+        sqlx::query("DELETE FROM stripe_webhook_events WHERE event_id = $1")
+            .bind(event_id)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(replay_error)
+    }
+}
+
+/// [`RetryStore`] on the `stripe_webhook_retries` table. `take_due` deletes
+/// the rows it returns in one statement, so several workers can poll the same
+/// table without retrying an event twice.
+#[derive(Debug, Clone)]
+pub struct PostgresRetryStore {
+    pool: PgPool,
+}
+
+impl PostgresRetryStore {
+    pub fn new(pool: PgPool) -> Self {
+        PostgresRetryStore { pool }
+    }
+
+    async fn insert(&self, entry: RetryEntry, dead: bool) -> Result<(), WebhookError> {
+        let event = serde_json::to_string(&entry.event).map_err(retry_error)?;
+        sqlx::query(
+            "INSERT INTO stripe_webhook_retries \
+             (event, attempts, next_attempt_at, last_error, dead) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(event)
+        .bind(entry.attempts as i32)
+        .bind(entry.next_attempt_at)
+        .bind(entry.last_error)
+        .bind(dead)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(retry_error)
+    }
+}
+
+fn retry_error(x: impl ToString) -> WebhookError {
+    WebhookError::RetryStore(x.to_string())
+}
+
+type RetryRow = (String, i32, i64, String);
+
+fn retry_entry(
+    (event, attempts, next_attempt_at, last_error): RetryRow,
+) -> Result<RetryEntry, WebhookError> {
+    Ok(RetryEntry {
+        event: serde_json::from_str::<WebhookEvent>(&event).map_err(retry_error)?,
+        attempts: attempts.max(0) as u32,
+        next_attempt_at,
+        last_error,
+    })
+}
+
+#[async_trait]
+impl RetryStore for PostgresRetryStore {
+    async fn push(&self, entry: RetryEntry) -> Result<(), WebhookError> {
+        self.insert(entry, false).await
+    }
+
+    async fn take_due(&self, now: i64) -> Result<Vec<RetryEntry>, WebhookError> {
+        sqlx::query_as::<_, RetryRow>(
+            "DELETE FROM stripe_webhook_retries WHERE NOT dead AND next_attempt_at <= $1 \
+             RETURNING event, attempts, next_attempt_at, last_error",
+        )
+        .bind(now)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(retry_error)?
+        .into_iter()
+        .map(retry_entry)
+        .collect()
+    }
+
+    async fn dead_letter(&self, entry: RetryEntry) -> Result<(), WebhookError> {
+        self.insert(entry, true).await
+    }
+
+    async fn dead_letters(&self) -> Result<Vec<RetryEntry>, WebhookError> {
+        sqlx::query_as::<_, RetryRow>(
+            "SELECT event, attempts, next_attempt_at, last_error FROM stripe_webhook_retries \
+             WHERE dead ORDER BY id",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(retry_error)?
+        .into_iter()
+        .map(retry_entry)
+        .collect()
+    }
+}
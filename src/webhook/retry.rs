@@ -0,0 +1,329 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use tokio_util::sync::CancellationToken;
+
+use super::{EventHandler, WebhookError, WebhookEvent};
+
+pub const DEFAULT_BASE_DELAY: Duration = Duration::from_secs(30);
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 8;
+
+/// An event whose handler failed, waiting for its next attempt.
+/// `next_attempt_at` is in unix seconds so stores can persist it.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct RetryEntry {
+    pub event: WebhookEvent,
+    pub attempts: u32,
+    pub next_attempt_at: i64,
+    pub last_error: String,
+}
+
+/// Where failed events wait between attempts. Implement it on top of the
+/// application database to survive restarts.
+#[async_trait]
+pub trait RetryStore: Send + Sync {
+    async fn push(&self, entry: RetryEntry) -> Result<(), WebhookError>;
+
+    /// Removes and returns the entries whose `next_attempt_at` is at or
+    /// before `now`.
+    async fn take_due(&self, now: i64) -> Result<Vec<RetryEntry>, WebhookError>;
+
+    async fn dead_letter(&self, entry: RetryEntry) -> Result<(), WebhookError>;
+
+    async fn dead_letters(&self) -> Result<Vec<RetryEntry>, WebhookError>;
+}
+
+#[derive(Debug, Default)]
+pub struct InMemoryRetryStore {
+    pending: Mutex<Vec<RetryEntry>>,
+    dead: Mutex<Vec<RetryEntry>>,
+}
+
+impl InMemoryRetryStore {
+    pub fn new() -> Self {
+        InMemoryRetryStore::default()
+    }
+}
+
+#[async_trait]
+impl RetryStore for InMemoryRetryStore {
+    async fn push(&self, entry: RetryEntry) -> Result<(), WebhookError> {
+        self.pending
+            .lock()
+            .map_err(|x| WebhookError::RetryStore(x.to_string()))?
+            .push(entry);
+        Ok(())
+    }
+
+    async fn take_due(&self, now: i64) -> Result<Vec<RetryEntry>, WebhookError> {
+        let mut pending = self
+            .pending
+            .lock()
+            .map_err(|x| WebhookError::RetryStore(x.to_string()))?;
+        let (due, waiting) = pending.drain(..).partition(|x| x.next_attempt_at <= now);
+        *pending = waiting;
+        Ok(due)
+    }
+
+    async fn dead_letter(&self, entry: RetryEntry) -> Result<(), WebhookError> {
+        self.dead
+            .lock()
+            .map_err(|x| WebhookError::RetryStore(x.to_string()))?
+            .push(entry);
+        Ok(())
+    }
+
+    async fn dead_letters(&self) -> Result<Vec<RetryEntry>, WebhookError> {
+        self.dead
+            .lock()
+            .map(|x| x.clone())
+            .map_err(|x| WebhookError::RetryStore(x.to_string()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RetryReport {
+    pub succeeded: usize,
+    pub requeued: usize,
+    pub dead_lettered: usize,
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Wraps a handler so that failed events are stored and retried with
+/// exponential backoff instead of being dropped. The endpoint can then answer
+/// Stripe with 200 right away; after `max_attempts` failures an event is moved
+/// to the dead letters for manual inspection.
+pub struct WebhookRetryQueue<H> {
+    handler: H,
+    store: Arc<dyn RetryStore>,
+    base_delay: Duration,
+    max_delay: Duration,
+    max_attempts: u32,
+}
+
+impl<H: EventHandler> WebhookRetryQueue<H> {
+    pub fn new(handler: H) -> Self {
+        WebhookRetryQueue {
+            handler,
+            store: Arc::new(InMemoryRetryStore::new()),
+            base_delay: DEFAULT_BASE_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+        }
+    }
+
+    pub fn with_store(mut self, store: Arc<dyn RetryStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    pub fn with_backoff(mut self, base_delay: Duration, max_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self.max_delay = max_delay;
+        self
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn store(&self) -> &Arc<dyn RetryStore> {
+        &self.store
+    }
+
+    /// Delay before the attempt following `attempts` failed ones.
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        self.base_delay
+            .checked_mul(1 << attempts.saturating_sub(1).min(16))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+
+    async fn failed(
+        &self,
+        event: WebhookEvent,
+        attempts: u32,
+        error: WebhookError,
+        now: i64,
+        report: &mut RetryReport,
+    ) -> Result<(), WebhookError> {
+        let entry = RetryEntry {
+            event,
+            attempts,
+            next_attempt_at: now + self.backoff(attempts).as_secs() as i64,
+            last_error: error.to_string(),
+        };
+        if attempts >= self.max_attempts {
+            tracing::error!(
+                "dead-lettering {} after {} attempts: {}",
+                entry.event.id,
+                attempts,
+                entry.last_error
+            );
+            report.dead_lettered += 1;
+            return self.store.dead_letter(entry).await;
+        }
+        tracing::warn!(
+            "handler failed for {} (attempt {}), retrying at {}: {}",
+            entry.event.id,
+            attempts,
+            entry.next_attempt_at,
+            entry.last_error
+        );
+        report.requeued += 1;
+        self.store.push(entry).await
+    }
+
+    /// Runs the handler once; on failure the event is queued for retry and
+    /// `Ok` is returned, since the event is now safely stored.
+    #[tracing::instrument(skip(self, event), fields(event_id = %event.id))]
+    pub async fn handle_or_enqueue(&self, event: &WebhookEvent) -> Result<(), WebhookError> {
+        match self.handler.handle(event).await {
+            Ok(()) => Ok(()),
+            Err(x) => {
+                self.failed(event.clone(), 1, x, now(), &mut RetryReport::default())
+                    .await
+            }
+        }
+    }
+
+    pub async fn retry_due(&self) -> Result<RetryReport, WebhookError> {
+        self.retry_due_at(now()).await
+    }
+
+    pub async fn retry_due_at(&self, now: i64) -> Result<RetryReport, WebhookError> {
+        let mut report = RetryReport::default();
+        for entry in self.store.take_due(now).await? {
+            match self.handler.handle(&entry.event).await {
+                Ok(()) => report.succeeded += 1,
+                Err(x) => {
+                    self.failed(entry.event, entry.attempts + 1, x, now, &mut report)
+                        .await?
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Background worker: retries due events every `poll_interval` until
+    /// `cancel` fires.
+    pub async fn run(&self, poll_interval: Duration, cancel: CancellationToken) {
+        let mut interval = tokio::time::interval(poll_interval);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                _ = interval.tick() => {
+                    if let Err(x) = self.retry_due().await {
+                        tracing::error!("webhook retry failed: {}", x);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<H: EventHandler> EventHandler for WebhookRetryQueue<H> {
+    async fn handle(&self, event: &WebhookEvent) -> Result<(), WebhookError> {
+        self.handle_or_enqueue(event).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Noop;
+
+    #[async_trait]
+    impl EventHandler for Noop {
+        async fn handle(&self, _: &WebhookEvent) -> Result<(), WebhookError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let queue = WebhookRetryQueue::new(Noop)
+            .with_backoff(Duration::from_secs(10), Duration::from_secs(60));
+        assert_eq!(queue.backoff(1), Duration::from_secs(10));
+        assert_eq!(queue.backoff(2), Duration::from_secs(20));
+        assert_eq!(queue.backoff(3), Duration::from_secs(40));
+        assert_eq!(queue.backoff(4), Duration::from_secs(60));
+        assert_eq!(queue.backoff(100), Duration::from_secs(60));
+    }
+
+    struct FailsForBad;
+
+    #[async_trait]
+    impl EventHandler for FailsForBad {
+        async fn handle(&self, event: &WebhookEvent) -> Result<(), WebhookError> {
+            match event.id.starts_with("evt_bad") {
+                true => Err(WebhookError::Handler("down".to_string())),
+                false => Ok(()),
+            }
+        }
+    }
+
+    fn entry(id: &str, next_attempt_at: i64) -> RetryEntry {
+        RetryEntry {
+            event: WebhookEvent::new(id, "invoice.paid", serde_json::json!({ "id": "in_1" })),
+            attempts: 1,
+            next_attempt_at,
+            last_error: "down".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn requeues_then_dead_letters_after_max_attempts() {
+        let store = Arc::new(InMemoryRetryStore::new());
+        let queue = WebhookRetryQueue::new(FailsForBad)
+            .with_store(store.clone())
+            .with_backoff(Duration::from_secs(10), Duration::from_secs(60))
+            .with_max_attempts(3);
+        store.push(entry("evt_good", 100)).await.unwrap();
+        store.push(entry("evt_bad_1", 100)).await.unwrap();
+        store.push(entry("evt_bad_2", 500)).await.unwrap();
+
+        assert_eq!(
+            queue.retry_due_at(100).await.unwrap(),
+            RetryReport {
+                succeeded: 1,
+                requeued: 1,
+                dead_lettered: 0,
+            }
+        );
+        assert_eq!(
+            queue.retry_due_at(119).await.unwrap(),
+            RetryReport::default()
+        );
+        assert_eq!(
+            queue.retry_due_at(120).await.unwrap(),
+            RetryReport {
+                succeeded: 0,
+                requeued: 0,
+                dead_lettered: 1,
+            }
+        );
+        let dead = store.dead_letters().await.unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].event.id, "evt_bad_1");
+        assert_eq!(dead[0].attempts, 3);
+        assert_eq!(dead[0].last_error, "event handler failed: down");
+
+        let waiting = store.take_due(i64::MAX).await.unwrap();
+        assert_eq!(waiting.len(), 1);
+        assert_eq!(waiting[0].event.id, "evt_bad_2");
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures_util::future::join_all;
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+use super::{WebhookError, WebhookEvent};
+
+pub const DEFAULT_SETTLE_WINDOW: Duration = Duration::from_secs(5);
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+#[async_trait]
+pub trait EventHandler: Send + Sync {
+    async fn handle(&self, event: &WebhookEvent) -> Result<(), WebhookError>;
+}
+
+type SortKey = (i64, u8);
+
+#[derive(Debug)]
+struct Pending {
+    at: Instant,
+    /// Failed attempts so far.
+    attempts: u32,
+    event: WebhookEvent,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    pending: HashMap<String, Vec<Pending>>,
+    /// Kept for one settle window after the object's last event was handled.
+    last_processed: HashMap<String, (Instant, SortKey)>,
+    /// Objects whose events are being handled; they're not taken again until
+    /// that finishes, so one object's events are never handled concurrently.
+    in_flight: HashSet<String>,
+}
+
+struct Batch {
+    object_id: String,
+    events: Vec<(u32, WebhookEvent)>,
+    last: Option<SortKey>,
+}
+
+impl State {
+    fn take_ready(&mut self, now: Instant, settle_window: Duration, force: bool) -> Vec<Batch> {
+        self.last_processed
+            .retain(|_, (at, _)| now.duration_since(*at) < settle_window);
+        let ready = self
+            .pending
+            .iter()
+            .filter(|(id, events)| {
+                !self.in_flight.contains(*id)
+                    && (force
+                        || events
+                            .iter()
+                            .map(|x| x.at)
+                            .min()
+                            .map(|x| now.duration_since(x) >= settle_window)
+                            .unwrap_or(false))
+            })
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        ready
+            .into_iter()
+            .map(|object_id| {
+                let mut events = self
+                    .pending
+                    .remove(&object_id)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|x| (x.attempts, x.event))
+                    .collect::<Vec<_>>();
+                events.sort_by_key(|(_, x)| sort_key(x));
+                self.in_flight.insert(object_id.clone());
+                Batch {
+                    last: self.last_processed.get(&object_id).map(|(_, x)| *x),
+                    object_id,
+                    events,
+                }
+            })
+            .collect()
+    }
+
+    /// Records what [`SequentialEventProcessor`] handled for `object_id` and
+    /// puts back the events it didn't get to, ahead of any that arrived since.
+    fn finish(
+        &mut self,
+        object_id: String,
+        last: Option<SortKey>,
+        now: Instant,
+        unhandled: Vec<(u32, WebhookEvent)>,
+    ) {
+        self.in_flight.remove(&object_id);
+        if let Some(last) = last {
+            self.last_processed.insert(object_id.clone(), (now, last));
+        }
+        if !unhandled.is_empty() {
+            let pending = self.pending.entry(object_id).or_default();
+            let arrived = std::mem::take(pending);
+            pending.extend(unhandled.into_iter().map(|(attempts, event)| Pending {
+                at: now,
+                attempts,
+                event,
+            }));
+            pending.extend(arrived);
+        }
+    }
+}
+
+/// Buffers events per object id for a settle window and hands them to the
+/// handler ordered by creation time, so `payment_intent.created` is always seen
+/// before `payment_intent.succeeded` even if Stripe delivers them the other way round.
+/// Events older than the last processed one for the same object are dropped.
+///
+/// Events are handed over from [`Self::push`] once their window has passed, so
+/// the last events of a burst wait for the next push; call
+/// [`Self::process_ready`] periodically or spawn [`Self::run`] to pick them up.
+/// A failed event is retried, with the object's later events held back behind
+/// it, until it has failed `max_attempts` times; it is then logged and dropped.
+#[derive(Debug)]
+pub struct SequentialEventProcessor<H> {
+    handler: H,
+    settle_window: Duration,
+    max_attempts: u32,
+    state: Mutex<State>,
+}
+
+impl<H: EventHandler> SequentialEventProcessor<H> {
+    pub fn new(handler: H) -> Self {
+        SequentialEventProcessor {
+            handler,
+            settle_window: DEFAULT_SETTLE_WINDOW,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn with_settle_window(mut self, settle_window: Duration) -> Self {
+        self.settle_window = settle_window;
+        self
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    #[tracing::instrument(skip(self, event), fields(event_id = %event.id))]
+    pub async fn push(&self, event: WebhookEvent) -> Result<usize, WebhookError> {
+        let object_id = match event.object_id() {
+            Some(x) => x.to_string(),
+            None => {
+                self.handler.handle(&event).await?;
+                return Ok(1);
+            }
+        };
+        self.state
+            .lock()
+            .await
+            .pending
+            .entry(object_id)
+            .or_default()
+            .push(Pending {
+                at: Instant::now(),
+                attempts: 0,
+                event,
+            });
+        self.process_ready().await
+    }
+
+    pub async fn process_ready(&self) -> Result<usize, WebhookError> {
+        self.process(false).await
+    }
+
+    pub async fn flush(&self) -> Result<usize, WebhookError> {
+        self.process(true).await
+    }
+
+    /// Background worker: hands over settled events every `poll_interval`
+    /// until `cancel` fires, then flushes whatever is still buffered.
+    pub async fn run(&self, poll_interval: Duration, cancel: CancellationToken) {
+        let mut interval = tokio::time::interval(poll_interval);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => {
+                    if let Err(x) = self.flush().await {
+                        tracing::error!("flushing webhook events failed: {}", x);
+                    }
+                    return;
+                }
+                _ = interval.tick() => {
+                    if let Err(x) = self.process_ready().await {
+                        tracing::error!("processing webhook events failed: {}", x);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The lock is only held to take the ready events out and to record the
+    /// outcome; objects are handled concurrently, each in order.
+    async fn process(&self, force: bool) -> Result<usize, WebhookError> {
+        let batches = self
+            .state
+            .lock()
+            .await
+            .take_ready(Instant::now(), self.settle_window, force);
+        let mut processed = 0;
+        let mut error = None;
+        for x in join_all(batches.into_iter().map(|x| self.dispatch(x))).await {
+            match x {
+                Ok(x) => processed += x,
+                Err(x) => error = error.or(Some(x)),
+            }
+        }
+        match error {
+            Some(x) => Err(x),
+            None => Ok(processed),
+        }
+    }
+
+    async fn dispatch(&self, batch: Batch) -> Result<usize, WebhookError> {
+        let Batch {
+            object_id,
+            events,
+            mut last,
+        } = batch;
+        let mut processed = 0;
+        let mut remaining = events.into_iter();
+        let mut result = Ok(());
+        let mut unhandled = Vec::new();
+        while let Some((attempts, event)) = remaining.next() {
+            let key = sort_key(&event);
+            if last.map(|x| key < x).unwrap_or(false) {
+                tracing::warn!(
+                    "dropping stale {} {} for {}",
+                    event.event_type,
+                    event.id,
+                    object_id
+                );
+                continue;
+            }
+            if let Err(e) = self.handler.handle(&event).await {
+                let attempts = attempts + 1;
+                if attempts < self.max_attempts {
+                    unhandled = std::iter::once((attempts, event))
+                        .chain(remaining)
+                        .collect();
+                    result = Err(e);
+                    break;
+                }
+                tracing::error!(
+                    "dropping {} {} for {} after {} failed attempts: {}",
+                    event.event_type,
+                    event.id,
+                    object_id,
+                    attempts,
+                    e
+                );
+                result = Err(e);
+                continue;
+            }
+            last = Some(key);
+            processed += 1;
+        }
+        self.state
+            .lock()
+            .await
+            .finish(object_id, last, Instant::now(), unhandled);
+        result.map(|_| processed)
+    }
+}
+
+/// Lets a processor be used wherever a plain handler is expected, e.g. by the
+/// framework integrations; events are buffered as with [`Self::push`]. Only
+/// failures of the events handed over during the push are returned, so
+/// [`WebhookVerifier::dispatch`](super::WebhookVerifier::dispatch) can't
+/// release an event that fails later and Stripe won't redeliver it. Wrap the
+/// inner handler in a [`WebhookRetryQueue`](super::retry::WebhookRetryQueue)
+/// to keep those events instead of dropping them after `max_attempts`.
+#[async_trait]
+impl<H: EventHandler> EventHandler for SequentialEventProcessor<H> {
+    async fn handle(&self, event: &WebhookEvent) -> Result<(), WebhookError> {
+        self.push(event.clone()).await.map(|_| ())
+    }
+}
+
+fn lifecycle_rank(event_type: &str) -> u8 {
+    let action = event_type.rsplit('.').next().unwrap_or_default();
+    match action {
+        "created" => 0,
+        "succeeded" | "canceled" | "payment_failed" | "deleted" | "paid" | "closed" => 2,
+        _ => 1,
+    }
+}
+
+fn sort_key(event: &WebhookEvent) -> SortKey {
+    (event.created, lifecycle_rank(&event.event_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(id: &str, event_type: &str, created: i64) -> WebhookEvent {
+        WebhookEvent::new(id, event_type, serde_json::json!({ "id": "pi_1" })).with_created(created)
+    }
+
+    fn pending(at: Instant, id: &str, created: i64) -> Pending {
+        Pending {
+            at,
+            attempts: 0,
+            event: event(id, "payment_intent.updated", created),
+        }
+    }
+
+    struct Fails;
+
+    #[async_trait]
+    impl EventHandler for Fails {
+        async fn handle(&self, _: &WebhookEvent) -> Result<(), WebhookError> {
+            Err(WebhookError::Handler("down".to_string()))
+        }
+    }
+
+    #[test]
+    fn orders_by_creation_then_lifecycle() {
+        let mut events = vec![
+            event("evt_3", "payment_intent.succeeded", 10),
+            event("evt_2", "payment_intent.requires_action", 10),
+            event("evt_1", "payment_intent.created", 10),
+            event("evt_0", "payment_intent.amount_capturable_updated", 9),
+        ];
+        events.sort_by_key(sort_key);
+        let ids = events.iter().map(|x| x.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, vec!["evt_0", "evt_1", "evt_2", "evt_3"]);
+    }
+
+    #[test]
+    fn takes_settled_objects_once_and_prunes_history() {
+        let window = Duration::from_secs(5);
+        let start = Instant::now();
+        let mut state = State::default();
+        state
+            .pending
+            .insert("pi_1".to_string(), vec![pending(start, "evt_1", 1)]);
+        assert!(state.take_ready(start, window, false).is_empty());
+
+        let later = start + window;
+        let batch = state.take_ready(later, window, false);
+        assert_eq!(batch.len(), 1);
+        state
+            .pending
+            .insert("pi_1".to_string(), vec![pending(later, "evt_2", 2)]);
+        assert!(state.take_ready(later, window, true).is_empty());
+
+        state.finish("pi_1".to_string(), Some((1, 0)), later, Vec::new());
+        assert!(state.last_processed.contains_key("pi_1"));
+        assert_eq!(state.take_ready(later, window, true)[0].last, Some((1, 0)));
+        state.finish("pi_1".to_string(), None, later, Vec::new());
+        assert!(state.take_ready(later + window, window, true).is_empty());
+        assert!(state.last_processed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn drops_an_event_after_max_attempts() {
+        let processor = SequentialEventProcessor::new(Fails)
+            .with_settle_window(Duration::ZERO)
+            .with_max_attempts(2);
+        assert!(processor
+            .push(event("evt_1", "payment_intent.created", 1))
+            .await
+            .is_err());
+        assert_eq!(processor.state.lock().await.pending["pi_1"][0].attempts, 1);
+        assert!(processor.process_ready().await.is_err());
+        assert!(processor.state.lock().await.pending.is_empty());
+        assert_eq!(processor.process_ready().await.unwrap(), 0);
+    }
+}